@@ -0,0 +1,22 @@
+/**
+Convert an HRIR set from the text `.hrir` format or a SOFA file to the binary `.hrir` format.
+
+Usage: cargo run --example convert_hrir <input.hrir|input.sofa> <output.hrir>
+*/
+use ambisonic::HrtfConfig;
+use std::fs::File;
+
+fn main() -> Result<(), ambisonic::Error> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: convert_hrir <input.hrir|input.sofa> <output.hrir>");
+        std::process::exit(1);
+    }
+
+    let config = if args[1].ends_with(".sofa") {
+        HrtfConfig::from_sofa(&args[1])?
+    } else {
+        HrtfConfig::from_file(&args[1])?
+    };
+    config.write_binary(File::create(&args[2])?)
+}
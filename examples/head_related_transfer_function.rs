@@ -2,9 +2,11 @@ use ambisonic::{sources, AmbisonicBuilder, HrtfConfig};
 use std::thread::sleep;
 use std::time::Duration;
 
-fn main() {
+fn main() -> Result<(), ambisonic::Error> {
     let cfg = HrtfConfig::default();
-    let scene = AmbisonicBuilder::default().with_config(cfg.into()).build();
+    let scene = AmbisonicBuilder::default()
+        .with_config(cfg.into())
+        .build()?;
 
     let source = sources::Noise::new(48000);
 
@@ -14,4 +16,6 @@ fn main() {
         sound.adjust_position([(500 - i) as f32 / 10.0, 1.0, 0.0]);
         sleep(Duration::from_millis(10));
     }
+
+    Ok(())
 }
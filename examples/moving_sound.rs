@@ -2,8 +2,8 @@ use ambisonic::AmbisonicBuilder;
 use std::thread::sleep;
 use std::time::Duration;
 
-fn main() {
-    let scene = AmbisonicBuilder::default().build();
+fn main() -> Result<(), ambisonic::Error> {
+    let scene = AmbisonicBuilder::default().build()?;
 
     let source = rodio::source::SineWave::new(440);
     let mut sound = scene.play_at(source, [50.0, 1.0, 0.0]);
@@ -15,4 +15,6 @@ fn main() {
         sleep(Duration::from_millis(10));
     }
     sound.set_velocity([0.0, 0.0, 0.0]);
+
+    Ok(())
 }
@@ -2,8 +2,8 @@ use ambisonic::AmbisonicBuilder;
 use std::thread::sleep;
 use std::time::Duration;
 
-fn main() {
-    let scene = AmbisonicBuilder::new().build();
+fn main() -> Result<(), ambisonic::Error> {
+    let scene = AmbisonicBuilder::new().build()?;
 
     let source = rodio::source::SineWave::new(440);
     let mut first = scene.play_omni(source);
@@ -26,4 +26,6 @@ fn main() {
     drop(scene);
 
     sleep(Duration::from_millis(1000));
+
+    Ok(())
 }
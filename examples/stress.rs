@@ -6,9 +6,9 @@ use ambisonic::AmbisonicBuilder;
 use std::thread::sleep;
 use std::time::Duration;
 
-fn main() {
+fn main() -> Result<(), ambisonic::Error> {
     use rodio::Source;
-    let scene = AmbisonicBuilder::default().build();
+    let scene = AmbisonicBuilder::default().build()?;
 
     for _ in 0..500 {
         let source = rodio::source::SineWave::new(440).amplify(0.001);
@@ -16,4 +16,6 @@ fn main() {
     }
 
     sleep(Duration::from_secs(10));
+
+    Ok(())
 }
@@ -0,0 +1,539 @@
+//! Reading and writing decoder presets in the AmbDec file format.
+//!
+//! AmbDec presets describe a loudspeaker layout together with one decoding matrix (single-band)
+//! or two matrices for low and high frequencies (dual-band). Matrix columns are *B-format*
+//! channels in ACN order, selected by the preset's channel mask and scaled according to its
+//! coefficient normalization.
+
+use std::f32::consts::PI;
+use std::fmt::Write;
+use std::fs;
+
+use crate::bformat::{n_channels, Bweights, MAX_CHANNELS, MAX_ORDER};
+use crate::convention::{ChannelOrder, Convention, Normalization};
+use crate::dualband::max_re_weights;
+use crate::error::Error;
+use crate::speakers::{SpeakerConfig, SpeakerLayout};
+
+impl SpeakerConfig {
+    /// Load a decoder preset from an `.ambdec` file.
+    ///
+    /// Speaker directions, decoding matrices, and the dual-band crossover are taken from the
+    /// preset. Speaker distances are compensated if the preset enables delay or level
+    /// compensation. Near-field compensation settings are ignored.
+    pub fn from_ambdec(filename: &str) -> Result<Self, Error> {
+        let data = fs::read_to_string(filename)?;
+        SpeakerConfig::parse_ambdec(&data)
+    }
+
+    /// Parse a decoder preset in AmbDec format.
+    ///
+    /// See `from_ambdec`.
+    pub fn parse_ambdec(text: &str) -> Result<Self, Error> {
+        parse(text)
+    }
+
+    /// Export the decoder in AmbDec format.
+    ///
+    /// Computed dual-band decoders are written as a single matrix with max-rE weights on the
+    /// high band. Coefficients use ACN order and N3D normalization.
+    pub fn to_ambdec(&self) -> String {
+        let order = self.order();
+        let n = n_channels(order);
+        let convention = Convention {
+            channel_order: ChannelOrder::Acn,
+            normalization: Normalization::N3d,
+        };
+        let matrix = self.decoder();
+        let hf = match (self.hf_decoder(), self.crossover()) {
+            (Some(hf), _) => Some((hf.to_vec(), [1.0; MAX_ORDER + 1])),
+            (None, Some(_)) => Some((matrix.clone(), max_re_weights(order))),
+            (None, None) => None,
+        };
+        let layout = self.layout();
+        let compensate = if layout.distances().is_some() {
+            "on"
+        } else {
+            "off"
+        };
+
+        let mut out = String::new();
+        writeln!(out, "# AmbDec configuration").unwrap();
+        writeln!(out, "# Written by the ambisonic crate").unwrap();
+        writeln!(out).unwrap();
+        writeln!(out, "/description      ambisonic_order_{}", order).unwrap();
+        writeln!(out).unwrap();
+        writeln!(out, "/version          3").unwrap();
+        writeln!(out).unwrap();
+        writeln!(out, "/dec/chan_mask    {:x}", (1u32 << n) - 1).unwrap();
+        writeln!(
+            out,
+            "/dec/freq_bands   {}",
+            if hf.is_some() { 2 } else { 1 }
+        )
+        .unwrap();
+        writeln!(out, "/dec/speakers     {}", layout.len()).unwrap();
+        writeln!(out, "/dec/coeff_scale  n3d").unwrap();
+        writeln!(out).unwrap();
+        writeln!(out, "/opt/input_scale  n3d").unwrap();
+        writeln!(out, "/opt/nfeff_comp   none").unwrap();
+        writeln!(out, "/opt/delay_comp   {}", compensate).unwrap();
+        writeln!(out, "/opt/level_comp   {}", compensate).unwrap();
+        writeln!(
+            out,
+            "/opt/xover_freq   {}",
+            self.crossover().unwrap_or(400.0)
+        )
+        .unwrap();
+        writeln!(out, "/opt/xover_ratio  0.0").unwrap();
+        writeln!(out).unwrap();
+
+        writeln!(out, "/speakers/{{").unwrap();
+        for (i, d) in layout.directions().iter().enumerate() {
+            let distance = layout.distances().map_or(1.0, |dist| dist[i]);
+            let azimuth = (-d[0]).atan2(d[1]) * 180.0 / PI;
+            let elevation = d[2].clamp(-1.0, 1.0).asin() * 180.0 / PI;
+            writeln!(
+                out,
+                "add_spkr    S{}    {:.3}    {:.3}    {:.3}    system:playback_{}",
+                i + 1,
+                distance,
+                azimuth,
+                elevation,
+                i + 1
+            )
+            .unwrap();
+        }
+        writeln!(out, "/}}").unwrap();
+        writeln!(out).unwrap();
+
+        let write_matrix = |out: &mut String, name: &str, rows: &[Bweights], gains: &[f32]| {
+            writeln!(out, "/{}/{{", name).unwrap();
+            write!(out, "order_gain").unwrap();
+            for g in gains {
+                write!(out, "  {:.6}", g).unwrap();
+            }
+            writeln!(out).unwrap();
+            for w in rows {
+                let mut coefficients = w.decoding_gains(convention);
+                coefficients.resize(n, 0.0);
+                write!(out, "add_row").unwrap();
+                for c in coefficients {
+                    write!(out, "  {:.6}", c).unwrap();
+                }
+                writeln!(out).unwrap();
+            }
+            writeln!(out, "/}}").unwrap();
+            writeln!(out).unwrap();
+        };
+
+        match hf {
+            None => write_matrix(&mut out, "matrix", &matrix, &[1.0; MAX_ORDER + 1]),
+            Some((hf, hf_gains)) => {
+                write_matrix(&mut out, "lfmatrix", &matrix, &[1.0; MAX_ORDER + 1]);
+                write_matrix(&mut out, "hfmatrix", &hf, &hf_gains);
+            }
+        }
+
+        writeln!(out, "/end").unwrap();
+        out
+    }
+}
+
+/// Decoding matrix as given in the preset
+#[derive(Default)]
+struct Matrix {
+    order_gain: Vec<f32>,
+    rows: Vec<Vec<f32>>,
+}
+
+enum Section {
+    Header,
+    Speakers,
+    Matrix(usize),
+}
+
+const LOW: usize = 0;
+const HIGH: usize = 1;
+
+fn parse(text: &str) -> Result<SpeakerConfig, Error> {
+    let mut chan_mask = None;
+    let mut freq_bands = None;
+    let mut n_speakers = None;
+    let mut coeff_scale = None;
+    let mut xover_freq = None;
+    let mut xover_ratio = 0.0;
+    let mut delay_comp = false;
+    let mut level_comp = false;
+    let mut speakers = vec![];
+    let mut matrices: [Option<Matrix>; 2] = [None, None];
+    let mut single_band = false;
+
+    let mut section = Section::Header;
+    let mut finished = false;
+
+    for (i, raw) in text.lines().enumerate() {
+        let err = |token: &str, msg: String| Error::at_token(i + 1, raw, token, msg);
+        let line = raw.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        if finished {
+            return Err(err(line, "unexpected content after /end".to_string()));
+        }
+        let mut tokens = line.split_whitespace();
+        let key = tokens.next().unwrap();
+        let args: Vec<&str> = tokens.collect();
+        let arg = |n: usize| -> Result<&str, Error> {
+            args.get(n)
+                .cloned()
+                .ok_or_else(|| err(&line[line.len()..], format!("missing argument for {}", key)))
+        };
+        let number = |s: &str| -> Result<f32, Error> {
+            s.parse()
+                .ok()
+                .filter(|x: &f32| x.is_finite())
+                .ok_or_else(|| err(s, format!("invalid number '{}'", s)))
+        };
+        let on_off = |s: &str| -> Result<bool, Error> {
+            match s {
+                "on" => Ok(true),
+                "off" => Ok(false),
+                _ => Err(err(s, format!("expected 'on' or 'off', found '{}'", s))),
+            }
+        };
+
+        match section {
+            Section::Speakers => match key {
+                "add_spkr" => {
+                    let distance = number(arg(1)?)?;
+                    let azimuth = number(arg(2)?)?;
+                    let elevation = number(arg(3)?)?;
+                    speakers.push((distance, azimuth, elevation));
+                }
+                "/}" => section = Section::Header,
+                _ => return Err(err(key, format!("unexpected '{}' in speaker list", key))),
+            },
+            Section::Matrix(band) => {
+                let matrix = matrices[band].get_or_insert_with(Matrix::default);
+                match key {
+                    "order_gain" => {
+                        matrix.order_gain =
+                            args.iter().map(|s| number(s)).collect::<Result<_, _>>()?;
+                    }
+                    "add_row" => {
+                        let row = args.iter().map(|s| number(s)).collect::<Result<_, _>>()?;
+                        matrix.rows.push(row);
+                    }
+                    "/}" => section = Section::Header,
+                    _ => return Err(err(key, format!("unexpected '{}' in matrix", key))),
+                }
+            }
+            Section::Header => match key {
+                "/description" | "/opt/input_scale" | "/opt/nfeff_comp" => {}
+                "/version" => {
+                    let s = arg(0)?;
+                    if s != "3" {
+                        return Err(err(s, format!("unsupported version {}", s)));
+                    }
+                }
+                "/dec/chan_mask" => {
+                    let s = arg(0)?;
+                    let mask = u32::from_str_radix(s, 16)
+                        .map_err(|_| err(s, format!("invalid channel mask '{}'", s)))?;
+                    if mask == 0 || mask >> MAX_CHANNELS != 0 {
+                        return Err(err(
+                            s,
+                            format!(
+                                "channel mask '{}' is empty or exceeds order {}",
+                                s, MAX_ORDER
+                            ),
+                        ));
+                    }
+                    chan_mask = Some(mask);
+                }
+                "/dec/freq_bands" => {
+                    freq_bands = match arg(0)? {
+                        "1" => Some(1),
+                        "2" => Some(2),
+                        n => return Err(err(n, format!("unsupported number of bands {}", n))),
+                    }
+                }
+                "/dec/speakers" => {
+                    let s = arg(0)?;
+                    n_speakers = Some(
+                        s.parse::<usize>()
+                            .map_err(|_| err(s, format!("invalid speaker count '{}'", s)))?,
+                    )
+                }
+                "/dec/coeff_scale" => {
+                    coeff_scale = Some(match arg(0)? {
+                        "n3d" => Normalization::N3d,
+                        "sn3d" => Normalization::Sn3d,
+                        "fuma" => Normalization::FuMa,
+                        s => return Err(err(s, format!("unknown coefficient scale '{}'", s))),
+                    })
+                }
+                "/opt/delay_comp" => delay_comp = on_off(arg(0)?)?,
+                "/opt/level_comp" => level_comp = on_off(arg(0)?)?,
+                "/opt/xover_freq" => xover_freq = Some(number(arg(0)?)?),
+                "/opt/xover_ratio" => xover_ratio = number(arg(0)?)?,
+                "/speakers/{" => section = Section::Speakers,
+                "/matrix/{" => {
+                    single_band = true;
+                    section = Section::Matrix(LOW);
+                }
+                "/lfmatrix/{" => section = Section::Matrix(LOW),
+                "/hfmatrix/{" => section = Section::Matrix(HIGH),
+                "/end" => finished = true,
+                k if k.starts_with("/opt/") => {}
+                _ => return Err(err(key, format!("unknown key '{}'", key))),
+            },
+        }
+    }
+
+    let invalid = |msg: String| Error::InvalidData(format!("invalid AmbDec preset: {}", msg));
+    let missing = |key: &str| invalid(format!("missing {}", key));
+    if !finished {
+        return Err(missing("/end"));
+    }
+    let chan_mask = chan_mask.ok_or_else(|| missing("/dec/chan_mask"))?;
+    let freq_bands = freq_bands.ok_or_else(|| missing("/dec/freq_bands"))?;
+    let n_speakers = n_speakers.ok_or_else(|| missing("/dec/speakers"))?;
+    let coeff_scale = coeff_scale.ok_or_else(|| missing("/dec/coeff_scale"))?;
+
+    if n_speakers == 0 {
+        return Err(invalid("no speakers".to_string()));
+    }
+    if speakers.len() != n_speakers {
+        return Err(invalid(format!(
+            "expected {} speakers, found {}",
+            n_speakers,
+            speakers.len()
+        )));
+    }
+    if single_band != (freq_bands == 1) {
+        return Err(invalid(
+            "matrix sections do not match the number of bands".to_string(),
+        ));
+    }
+
+    let channels: Vec<usize> = (0..MAX_CHANNELS)
+        .filter(|c| chan_mask & (1 << c) != 0)
+        .collect();
+    let order = degree(*channels.last().unwrap());
+    let convention = Convention {
+        channel_order: ChannelOrder::Acn,
+        normalization: coeff_scale,
+    };
+
+    let to_weights = |matrix: &Matrix, gain: f32| -> Result<Vec<Bweights>, Error> {
+        if matrix.rows.len() != n_speakers {
+            return Err(invalid(format!(
+                "expected {} matrix rows, found {}",
+                n_speakers,
+                matrix.rows.len()
+            )));
+        }
+        matrix
+            .rows
+            .iter()
+            .map(|row| {
+                if row.len() != channels.len() {
+                    return Err(invalid(format!(
+                        "expected {} coefficients per row, found {}",
+                        channels.len(),
+                        row.len()
+                    )));
+                }
+                let mut gains = vec![0.0; n_channels(order)];
+                for (&c, &x) in channels.iter().zip(row) {
+                    let order_gain = matrix.order_gain.get(degree(c)).cloned().unwrap_or(1.0);
+                    gains[c] = x * order_gain * gain;
+                }
+                Ok(Bweights::from_decoding_gains(&gains, convention))
+            })
+            .collect()
+    };
+
+    let layout = SpeakerLayout::from_angles(
+        &speakers
+            .iter()
+            .map(|&(_, az, el)| (az * PI / 180.0, el * PI / 180.0))
+            .collect::<Vec<_>>(),
+    );
+    let layout = if delay_comp || level_comp {
+        if speakers.iter().any(|&(d, _, _)| d <= 0.0) {
+            return Err(invalid("speaker distances must be positive".to_string()));
+        }
+        layout.with_distances(speakers.iter().map(|&(d, _, _)| d).collect())
+    } else {
+        layout
+    };
+    let config = SpeakerConfig::new(layout);
+
+    let low = matrices[LOW]
+        .as_ref()
+        .ok_or_else(|| missing("decoding matrix"))?;
+    if freq_bands == 1 {
+        return Ok(config.with_matrices(to_weights(low, 1.0)?, None));
+    }
+
+    let high = matrices[HIGH]
+        .as_ref()
+        .ok_or_else(|| missing("/hfmatrix/"))?;
+    let xover_freq = xover_freq.ok_or_else(|| missing("/opt/xover_freq"))?;
+    if xover_freq <= 0.0 {
+        return Err(invalid(format!(
+            "invalid crossover frequency {}",
+            xover_freq
+        )));
+    }
+    // the ratio (in dB) of high to low band gain is split evenly between both bands
+    let ratio = 10f32.powf(xover_ratio / 40.0);
+    let low = to_weights(low, 1.0 / ratio)?;
+    let high = to_weights(high, ratio)?;
+    Ok(config
+        .with_dual_band(xover_freq)
+        .with_matrices(low, Some(high)))
+}
+
+/// Degree of the ACN channel `c`
+fn degree(c: usize) -> usize {
+    (c as f32).sqrt() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SQUARE: &str = "
+# AmbDec configuration
+
+/description      square
+
+/version          3
+
+/dec/chan_mask    b
+/dec/freq_bands   2
+/dec/speakers     4
+/dec/coeff_scale  sn3d
+
+/opt/input_scale  sn3d
+/opt/nfeff_comp   input
+/opt/delay_comp   on
+/opt/level_comp   on
+/opt/xover_freq   400
+/opt/xover_ratio  0.0
+
+/speakers/{
+add_spkr    LF    2.000    45.0    0.0    system:playback_1
+add_spkr    RF    2.000   -45.0    0.0    system:playback_2
+add_spkr    RB    1.500  -135.0    0.0    system:playback_3
+add_spkr    LB    2.000   135.0    0.0    system:playback_4
+/}
+
+/lfmatrix/{
+order_gain     1.0  1.0  0.0  0.0
+add_row        0.25  0.25  0.25
+add_row        0.25 -0.25  0.25
+add_row        0.25 -0.25 -0.25
+add_row        0.25  0.25 -0.25
+/}
+
+/hfmatrix/{
+order_gain     1.0  0.5  0.0  0.0
+add_row        0.25  0.25  0.25
+add_row        0.25 -0.25  0.25
+add_row        0.25 -0.25 -0.25
+add_row        0.25  0.25 -0.25
+/}
+
+/end
+";
+
+    fn decode(weights: &[Bweights], dir: [f32; 3]) -> Vec<f32> {
+        let sample = Bweights::from_direction(dir, 1).scale(1.0);
+        weights.iter().map(|w| w.dot(sample)).collect()
+    }
+
+    #[test]
+    fn parse_dual_band_preset() {
+        let config = SpeakerConfig::parse_ambdec(SQUARE).unwrap();
+        assert_eq!(config.layout().len(), 4);
+        assert_eq!(config.order(), 1);
+        assert_eq!(config.crossover(), Some(400.0));
+        assert_eq!(config.layout().distances(), Some(&[2.0, 2.0, 1.5, 2.0][..]));
+
+        let d = config.layout().directions()[0];
+        assert!((d[0] + 0.5f32.sqrt()).abs() < 1e-6 && (d[1] - 0.5f32.sqrt()).abs() < 1e-6);
+
+        // an SN3D plane wave from the left has W = Y = 1 and X = 0
+        let low = decode(&config.decoder(), [-1.0, 0.0, 0.0]);
+        let expected = [0.5, 0.0, 0.0, 0.5];
+        for (l, e) in low.iter().zip(&expected) {
+            assert!((l - e).abs() < 1e-5, "{:?}", low);
+        }
+
+        let high = decode(config.hf_decoder().unwrap(), [-1.0, 0.0, 0.0]);
+        let expected = [0.375, 0.125, 0.125, 0.375];
+        for (h, e) in high.iter().zip(&expected) {
+            assert!((h - e).abs() < 1e-5, "{:?}", high);
+        }
+    }
+
+    #[test]
+    fn export_roundtrip() {
+        for config in &[
+            SpeakerConfig::parse_ambdec(SQUARE).unwrap(),
+            SpeakerConfig::new(SpeakerLayout::surround_5_0()).with_order(2),
+            SpeakerConfig::new(SpeakerLayout::cube()).with_dual_band(500.0),
+        ] {
+            let loaded = SpeakerConfig::parse_ambdec(&config.to_ambdec()).unwrap();
+            assert_eq!(loaded.order(), config.order());
+            assert_eq!(loaded.crossover(), config.crossover());
+
+            for (a, b) in loaded
+                .layout()
+                .directions()
+                .iter()
+                .zip(config.layout().directions())
+            {
+                assert!(a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-4));
+            }
+
+            for (a, b) in loaded.decoder().iter().zip(config.decoder()) {
+                let (a, b) = (a.components(), b.components());
+                assert!(a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-5));
+            }
+        }
+    }
+
+    #[test]
+    fn report_position_of_error() {
+        match SpeakerConfig::parse_ambdec("/version 3\n\n/dec/chan_mask  xyz\n/end\n") {
+            Err(Error::Parse { line, column, .. }) => assert_eq!((line, column), (3, 17)),
+            _ => panic!("expected a parse error"),
+        }
+        match SpeakerConfig::parse_ambdec("/version 3\n") {
+            Err(Error::InvalidData(msg)) => assert!(msg.contains("missing /end"), "{}", msg),
+            _ => panic!("expected missing /end"),
+        }
+    }
+
+    #[test]
+    fn reject_degenerate_presets() {
+        let invalid =
+            |from: &str, to: &str| match SpeakerConfig::parse_ambdec(&SQUARE.replace(from, to)) {
+                Err(Error::InvalidData(msg)) => msg,
+                _ => panic!("expected invalid data for '{}'", to),
+            };
+        assert!(invalid("/dec/speakers     4", "/dec/speakers 0").contains("no speakers"));
+        assert!(invalid("xover_freq   400", "xover_freq 0").contains("crossover"));
+        assert!(invalid("xover_freq   400", "xover_freq -400").contains("crossover"));
+        assert!(invalid("1.500", "0.000").contains("distances"));
+
+        let non_finite = SpeakerConfig::parse_ambdec(&SQUARE.replace("400", "inf"));
+        assert!(matches!(non_finite, Err(Error::Parse { .. })));
+    }
+}
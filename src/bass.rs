@@ -0,0 +1,154 @@
+//! Bass management for loudspeaker playback.
+
+use crate::filter::Crossover;
+
+/// Which signal feeds the subwoofer
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum BassSource {
+    /// The sum of the low frequencies removed from all main speakers.
+    #[default]
+    Mains,
+
+    /// The omnidirectional *B-format* component `W`, independent of the decoder.
+    Omni,
+}
+
+/// Redirect low frequencies from the main speakers to a subwoofer
+///
+/// The main speakers are high-passed at the crossover frequency, and a subwoofer (LFE) channel
+/// plays the low frequencies instead. The LFE signals of the streams are added to the
+/// subwoofer, after low-passing them at the crossover frequency.
+///
+/// If the layout specifies speaker distances, the subwoofer is aligned with the farthest speaker.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BassManagement {
+    lfe_channel: usize,
+    crossover: f32,
+    source: BassSource,
+    gain: f32,
+}
+
+impl BassManagement {
+    /// Insert the subwoofer as output channel `lfe_channel`.
+    ///
+    /// For example, channel 3 of a 5.0 layout results in the common 5.1 channel order
+    /// L R C LFE Ls Rs.
+    pub fn new(lfe_channel: usize) -> Self {
+        BassManagement {
+            lfe_channel,
+            crossover: 80.0,
+            source: BassSource::Mains,
+            gain: 1.0,
+        }
+    }
+
+    /// Set the crossover frequency in Hz (defaults to 80).
+    pub fn with_crossover(mut self, frequency: f32) -> Self {
+        assert!(frequency > 0.0);
+        self.crossover = frequency;
+        self
+    }
+
+    /// Set which signal feeds the subwoofer (defaults to the main speakers).
+    pub fn with_source(mut self, source: BassSource) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Set the gain of the subwoofer channel (defaults to 1).
+    pub fn with_gain(mut self, gain: f32) -> Self {
+        assert!(gain >= 0.0);
+        self.gain = gain;
+        self
+    }
+
+    /// Index of the subwoofer among the output channels
+    pub fn lfe_channel(&self) -> usize {
+        self.lfe_channel
+    }
+}
+
+/// Splits decoded speaker signals into high-passed mains and a subwoofer signal
+pub(crate) struct BassManager {
+    config: BassManagement,
+    mains: Vec<Crossover>,
+    sub: Crossover,
+}
+
+impl BassManager {
+    pub fn new(config: BassManagement, n_mains: usize, sample_rate: u32) -> Self {
+        assert!(config.lfe_channel <= n_mains);
+        let crossover = Crossover::new(config.crossover, sample_rate);
+        BassManager {
+            config,
+            mains: vec![crossover.clone(); n_mains],
+            sub: crossover,
+        }
+    }
+
+    pub fn config(&self) -> &BassManagement {
+        &self.config
+    }
+
+    /// High-pass the speaker signals in place and return the subwoofer signal.
+    ///
+    /// `w` is the omnidirectional *B-format* component and `lfe` the summed LFE signal of the
+    /// streams. Linkwitz-Riley crossovers sum to an allpass, so summing low bands after the
+    /// crossover is equivalent to crossing over the sum.
+    #[inline]
+    pub fn process(&mut self, w: f32, lfe: f32, mains: &mut [f32]) -> f32 {
+        let mut sum = 0.0;
+        for (x, xo) in mains.iter_mut().zip(&mut self.mains) {
+            sum += *x;
+            *x = xo.process(*x).1;
+        }
+        let bass = match self.config.source {
+            BassSource::Mains => sum,
+            BassSource::Omni => w * std::f32::consts::SQRT_2,
+        };
+        self.sub.process(bass + lfe).0 * self.config.gain
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    /// RMS of the subwoofer and the first main speaker for a sine wave on all channels
+    fn levels(source: BassSource, freq: f32, lfe: f32) -> (f32, f32) {
+        let config = BassManagement::new(0).with_source(source);
+        let mut bm = BassManager::new(config, 2, 48000);
+        let w = 2.0 * PI * freq / 48000.0;
+        let (mut sub, mut main) = (0.0, 0.0);
+        for i in 0..48000 {
+            let x = (w * i as f32).sin();
+            let mut mains = [x * (1.0 - lfe), x * (1.0 - lfe)];
+            let s = bm.process(x / 2f32.sqrt(), lfe * x, &mut mains);
+            if i >= 24000 {
+                sub += s * s / 24000.0;
+                main += mains[0] * mains[0] / 24000.0;
+            }
+        }
+        (sub.sqrt(), main.sqrt())
+    }
+
+    #[test]
+    fn low_frequencies_go_to_the_subwoofer() {
+        for &source in &[BassSource::Mains, BassSource::Omni] {
+            let (sub, main) = levels(source, 30.0, 0.0);
+            assert!(sub > 0.5 && main < 0.1, "{:?} {} {}", source, sub, main);
+
+            let (sub, main) = levels(source, 2000.0, 0.0);
+            assert!(sub < 1e-3 && main > 0.6, "{:?} {} {}", source, sub, main);
+        }
+    }
+
+    #[test]
+    fn lfe_signal_is_added_to_the_subwoofer() {
+        let (sub, main) = levels(BassSource::Omni, 30.0, 1.0);
+        // W carries the full signal, plus the LFE signal of the same amplitude
+        assert!((sub - 2f32.sqrt()).abs() < 0.1, "{}", sub);
+        assert!(main < 1e-3);
+    }
+}
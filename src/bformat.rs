@@ -5,115 +5,326 @@ use std::iter::FromIterator;
 use cpal::{Sample as CpalSample, SampleFormat};
 use rodio::Sample;
 
-/// Audio sample in first-order *B-format*.
+use crate::distance::DistanceModel;
+
+/// Highest supported ambisonic order.
+pub const MAX_ORDER: usize = 3;
+
+/// Number of *B-format* channels required to represent a sound field of `MAX_ORDER`.
+pub const MAX_CHANNELS: usize = (MAX_ORDER + 1) * (MAX_ORDER + 1);
+
+/// Number of *B-format* channels used by a sound field of given `order`.
+pub fn n_channels(order: usize) -> usize {
+    (order + 1) * (order + 1)
+}
+
+/// Audio sample in *B-format*.
 ///
-/// It encodes four components of the sound field at the listener position: omnidirectional level
-/// `w` and the level gradient in `x`, `y`, and `z` directions.
+/// It encodes the components of the sound field at the listener position up to `MAX_ORDER`.
+/// The zeroth order is the omnidirectional level `w`, the first order the level gradient in `x`,
+/// `y`, and `z` directions. Higher orders describe the sound field with increasing spatial
+/// resolution.
+///
+/// Internally, channels are stored in the Furse-Malham convention (see `Convention::FUMA`).
+/// Channels above the order of the sound field are zero. Use `to_convention` and
+/// `from_convention` to exchange samples with other conventions.
+///
+/// Alongside the sound field, a sample carries a non-directional low-frequency effects (LFE)
+/// signal, which speaker renderers with bass management route to the subwoofer.
 #[derive(Debug, Copy, Clone)]
 pub struct Bformat {
-    w: f32,
-    x: f32,
-    y: f32,
-    z: f32,
+    components: [f32; MAX_CHANNELS],
+    lfe: f32,
+}
+
+impl Bformat {
+    /// Construct a sample from its raw channels in internal order.
+    pub fn from_components(components: [f32; MAX_CHANNELS]) -> Self {
+        Bformat {
+            components,
+            lfe: 0.0,
+        }
+    }
+
+    /// Raw channels in internal order.
+    pub fn components(&self) -> &[f32; MAX_CHANNELS] {
+        &self.components
+    }
+
+    /// Replace the low-frequency effects signal
+    pub fn with_lfe(mut self, lfe: f32) -> Self {
+        self.lfe = lfe;
+        self
+    }
+
+    /// Low-frequency effects signal
+    pub fn lfe(&self) -> f32 {
+        self.lfe
+    }
 }
 
 impl Sample for Bformat {
     fn lerp(first: Self, second: Self, numerator: u32, denominator: u32) -> Self {
         let alpha = numerator as f32 / denominator as f32;
-        Bformat {
-            w: first.w * alpha + second.w * (1.0 - alpha),
-            x: first.x * alpha + second.x * (1.0 - alpha),
-            y: first.y * alpha + second.y * (1.0 - alpha),
-            z: first.z * alpha + second.z * (1.0 - alpha),
+        let mut result = first;
+        for (r, s) in result.components.iter_mut().zip(&second.components) {
+            *r = *r * alpha + s * (1.0 - alpha);
         }
+        result.lfe = result.lfe * alpha + second.lfe * (1.0 - alpha);
+        result
     }
 
-    fn amplify(self, alpha: f32) -> Self {
-        Bformat {
-            w: self.w * alpha,
-            x: self.x * alpha,
-            y: self.y * alpha,
-            z: self.z * alpha,
+    fn amplify(mut self, alpha: f32) -> Self {
+        for c in &mut self.components {
+            *c *= alpha;
         }
+        self.lfe *= alpha;
+        self
     }
 
-    fn saturating_add(self, other: Self) -> Self {
-        Bformat {
-            w: self.w + other.w,
-            x: self.x + other.x,
-            y: self.y + other.y,
-            z: self.z + other.z,
+    fn saturating_add(mut self, other: Self) -> Self {
+        for (c, o) in self.components.iter_mut().zip(&other.components) {
+            *c += o;
         }
+        self.lfe += other.lfe;
+        self
     }
 
     fn zero_value() -> Self {
         Bformat {
-            w: 0.0,
-            x: 0.0,
-            y: 0.0,
-            z: 0.0,
+            components: [0.0; MAX_CHANNELS],
+            lfe: 0.0,
         }
     }
 }
 
+/// Panic message of the `CpalSample` conversions
+const NOT_A_CPAL_SAMPLE: &str =
+    "The B-format is not intended to be used as a CPAL sample directly. Use a renderer instead.";
+
 // Why? Oh, why!?
 unsafe impl CpalSample for Bformat {
     const FORMAT: SampleFormat = SampleFormat::F32;
 
     fn to_f32(&self) -> f32 {
-        panic!("The B-format is not intended to be used as a CPAL sample directly. Use a renderer instead.")
+        panic!("{}", NOT_A_CPAL_SAMPLE)
     }
 
     fn to_i16(&self) -> i16 {
-        panic!("The B-format is not intended to be used as a CPAL sample directly. Use a renderer instead.")
+        panic!("{}", NOT_A_CPAL_SAMPLE)
     }
 
     fn to_u16(&self) -> u16 {
-        panic!("The B-format is not intended to be used as a CPAL sample directly. Use a renderer instead.")
+        panic!("{}", NOT_A_CPAL_SAMPLE)
     }
 
     fn from<S>(_s: &S) -> Self
     where
         S: CpalSample,
     {
-        panic!("The B-format is not intended to be used as a CPAL sample directly. Use a renderer instead.")
+        panic!("{}", NOT_A_CPAL_SAMPLE)
+    }
+}
+
+/// How `Bweights::from_position_with` encodes a sound source
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct EncodingParams {
+    order: usize,
+    distance_model: DistanceModel,
+    interior_radius: f32,
+}
+
+impl Default for EncodingParams {
+    fn default() -> Self {
+        EncodingParams {
+            order: 1,
+            distance_model: DistanceModel::default(),
+            interior_radius: 0.0,
+        }
     }
 }
 
+impl EncodingParams {
+    /// Create new `EncodingParams` for first-order weights with the default distance model.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set the ambisonic order of the weights.
+    pub fn with_order(mut self, order: usize) -> Self {
+        assert!(order <= MAX_ORDER);
+        self.order = order;
+        self
+    }
+
+    /// Set the distance model that attenuates the source.
+    pub fn with_distance_model(mut self, model: DistanceModel) -> Self {
+        self.distance_model = model;
+        self
+    }
+
+    /// Set the radius around the listener within which the source gradually becomes
+    /// omnidirectional (defaults to 0).
+    pub fn with_interior_radius(mut self, r: f32) -> Self {
+        assert!(r >= 0.0);
+        self.interior_radius = r;
+        self
+    }
+}
+
+/// Distance below which a source is considered to be at the listener position
+const INTERIOR_EPS: f32 = 1e-6;
+
 /// Weights for manipulating `Bformat` samples.
+///
+/// Weights know their ambisonic order; components above that order are ignored.
 #[derive(Debug, Copy, Clone)]
 pub struct Bweights {
-    w: f32,
-    x: f32,
-    y: f32,
-    z: f32,
+    order: usize,
+    components: [f32; MAX_CHANNELS],
 }
 
 impl Bweights {
-    /// Initialze new weights with given values
+    /// Initialze new first-order weights with given values
+    ///
+    /// `x`, `y`, and `z` follow the library's coordinate system: `x` points to the right, `y` to
+    /// the front, and `z` upwards.
     pub fn new(w: f32, x: f32, y: f32, z: f32) -> Self {
-        Bweights { w, x, y, z }
+        let mut components = [0.0; MAX_CHANNELS];
+        components[0] = w;
+        components[1] = y;
+        components[2] = -x;
+        components[3] = z;
+        Bweights {
+            order: 1,
+            components,
+        }
+    }
+
+    /// Initialize weights of given order from raw channels in internal order.
+    pub fn from_components(order: usize, components: [f32; MAX_CHANNELS]) -> Self {
+        assert!(order <= MAX_ORDER);
+        let mut bw = Bweights { order, components };
+        for c in &mut bw.components[n_channels(order)..] {
+            *c = 0.0;
+        }
+        bw
+    }
+
+    /// Ambisonic order of the weights
+    pub fn order(&self) -> usize {
+        self.order
+    }
+
+    /// Raw channels in internal order.
+    pub fn components(&self) -> &[f32; MAX_CHANNELS] {
+        &self.components
     }
 
     /// Weights that correspond to a omnidirectional source
+    ///
+    /// Following the Furse-Malham convention, `w` is attenuated by 1/√2.
     pub fn omni_source() -> Self {
+        let mut components = [0.0; MAX_CHANNELS];
+        components[0] = 1.0 / 2f32.sqrt();
         Bweights {
-            w: 1.0 / 2f32.sqrt(),
-            x: 0.0,
-            y: 0.0,
-            z: 0.0,
+            order: 0,
+            components,
         }
     }
 
-    /// Compute weights that correspond to a sound source at given position.
+    /// Compute first-order weights that correspond to a sound source at given position.
+    ///
+    /// The source is attenuated with the default distance model. See `from_position_with`.
     pub fn from_position(pos: [f32; 3]) -> Self {
+        Bweights::from_position_with(pos, &EncodingParams::default())
+    }
+
+    /// Compute weights that correspond to a sound source at given position.
+    ///
+    /// The weights have the order of `params`, and the source is attenuated according to their
+    /// distance model. Inside the interior radius around the listener the source gradually
+    /// becomes omnidirectional, so that sources can pass through the listener without jumping
+    /// from one side to the other. The radius may be zero, but a source exactly at the listener
+    /// is always rendered omnidirectional.
+    pub fn from_position_with(pos: [f32; 3], params: &EncodingParams) -> Self {
+        let EncodingParams {
+            order,
+            distance_model,
+            interior_radius,
+        } = *params;
         let dist = (pos[0] * pos[0] + pos[1] * pos[1] + pos[2] * pos[2]).sqrt();
-        let falloff = 1.0 / dist.max(1.0); // todo: proper falloff and distance model(s)
+        let falloff = distance_model.gain(dist);
+
+        if dist < INTERIOR_EPS {
+            let mut bw = Bweights::omni_source();
+            bw.order = order;
+            bw.scale_weights(falloff * 2f32.sqrt());
+            return bw;
+        }
+
+        let mut bw = Bweights::from_direction(pos, order);
+
+        if dist < interior_radius {
+            // fade out directional components, higher orders faster than lower ones, and
+            // boost the omnidirectional component to preserve the source's energy
+            let f = dist / interior_radius;
+            bw.components[0] *= (2.0 - f * f).sqrt();
+            for o in 1..=order {
+                for c in &mut bw.components[n_channels(o - 1)..n_channels(o)] {
+                    *c *= f.powi(o as i32);
+                }
+            }
+        }
+
+        bw.scale_weights(falloff);
+        bw
+    }
+
+    /// Compute unit-gain weights of given order that encode a plane wave from `direction`.
+    ///
+    /// The direction does not need to be normalized.
+    pub fn from_direction(direction: [f32; 3], order: usize) -> Self {
+        assert!(order <= MAX_ORDER);
+        let l = (direction[0] * direction[0]
+            + direction[1] * direction[1]
+            + direction[2] * direction[2])
+            .sqrt();
+
+        // convert to the conventional ambisonic axes (x front, y left, z up)
+        let x = direction[1] / l;
+        let y = -direction[0] / l;
+        let z = direction[2] / l;
+
+        let mut c = [0.0; MAX_CHANNELS];
+        c[0] = 1.0 / 2f32.sqrt();
+        if order >= 1 {
+            c[1] = x;
+            c[2] = y;
+            c[3] = z;
+        }
+        if order >= 2 {
+            c[4] = (3.0 * z * z - 1.0) / 2.0;
+            c[5] = 2.0 * x * z;
+            c[6] = 2.0 * y * z;
+            c[7] = x * x - y * y;
+            c[8] = 2.0 * x * y;
+        }
+        if order >= 3 {
+            let l_norm = (135.0f32 / 256.0).sqrt();
+            let n_norm = 3.0 * 3f32.sqrt() / 2.0;
+            c[9] = z * (5.0 * z * z - 3.0) / 2.0;
+            c[10] = l_norm * x * (5.0 * z * z - 1.0);
+            c[11] = l_norm * y * (5.0 * z * z - 1.0);
+            c[12] = n_norm * z * (x * x - y * y);
+            c[13] = 2.0 * n_norm * x * y * z;
+            c[14] = x * (x * x - 3.0 * y * y);
+            c[15] = y * (3.0 * x * x - y * y);
+        }
+
         Bweights {
-            w: falloff / 2f32.sqrt(),
-            x: falloff * pos[0] / dist,
-            y: falloff * pos[1] / dist,
-            z: falloff * pos[2] / dist,
+            order,
+            components: c,
         }
     }
 
@@ -126,14 +337,14 @@ impl Bweights {
     pub fn virtual_microphone(direction: [f32; 3], p: f32) -> Self {
         let l = (direction[0] * direction[0]
             + direction[1] * direction[1]
-            + direction[1] * direction[2])
+            + direction[2] * direction[2])
             .sqrt();
-        Bweights {
-            w: p * 2f32.sqrt(),
-            x: direction[0] * (1.0 - p) / l,
-            y: direction[1] * (1.0 - p) / l,
-            z: direction[2] * (1.0 - p) / l,
-        }
+        Bweights::new(
+            p * 2f32.sqrt(),
+            direction[0] * (1.0 - p) / l,
+            direction[1] * (1.0 - p) / l,
+            direction[2] * (1.0 - p) / l,
+        )
     }
 
     /// Dot product of *B-format* weights and sample.
@@ -141,7 +352,12 @@ impl Bweights {
     /// If the weights correspond to a virtual microphone, the result is the signal recorded by that
     /// microphone.
     pub fn dot(&self, b: Bformat) -> f32 {
-        self.w * b.w + self.x * b.x + self.y * b.y + self.z * b.z
+        let n = n_channels(self.order);
+        self.components[..n]
+            .iter()
+            .zip(&b.components[..n])
+            .map(|(w, x)| w * x)
+            .sum()
     }
 
     /// Produce a *B-format* sample by scaling weights.
@@ -149,46 +365,243 @@ impl Bweights {
     /// If the weights correspond to a sound source, and `s` is the source's current level, the
     /// result is the *B-format* representation of the source.
     pub fn scale(&self, s: f32) -> Bformat {
-        Bformat {
-            w: self.w * s,
-            x: self.x * s,
-            y: self.y * s,
-            z: self.z * s,
+        let mut b = Bformat::zero_value();
+        let n = n_channels(self.order);
+        for (x, w) in b.components[..n].iter_mut().zip(&self.components[..n]) {
+            *x = w * s;
+        }
+        b
+    }
+
+    /// Like `scale`, but with a separate level `s1` for the first-order components.
+    ///
+    /// This allows filtering the first-order components, for example to apply near-field
+    /// compensation.
+    pub(crate) fn scale_near_field(&self, s: f32, s1: f32) -> Bformat {
+        let mut b = self.scale(s);
+        if self.order >= 1 {
+            for (x, w) in b.components[1..4].iter_mut().zip(&self.components[1..4]) {
+                *x = w * s1;
+            }
+        }
+        b
+    }
+
+    /// Multiply all weights by a common factor.
+    pub fn scale_weights(&mut self, s: f32) {
+        for w in &mut self.components[..n_channels(self.order)] {
+            *w *= s;
         }
     }
 
     /// adjust weights towards target
     pub fn approach(&mut self, target: &Bweights, max_step: f32) {
-        // if this turns out too slow we could try to replace it with simple steps along each dimension
-        let dir = [
-            target.w - self.w,
-            target.x - self.x,
-            target.y - self.y,
-            target.z - self.z,
-        ];
-        let dist = (dir[0] * dir[0] + dir[1] * dir[1] + dir[2] * dir[2] + dir[3] * dir[3]).sqrt();
+        // if this turns out too slow we could try to replace it with simple steps along each
+        // dimension
+        let order = self.order.max(target.order);
+        let n = n_channels(order);
+
+        let dist = self.components[..n]
+            .iter()
+            .zip(&target.components[..n])
+            .map(|(a, b)| (b - a) * (b - a))
+            .sum::<f32>()
+            .sqrt();
+
         if dist <= max_step {
             *self = *target;
         } else {
             let d = max_step / dist;
-            self.w += dir[0] * d;
-            self.x += dir[1] * d;
-            self.y += dir[2] * d;
-            self.z += dir[3] * d;
+            for (a, b) in self.components[..n].iter_mut().zip(&target.components[..n]) {
+                *a += (b - *a) * d;
+            }
+            self.order = order;
         }
     }
 }
 
+/// Collect weights from `w`, `x`, `y`, `z` (in the library's coordinate system), optionally
+/// followed by the higher-order channels in internal order.
+///
+/// The number of items determines the order and must be 4, 9, or 16.
 impl FromIterator<f32> for Bweights {
     fn from_iter<T: IntoIterator<Item = f32>>(iter: T) -> Self {
         let mut iter = iter.into_iter();
-        let bw = Bweights {
-            w: iter.next().unwrap(),
-            x: iter.next().unwrap(),
-            y: iter.next().unwrap(),
-            z: iter.next().unwrap(),
-        };
-        assert!(iter.next().is_none());
+        let mut bw = Bweights::new(
+            iter.next().unwrap(),
+            iter.next().unwrap(),
+            iter.next().unwrap(),
+            iter.next().unwrap(),
+        );
+
+        let mut n = 4;
+        for x in iter {
+            assert!(n < MAX_CHANNELS);
+            bw.components[n] = x;
+            n += 1;
+        }
+
+        bw.order = (n as f32).sqrt() as usize - 1;
+        assert_eq!(n_channels(bw.order), n);
         bw
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIRECTIONS: [[f32; 3]; 5] = [
+        [1.0, 0.0, 0.0],
+        [0.0, 1.0, 0.0],
+        [0.0, 0.0, -1.0],
+        [1.0, 2.0, 3.0],
+        [-0.3, 0.2, 0.1],
+    ];
+
+    #[test]
+    fn first_order_encoding_matches_position() {
+        let bw = Bweights::from_direction([1.0, 0.0, 0.0], 1);
+        let right = Bweights::new(0.0, 1.0, 0.0, 0.0);
+        let front = Bweights::new(0.0, 0.0, 1.0, 0.0);
+        assert!((right.dot(bw.scale(1.0)) - 1.0).abs() < 1e-6);
+        assert!(front.dot(bw.scale(1.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn encoding_is_order_consistent() {
+        for &dir in &DIRECTIONS {
+            let lo = Bweights::from_direction(dir, 1);
+            let hi = Bweights::from_direction(dir, MAX_ORDER);
+            assert_eq!(hi.order(), MAX_ORDER);
+            for i in 0..n_channels(1) {
+                assert_eq!(lo.components()[i], hi.components()[i]);
+            }
+        }
+    }
+
+    #[test]
+    fn furse_malham_channels_are_max_normalized() {
+        // Furse-Malham normalization scales all channels above order zero to a maximum gain of 1
+        let mut max = [0.0f32; MAX_CHANNELS];
+        for i in 0..180 {
+            for j in 0..90 {
+                let azi = i as f32 * 2.0 * std::f32::consts::PI / 180.0;
+                let ele = (j as f32 - 45.0) * std::f32::consts::PI / 90.0;
+                let dir = [azi.cos() * ele.cos(), azi.sin() * ele.cos(), ele.sin()];
+                let bw = Bweights::from_direction(dir, MAX_ORDER);
+                for (m, c) in max.iter_mut().zip(bw.components()) {
+                    *m = m.max(c.abs());
+                }
+            }
+        }
+        for m in &max[1..] {
+            assert!((m - 1.0).abs() < 1e-2, "{:?}", max);
+        }
+    }
+
+    #[test]
+    fn approach_reaches_target_of_higher_order() {
+        let mut bw = Bweights::omni_source();
+        let target = Bweights::from_direction([0.0, 1.0, 0.0], 3);
+        for _ in 0..10000 {
+            bw.approach(&target, 0.001);
+        }
+        assert_eq!(bw.order(), 3);
+        assert_eq!(bw.components(), target.components());
+    }
+
+    fn params(order: usize, model: DistanceModel, interior_radius: f32) -> EncodingParams {
+        EncodingParams::new()
+            .with_order(order)
+            .with_distance_model(model)
+            .with_interior_radius(interior_radius)
+    }
+
+    #[test]
+    fn position_weights_default_to_first_order() {
+        let bw = Bweights::from_position([0.0, 2.0, 0.0]);
+        let target = Bweights::new(0.5 / 2f32.sqrt(), 0.0, 0.5, 0.0);
+        assert_eq!(bw.order(), 1);
+        for (a, b) in bw.components().iter().zip(target.components()) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn virtual_microphone_normalizes_direction() {
+        // the length of the direction once mixed up the `y` and `z` components
+        let mic = Bweights::virtual_microphone([0.0, 3.0, 4.0], 0.0);
+        let target = Bweights::new(0.0, 0.0, 0.6, 0.8);
+        for (a, b) in mic.components().iter().zip(target.components()) {
+            assert!((a - b).abs() < 1e-6);
+        }
+
+        let mic = Bweights::virtual_microphone([0.0, 0.0, 2.0], 0.5);
+        let target = Bweights::new(0.5 * 2f32.sqrt(), 0.0, 0.0, 0.5);
+        for (a, b) in mic.components().iter().zip(target.components()) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn source_at_listener_is_omnidirectional() {
+        let model = DistanceModel::default();
+        for &r in &[0.0, 1.0] {
+            let bw = Bweights::from_position_with([0.0, 0.0, 0.0], &params(3, model, r));
+            assert!(bw.components().iter().all(|x| x.is_finite()));
+            assert!((bw.components()[0] - 1.0).abs() < 1e-6);
+            assert!(bw.components()[1..].iter().all(|&x| x == 0.0));
+        }
+    }
+
+    #[test]
+    fn source_at_listener_has_finite_weights_with_any_falloff() {
+        use crate::distance::Falloff;
+        for &falloff in &[
+            Falloff::None,
+            Falloff::Inverse,
+            Falloff::InverseClamped,
+            Falloff::Linear,
+            Falloff::LinearClamped,
+            Falloff::Exponential,
+            Falloff::ExponentialClamped,
+        ] {
+            for model in &[
+                DistanceModel::new(falloff),
+                DistanceModel::new(falloff).with_rolloff_factor(4.0),
+                DistanceModel::new(falloff).with_max_distance(1.0),
+            ] {
+                let bw = Bweights::from_position_with([0.0, 0.0, 0.0], &params(3, *model, 0.5));
+                assert!(bw.components()[0].is_finite(), "{:?}", model);
+                assert!(bw.components()[0] >= 0.0, "{:?}", model);
+                assert!(
+                    bw.components()[1..].iter().all(|&x| x == 0.0),
+                    "{:?}",
+                    model
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn interior_panning_is_continuous_through_listener() {
+        let model = DistanceModel::default();
+        let mut previous = Bweights::from_position_with([-1.0, 0.1, 0.0], &params(2, model, 0.5));
+        for i in 1..=200 {
+            let x = -1.0 + i as f32 / 100.0;
+            let bw = Bweights::from_position_with([x, 0.1, 0.0], &params(2, model, 0.5));
+            for (a, b) in bw.components().iter().zip(previous.components()) {
+                assert!((a - b).abs() < 0.1);
+            }
+            previous = bw;
+        }
+    }
+
+    #[test]
+    fn collect_higher_order_weights() {
+        let bw: Bweights = (0..9).map(|i| i as f32).collect();
+        assert_eq!(bw.order(), 2);
+        assert_eq!(bw.components()[8], 8.0);
+    }
+}
@@ -3,20 +3,30 @@
 //! This module provides functionality for dynamically composing sound sources into a 3D sound
 //! scene.
 
-use crate::bformat::Bformat;
-use crate::bstream::{self, Bstream, BstreamConfig, SoundController};
+use crate::bformat::{Bformat, MAX_ORDER};
+use crate::bstream::{self, Bstream, BstreamBridge, BstreamConfig, SoundController};
+use crate::distance::DistanceModel;
+use crate::listener::Listener;
+use crate::occlusion::LineOfSight;
+use crate::panning::Panner;
 use rodio::{source::UniformSourceIterator, Sample, Source};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, Weak};
 use std::time::Duration;
 
 /// Construct a 3D sound mixer and associated sound composer.
+///
+/// All sounds are mixed into a first-order *B-format* stream. See `bmixer_with_order`.
 pub fn bmixer(sample_rate: u32) -> (BstreamMixer, Arc<BmixerComposer>) {
-    let controller = Arc::new(BmixerComposer {
-        sample_rate,
-        pending_streams: Mutex::new(Vec::new()),
-        has_pending: AtomicBool::new(false),
-    });
+    bmixer_with_order(sample_rate, 1)
+}
+
+/// Construct a 3D sound mixer and associated sound composer.
+///
+/// All sounds are mixed into a *B-format* stream of given ambisonic `order`.
+pub fn bmixer_with_order(sample_rate: u32, order: usize) -> (BstreamMixer, Arc<BmixerComposer>) {
+    assert!(order <= MAX_ORDER);
+    let controller = new_composer(sample_rate, order, None);
 
     let mixer = BstreamMixer {
         controller: controller.clone(),
@@ -26,6 +36,42 @@ pub fn bmixer(sample_rate: u32) -> (BstreamMixer, Arc<BmixerComposer>) {
     (mixer, controller)
 }
 
+/// Construct a mixer whose sounds are panned directly to speaker feeds.
+pub(crate) fn panning_mixer(
+    sample_rate: u32,
+    panner: Panner,
+) -> (PanningMixer, Arc<BmixerComposer>) {
+    let controller = new_composer(sample_rate, 0, Some(Arc::new(panner)));
+
+    let mixer = PanningMixer {
+        controller: controller.clone(),
+        active_streams: Vec::with_capacity(8),
+    };
+
+    (mixer, controller)
+}
+
+fn new_composer(
+    sample_rate: u32,
+    order: usize,
+    panner: Option<Arc<Panner>>,
+) -> Arc<BmixerComposer> {
+    Arc::new(BmixerComposer {
+        sample_rate,
+        order,
+        panner,
+        pending_streams: Mutex::new(Vec::new()),
+        has_pending: AtomicBool::new(false),
+        scene: Mutex::new(Scene {
+            listener: Listener::default(),
+            line_of_sight: None,
+            version: 0,
+        }),
+        sources: Mutex::new(Vec::new()),
+        distance_model: Mutex::new(DistanceModel::default()),
+    })
+}
+
 /// Combine all currently playing 3D sound sources into a single *B-format* stream.
 ///
 /// The mixer implements `rodio::Source<Item = Bformat>`, which must be passed to a renderer before
@@ -43,7 +89,7 @@ impl Source for BstreamMixer {
 
     #[inline(always)]
     fn channels(&self) -> u16 {
-        1 // actually 4, but they are packed into one struct
+        1 // actually (order + 1)², but they are packed into one struct
     }
 
     #[inline(always)]
@@ -61,15 +107,7 @@ impl Iterator for BstreamMixer {
     type Item = Bformat;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.controller.has_pending.load(Ordering::SeqCst) {
-            let mut pending = self
-                .controller
-                .pending_streams
-                .lock()
-                .expect("Cannot lock pending streams");
-            self.active_streams.extend(pending.drain(..));
-            self.controller.has_pending.store(false, Ordering::SeqCst);
-        }
+        self.controller.take_pending(&mut self.active_streams);
 
         let mut mix = Bformat::zero_value();
 
@@ -90,21 +128,169 @@ impl Iterator for BstreamMixer {
     }
 }
 
+/// Combine all currently playing panned sound sources into one feed per speaker.
+pub(crate) struct PanningMixer {
+    controller: Arc<BmixerComposer>,
+    active_streams: Vec<Bstream>,
+}
+
+impl PanningMixer {
+    pub fn sample_rate(&self) -> u32 {
+        self.controller.sample_rate
+    }
+
+    /// Mix the next sample of all sources into the speaker `feeds`, and return the sum of their
+    /// LFE signals.
+    pub fn mix(&mut self, feeds: &mut [f32]) -> f32 {
+        self.controller.take_pending(&mut self.active_streams);
+
+        for x in feeds.iter_mut() {
+            *x = 0.0;
+        }
+        let mut lfe = 0.0;
+        self.active_streams
+            .retain_mut(|stream| match stream.mix_panned(feeds) {
+                Some(x) => {
+                    lfe += x;
+                    true
+                }
+                None => false,
+            });
+        lfe
+    }
+}
+
 /// Compose the 3D sound scene
 pub struct BmixerComposer {
     has_pending: AtomicBool,
     pending_streams: Mutex<Vec<Bstream>>,
     sample_rate: u32,
+    order: usize,
+    panner: Option<Arc<Panner>>,
+    scene: Mutex<Scene>,
+    sources: Mutex<Vec<Weak<BstreamBridge>>>,
+    distance_model: Mutex<DistanceModel>,
+}
+
+/// State of the scene that sources follow
+#[derive(Clone)]
+struct Scene {
+    listener: Listener,
+    line_of_sight: Option<Arc<dyn LineOfSight>>,
+    /// incremented with every change, so that sources can ignore outdated updates
+    version: u64,
 }
 
 impl BmixerComposer {
-    /// Add a single-channel `Source` to the sound scene at a position relative to the listener
+    /// Ambisonic order of the mixed sound field
+    ///
+    /// Object panning playback mixes speaker feeds instead of a sound field, and reports order 0.
+    pub fn order(&self) -> usize {
+        self.order
+    }
+
+    /// Move newly played streams to the mixer
+    fn take_pending(&self, active_streams: &mut Vec<Bstream>) {
+        if self.has_pending.load(Ordering::SeqCst) {
+            let mut pending = self
+                .pending_streams
+                .lock()
+                .expect("Cannot lock pending streams");
+            active_streams.extend(pending.drain(..));
+            self.has_pending.store(false, Ordering::SeqCst);
+        }
+    }
+
+    /// Distance model used by sources that do not specify their own
+    pub fn default_distance_model(&self) -> DistanceModel {
+        *self.distance_model.lock().unwrap()
+    }
+
+    /// Set the distance model for sources that do not specify their own
+    ///
+    /// Only affects sources that are played after the change.
+    pub fn set_default_distance_model(&self, model: DistanceModel) {
+        *self.distance_model.lock().unwrap() = model;
+    }
+
+    /// Set the line-of-sight test that attenuates sources behind obstacles
+    ///
+    /// Applies to future sources, and to playing sources that use world coordinates or already
+    /// have a test. Sources that were configured with their own test keep it. Pass `None` to
+    /// disable.
+    pub fn set_line_of_sight(&self, line_of_sight: Option<Arc<dyn LineOfSight>>) {
+        let scene = {
+            let mut scene = self.scene.lock().unwrap();
+            scene.line_of_sight = line_of_sight;
+            scene.version += 1;
+            scene.clone()
+        };
+        self.broadcast(&scene);
+    }
+
+    /// Current state of the listener
+    pub fn listener(&self) -> Listener {
+        self.scene.lock().unwrap().listener
+    }
+
+    /// Set position, orientation, and velocity of the listener
+    ///
+    /// All sources that are placed in world coordinates transition smoothly to their new position
+    /// relative to the listener.
+    pub fn set_listener(&self, listener: Listener) {
+        self.update_listener(|l| *l = listener);
+    }
+
+    /// Modify the listener in place
+    ///
+    /// All sources that are placed in world coordinates transition smoothly to their new position
+    /// relative to the listener.
+    pub fn update_listener(&self, f: impl FnOnce(&mut Listener)) {
+        let scene = {
+            let mut scene = self.scene.lock().unwrap();
+            f(&mut scene.listener);
+            scene.version += 1;
+            scene.clone()
+        };
+        self.broadcast(&scene);
+    }
+
+    /// Send the scene to all sources that follow it
+    ///
+    /// No locks are held while the sources run their line-of-sight queries, so that the queries
+    /// may use the composer.
+    fn broadcast(&self, scene: &Scene) {
+        let bridges: Vec<_> = {
+            let mut sources = self.sources.lock().unwrap();
+            sources.retain(|source| source.upgrade().is_some_and(|bridge| !bridge.is_stopped()));
+            sources.iter().filter_map(Weak::upgrade).collect()
+        };
+        for bridge in bridges {
+            bridge.set_scene(scene.listener, scene.line_of_sight.clone(), scene.version);
+        }
+    }
+
+    /// Add a single-channel `Source` to the sound scene
     ///
     /// Returns a controller object that can be used to control the source during playback.
     pub fn play<I>(&self, input: I, config: BstreamConfig) -> SoundController
     where
         I: Source<Item = f32> + Send + 'static,
     {
+        let scene = self.scene.lock().unwrap().clone();
+        let mut config = config
+            .with_order(self.order)
+            .with_listener(scene.listener)
+            .with_scene_version(scene.version)
+            .with_panner(self.panner.clone());
+        if config.distance_model().is_none() {
+            config = config.with_distance_model(self.default_distance_model());
+        }
+        if !config.has_line_of_sight() {
+            config = config.inherit_line_of_sight(scene.line_of_sight.clone());
+        }
+        // head-relative sources without line-of-sight test do not depend on the listener
+        let needs_updates = !config.is_head_relative() || config.has_line_of_sight();
         let (bstream, sound_ctl) = if input.sample_rate() == self.sample_rate {
             bstream::bstream(input, config)
         } else {
@@ -118,6 +304,81 @@ impl BmixerComposer {
             .push(bstream);
         self.has_pending.store(true, Ordering::SeqCst);
 
+        if needs_updates {
+            {
+                let mut sources = self.sources.lock().unwrap();
+                sources
+                    .retain(|source| source.upgrade().is_some_and(|bridge| !bridge.is_stopped()));
+                sources.push(Arc::downgrade(sound_ctl.bridge()));
+            }
+
+            // scene updates that started before the source was registered did not reach it
+            let current = self.scene.lock().unwrap().clone();
+            if current.version != scene.version {
+                sound_ctl.bridge().set_scene(
+                    current.listener,
+                    current.line_of_sight,
+                    current.version,
+                );
+            }
+        }
+
         sound_ctl
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sources::Constant;
+
+    #[test]
+    fn only_sources_that_follow_the_listener_are_tracked() {
+        let (mut mixer, composer) = bmixer(1000);
+        let input = || Constant::new(1.0, 1000).take_duration(Duration::from_millis(10));
+
+        for _ in 0..10 {
+            composer.play(input(), BstreamConfig::new());
+        }
+        assert_eq!(composer.sources.lock().unwrap().len(), 0);
+
+        let world = BstreamConfig::new().with_head_relative(false);
+        let _playing = composer.play(input(), world);
+        composer.play(input(), BstreamConfig::new().with_head_relative(false));
+        assert_eq!(composer.sources.lock().unwrap().len(), 2);
+
+        // finished and dropped sources are removed when the next source is played
+        mixer.by_ref().take(100).count();
+        composer.play(input(), BstreamConfig::new().with_head_relative(false));
+        assert_eq!(composer.sources.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn line_of_sight_queries_may_use_the_composer() {
+        use crate::occlusion::Occlusion;
+
+        #[derive(Default)]
+        struct AskListener(Mutex<Weak<BmixerComposer>>);
+        impl LineOfSight for AskListener {
+            fn query(&self, listener: [f32; 3], _source: [f32; 3]) -> Occlusion {
+                if let Some(composer) = self.0.lock().unwrap().upgrade() {
+                    assert_eq!(composer.listener().position, listener);
+                }
+                Occlusion::none()
+            }
+        }
+
+        let (_mixer, composer) = bmixer(1000);
+        let los = Arc::new(AskListener::default());
+        *los.0.lock().unwrap() = Arc::downgrade(&composer);
+        composer.set_line_of_sight(Some(los));
+
+        let config = BstreamConfig::new()
+            .with_head_relative(false)
+            .with_position([0.0, 1.0, 0.0]);
+        let mut sound = composer.play(Constant::new(1.0, 1000), config);
+        composer.update_listener(|l| l.position = [1.0, 0.0, 0.0]);
+        sound.adjust_position([0.0, 2.0, 0.0]);
+        composer.set_line_of_sight(None);
+    }
+}
@@ -1,11 +1,19 @@
 //! Represent audio sources in *B-format*.
 
-use crate::bformat::{Bformat, Bweights};
-use crate::constants::SPEED_OF_SOUND;
-use rodio::{Sample, Source};
+use crate::bformat::{Bformat, Bweights, EncodingParams, MAX_ORDER};
+use crate::constants::{MAX_DOPPLER_FACTOR, SPEED_OF_SOUND};
+use crate::directivity::Directivity;
+use crate::distance::DistanceModel;
+use crate::filter::{NearFieldFilter, OnePoleLowpass};
+use crate::listener::Listener;
+use crate::occlusion::{LineOfSight, Occlusion};
+use crate::panning::Panner;
+use crate::propagation::{DelayLine, PropagationModel};
+use crate::rotation::Rotation;
+use rodio::Source;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
 /// Convert a `rodio::Source` to a spatial `Bstream` source with associated controller
 ///
@@ -16,37 +24,96 @@ pub fn bstream<I: Source<Item = f32> + Send + 'static>(
 ) -> (Bstream, SoundController) {
     assert_eq!(source.channels(), 1);
 
+    let mut spatial = SpatialState {
+        order: config.order,
+        position: config.position,
+        velocity: config.velocity,
+        doppler_factor: config.doppler_factor,
+        speed_of_sound: config.speed_of_sound,
+        head_relative: config.head_relative,
+        listener: config.listener,
+        distance_model: config.distance_model.unwrap_or_default(),
+        interior_radius: config.interior_radius,
+        propagation: config.propagation,
+        orientation: config.orientation,
+        directivity: config.directivity,
+        occlusion: config.occlusion,
+        obstruction: config.obstruction,
+        line_of_sight: config.line_of_sight,
+        inherited_line_of_sight: config.inherited_line_of_sight,
+        visibility: Occlusion::none(),
+        scene_version: config.scene_version,
+        generation: 0,
+        jump: false,
+        near_field_radius: config.near_field_radius,
+        lfe_send: config.lfe_send,
+        panner: config.panner,
+        velocity_estimator: None,
+    };
+
+    spatial.visibility = query_visibility(spatial.line_of_sight_query());
+    let weights = spatial.weights();
+    let gains = spatial.speaker_gains().unwrap_or_default();
+    let speed = spatial.doppler_rate();
+    let mut lowpass = OnePoleLowpass::new();
+    lowpass.set_coefficient(OnePoleLowpass::coefficient(
+        spatial.hf_gain(),
+        source.sample_rate(),
+    ));
+    let mut near_field = NearFieldFilter::new();
+    let (zero, pole) = near_field_coefficients(
+        spatial.near_field(),
+        spatial.speed_of_sound,
+        source.sample_rate(),
+    );
+    near_field.set_coefficients(zero, pole);
+    let lfe_gain = spatial.lfe_gain();
+
     let bridge = Arc::new(BstreamBridge {
         commands: Mutex::new(Vec::new()),
         pending_commands: AtomicBool::new(false),
         stopped: AtomicBool::new(false),
+        spatial: Mutex::new(spatial),
     });
 
-    let (position, weights) = match config.position {
-        Some(p) => (p, Bweights::from_position(p)),
-        None => ([0.0, 0.0, 0.0], Bweights::omni_source()),
-    };
-
     let controller = SoundController {
         bridge: bridge.clone(),
-        position,
-        velocity: config.velocity,
-        doppler_factor: config.doppler_factor,
-        speed_of_sound: config.speed_of_sound,
+    };
+
+    let delay_line = match config.propagation {
+        PropagationModel::Instantaneous => None,
+        PropagationModel::Delayed { max_distance } => {
+            let mut dl = DelayLine::new(
+                max_distance / config.speed_of_sound * MAX_DOPPLER_FACTOR,
+                source.sample_rate(),
+            );
+            let (delay, rate) = bridge.spatial.lock().unwrap().propagation_delay();
+            dl.set_delay(delay, rate);
+            Some(dl)
+        }
+    };
+
+    // the delay line consumes the input directly; otherwise prime the resampler
+    let (previous_sample, next_sample) = match delay_line {
+        Some(_) => (0.0, 0.0),
+        None => (source.next().unwrap_or(0.0), source.next().unwrap_or(0.0)),
     };
 
     let stream = Bstream {
         bweights: weights,
         target_weights: weights,
-        speed: compute_doppler_rate(
-            position,
-            config.velocity,
-            config.doppler_factor,
-            config.speed_of_sound,
-        ),
+        gains: gains.clone(),
+        target_gains: gains,
+        lowpass,
+        near_field,
+        lfe_gain,
+        target_lfe_gain: lfe_gain,
+        speed,
         sampling_offset: 0.0,
-        previous_sample: source.next().unwrap_or(0.0),
-        next_sample: source.next().unwrap_or(0.0),
+        previous_sample,
+        next_sample,
+        delay_line,
+        tail: None,
         bridge,
         input: Box::new(source),
         paused: false,
@@ -57,30 +124,77 @@ pub fn bstream<I: Source<Item = f32> + Send + 'static>(
 
 /// Initial configuration for constructing `Bstream`s
 pub struct BstreamConfig {
+    order: usize,
     position: Option<[f32; 3]>,
     velocity: [f32; 3],
     doppler_factor: f32,
     speed_of_sound: f32,
+    head_relative: bool,
+    listener: Listener,
+    scene_version: u64,
+    distance_model: Option<DistanceModel>,
+    interior_radius: f32,
+    propagation: PropagationModel,
+    orientation: Rotation,
+    directivity: Directivity,
+    occlusion: Occlusion,
+    obstruction: Occlusion,
+    line_of_sight: Option<Arc<dyn LineOfSight>>,
+    inherited_line_of_sight: bool,
+    near_field_radius: Option<f32>,
+    lfe_send: f32,
+    panner: Option<Arc<Panner>>,
 }
 
 impl Default for BstreamConfig {
     fn default() -> Self {
         BstreamConfig {
+            order: 1,
             position: None,
             velocity: [0.0, 0.0, 0.0],
             doppler_factor: 1.0,
             speed_of_sound: SPEED_OF_SOUND,
+            head_relative: true,
+            listener: Listener::default(),
+            scene_version: 0,
+            distance_model: None,
+            interior_radius: DEFAULT_INTERIOR_RADIUS,
+            propagation: PropagationModel::default(),
+            orientation: Rotation::identity(),
+            directivity: Directivity::default(),
+            occlusion: Occlusion::none(),
+            obstruction: Occlusion::none(),
+            line_of_sight: None,
+            inherited_line_of_sight: false,
+            near_field_radius: None,
+            lfe_send: 0.0,
+            panner: None,
         }
     }
 }
 
+/// Radius around the listener within which sources become omnidirectional
+const DEFAULT_INTERIOR_RADIUS: f32 = 0.5;
+
 impl BstreamConfig {
     /// Create new `BstreamConfig` with default settings.
     pub fn new() -> Self {
         Default::default()
     }
 
-    /// Set initial position relative to listener.
+    /// Set ambisonic order of the stream.
+    ///
+    /// When playing through a `BmixerComposer` the order is set to the order of the mixer.
+    pub fn with_order(mut self, order: usize) -> Self {
+        assert!(order <= MAX_ORDER);
+        self.order = order;
+        self
+    }
+
+    /// Set initial position.
+    ///
+    /// The position is relative to the listener for head-relative streams (the default), and
+    /// in world coordinates otherwise.
     pub fn with_position(mut self, p: [f32; 3]) -> Self {
         self.position = Some(p);
         self
@@ -93,7 +207,11 @@ impl BstreamConfig {
     }
 
     /// Set doppler factor for this stream.
+    ///
+    /// The factor must be between 0 and `MAX_DOPPLER_FACTOR`. With delayed propagation it also
+    /// scales the propagation delay.
     pub fn with_doppler_factor(mut self, d: f32) -> Self {
+        assert!((0.0..=MAX_DOPPLER_FACTOR).contains(&d));
         self.doppler_factor = d;
         self
     }
@@ -103,6 +221,141 @@ impl BstreamConfig {
         self.speed_of_sound = s;
         self
     }
+
+    /// Set whether position and velocity are relative to the listener (the default) or in world
+    /// coordinates.
+    ///
+    /// World coordinates are transformed to the listener's point of view, taking into account the
+    /// listener's position, orientation, and velocity.
+    pub fn with_head_relative(mut self, relative: bool) -> Self {
+        self.head_relative = relative;
+        self
+    }
+
+    /// Set the distance model of this stream.
+    ///
+    /// When playing through a `BmixerComposer`, streams without a distance model use the
+    /// scene's default model.
+    pub fn with_distance_model(mut self, model: DistanceModel) -> Self {
+        self.distance_model = Some(model);
+        self
+    }
+
+    /// Set the radius around the listener within which the stream gradually becomes
+    /// omnidirectional (defaults to 0.5).
+    pub fn with_interior_radius(mut self, r: f32) -> Self {
+        assert!(r >= 0.0);
+        self.interior_radius = r;
+        self
+    }
+
+    /// Set how sound travels from the stream to the listener (defaults to instantaneous).
+    pub fn with_propagation(mut self, model: PropagationModel) -> Self {
+        self.propagation = model;
+        self
+    }
+
+    /// Set the stream's orientation (defaults to facing front, along the `y` axis).
+    ///
+    /// Like the position, the orientation is relative to the listener unless the stream uses
+    /// world coordinates.
+    pub fn with_orientation(mut self, orientation: Rotation) -> Self {
+        self.orientation = orientation;
+        self
+    }
+
+    /// Set how the stream radiates into different directions (defaults to omnidirectional).
+    pub fn with_directivity(mut self, directivity: Directivity) -> Self {
+        self.directivity = directivity;
+        self
+    }
+
+    /// Set the initial occlusion of the stream (defaults to none).
+    pub fn with_occlusion(mut self, occlusion: Occlusion) -> Self {
+        self.occlusion = occlusion;
+        self
+    }
+
+    /// Set the initial obstruction of the stream (defaults to none).
+    pub fn with_obstruction(mut self, obstruction: Occlusion) -> Self {
+        self.obstruction = obstruction;
+        self
+    }
+
+    /// Set a line-of-sight test that attenuates the stream when obstacles block the direct
+    /// path (defaults to none).
+    ///
+    /// When playing through a `BmixerComposer` the scene's line-of-sight test is used, if the
+    /// stream does not specify its own.
+    pub fn with_line_of_sight(mut self, line_of_sight: Arc<dyn LineOfSight>) -> Self {
+        self.line_of_sight = Some(line_of_sight);
+        self
+    }
+
+    /// Enable near-field compensation for loudspeakers at distance `radius` (in meters) from the
+    /// listener (defaults to disabled).
+    ///
+    /// The first-order components of close sources receive a bass boost, which is what
+    /// makes sources sound close. Sources farther away than `radius` receive a bass cut, which
+    /// compensates for the near-field effect of the loudspeakers. Only first-order components
+    /// are filtered.
+    pub fn with_near_field_compensation(mut self, radius: f32) -> Self {
+        assert!(radius > 0.0);
+        self.near_field_radius = Some(radius);
+        self
+    }
+
+    /// Send the stream to the LFE channel with given gain (defaults to 0, no LFE signal).
+    ///
+    /// The LFE signal is attenuated with distance like the stream itself, and is played by the
+    /// subwoofer of speaker renderers with bass management. Other renderers ignore it.
+    pub fn with_lfe_send(mut self, gain: f32) -> Self {
+        assert!(gain >= 0.0);
+        self.lfe_send = gain;
+        self
+    }
+
+    /// Pan the stream directly to speaker feeds instead of encoding it to *B-format*
+    pub(crate) fn with_panner(mut self, panner: Option<Arc<Panner>>) -> Self {
+        self.panner = panner;
+        self
+    }
+
+    pub(crate) fn is_head_relative(&self) -> bool {
+        self.head_relative
+    }
+
+    pub(crate) fn has_line_of_sight(&self) -> bool {
+        self.line_of_sight.is_some()
+    }
+
+    /// Version of the scene that the listener and line-of-sight test were taken from
+    pub(crate) fn with_scene_version(mut self, version: u64) -> Self {
+        self.scene_version = version;
+        self
+    }
+
+    /// Use the scene's line-of-sight test, and follow later changes to it
+    pub(crate) fn inherit_line_of_sight(
+        mut self,
+        line_of_sight: Option<Arc<dyn LineOfSight>>,
+    ) -> Self {
+        self.line_of_sight = line_of_sight;
+        self.inherited_line_of_sight = true;
+        self
+    }
+
+    pub(crate) fn distance_model(&self) -> Option<DistanceModel> {
+        self.distance_model
+    }
+
+    /// Set the listener that world coordinates are relative to.
+    ///
+    /// When playing through a `BmixerComposer` the listener is set to the scene's listener.
+    pub fn with_listener(mut self, listener: Listener) -> Self {
+        self.listener = listener;
+        self
+    }
 }
 
 /// Spatial source
@@ -114,43 +367,35 @@ pub struct Bstream {
 
     bweights: Bweights,
     target_weights: Bweights,
+    /// speaker gains of panned streams
+    gains: Vec<f32>,
+    target_gains: Vec<f32>,
+    lowpass: OnePoleLowpass,
+    near_field: NearFieldFilter,
+    lfe_gain: f32,
+    target_lfe_gain: f32,
 
     speed: f32,
     sampling_offset: f32,
     previous_sample: f32,
     next_sample: f32,
+    delay_line: Option<DelayLine>,
+    tail: Option<usize>,
     paused: bool,
 }
 
-impl Bstream {}
-
-impl Source for Bstream {
-    #[inline(always)]
-    fn current_frame_len(&self) -> Option<usize> {
-        self.input.current_frame_len()
-    }
-
-    #[inline(always)]
-    fn channels(&self) -> u16 {
-        assert_eq!(self.input.channels(), 1);
-        1
-    }
-
-    #[inline(always)]
-    fn sample_rate(&self) -> u32 {
-        self.input.sample_rate()
-    }
-
-    #[inline(always)]
-    fn total_duration(&self) -> Option<Duration> {
-        self.input.total_duration()
+impl Bstream {
+    /// Add the next sample of a panned stream to the speaker `feeds`, and return its LFE signal
+    pub(crate) fn mix_panned(&mut self, feeds: &mut [f32]) -> Option<f32> {
+        let (x, _, lfe) = self.next_sample()?;
+        for (y, g) in feeds.iter_mut().zip(&self.gains) {
+            *y += g * x;
+        }
+        Some(lfe)
     }
-}
-
-impl Iterator for Bstream {
-    type Item = Bformat;
 
-    fn next(&mut self) -> Option<Self::Item> {
+    /// Produce the next sample, low-passed and near-field filtered, and the LFE signal
+    fn next_sample(&mut self) -> Option<(f32, f32, f32)> {
         if self.bridge.pending_commands.load(Ordering::SeqCst) {
             let mut commands = self.bridge.commands.lock().unwrap();
 
@@ -158,7 +403,32 @@ impl Iterator for Bstream {
                 match cmd {
                     Command::SetWeights(bw) => self.bweights = bw,
                     Command::SetTarget(bw) => self.target_weights = bw,
+                    Command::SetGains(g) => self.gains = g,
+                    Command::SetTargetGains(g) => self.target_gains = g,
                     Command::SetSpeed(s) => self.speed = s,
+                    Command::SetDelay(d, r) => {
+                        if let Some(dl) = &mut self.delay_line {
+                            dl.set_delay(d, r)
+                        }
+                    }
+                    Command::SetDelayTarget(d, r) => {
+                        if let Some(dl) = &mut self.delay_line {
+                            dl.set_target(d, r)
+                        }
+                    }
+                    Command::SetDelayRate(r) => {
+                        if let Some(dl) = &mut self.delay_line {
+                            dl.set_rate(r)
+                        }
+                    }
+                    Command::SetLowpass(g) => self
+                        .lowpass
+                        .set_target(OnePoleLowpass::coefficient(g, self.input.sample_rate())),
+                    Command::SetNearField(nf, c) => {
+                        let (zero, pole) = near_field_coefficients(nf, c, self.input.sample_rate());
+                        self.near_field.set_target(zero, pole);
+                    }
+                    Command::SetLfeGain(g) => self.target_lfe_gain = g,
                     Command::Stop => {
                         self.bridge.stopped.store(true, Ordering::SeqCst);
                         return None;
@@ -172,33 +442,118 @@ impl Iterator for Bstream {
         }
 
         if self.paused {
-            self.bweights = self.target_weights; // during pause we can allow the source to jump
-            return Some(Bformat::zero_value());
+            // during pause we can allow the source to jump
+            self.bweights = self.target_weights;
+            self.gains.clone_from(&self.target_gains);
+            return Some((0.0, 0.0, 0.0));
         }
 
         // adjusting the weights slowly avoids audio artifacts but prevents very fast position
         // changes
         self.bweights.approach(&self.target_weights, 0.001);
+        approach(&mut self.gains, &self.target_gains, 0.001);
 
-        while self.sampling_offset >= 1.0 {
-            match self.input.next() {
-                Some(x) => {
-                    self.previous_sample = self.next_sample;
-                    self.next_sample = x;
-                }
-                None => {
-                    self.bridge.stopped.store(true, Ordering::SeqCst);
-                    return None;
+        let x = match self.delay_line.as_mut() {
+            Some(dl) => {
+                // keep playing until the end of the input has passed through the delay line
+                let x = match self.tail {
+                    Some(0) => {
+                        self.bridge.stopped.store(true, Ordering::SeqCst);
+                        return None;
+                    }
+                    Some(n) => {
+                        self.tail = Some(n - 1);
+                        0.0
+                    }
+                    None => match self.input.next() {
+                        Some(x) => x,
+                        None => {
+                            self.tail = Some(dl.delay().ceil() as usize + 1);
+                            0.0
+                        }
+                    },
+                };
+                dl.process(x)
+            }
+            None => {
+                while self.sampling_offset >= 1.0 {
+                    match self.input.next() {
+                        Some(x) => {
+                            self.previous_sample = self.next_sample;
+                            self.next_sample = x;
+                        }
+                        None => {
+                            self.bridge.stopped.store(true, Ordering::SeqCst);
+                            return None;
+                        }
+                    };
+                    self.sampling_offset -= 1.0;
                 }
-            };
-            self.sampling_offset -= 1.0;
-        }
 
-        let x = self.next_sample * self.sampling_offset
-            + self.previous_sample * (1.0 - self.sampling_offset);
+                let x = self.next_sample * self.sampling_offset
+                    + self.previous_sample * (1.0 - self.sampling_offset);
+                self.sampling_offset += self.speed;
+                x
+            }
+        };
+        self.lfe_gain += (self.target_lfe_gain - self.lfe_gain).clamp(-0.001, 0.001);
+        let lfe = x * self.lfe_gain;
+
+        let x = self.lowpass.process(x, 0.001);
+        let x1 = self.near_field.process(x, 0.001);
+
+        Some((x, x1, lfe))
+    }
+}
+
+impl Source for Bstream {
+    #[inline(always)]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    #[inline(always)]
+    fn channels(&self) -> u16 {
+        assert_eq!(self.input.channels(), 1);
+        1
+    }
+
+    #[inline(always)]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline(always)]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+impl Iterator for Bstream {
+    type Item = Bformat;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (x, x1, lfe) = self.next_sample()?;
+        Some(self.bweights.scale_near_field(x, x1).with_lfe(lfe))
+    }
+}
 
-        self.sampling_offset += self.speed;
-        Some(self.bweights.scale(x))
+/// Move `gains` towards `target` by at most `max_step` (Euclidean distance)
+fn approach(gains: &mut [f32], target: &[f32], max_step: f32) {
+    let dist = gains
+        .iter()
+        .zip(target)
+        .map(|(a, b)| (b - a) * (b - a))
+        .sum::<f32>()
+        .sqrt();
+
+    if dist <= max_step {
+        gains.copy_from_slice(target);
+    } else {
+        let d = max_step / dist;
+        for (a, b) in gains.iter_mut().zip(target) {
+            *a += (b - *a) * d;
+        }
     }
 }
 
@@ -206,7 +561,15 @@ impl Iterator for Bstream {
 enum Command {
     SetWeights(Bweights),
     SetTarget(Bweights),
+    SetGains(Vec<f32>),
+    SetTargetGains(Vec<f32>),
     SetSpeed(f32),
+    SetDelay(f32, f32),
+    SetDelayTarget(f32, f32),
+    SetDelayRate(f32),
+    SetLowpass(f32),
+    SetNearField(Option<(f32, f32)>, f32),
+    SetLfeGain(f32),
     Stop,
     Pause,
     Resume,
@@ -217,62 +580,436 @@ pub struct BstreamBridge {
     commands: Mutex<Vec<Command>>,
     pending_commands: AtomicBool,
     stopped: AtomicBool,
+    spatial: Mutex<SpatialState>,
 }
 
-/// Controls playback and position of a spatial audio source
-pub struct SoundController {
-    bridge: Arc<BstreamBridge>,
-    position: [f32; 3],
+impl BstreamBridge {
+    /// Returns `true` if the stream has finished playing
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Update the listener and, unless the source has its own, the line-of-sight test
+    ///
+    /// Updates from scene versions older than the source's current one are ignored, so that
+    /// concurrent updates cannot leave the source with an outdated scene.
+    pub(crate) fn set_scene(
+        &self,
+        listener: Listener,
+        line_of_sight: Option<Arc<dyn LineOfSight>>,
+        version: u64,
+    ) {
+        let mut spatial = self.spatial.lock().unwrap();
+        if version <= spatial.scene_version {
+            return;
+        }
+        spatial.scene_version = version;
+        spatial.listener = listener;
+        if spatial.inherited_line_of_sight {
+            spatial.line_of_sight = line_of_sight;
+        }
+        if !spatial.head_relative || spatial.line_of_sight.is_some() {
+            self.update(spatial, false);
+        }
+    }
+
+    /// Send new weights and doppler rate to the stream
+    ///
+    /// The line-of-sight test runs without holding the lock of the spatial state, so that it
+    /// may call back into the scene. If another update starts in the meantime, this one is
+    /// dropped in favor of the newer one.
+    fn update<'a>(&'a self, mut spatial: MutexGuard<'a, SpatialState>, jump: bool) {
+        spatial.generation += 1;
+        spatial.jump |= jump;
+        let generation = spatial.generation;
+
+        let query = spatial.line_of_sight_query();
+        if query.is_some() {
+            drop(spatial);
+            let visibility = query_visibility(query);
+            spatial = self.spatial.lock().unwrap();
+            if spatial.generation != generation {
+                return;
+            }
+            spatial.visibility = visibility;
+        } else {
+            spatial.visibility = Occlusion::none();
+        }
+        let jump = std::mem::take(&mut spatial.jump);
+
+        let weights = spatial.weights();
+        let gains = spatial.speaker_gains();
+        let rate = spatial.doppler_rate();
+        let hf_gain = spatial.hf_gain();
+        {
+            let mut cmds = self.commands.lock().unwrap();
+            cmds.push(Command::SetSpeed(rate));
+            cmds.push(Command::SetLowpass(hf_gain));
+            cmds.push(Command::SetNearField(
+                spatial.near_field(),
+                spatial.speed_of_sound,
+            ));
+            cmds.push(Command::SetLfeGain(spatial.lfe_gain()));
+            if let PropagationModel::Delayed { .. } = spatial.propagation {
+                let (delay, rate) = spatial.propagation_delay();
+                if jump {
+                    cmds.push(Command::SetDelay(delay, rate));
+                } else {
+                    cmds.push(Command::SetDelayTarget(delay, rate));
+                }
+            }
+            if jump {
+                cmds.push(Command::SetWeights(weights));
+            }
+            cmds.push(Command::SetTarget(weights));
+            if let Some(gains) = gains {
+                if jump {
+                    cmds.push(Command::SetGains(gains.clone()));
+                }
+                cmds.push(Command::SetTargetGains(gains));
+            }
+        }
+        self.pending_commands.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Spatial parameters of a source
+struct SpatialState {
+    order: usize,
+    position: Option<[f32; 3]>,
     velocity: [f32; 3],
     doppler_factor: f32,
     speed_of_sound: f32,
+    head_relative: bool,
+    listener: Listener,
+    distance_model: DistanceModel,
+    interior_radius: f32,
+    propagation: PropagationModel,
+    orientation: Rotation,
+    directivity: Directivity,
+    occlusion: Occlusion,
+    obstruction: Occlusion,
+    line_of_sight: Option<Arc<dyn LineOfSight>>,
+    inherited_line_of_sight: bool,
+    /// result of the last line-of-sight query
+    visibility: Occlusion,
+    /// version of the scene that listener and line-of-sight test were taken from
+    scene_version: u64,
+    /// counts updates, to detect when a newer update started during a line-of-sight query
+    generation: u64,
+    /// whether an update that was superseded should have jumped to its target
+    jump: bool,
+    near_field_radius: Option<f32>,
+    lfe_send: f32,
+    panner: Option<Arc<Panner>>,
+    velocity_estimator: Option<VelocityEstimator>,
+}
+
+impl SpatialState {
+    /// position from the listener's point of view
+    fn relative_position(&self) -> Option<[f32; 3]> {
+        match self.position {
+            Some(p) if !self.head_relative => Some(self.listener.relative_position(p)),
+            p => p,
+        }
+    }
+
+    /// velocity from the listener's point of view
+    fn relative_velocity(&self) -> [f32; 3] {
+        if self.head_relative {
+            self.velocity
+        } else {
+            self.listener.relative_velocity(self.velocity)
+        }
+    }
+
+    /// front direction of the source from the listener's point of view
+    fn relative_front(&self) -> [f32; 3] {
+        let front = self.orientation.rotate([0.0, 1.0, 0.0]);
+        if self.head_relative {
+            front
+        } else {
+            self.listener.orientation.inverse().rotate(front)
+        }
+    }
+
+    /// gain and high-frequency gain due to the source's directivity
+    fn directivity_gains(&self) -> (f32, f32) {
+        let p = match self.relative_position() {
+            Some(p) => p,
+            None => return (1.0, 1.0),
+        };
+        let dist = (p[0] * p[0] + p[1] * p[1] + p[2] * p[2]).sqrt();
+        if dist < EPS {
+            return (1.0, 1.0);
+        }
+        let f = self.relative_front();
+        let cos = -(p[0] * f[0] + p[1] * f[1] + p[2] * f[2]) / dist;
+        self.directivity.gains(cos.clamp(-1.0, 1.0).acos())
+    }
+
+    /// line-of-sight test with listener and source position in world coordinates, if any
+    fn line_of_sight_query(&self) -> Option<LineOfSightQuery> {
+        let los = self.line_of_sight.clone()?;
+        let p = self.position?;
+        let source = if self.head_relative {
+            let r = self.listener.orientation.rotate(p);
+            let l = self.listener.position;
+            [l[0] + r[0], l[1] + r[1], l[2] + r[2]]
+        } else {
+            p
+        };
+        Some((los, self.listener.position, source))
+    }
+
+    /// attenuation of the direct path by obstacles
+    fn direct_path(&self) -> Occlusion {
+        self.occlusion.then(self.obstruction).then(self.visibility)
+    }
+
+    fn weights(&self) -> Bweights {
+        let mut weights = match self.relative_position() {
+            Some(p) => {
+                let params = EncodingParams::new()
+                    .with_order(self.order)
+                    .with_distance_model(self.distance_model)
+                    .with_interior_radius(self.interior_radius);
+                let mut weights = Bweights::from_position_with(p, &params);
+                weights.scale_weights(self.directivity_gains().0);
+                weights
+            }
+            None => Bweights::omni_source(),
+        };
+        weights.scale_weights(self.direct_path().gain());
+        weights
+    }
+
+    /// speaker gains of panned streams
+    fn speaker_gains(&self) -> Option<Vec<f32>> {
+        let panner = self.panner.as_ref()?;
+        let (mut gains, directivity) = match self.relative_position() {
+            Some(p) => (
+                panner.gains(p, &self.distance_model, self.interior_radius),
+                self.directivity_gains().0,
+            ),
+            None => (panner.omni(), 1.0),
+        };
+        let gain = directivity * self.direct_path().gain();
+        for g in &mut gains {
+            *g *= gain;
+        }
+        Some(gains)
+    }
+
+    /// attenuation of high frequencies on the direct path
+    fn hf_gain(&self) -> f32 {
+        let hf_gain = match self.relative_position() {
+            Some(p) => {
+                let dist = (p[0] * p[0] + p[1] * p[1] + p[2] * p[2]).sqrt();
+                self.distance_model.air_absorption_gain(dist) * self.directivity_gains().1
+            }
+            None => 1.0,
+        };
+        hf_gain * self.direct_path().hf_gain()
+    }
+
+    /// gain of the LFE signal, which follows the stream's loudness but not its direction
+    fn lfe_gain(&self) -> f32 {
+        let gain = match self.relative_position() {
+            Some(p) => {
+                let dist = (p[0] * p[0] + p[1] * p[1] + p[2] * p[2]).sqrt();
+                self.distance_model.gain(dist) * self.directivity_gains().0
+            }
+            None => 1.0,
+        };
+        self.lfe_send * gain * self.direct_path().gain()
+    }
+
+    /// source distance and loudspeaker radius for near-field compensation, if enabled
+    ///
+    /// Panned streams have no first-order components to compensate.
+    fn near_field(&self) -> Option<(f32, f32)> {
+        if self.panner.is_some() {
+            return None;
+        }
+        let radius = self.near_field_radius?;
+        let p = self.relative_position()?;
+        let dist = (p[0] * p[0] + p[1] * p[1] + p[2] * p[2]).sqrt();
+        Some((dist.max(radius / MAX_NEAR_FIELD_BOOST), radius))
+    }
+
+    /// propagation delay (in seconds) and its rate of change
+    fn propagation_delay(&self) -> (f32, f32) {
+        let p = self.relative_position().unwrap_or([0.0, 0.0, 0.0]);
+        let v = self.relative_velocity();
+        let dist = (p[0] * p[0] + p[1] * p[1] + p[2] * p[2]).sqrt();
+        let radial_velocity = if dist < EPS {
+            0.0
+        } else {
+            (p[0] * v[0] + p[1] * v[1] + p[2] * v[2]) / dist
+        };
+        let scale = self.doppler_factor / self.speed_of_sound;
+        (dist * scale, radial_velocity * scale)
+    }
+
+    fn doppler_rate(&self) -> f32 {
+        compute_doppler_rate(
+            self.relative_position().unwrap_or([0.0, 0.0, 0.0]),
+            self.relative_velocity(),
+            self.doppler_factor,
+            self.speed_of_sound,
+        )
+    }
+}
+
+type LineOfSightQuery = (Arc<dyn LineOfSight>, [f32; 3], [f32; 3]);
+
+/// Run a line-of-sight query; no query means an unobstructed path
+fn query_visibility(query: Option<LineOfSightQuery>) -> Occlusion {
+    match query {
+        Some((los, listener, source)) => los.query(listener, source),
+        None => Occlusion::none(),
+    }
+}
+
+/// Derives a smoothed velocity from successive timestamped positions
+struct VelocityEstimator {
+    smoothing: Duration,
+    last: Option<(Instant, [f32; 3])>,
+    velocity: [f32; 3],
+}
+
+impl VelocityEstimator {
+    fn new(smoothing: Duration) -> Self {
+        VelocityEstimator {
+            smoothing,
+            last: None,
+            velocity: [0.0, 0.0, 0.0],
+        }
+    }
+
+    /// Forget the previous position, so that the next update does not estimate a velocity
+    fn reset(&mut self, pos: [f32; 3], time: Instant) {
+        self.last = Some((time, pos));
+    }
+
+    /// Record a new position and return the updated velocity estimate
+    fn update(&mut self, pos: [f32; 3], time: Instant) -> Option<[f32; 3]> {
+        let (t0, p0) = self.last.replace((time, pos))?;
+        let dt = time.checked_duration_since(t0)?.as_secs_f32();
+        if dt <= 0.0 {
+            return None;
+        }
+
+        let tau = self.smoothing.as_secs_f32();
+        let alpha = if tau > 0.0 {
+            1.0 - (-dt / tau).exp()
+        } else {
+            1.0
+        };
+
+        for i in 0..3 {
+            let raw = (pos[i] - p0[i]) / dt;
+            self.velocity[i] += alpha * (raw - self.velocity[i]);
+        }
+        Some(self.velocity)
+    }
+}
+
+/// Controls playback and position of a spatial audio source
+pub struct SoundController {
+    bridge: Arc<BstreamBridge>,
 }
 
 impl SoundController {
-    /// Set source position relative to listener
+    /// Set source position
+    ///
+    /// The position is relative to the listener, unless the source was configured to use world
+    /// coordinates.
     ///
     /// Abruptly changing the position of a sound source may cause
     /// popping artifacts. Use this function only to set the source's
     /// initial position, and dynamically adjust the position with
     /// `adjust_position`.
     pub fn set_position(&mut self, pos: [f32; 3]) {
-        self.position = pos;
-        let weights = Bweights::from_position(pos);
-        let rate = self.doppler_rate();
-        {
-            let mut cmds = self.bridge.commands.lock().unwrap();
-            cmds.push(Command::SetSpeed(rate));
-            cmds.push(Command::SetWeights(weights));
-            cmds.push(Command::SetTarget(weights));
+        let mut spatial = self.bridge.spatial.lock().unwrap();
+        spatial.position = Some(pos);
+        if let Some(estimator) = &mut spatial.velocity_estimator {
+            estimator.reset(pos, Instant::now());
         }
-        self.bridge.pending_commands.store(true, Ordering::SeqCst);
+        self.bridge.update(spatial, true);
     }
-    /// Adjust source position relative to listener
+
+    /// Adjust source position
     ///
     /// The source transitions smoothly to the new position.
     /// Use this function to dynamically change the position of a
     /// sound source while it is playing.
+    ///
+    /// If velocity estimation is enabled, the velocity is derived from the time elapsed since
+    /// the previous call.
     pub fn adjust_position(&mut self, pos: [f32; 3]) {
-        self.position = pos;
-        let weights = Bweights::from_position(pos);
-        let rate = self.doppler_rate();
-        {
-            let mut cmds = self.bridge.commands.lock().unwrap();
-            cmds.push(Command::SetSpeed(rate));
-            cmds.push(Command::SetTarget(weights));
+        self.adjust_position_at(pos, Instant::now());
+    }
+
+    /// Adjust source position, observed at the given time
+    ///
+    /// Like `adjust_position`, but velocity estimation uses the provided timestamp instead of
+    /// the current time. This is useful if positions come from a simulation that runs with its
+    /// own clock.
+    pub fn adjust_position_at(&mut self, pos: [f32; 3], time: Instant) {
+        let mut guard = self.bridge.spatial.lock().unwrap();
+        let spatial = &mut *guard;
+        spatial.position = Some(pos);
+        if let Some(estimator) = &mut spatial.velocity_estimator {
+            if let Some(vel) = estimator.update(pos, time) {
+                spatial.velocity = vel;
+            }
         }
-        self.bridge.pending_commands.store(true, Ordering::SeqCst);
+        self.bridge.update(guard, false);
+    }
+
+    /// Derive the source velocity from successive position updates
+    ///
+    /// When enabled, every call to `adjust_position` updates the velocity, and therefore the
+    /// doppler effect. The estimate is low-pass filtered with time constant `smoothing` to
+    /// hide jitter in the update timing; a zero duration disables smoothing.
+    pub fn enable_velocity_estimation(&mut self, smoothing: Duration) {
+        let mut spatial = self.bridge.spatial.lock().unwrap();
+        let mut estimator = VelocityEstimator::new(smoothing);
+        estimator.velocity = spatial.velocity;
+        if let Some(pos) = spatial.position {
+            estimator.reset(pos, Instant::now());
+        }
+        spatial.velocity_estimator = Some(estimator);
+    }
+
+    /// Stop deriving the source velocity from position updates
+    ///
+    /// The last estimated velocity remains in effect until changed with `set_velocity`.
+    pub fn disable_velocity_estimation(&mut self) {
+        self.bridge.spatial.lock().unwrap().velocity_estimator = None;
     }
 
-    /// Set source velocity relative to listener
+    /// Set source velocity
     ///
     /// The velocity determines how much doppler effect to apply
     /// but has no effect on the source's position. Use
     /// `adjust_position` to update the source's position.
+    ///
+    /// If velocity estimation is enabled, the velocity is overridden by the next position
+    /// update.
     pub fn set_velocity(&mut self, vel: [f32; 3]) {
-        self.velocity = vel;
-        let rate = self.doppler_rate();
+        let (rate, (_, delay_rate)) = {
+            let mut spatial = self.bridge.spatial.lock().unwrap();
+            spatial.velocity = vel;
+            if let Some(estimator) = &mut spatial.velocity_estimator {
+                estimator.velocity = vel;
+            }
+            (spatial.doppler_rate(), spatial.propagation_delay())
+        };
         self.send_command(Command::SetSpeed(rate));
+        self.send_command(Command::SetDelayRate(delay_rate));
     }
 
     /// Stop playback
@@ -290,25 +1027,98 @@ impl SoundController {
         self.send_command(Command::Resume);
     }
 
+    /// Set distance model
+    ///
+    /// The level of the source transitions smoothly to the new model.
+    pub fn set_distance_model(&mut self, model: DistanceModel) {
+        let mut spatial = self.bridge.spatial.lock().unwrap();
+        spatial.distance_model = model;
+        self.bridge.update(spatial, false);
+    }
+
+    /// Set the radius around the listener within which the source gradually becomes
+    /// omnidirectional
+    pub fn set_interior_radius(&mut self, r: f32) {
+        assert!(r >= 0.0);
+        let mut spatial = self.bridge.spatial.lock().unwrap();
+        spatial.interior_radius = r;
+        self.bridge.update(spatial, false);
+    }
+
+    /// Set source orientation
+    ///
+    /// The source's directivity pattern turns smoothly to the new orientation.
+    pub fn set_orientation(&mut self, orientation: Rotation) {
+        let mut spatial = self.bridge.spatial.lock().unwrap();
+        spatial.orientation = orientation;
+        self.bridge.update(spatial, false);
+    }
+
+    /// Set source directivity
+    pub fn set_directivity(&mut self, directivity: Directivity) {
+        let mut spatial = self.bridge.spatial.lock().unwrap();
+        spatial.directivity = directivity;
+        self.bridge.update(spatial, false);
+    }
+
+    /// Set source occlusion
+    ///
+    /// Occlusion describes a source that is completely separated from the listener, such as
+    /// a sound in another room. The level and filtering of the source transition smoothly.
+    pub fn set_occlusion(&mut self, occlusion: Occlusion) {
+        let mut spatial = self.bridge.spatial.lock().unwrap();
+        spatial.occlusion = occlusion;
+        self.bridge.update(spatial, false);
+    }
+
+    /// Set source obstruction
+    ///
+    /// Obstruction describes an obstacle that blocks the direct path between source and
+    /// listener, such as a pillar. The level and filtering of the source transition smoothly.
+    pub fn set_obstruction(&mut self, obstruction: Occlusion) {
+        let mut spatial = self.bridge.spatial.lock().unwrap();
+        spatial.obstruction = obstruction;
+        self.bridge.update(spatial, false);
+    }
+
+    /// Enable or disable near-field compensation
+    ///
+    /// See `BstreamConfig::with_near_field_compensation`.
+    pub fn set_near_field_compensation(&mut self, radius: Option<f32>) {
+        assert!(radius.is_none_or(|r| r > 0.0));
+        let mut spatial = self.bridge.spatial.lock().unwrap();
+        spatial.near_field_radius = radius;
+        self.bridge.update(spatial, false);
+    }
+
+    /// Set the gain of the stream's LFE signal
+    ///
+    /// See `BstreamConfig::with_lfe_send`.
+    pub fn set_lfe_send(&mut self, gain: f32) {
+        assert!(gain >= 0.0);
+        let mut spatial = self.bridge.spatial.lock().unwrap();
+        spatial.lfe_send = gain;
+        self.bridge.update(spatial, false);
+    }
+
     /// Set doppler factor
+    ///
+    /// See `BstreamConfig::with_doppler_factor`.
     pub fn set_doppler_factor(&mut self, factor: f32) {
-        self.doppler_factor = factor;
+        assert!((0.0..=MAX_DOPPLER_FACTOR).contains(&factor));
+        let mut spatial = self.bridge.spatial.lock().unwrap();
+        spatial.doppler_factor = factor;
+        self.bridge.update(spatial, false);
+    }
+
+    pub(crate) fn bridge(&self) -> &Arc<BstreamBridge> {
+        &self.bridge
     }
 
     fn send_command(&self, cmd: Command) {
         self.bridge.commands.lock().unwrap().push(cmd);
         self.bridge.pending_commands.store(true, Ordering::SeqCst);
     }
-
-    /// compute doppler rate
-    fn doppler_rate(&self) -> f32 {
-        compute_doppler_rate(
-            self.position,
-            self.velocity,
-            self.doppler_factor,
-            self.speed_of_sound,
-        )
-    }
 }
 
 /// compute doppler rate
@@ -321,27 +1131,40 @@ fn compute_doppler_rate(
     let dist =
         (position[0] * position[0] + position[1] * position[1] + position[2] * position[2]).sqrt();
 
-    let relative_velocity;
-
-    if dist.abs() < EPS {
-        relative_velocity =
-            (velocity[0] * velocity[0] + velocity[1] * velocity[1] + velocity[2] * velocity[2])
-                .sqrt();
+    let relative_velocity = if dist.abs() < EPS {
+        (velocity[0] * velocity[0] + velocity[1] * velocity[1] + velocity[2] * velocity[2]).sqrt()
     } else {
-        relative_velocity =
-            (position[0] * velocity[0] + position[1] * velocity[1] + position[2] * velocity[2])
-                / dist;
-    }
+        (position[0] * velocity[0] + position[1] * velocity[1] + position[2] * velocity[2]) / dist
+    };
 
     speed_of_sound / (speed_of_sound + doppler_factor * relative_velocity)
 }
 
+/// zero and pole of the near-field filter; without compensation the filter passes everything
+fn near_field_coefficients(
+    near_field: Option<(f32, f32)>,
+    speed_of_sound: f32,
+    sample_rate: u32,
+) -> (f32, f32) {
+    match near_field {
+        Some((distance, radius)) => (
+            NearFieldFilter::coefficient(distance, speed_of_sound, sample_rate),
+            NearFieldFilter::coefficient(radius, speed_of_sound, sample_rate),
+        ),
+        None => (0.0, 0.0),
+    }
+}
+
 const EPS: f32 = 1e-6;
 
+/// Upper limit of the low-frequency boost of close sources (20 dB)
+const MAX_NEAR_FIELD_BOOST: f32 = 10.0;
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::sources::Ramp;
+    use crate::rotation::Rotation;
+    use crate::sources::{Constant, Noise, Ramp};
 
     #[test]
     fn no_doppler_effect_if_velocity_is_zero() {
@@ -419,6 +1242,262 @@ mod tests {
         assert_eq!(stream.next(), Some(3.0));
     }
 
+    #[test]
+    fn world_space_sources_are_relative_to_listener() {
+        let (mut stream, controller) = bstream(
+            Constant::new(1.0, 48000),
+            BstreamConfig::new()
+                .with_head_relative(false)
+                .with_position([3.0, 1.0, 0.0]),
+        );
+
+        let listener = Listener {
+            position: [1.0, 1.0, 0.0],
+            orientation: Rotation::from_euler(-std::f32::consts::FRAC_PI_2, 0.0, 0.0),
+            velocity: [0.0, 0.0, 0.0],
+        };
+        controller.bridge().set_scene(listener, None, 1);
+
+        // the listener looks to the right at the source, so it is now in front of them
+        let front = Bweights::new(0.0, 0.0, 1.0, 0.0);
+        let x = (0..48000).map(|_| stream.next().unwrap()).last().unwrap();
+        assert!((front.dot(x) - 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn listener_velocity_causes_doppler_effect() {
+        let listener = Listener {
+            velocity: [0.0, 1.0, 0.0],
+            ..Listener::default()
+        };
+        let (_, controller) = bstream(
+            Constant::new(1.0, 48000),
+            BstreamConfig::new()
+                .with_head_relative(false)
+                .with_listener(listener)
+                .with_position([0.0, 10.0, 0.0]),
+        );
+
+        let spatial = controller.bridge().spatial.lock().unwrap();
+        assert!(spatial.doppler_rate() > 1.0);
+    }
+
+    #[test]
+    fn air_absorption_attenuates_distant_sources() {
+        let model = DistanceModel::default().with_air_absorption(10.0);
+        let (near, _) = bstream(
+            Noise::new(48000),
+            BstreamConfig::new()
+                .with_distance_model(model)
+                .with_position([0.0, 1.0, 0.0]),
+        );
+        let (far, _) = bstream(
+            Noise::new(48000),
+            BstreamConfig::new()
+                .with_distance_model(model)
+                .with_position([0.0, 100.0, 0.0]),
+        );
+
+        // compare high-frequency energy after compensating for the distance attenuation
+        let hf_energy = |stream: Bstream, gain: f32| -> f32 {
+            let mut previous = 0.0;
+            stream
+                .take(4800)
+                .map(|b| Bweights::omni_source().dot(b) / gain)
+                .map(|x| {
+                    let d = x - previous;
+                    previous = x;
+                    d * d
+                })
+                .sum()
+        };
+
+        assert!(hf_energy(far, 0.01) < 0.5 * hf_energy(near, 1.0));
+    }
+
+    #[test]
+    fn delayed_propagation_arrives_late() {
+        let (stream, _) = bstream(
+            Constant::new(1.0, 1000),
+            BstreamConfig::new()
+                .with_propagation(PropagationModel::delayed())
+                .with_speed_of_sound(10.0)
+                .with_position([0.0, 1.0, 0.0]),
+        );
+
+        let mut stream = stream.map(|b| Bweights::omni_source().dot(b));
+        for _ in 0..100 {
+            assert_eq!(stream.next(), Some(0.0));
+        }
+        assert!(stream.next().unwrap() > 0.0);
+    }
+
+    #[test]
+    fn doppler_factor_changes_the_delay_during_playback() {
+        let delay_after = |initial: f32, factor: f32| {
+            let (mut stream, mut controller) = bstream(
+                Constant::new(1.0, 1000),
+                BstreamConfig::new()
+                    .with_propagation(PropagationModel::delayed())
+                    .with_speed_of_sound(10.0)
+                    .with_doppler_factor(initial)
+                    .with_position([0.0, 1.0, 0.0]),
+            );
+            stream.by_ref().take(100).count();
+            controller.set_doppler_factor(factor);
+            stream.by_ref().take(2000).count();
+            stream.delay_line.as_ref().unwrap().delay()
+        };
+
+        // 1m at 10m/s is 100 samples, scaled by the doppler factor
+        assert!((delay_after(1.0, 2.0) - 200.0).abs() < 0.5);
+        assert!((delay_after(0.0, 1.0) - 100.0).abs() < 0.5);
+        assert!(delay_after(1.0, 0.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn delayed_propagation_plays_the_tail() {
+        use rodio::Source;
+        let input = || Constant::new(1.0, 1000).take_duration(Duration::from_millis(10));
+        let (stream, _) = bstream(
+            input(),
+            BstreamConfig::new()
+                .with_propagation(PropagationModel::delayed())
+                .with_speed_of_sound(10.0)
+                .with_position([0.0, 1.0, 0.0]),
+        );
+
+        let n_nonzero = stream
+            .map(|b| Bweights::omni_source().dot(b))
+            .filter(|&x| x > 0.0)
+            .count();
+        assert_eq!(n_nonzero, input().count());
+    }
+
+    #[test]
+    fn directional_source_is_quieter_from_behind() {
+        use std::f32::consts::PI;
+        let level = |yaw: f32| {
+            let (mut stream, _) = bstream(
+                Constant::new(1.0, 1000),
+                BstreamConfig::new()
+                    .with_position([0.0, 1.0, 0.0])
+                    .with_orientation(Rotation::from_euler(yaw, 0.0, 0.0))
+                    .with_directivity(Directivity::cone(PI / 2.0, 2.0 * PI).with_outer_gain(0.25)),
+            );
+            Bweights::omni_source().dot(stream.nth(100).unwrap())
+        };
+
+        // the source is in front of the listener; turned towards the listener it faces back
+        let facing_listener = level(PI);
+        let facing_away = level(0.0);
+        let sideways = level(PI / 2.0);
+        assert!((facing_away / facing_listener - 0.25).abs() < 1e-3);
+        assert!(sideways < facing_listener && sideways > facing_away);
+    }
+
+    #[test]
+    fn occlusion_attenuates_smoothly() {
+        let (stream, mut ctl) = bstream(
+            Constant::new(1.0, 1000),
+            BstreamConfig::new().with_position([0.0, 1.0, 0.0]),
+        );
+        let mut stream = stream.map(|b| Bweights::omni_source().dot(b));
+        let open = stream.nth(10).unwrap();
+
+        ctl.set_occlusion(Occlusion::new(0.5, 1.0));
+        let first = stream.next().unwrap();
+        assert!(first < open && first > 0.5 * open);
+
+        let occluded = stream.nth(2000).unwrap();
+        assert!((occluded / open - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn line_of_sight_is_queried_in_world_coordinates() {
+        struct Wall;
+        impl LineOfSight for Wall {
+            fn query(&self, listener: [f32; 3], source: [f32; 3]) -> Occlusion {
+                if (listener[1] < 5.0) != (source[1] < 5.0) {
+                    Occlusion::new(0.0, 1.0)
+                } else {
+                    Occlusion::none()
+                }
+            }
+        }
+
+        let listener = Listener {
+            position: [0.0, 4.0, 0.0],
+            ..Listener::default()
+        };
+        let (_, ctl) = bstream(
+            Constant::new(1.0, 1000),
+            BstreamConfig::new()
+                .with_head_relative(false)
+                .with_listener(listener)
+                .with_line_of_sight(Arc::new(Wall))
+                .with_position([0.0, 6.0, 0.0]),
+        );
+        assert_eq!(
+            ctl.bridge.spatial.lock().unwrap().weights().components()[0],
+            0.0
+        );
+
+        let listener = Listener {
+            position: [0.0, 5.5, 0.0],
+            ..listener
+        };
+        ctl.bridge.set_scene(listener, None, 1);
+        assert!(ctl.bridge.spatial.lock().unwrap().weights().components()[0] > 0.0);
+    }
+
+    #[test]
+    fn near_field_compensation_boosts_close_sources() {
+        let directivity = |config: BstreamConfig| {
+            let (mut stream, _) = bstream(
+                Constant::new(1.0, 48000),
+                config.with_position([0.0, 0.6, 0.0]),
+            );
+            let b = stream.nth(10000).unwrap();
+            b.components()[1] / b.components()[0]
+        };
+
+        let plain = directivity(BstreamConfig::new());
+        let compensated = directivity(BstreamConfig::new().with_near_field_compensation(1.0));
+        assert!((compensated / plain - 1.0 / 0.6).abs() < 1e-2);
+    }
+
+    #[test]
+    fn velocity_is_estimated_from_position_updates() {
+        let (_, mut ctl) = bstream(Constant::new(1.0, 1000), BstreamConfig::new());
+        ctl.enable_velocity_estimation(Duration::from_secs(0));
+
+        let t0 = Instant::now();
+        ctl.adjust_position_at([0.0, 1.0, 0.0], t0);
+        ctl.adjust_position_at([0.0, 2.0, 0.0], t0 + Duration::from_millis(100));
+
+        let vel = ctl.bridge.spatial.lock().unwrap().velocity;
+        assert!((vel[1] - 10.0).abs() < 1e-3, "{:?}", vel);
+    }
+
+    #[test]
+    fn estimated_velocity_is_smoothed() {
+        let (_, mut ctl) = bstream(Constant::new(1.0, 1000), BstreamConfig::new());
+        ctl.enable_velocity_estimation(Duration::from_millis(100));
+
+        let t0 = Instant::now();
+        let mut velocities = vec![];
+        for i in 0..50 {
+            let t = t0 + Duration::from_millis(10 * i);
+            ctl.adjust_position_at([i as f32 * 0.1, 0.0, 0.0], t);
+            velocities.push(ctl.bridge.spatial.lock().unwrap().velocity[0]);
+        }
+
+        assert!(velocities[1] > 0.0 && velocities[1] < 5.0);
+        assert!(velocities.windows(2).all(|w| w[1] >= w[0]));
+        assert!((velocities[49] - 10.0).abs() < 0.1);
+    }
+
     fn extract_x_component(stream: impl Iterator<Item = Bformat>) -> impl Iterator<Item = f32> {
         stream.map(|bsample| Bweights::new(0.0, 1.0, 0.0, 0.0).dot(bsample))
     }
@@ -0,0 +1,77 @@
+//! Alignment of loudspeakers at different distances from the listener.
+
+use crate::constants::SPEED_OF_SOUND;
+
+/// Per-channel delay and gain that make all speakers arrive aligned at the listener
+///
+/// Sound from closer speakers arrives earlier and louder than from distant ones. Closer speakers
+/// are therefore delayed to match the most distant speaker, and attenuated according to the
+/// inverse distance law.
+pub(crate) struct DistanceCompensation {
+    channels: Vec<DelayedChannel>,
+}
+
+struct DelayedChannel {
+    gain: f32,
+    buffer: Vec<f32>,
+    pos: usize,
+}
+
+impl DistanceCompensation {
+    /// Compensate speakers at the given distances (in meters)
+    pub fn new(distances: &[f32], sample_rate: u32) -> Self {
+        assert!(distances.iter().all(|&d| d > 0.0));
+        let max_distance = distances.iter().cloned().fold(0.0, f32::max);
+        let channels = distances
+            .iter()
+            .map(|&d| {
+                let delay = (max_distance - d) / SPEED_OF_SOUND * sample_rate as f32;
+                DelayedChannel {
+                    gain: d / max_distance,
+                    buffer: vec![0.0; delay.round() as usize],
+                    pos: 0,
+                }
+            })
+            .collect();
+        DistanceCompensation { channels }
+    }
+
+    /// Delay and scale one frame with a sample for each channel
+    #[inline]
+    pub fn process(&mut self, frame: &mut [f32]) {
+        for (x, ch) in frame.iter_mut().zip(&mut self.channels) {
+            let y = if ch.buffer.is_empty() {
+                *x
+            } else {
+                let y = ch.buffer[ch.pos];
+                ch.buffer[ch.pos] = *x;
+                ch.pos = (ch.pos + 1) % ch.buffer.len();
+                y
+            };
+            *x = y * ch.gain;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closer_speakers_are_delayed_and_attenuated() {
+        let d = SPEED_OF_SOUND / 1000.0;
+        let mut comp = DistanceCompensation::new(&[3.0 * d, 5.0 * d], 1000);
+
+        let mut out = vec![];
+        for i in 0..5 {
+            let mut frame = if i == 0 { [1.0, 1.0] } else { [0.0, 0.0] };
+            comp.process(&mut frame);
+            out.push(frame);
+        }
+
+        assert_eq!(out[0], [0.0, 1.0]);
+        assert_eq!(out[1], [0.0, 0.0]);
+        assert!((out[2][0] - 0.6).abs() < 1e-6);
+        assert_eq!(out[3], [0.0, 0.0]);
+    }
+}
@@ -1 +1,4 @@
 pub const SPEED_OF_SOUND: f32 = 343.5; // m/s in air
+
+/// Largest doppler factor of a stream; delay lines are sized for it
+pub const MAX_DOPPLER_FACTOR: f32 = 2.0;
@@ -0,0 +1,412 @@
+//! Channel ordering and normalization conventions of *B-format* signals
+//!
+//! Internally, the library represents *B-format* in the Furse-Malham (FuMa) convention up to third
+//! order. Other tools commonly use AmbiX (ACN channel order with SN3D normalization) or ACN with
+//! N3D normalization. This module provides conversions between these conventions for `Bformat`
+//! samples, `Bweights`, and streams of interleaved multi-channel audio.
+//!
+//! All conventions share the usual ambisonic axes, where `X` points to the front, `Y` to the
+//! left, and `Z` upwards.
+
+use std::time::Duration;
+
+use rodio::Source;
+
+use crate::bformat::{n_channels, Bformat, Bweights, MAX_CHANNELS, MAX_ORDER};
+
+/// Order of channels in a *B-format* signal
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ChannelOrder {
+    /// Furse-Malham order: W, X, Y, Z, R, S, T, U, V, K, L, M, N, O, P, Q
+    FuMa,
+
+    /// Ambisonic Channel Number: W, Y, Z, X, V, T, R, S, U, Q, O, M, K, L, N, P
+    Acn,
+}
+
+/// Normalization of *B-format* channels
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Normalization {
+    /// Furse-Malham: W is attenuated by 1/√2, all other channels have a maximum gain of 1
+    FuMa,
+
+    /// Schmidt semi-normalized: all channels have a maximum gain of 1
+    Sn3d,
+
+    /// Fully normalized: channels are orthonormal over the sphere
+    N3d,
+}
+
+/// Combination of channel order and normalization
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Convention {
+    pub channel_order: ChannelOrder,
+    pub normalization: Normalization,
+}
+
+impl Convention {
+    /// Traditional Furse-Malham *B-format*. This is the library's internal representation.
+    pub const FUMA: Convention = Convention {
+        channel_order: ChannelOrder::FuMa,
+        normalization: Normalization::FuMa,
+    };
+
+    /// AmbiX: ACN channel order with SN3D normalization
+    pub const AMBIX: Convention = Convention {
+        channel_order: ChannelOrder::Acn,
+        normalization: Normalization::Sn3d,
+    };
+
+    /// ACN channel order with N3D normalization
+    pub const N3D: Convention = Convention {
+        channel_order: ChannelOrder::Acn,
+        normalization: Normalization::N3d,
+    };
+
+    /// Position of the internal channel `i` in this convention
+    fn channel_index(&self, i: usize) -> usize {
+        match self.channel_order {
+            ChannelOrder::FuMa => i,
+            ChannelOrder::Acn => FUMA_TO_ACN[i],
+        }
+    }
+
+    /// Gain that converts the internal channel `i` of a signal to this convention
+    fn signal_gain(&self, i: usize) -> f32 {
+        match self.normalization {
+            Normalization::FuMa => 1.0,
+            Normalization::Sn3d => 1.0 / FUMA_FROM_SN3D[i],
+            Normalization::N3d => {
+                let degree = (FUMA_TO_ACN[i] as f32).sqrt().floor();
+                (2.0 * degree + 1.0).sqrt() / FUMA_FROM_SN3D[i]
+            }
+        }
+    }
+}
+
+impl Default for Convention {
+    fn default() -> Self {
+        Convention::FUMA
+    }
+}
+
+/// ACN index of each channel in Furse-Malham order
+const FUMA_TO_ACN: [usize; MAX_CHANNELS] = [0, 3, 1, 2, 6, 7, 5, 8, 4, 12, 13, 11, 14, 10, 15, 9];
+
+/// Gain of each Furse-Malham channel relative to the SN3D normalized channel
+#[allow(clippy::excessive_precision)]
+#[allow(clippy::unreadable_literal)]
+const FUMA_FROM_SN3D: [f32; MAX_CHANNELS] = [
+    std::f32::consts::FRAC_1_SQRT_2, // W
+    1.0,
+    1.0,
+    1.0,
+    1.0,
+    1.1547005383792517, // S, T, U, V: 2/√3
+    1.1547005383792517,
+    1.1547005383792517,
+    1.1547005383792517,
+    1.0,
+    1.1858541225631423, // L, M: √(45/32)
+    1.1858541225631423,
+    1.3416407864998738, // N, O: 3/√5
+    1.3416407864998738,
+    1.2649110640673518, // P, Q: √(8/5)
+    1.2649110640673518,
+];
+
+/// Ambisonic order of `n` channels
+///
+/// # Panics
+///
+/// Panics if the channels don't form a full order up to `MAX_ORDER`.
+fn order_from_channels(n: usize) -> usize {
+    let order = ((n as f32).sqrt() as usize).saturating_sub(1);
+    assert!(
+        n > 0 && n_channels(order) == n,
+        "channel count is not a full ambisonic order"
+    );
+    assert!(order <= MAX_ORDER);
+    order
+}
+
+impl Bformat {
+    /// Convert the first `(order + 1)²` channels of the sample to the given convention.
+    pub fn to_convention(&self, convention: Convention, order: usize) -> Vec<f32> {
+        let mut out = vec![0.0; n_channels(order)];
+        for (i, x) in self.components()[..out.len()].iter().enumerate() {
+            out[convention.channel_index(i)] = x * convention.signal_gain(i);
+        }
+        out
+    }
+
+    /// Construct a sample from channels in the given convention.
+    ///
+    /// The number of channels determines the order.
+    pub fn from_convention(channels: &[f32], convention: Convention) -> Self {
+        order_from_channels(channels.len());
+        let mut components = [0.0; MAX_CHANNELS];
+        for (i, c) in components[..channels.len()].iter_mut().enumerate() {
+            *c = channels[convention.channel_index(i)] / convention.signal_gain(i);
+        }
+        Bformat::from_components(components)
+    }
+}
+
+impl Bweights {
+    /// Weights that encode a source, expressed in the given convention.
+    ///
+    /// Encoding weights (such as those of `from_position`) scale like the signal they produce.
+    pub fn encoding_gains(&self, convention: Convention) -> Vec<f32> {
+        let mut out = vec![0.0; n_channels(self.order())];
+        for (i, w) in self.components()[..out.len()].iter().enumerate() {
+            out[convention.channel_index(i)] = w * convention.signal_gain(i);
+        }
+        out
+    }
+
+    /// Construct encoding weights from gains in the given convention.
+    pub fn from_encoding_gains(gains: &[f32], convention: Convention) -> Self {
+        let order = order_from_channels(gains.len());
+        let mut components = [0.0; MAX_CHANNELS];
+        for (i, c) in components[..gains.len()].iter_mut().enumerate() {
+            *c = gains[convention.channel_index(i)] / convention.signal_gain(i);
+        }
+        Bweights::from_components(order, components)
+    }
+
+    /// Weights that decode a signal, expressed in the given convention.
+    ///
+    /// Decoding weights (such as those of `virtual_microphone`) scale inversely to the signal
+    /// they are applied to, so that the decoded output does not depend on the convention.
+    pub fn decoding_gains(&self, convention: Convention) -> Vec<f32> {
+        let mut out = vec![0.0; n_channels(self.order())];
+        for (i, w) in self.components()[..out.len()].iter().enumerate() {
+            out[convention.channel_index(i)] = w / convention.signal_gain(i);
+        }
+        out
+    }
+
+    /// Construct decoding weights from gains in the given convention.
+    pub fn from_decoding_gains(gains: &[f32], convention: Convention) -> Self {
+        let order = order_from_channels(gains.len());
+        let mut components = [0.0; MAX_CHANNELS];
+        for (i, c) in components[..gains.len()].iter_mut().enumerate() {
+            *c = gains[convention.channel_index(i)] * convention.signal_gain(i);
+        }
+        Bweights::from_components(order, components)
+    }
+}
+
+/// Read interleaved multi-channel audio in a given convention as a *B-format* stream.
+///
+/// The number of channels of the input determines the order. This allows playing *B-format*
+/// material produced by other tools through the library's renderers.
+pub struct BformatImport<I> {
+    input: I,
+    convention: Convention,
+    frame: Vec<f32>,
+}
+
+impl<I> BformatImport<I>
+where
+    I: Source<Item = f32>,
+{
+    /// Wrap an interleaved multi-channel source
+    pub fn new(input: I, convention: Convention) -> Self {
+        let n = input.channels() as usize;
+        order_from_channels(n);
+        BformatImport {
+            input,
+            convention,
+            frame: vec![0.0; n],
+        }
+    }
+}
+
+impl<I> Source for BformatImport<I>
+where
+    I: Source<Item = f32>,
+{
+    #[inline(always)]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input
+            .current_frame_len()
+            .map(|len| len / self.frame.len())
+    }
+
+    #[inline(always)]
+    fn channels(&self) -> u16 {
+        1 // all channels are packed into one struct
+    }
+
+    #[inline(always)]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline(always)]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+impl<I> Iterator for BformatImport<I>
+where
+    I: Source<Item = f32>,
+{
+    type Item = Bformat;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for x in &mut self.frame {
+            *x = self.input.next()?;
+        }
+        Some(Bformat::from_convention(&self.frame, self.convention))
+    }
+}
+
+/// Write a *B-format* stream as interleaved multi-channel audio in a given convention.
+///
+/// This allows exchanging the library's sound scenes with other tools, for example by recording
+/// the output of a `BstreamMixer`.
+pub struct BformatExport<I> {
+    input: I,
+    convention: Convention,
+    order: usize,
+    frame: Vec<f32>,
+    position: usize,
+}
+
+impl<I> BformatExport<I>
+where
+    I: Source<Item = Bformat>,
+{
+    /// Wrap a *B-format* stream and export the channels up to `order`
+    pub fn new(input: I, convention: Convention, order: usize) -> Self {
+        assert!(order <= MAX_ORDER);
+        BformatExport {
+            input,
+            convention,
+            order,
+            frame: vec![],
+            position: 0,
+        }
+    }
+}
+
+impl<I> Source for BformatExport<I>
+where
+    I: Source<Item = Bformat>,
+{
+    #[inline(always)]
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    #[inline(always)]
+    fn channels(&self) -> u16 {
+        n_channels(self.order) as u16
+    }
+
+    #[inline(always)]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline(always)]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+impl<I> Iterator for BformatExport<I>
+where
+    I: Source<Item = Bformat>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position >= self.frame.len() {
+            let sample = self.input.next()?;
+            self.frame = sample.to_convention(self.convention, self.order);
+            self.position = 0;
+        }
+        self.position += 1;
+        Some(self.frame[self.position - 1])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: &[f32], b: &[f32]) {
+        assert_eq!(a.len(), b.len());
+        for (x, y) in a.iter().zip(b) {
+            assert!((x - y).abs() < 1e-5, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "channel count is not a full ambisonic order")]
+    fn reject_empty_channels() {
+        Bformat::from_convention(&[], Convention::AMBIX);
+    }
+
+    #[test]
+    #[should_panic(expected = "channel count is not a full ambisonic order")]
+    fn reject_partial_orders() {
+        Bformat::from_convention(&[0.0; 5], Convention::AMBIX);
+    }
+
+    #[test]
+    fn front_source_in_ambix() {
+        let bw = Bweights::from_direction([0.0, 1.0, 0.0], 1);
+        assert_close(&bw.encoding_gains(Convention::AMBIX), &[1.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn left_source_in_n3d() {
+        let bw = Bweights::from_direction([-1.0, 0.0, 0.0], 1);
+        let s3 = 3f32.sqrt();
+        assert_close(&bw.encoding_gains(Convention::N3D), &[1.0, s3, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn sn3d_channels_are_max_normalized() {
+        let bw = Bweights::from_direction([0.0, 0.0, 1.0], MAX_ORDER);
+        let gains = bw.encoding_gains(Convention::AMBIX);
+        assert!((gains[0] - 1.0).abs() < 1e-6); // W
+        assert!((gains[2] - 1.0).abs() < 1e-6); // Z
+        assert!((gains[6] - 1.0).abs() < 1e-6); // R
+        assert!((gains[12] - 1.0).abs() < 1e-6); // K
+    }
+
+    #[test]
+    fn sample_conversion_roundtrip() {
+        let b = Bweights::from_direction([1.0, 2.0, 3.0], MAX_ORDER).scale(0.5);
+        for &conv in &[Convention::FUMA, Convention::AMBIX, Convention::N3D] {
+            let channels = b.to_convention(conv, MAX_ORDER);
+            let c = Bformat::from_convention(&channels, conv);
+            assert_close(b.components(), c.components());
+        }
+    }
+
+    #[test]
+    fn decoding_is_independent_of_convention() {
+        let source = Bweights::from_direction([1.0, 2.0, 3.0], 2);
+        let mic = Bweights::from_direction([-1.0, 1.0, 0.5], 2);
+        let expected = mic.dot(source.scale(1.0));
+
+        for &conv in &[Convention::FUMA, Convention::AMBIX, Convention::N3D] {
+            let enc = source.encoding_gains(conv);
+            let dec = mic.decoding_gains(conv);
+            let y: f32 = enc.iter().zip(&dec).map(|(a, b)| a * b).sum();
+            assert!((y - expected).abs() < 1e-5);
+
+            let mic2 = Bweights::from_decoding_gains(&dec, conv);
+            let src2 = Bweights::from_encoding_gains(&enc, conv);
+            assert!((mic2.dot(src2.scale(1.0)) - expected).abs() < 1e-5);
+        }
+    }
+}
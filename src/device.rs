@@ -0,0 +1,113 @@
+//! Output streams with as many channels as the renderer needs.
+//!
+//! rodio always opens a device with its default configuration, which often has only two channels,
+//! and silently drops renderer outputs that don't fit. Here the stream is opened with a
+//! configuration that has at least the requested number of channels.
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{Sample, SampleFormat, SampleRate, SupportedStreamConfig};
+use rodio::source::UniformSourceIterator;
+
+use crate::error::Error;
+
+/// An open output stream; playback stops when it is dropped.
+pub(crate) struct OutputStream {
+    _stream: cpal::Stream,
+    channels: usize,
+}
+
+impl OutputStream {
+    /// Open a stream with at least `channels` channels on `device`, or on the default device.
+    ///
+    /// The stream runs at `sample_rate` if the device supports it. Returns the stream and a sink
+    /// that plays on it. Fails if the device has no configuration with enough channels.
+    pub fn open(
+        device: Option<rodio::Device>,
+        channels: usize,
+        sample_rate: u32,
+    ) -> Result<(Self, rodio::Sink), Error> {
+        let device = match device {
+            Some(device) => device,
+            None => cpal::default_host()
+                .default_output_device()
+                .ok_or(rodio::StreamError::NoDevice)?,
+        };
+        let config = choose_config(&device, channels, sample_rate)?;
+
+        let (sink, queue) = rodio::Sink::new_idle();
+        let samples = UniformSourceIterator::new(queue, config.channels(), config.sample_rate().0);
+        let error_callback = |err| eprintln!("an error occurred on output stream: {}", err);
+        let stream = match config.sample_format() {
+            SampleFormat::F32 => {
+                device.build_output_stream(&config.config(), filler::<f32>(samples), error_callback)
+            }
+            SampleFormat::I16 => {
+                device.build_output_stream(&config.config(), filler::<i16>(samples), error_callback)
+            }
+            SampleFormat::U16 => {
+                device.build_output_stream(&config.config(), filler::<u16>(samples), error_callback)
+            }
+        }
+        .map_err(|e| Error::Device(e.to_string()))?;
+        stream.play().map_err(rodio::StreamError::PlayStreamError)?;
+
+        let stream = OutputStream {
+            _stream: stream,
+            channels: config.channels() as usize,
+        };
+        Ok((stream, sink))
+    }
+
+    /// Number of device channels, which may exceed the number of requested channels
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+}
+
+/// Pick the configuration with the fewest channels that are at least `channels`, preferring
+/// configurations that support `sample_rate`.
+fn choose_config(
+    device: &rodio::Device,
+    channels: usize,
+    sample_rate: u32,
+) -> Result<SupportedStreamConfig, Error> {
+    let rate = SampleRate(sample_rate);
+    let supports_rate = |c: &cpal::SupportedStreamConfigRange| {
+        c.min_sample_rate() <= rate && rate <= c.max_sample_rate()
+    };
+
+    let mut configs: Vec<_> = device
+        .supported_output_configs()
+        .map_err(|e| Error::Device(e.to_string()))?
+        .filter(|c| c.channels() as usize >= channels)
+        .collect();
+    configs.sort_by(|a, b| {
+        a.channels()
+            .cmp(&b.channels())
+            .then_with(|| supports_rate(b).cmp(&supports_rate(a)))
+            .then_with(|| b.cmp_default_heuristics(a))
+    });
+
+    let config = configs.into_iter().next().ok_or_else(|| {
+        Error::Device(format!(
+            "the audio device has no output with {} channels",
+            channels
+        ))
+    })?;
+    if supports_rate(&config) {
+        Ok(config.with_sample_rate(rate))
+    } else {
+        Ok(config.with_max_sample_rate())
+    }
+}
+
+/// Stream callback that fills the device buffer from `samples`
+fn filler<T: Sample>(
+    mut samples: impl Iterator<Item = f32> + Send + 'static,
+) -> impl FnMut(&mut [T], &cpal::OutputCallbackInfo) + Send + 'static {
+    move |data, _| {
+        for d in data {
+            *d = T::from(&samples.next().unwrap_or(0.0));
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! Direction-dependent radiation of sound sources
+
+use std::f32::consts::PI;
+
+/// How a source radiates sound into different directions
+///
+/// The cone model follows OpenAL: inside the inner cone the source is heard at full level,
+/// outside the outer cone at `outer_gain`, and in between the gain is interpolated linearly.
+/// Cone angles are the full opening angles in radians, centered on the source's front
+/// direction.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Directivity {
+    inner_angle: f32,
+    outer_angle: f32,
+    outer_gain: f32,
+    outer_hf_gain: f32,
+}
+
+impl Directivity {
+    /// A source that radiates equally in all directions
+    pub fn omni() -> Self {
+        Directivity {
+            inner_angle: 2.0 * PI,
+            outer_angle: 2.0 * PI,
+            outer_gain: 1.0,
+            outer_hf_gain: 1.0,
+        }
+    }
+
+    /// Create a cone with given inner and outer opening angles (in radians).
+    ///
+    /// Outside the outer cone the source is silent unless configured otherwise with
+    /// `with_outer_gain`.
+    pub fn cone(inner_angle: f32, outer_angle: f32) -> Self {
+        assert!(inner_angle >= 0.0);
+        assert!(outer_angle >= inner_angle);
+        Directivity {
+            inner_angle: inner_angle.min(2.0 * PI),
+            outer_angle: outer_angle.min(2.0 * PI),
+            outer_gain: 0.0,
+            outer_hf_gain: 1.0,
+        }
+    }
+
+    /// Set the gain outside the outer cone (defaults to 0).
+    pub fn with_outer_gain(mut self, gain: f32) -> Self {
+        assert!(gain >= 0.0);
+        self.outer_gain = gain;
+        self
+    }
+
+    /// Set the attenuation of high frequencies outside the outer cone (defaults to 1, which
+    /// disables the low-pass).
+    ///
+    /// Like air absorption, the value is the gain at the filter reference frequency of 5 kHz.
+    pub fn with_outer_hf_gain(mut self, gain: f32) -> Self {
+        assert!((0.0..=1.0).contains(&gain));
+        self.outer_hf_gain = gain;
+        self
+    }
+
+    /// Compute gain and high-frequency gain for a listener at `angle` (in radians) off the
+    /// source's front direction.
+    pub fn gains(&self, angle: f32) -> (f32, f32) {
+        let t = self.outer_weight(angle.abs());
+        (
+            (1.0 - t) + t * self.outer_gain,
+            (1.0 - t) + t * self.outer_hf_gain,
+        )
+    }
+
+    /// 0 inside the inner cone, 1 outside the outer cone
+    fn outer_weight(&self, angle: f32) -> f32 {
+        let inner = self.inner_angle / 2.0;
+        let outer = self.outer_angle / 2.0;
+        if angle <= inner {
+            0.0
+        } else if angle >= outer {
+            1.0
+        } else {
+            (angle - inner) / (outer - inner)
+        }
+    }
+}
+
+impl Default for Directivity {
+    fn default() -> Self {
+        Directivity::omni()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn omni_has_unit_gain_everywhere() {
+        let d = Directivity::omni();
+        for i in 0..10 {
+            assert_eq!(d.gains(i as f32 * PI / 9.0), (1.0, 1.0));
+        }
+    }
+
+    #[test]
+    fn cone_interpolates_between_inner_and_outer_angle() {
+        let d = Directivity::cone(PI / 2.0, PI)
+            .with_outer_gain(0.2)
+            .with_outer_hf_gain(0.5);
+
+        assert_eq!(d.gains(0.0), (1.0, 1.0));
+        assert_eq!(d.gains(PI / 4.0), (1.0, 1.0));
+        assert_eq!(d.gains(PI), (0.2, 0.5));
+        assert_eq!(d.gains(-PI / 2.0), (0.2, 0.5));
+
+        let (g, hf) = d.gains(3.0 * PI / 8.0);
+        assert!((g - 0.6).abs() < 1e-6);
+        assert!((hf - 0.75).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,237 @@
+//! Attenuation of sound sources with distance
+
+use std::f32;
+
+/// How the level of a source falls off with distance
+///
+/// The formulas follow OpenAL. Distances are measured in meters.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Falloff {
+    /// No attenuation
+    None,
+
+    /// `ref / (ref + rolloff * (d - ref))`
+    Inverse,
+
+    /// Like `Inverse`, but the distance is clamped between reference and maximum distance
+    InverseClamped,
+
+    /// `1 - rolloff * (d - ref) / (max - ref)`
+    Linear,
+
+    /// Like `Linear`, but the distance is clamped between reference and maximum distance
+    LinearClamped,
+
+    /// `(d / ref) ^ -rolloff`
+    Exponential,
+
+    /// Like `Exponential`, but the distance is clamped between reference and maximum distance
+    ExponentialClamped,
+}
+
+/// Distance model of a sound source
+///
+/// The default model attenuates sources inversely proportional to their distance, but does not
+/// amplify sources closer than one meter.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DistanceModel {
+    falloff: Falloff,
+    reference_distance: f32,
+    rolloff_factor: f32,
+    max_distance: f32,
+    air_absorption: f32,
+}
+
+impl DistanceModel {
+    /// Create a new distance model with given falloff and default parameters.
+    pub fn new(falloff: Falloff) -> Self {
+        DistanceModel {
+            falloff,
+            ..Default::default()
+        }
+    }
+
+    /// Set the distance at which the source is heard at its original level (defaults to 1).
+    ///
+    /// The distance must not exceed the maximum distance.
+    pub fn with_reference_distance(mut self, d: f32) -> Self {
+        assert!(d > 0.0 && d <= self.max_distance);
+        self.reference_distance = d;
+        self
+    }
+
+    /// Set how fast the level falls off with distance (defaults to 1).
+    pub fn with_rolloff_factor(mut self, r: f32) -> Self {
+        assert!(r >= 0.0);
+        self.rolloff_factor = r;
+        self
+    }
+
+    /// Set the distance beyond which the clamped models do no longer attenuate, and where the
+    /// linear models reach their minimum (defaults to infinity).
+    ///
+    /// The distance must be finite and at least the reference distance.
+    pub fn with_max_distance(mut self, d: f32) -> Self {
+        assert!(d.is_finite() && d >= self.reference_distance);
+        self.max_distance = d;
+        self
+    }
+
+    /// Set how strongly high frequencies are absorbed by the air (defaults to 0).
+    ///
+    /// A factor of 1 attenuates high frequencies by 0.05 dB per meter, which corresponds to
+    /// typical outdoor conditions. Larger values simulate humid or foggy air.
+    pub fn with_air_absorption(mut self, factor: f32) -> Self {
+        assert!(factor >= 0.0);
+        self.air_absorption = factor;
+        self
+    }
+
+    /// Falloff of the model
+    pub fn falloff(&self) -> Falloff {
+        self.falloff
+    }
+
+    /// Compute the gain of a source at given distance.
+    ///
+    /// The unclamped `Inverse` and `Exponential` models amplify sources closer than the
+    /// reference distance, by at most 20 dB. The gain is always finite and non-negative.
+    pub fn gain(&self, distance: f32) -> f32 {
+        let r = self.reference_distance;
+        let clamped = distance.max(r).min(self.max_distance.max(r));
+
+        let gain = match self.falloff {
+            Falloff::None => 1.0,
+            Falloff::Inverse => {
+                // the attenuated distance reaches zero at the listener, or earlier if rolloff > 1
+                r / (r + self.rolloff_factor * (distance - r)).max(r / MAX_GAIN)
+            }
+            Falloff::InverseClamped => r / (r + self.rolloff_factor * (clamped - r)),
+            Falloff::Linear => self.linear_gain(distance.min(self.max_distance)),
+            Falloff::LinearClamped => self.linear_gain(clamped),
+            Falloff::Exponential => (distance / r).powf(-self.rolloff_factor).min(MAX_GAIN),
+            Falloff::ExponentialClamped => (clamped / r).powf(-self.rolloff_factor),
+        };
+
+        gain.max(0.0)
+    }
+
+    /// Compute the attenuation of high frequencies due to air absorption at given distance.
+    ///
+    /// The result is the gain at the filter reference frequency of 5 kHz.
+    pub fn air_absorption_gain(&self, distance: f32) -> f32 {
+        let distance = distance.max(0.0).min(self.max_distance);
+        AIR_ABSORPTION_GAIN_HF.powf(self.air_absorption * distance)
+    }
+
+    fn linear_gain(&self, distance: f32) -> f32 {
+        let r = self.reference_distance;
+        // without a range to fade out over, the source is not attenuated
+        if self.max_distance.is_infinite() || self.max_distance == r {
+            return 1.0;
+        }
+        1.0 - self.rolloff_factor * (distance - r) / (self.max_distance - r)
+    }
+}
+
+impl Default for DistanceModel {
+    fn default() -> Self {
+        DistanceModel {
+            falloff: Falloff::InverseClamped,
+            reference_distance: 1.0,
+            rolloff_factor: 1.0,
+            max_distance: f32::INFINITY,
+            air_absorption: 0.0,
+        }
+    }
+}
+
+/// Upper limit of the amplification of close sources (20 dB)
+const MAX_GAIN: f32 = 10.0;
+
+/// High-frequency gain per meter of air (-0.05 dB)
+const AIR_ABSORPTION_GAIN_HF: f32 = 0.994_260_6;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_model_does_not_amplify_close_sources() {
+        let model = DistanceModel::default();
+        assert_eq!(model.gain(0.0), 1.0);
+        assert_eq!(model.gain(0.5), 1.0);
+        assert_eq!(model.gain(1.0), 1.0);
+        assert_eq!(model.gain(4.0), 0.25);
+    }
+
+    #[test]
+    fn clamped_models_stop_attenuating_at_max_distance() {
+        for &falloff in &[
+            Falloff::InverseClamped,
+            Falloff::LinearClamped,
+            Falloff::ExponentialClamped,
+        ] {
+            let model = DistanceModel::new(falloff)
+                .with_reference_distance(2.0)
+                .with_rolloff_factor(0.5)
+                .with_max_distance(10.0);
+            assert_eq!(model.gain(2.0), 1.0);
+            assert_eq!(model.gain(10.0), model.gain(20.0));
+            assert!(model.gain(5.0) < 1.0);
+        }
+    }
+
+    #[test]
+    fn linear_model_reaches_zero_at_max_distance() {
+        let model = DistanceModel::new(Falloff::Linear).with_max_distance(11.0);
+        assert_eq!(model.gain(6.0), 0.5);
+        assert_eq!(model.gain(11.0), 0.0);
+        assert_eq!(model.gain(100.0), 0.0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn reject_max_distance_below_reference_distance() {
+        DistanceModel::default()
+            .with_reference_distance(2.0)
+            .with_max_distance(1.0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn reject_infinite_max_distance() {
+        DistanceModel::default().with_max_distance(f32::INFINITY);
+    }
+
+    #[test]
+    fn air_absorption_increases_with_distance() {
+        let model = DistanceModel::default();
+        assert_eq!(model.air_absorption_gain(1000.0), 1.0);
+
+        let model = model.with_air_absorption(1.0);
+        assert_eq!(model.air_absorption_gain(0.0), 1.0);
+        let db = 20.0 * model.air_absorption_gain(100.0).log10();
+        assert!((db + 5.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn unclamped_models_limit_the_gain_of_close_sources() {
+        for &falloff in &[Falloff::Inverse, Falloff::Exponential] {
+            for &rolloff in &[0.5, 1.0, 2.0, 4.0] {
+                let model = DistanceModel::new(falloff).with_rolloff_factor(rolloff);
+                let gains: Vec<_> = (0..=20).map(|i| model.gain(i as f32 * 0.1)).collect();
+                let max = gains[0];
+                assert!(max > 1.0 && max <= MAX_GAIN, "{:?} {}", falloff, rolloff);
+                assert!(gains.windows(2).all(|g| g[1] <= g[0]), "{:?}", gains);
+            }
+        }
+    }
+
+    #[test]
+    fn exponential_model() {
+        let model = DistanceModel::new(Falloff::Exponential).with_rolloff_factor(2.0);
+        assert_eq!(model.gain(2.0), 0.25);
+        assert_eq!(model.gain(0.5), 4.0);
+    }
+}
@@ -0,0 +1,182 @@
+//! Dual-band decoding with max-rE weighting at high frequencies.
+//!
+//! Below the crossover frequency, *B-format* channels are decoded unchanged (*basic* decoding),
+//! which reconstructs the sound field's velocity vector and thus the interaural time differences
+//! that dominate localization at low frequencies. Above the crossover, channels of order `l` are
+//! weighted to maximize the energy vector `rE`, which governs localization at high frequencies.
+
+use crate::bformat::{n_channels, Bformat, MAX_CHANNELS, MAX_ORDER};
+use crate::filter::Crossover;
+
+/// Per-order weights that maximize the energy vector of a 3D decoder of given order.
+///
+/// The weights are normalized to preserve the energy of a diffuse sound field.
+pub(crate) fn max_re_weights(order: usize) -> [f32; MAX_ORDER + 1] {
+    assert!(order <= MAX_ORDER);
+    // approximation of the largest root of the Legendre polynomial of degree order + 1
+    let r_e = (137.9f32.to_radians() / (order as f32 + 1.51)).cos();
+
+    let mut weights = [0.0; MAX_ORDER + 1];
+    let (mut p0, mut p1) = (1.0, r_e);
+    for (l, w) in weights.iter_mut().enumerate().take(order + 1) {
+        *w = match l {
+            0 => p0,
+            1 => p1,
+            _ => {
+                let p2 = ((2 * l - 1) as f32 * r_e * p1 - (l - 1) as f32 * p0) / l as f32;
+                p0 = p1;
+                p1 = p2;
+                p2
+            }
+        };
+    }
+
+    let energy = |w: &[f32]| -> f32 {
+        w.iter()
+            .enumerate()
+            .map(|(l, g)| (2 * l + 1) as f32 * g * g)
+            .sum()
+    };
+    let basic = [1.0; MAX_ORDER + 1];
+    let scale = (energy(&basic[..=order]) / energy(&weights[..=order])).sqrt();
+    for w in &mut weights[..=order] {
+        *w *= scale;
+    }
+    weights
+}
+
+/// Phase-matched dual-band weighting of *B-format* samples
+///
+/// Splits every channel with a Linkwitz-Riley crossover and applies max-rE weights to the high
+/// band. Decoding the result with a basic decoder yields a dual-band decoder.
+pub(crate) struct DualBand {
+    order: usize,
+    crossovers: Vec<Crossover>,
+    hf_gains: [f32; MAX_CHANNELS],
+}
+
+impl DualBand {
+    pub fn new(order: usize, crossover: f32, sample_rate: u32) -> Self {
+        DualBand::with_weights(order, max_re_weights(order), crossover, sample_rate)
+    }
+
+    /// Dual-band weighting with given per-order weights of the high band
+    pub fn with_weights(
+        order: usize,
+        weights: [f32; MAX_ORDER + 1],
+        crossover: f32,
+        sample_rate: u32,
+    ) -> Self {
+        let mut hf_gains = [0.0; MAX_CHANNELS];
+        for l in 0..=order {
+            let channels = if l == 0 { 0 } else { n_channels(l - 1) };
+            for g in &mut hf_gains[channels..n_channels(l)] {
+                *g = weights[l];
+            }
+        }
+
+        DualBand {
+            order,
+            crossovers: vec![Crossover::new(crossover, sample_rate); n_channels(order)],
+            hf_gains,
+        }
+    }
+
+    #[inline]
+    pub fn process(&mut self, sample: Bformat) -> Bformat {
+        let mut out = [0.0; MAX_CHANNELS];
+        let channels = sample.components()[..n_channels(self.order)].iter();
+        for (((y, &x), xo), g) in out
+            .iter_mut()
+            .zip(channels)
+            .zip(&mut self.crossovers)
+            .zip(&self.hf_gains)
+        {
+            let (low, high) = xo.process(x);
+            *y = low + g * high;
+        }
+        Bformat::from_components(out).with_lfe(sample.lfe())
+    }
+
+    /// Split a sample into its low and (weighted) high band.
+    ///
+    /// The LFE signal is kept in the low band.
+    #[inline]
+    pub fn split(&mut self, sample: Bformat) -> (Bformat, Bformat) {
+        let mut low = [0.0; MAX_CHANNELS];
+        let mut high = [0.0; MAX_CHANNELS];
+        let channels = sample.components()[..n_channels(self.order)].iter();
+        for ((((yl, yh), &x), xo), g) in low
+            .iter_mut()
+            .zip(high.iter_mut())
+            .zip(channels)
+            .zip(&mut self.crossovers)
+            .zip(&self.hf_gains)
+        {
+            let (l, h) = xo.process(x);
+            *yl = l;
+            *yh = g * h;
+        }
+        (
+            Bformat::from_components(low).with_lfe(sample.lfe()),
+            Bformat::from_components(high),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    #[test]
+    fn first_order_weights() {
+        let w = max_re_weights(1);
+        // the classic 3D max-rE weight for first order is 1/√3
+        assert!((w[1] / w[0] - 1.0 / 3f32.sqrt()).abs() < 1e-2);
+        assert!((w[0] * w[0] + 3.0 * w[1] * w[1] - 4.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn weights_decrease_with_order() {
+        let w = max_re_weights(3);
+        assert!(w[0] > w[1] && w[1] > w[2] && w[2] > w[3] && w[3] > 0.0);
+    }
+
+    /// amplitude of each channel, estimated from its RMS value
+    fn band_gains(freq: f32) -> [f32; 4] {
+        let mut db = DualBand::new(1, 400.0, 48000);
+        let w = 2.0 * PI * freq / 48000.0;
+        let mut power = [0.0f32; 4];
+        for i in 0..48000 {
+            let x = (w * i as f32).sin();
+            let mut c = [0.0; MAX_CHANNELS];
+            for ci in &mut c[..4] {
+                *ci = x;
+            }
+            let y = db.process(Bformat::from_components(c));
+            if i >= 24000 {
+                for (p, yi) in power.iter_mut().zip(y.components()) {
+                    *p += yi * yi / 24000.0;
+                }
+            }
+        }
+        let mut gains = [0.0; 4];
+        for (g, p) in gains.iter_mut().zip(&power) {
+            *g = (2.0 * p).sqrt();
+        }
+        gains
+    }
+
+    #[test]
+    fn basic_below_and_max_re_above_crossover() {
+        let w = max_re_weights(1);
+
+        let low = band_gains(30.0);
+        assert!(low.iter().all(|&g| (g - 1.0).abs() < 1e-2), "{:?}", low);
+
+        let high = band_gains(8000.0);
+        assert!((high[0] - w[0]).abs() < 1e-2, "{:?}", high);
+        assert!((high[1] - w[1]).abs() < 1e-2, "{:?}", high);
+    }
+}
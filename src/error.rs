@@ -0,0 +1,111 @@
+//! The error type of the crate.
+
+use std::error;
+use std::fmt;
+use std::io;
+
+/// Errors that can occur when loading configurations or starting playback
+#[derive(Debug)]
+pub enum Error {
+    /// Reading a file failed
+    Io(io::Error),
+
+    /// A text file could not be parsed
+    ///
+    /// Lines and columns are counted from 1.
+    Parse {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+
+    /// A file or configuration is malformed, or uses features that are not supported
+    InvalidData(String),
+
+    /// The audio output stream could not be opened
+    Stream(rodio::StreamError),
+
+    /// The audio device does not support the requested output, such as the number of channels
+    Device(String),
+
+    /// Playback on the audio output stream could not be started
+    Play(rodio::PlayError),
+}
+
+impl Error {
+    /// Parse error at the position of `token`, which must be a slice of `line`
+    pub(crate) fn at_token(line_number: usize, line: &str, token: &str, message: String) -> Self {
+        let offset = token.as_ptr() as usize - line.as_ptr() as usize;
+        Error::Parse {
+            line: line_number,
+            column: line[..offset].chars().count() + 1,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Parse {
+                line,
+                column,
+                message,
+            } => write!(f, "line {}, column {}: {}", line, column, message),
+            Error::InvalidData(message) => write!(f, "{}", message),
+            Error::Device(message) => write!(f, "{}", message),
+            Error::Stream(e) => write!(f, "{}", e),
+            Error::Play(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Stream(e) => Some(e),
+            Error::Play(e) => Some(e),
+            Error::Parse { .. } | Error::InvalidData(_) | Error::Device(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<rodio::StreamError> for Error {
+    fn from(e: rodio::StreamError) -> Self {
+        Error::Stream(e)
+    }
+}
+
+impl From<rodio::PlayError> for Error {
+    fn from(e: rodio::PlayError) -> Self {
+        Error::Play(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn columns_of_tokens() {
+        let line = "  add_row  0.25  x";
+        let token = line.split_whitespace().nth(2).unwrap();
+        match Error::at_token(7, line, token, "invalid number".to_string()) {
+            Error::Parse { line, column, .. } => assert_eq!((line, column), (7, 18)),
+            e => panic!("{}", e),
+        }
+        let line = "/version";
+        match Error::at_token(2, line, &line[line.len()..], "missing".to_string()) {
+            Error::Parse { column, .. } => assert_eq!(column, 9),
+            e => panic!("{}", e),
+        }
+    }
+}
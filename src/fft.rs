@@ -0,0 +1,286 @@
+//! Fast Fourier transform and partitioned block convolution.
+
+use std::f32::consts::PI;
+use std::ops::{Add, AddAssign, Mul, Sub};
+
+/// Complex number with single precision
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub(crate) struct Complex {
+    pub re: f32,
+    pub im: f32,
+}
+
+impl Complex {
+    pub fn new(re: f32, im: f32) -> Self {
+        Complex { re, im }
+    }
+
+    pub fn conj(self) -> Self {
+        Complex::new(self.re, -self.im)
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+    #[inline(always)]
+    fn add(self, other: Complex) -> Complex {
+        Complex::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl AddAssign for Complex {
+    #[inline(always)]
+    fn add_assign(&mut self, other: Complex) {
+        self.re += other.re;
+        self.im += other.im;
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+    #[inline(always)]
+    fn sub(self, other: Complex) -> Complex {
+        Complex::new(self.re - other.re, self.im - other.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+    #[inline(always)]
+    fn mul(self, other: Complex) -> Complex {
+        Complex::new(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re,
+        )
+    }
+}
+
+/// Iterative radix-2 FFT of fixed size
+pub(crate) struct Fft {
+    twiddles: Vec<Complex>,
+    bit_reversed: Vec<usize>,
+}
+
+impl Fft {
+    /// Prepare transforms of size `n`, which must be a power of two.
+    pub fn new(n: usize) -> Self {
+        assert!(n.is_power_of_two());
+        let bits = n.trailing_zeros();
+        let twiddles = (0..n / 2)
+            .map(|k| {
+                let phi = -2.0 * PI * k as f32 / n as f32;
+                Complex::new(phi.cos(), phi.sin())
+            })
+            .collect();
+        let bit_reversed = (0..n)
+            .map(|i| {
+                if bits == 0 {
+                    0
+                } else {
+                    i.reverse_bits() >> (usize::BITS - bits)
+                }
+            })
+            .collect();
+        Fft {
+            twiddles,
+            bit_reversed,
+        }
+    }
+
+    /// Size of the transform
+    pub fn len(&self) -> usize {
+        self.bit_reversed.len()
+    }
+
+    /// Forward transform in place
+    pub fn forward(&self, data: &mut [Complex]) {
+        assert_eq!(data.len(), self.len());
+        for (i, &j) in self.bit_reversed.iter().enumerate() {
+            if i < j {
+                data.swap(i, j);
+            }
+        }
+
+        let n = data.len();
+        let mut size = 2;
+        while size <= n {
+            let half = size / 2;
+            let stride = n / size;
+            for start in (0..n).step_by(size) {
+                for k in 0..half {
+                    let t = self.twiddles[k * stride] * data[start + k + half];
+                    let u = data[start + k];
+                    data[start + k] = u + t;
+                    data[start + k + half] = u - t;
+                }
+            }
+            size *= 2;
+        }
+    }
+
+    /// Inverse transform in place, including the 1/n scaling
+    pub fn inverse(&self, data: &mut [Complex]) {
+        for x in data.iter_mut() {
+            *x = x.conj();
+        }
+        self.forward(data);
+        let scale = 1.0 / data.len() as f32;
+        for x in data.iter_mut() {
+            *x = Complex::new(x.re * scale, -x.im * scale);
+        }
+    }
+}
+
+/// Spectra of the partitions of a filter's impulse response
+///
+/// The impulse response is split into blocks of equal length, each of which is zero-padded to
+/// twice the block length and transformed. Only the non-negative frequencies are stored, since
+/// the spectra of real signals are symmetric.
+pub(crate) struct PartitionedFilter {
+    partitions: Vec<Vec<Complex>>,
+}
+
+impl PartitionedFilter {
+    pub fn new(impulse_response: &[f32], fft: &Fft) -> Self {
+        let block = fft.len() / 2;
+        let partitions = impulse_response
+            .chunks(block)
+            .map(|chunk| {
+                let mut spectrum = vec![Complex::default(); fft.len()];
+                for (s, &h) in spectrum.iter_mut().zip(chunk) {
+                    s.re = h;
+                }
+                fft.forward(&mut spectrum);
+                spectrum.truncate(block + 1);
+                spectrum
+            })
+            .collect();
+        PartitionedFilter { partitions }
+    }
+
+    /// Number of partitions
+    pub fn len(&self) -> usize {
+        self.partitions.len()
+    }
+}
+
+/// Recent input spectra for uniformly partitioned overlap-save convolution
+///
+/// Every block of new input is transformed together with the previous block. Convolving with a
+/// partitioned filter then reduces to multiplying each partition with the input spectrum that
+/// is as many blocks old, and summing the products.
+pub(crate) struct FrequencyDelayLine {
+    spectra: Vec<Vec<Complex>>,
+    newest: usize,
+    /// the previous and the current block of input samples
+    window: Vec<f32>,
+}
+
+impl FrequencyDelayLine {
+    /// Hold enough input spectra for filters with up to `n_partitions` partitions.
+    pub fn new(n_partitions: usize, fft: &Fft) -> Self {
+        let block = fft.len() / 2;
+        FrequencyDelayLine {
+            spectra: vec![vec![Complex::default(); block + 1]; n_partitions.max(1)],
+            newest: 0,
+            window: vec![0.0; fft.len()],
+        }
+    }
+
+    /// Transform a new block of input samples, replacing the oldest spectrum.
+    pub fn push(&mut self, block: &[f32], fft: &Fft, scratch: &mut [Complex]) {
+        let half = fft.len() / 2;
+        assert_eq!(block.len(), half);
+        self.window.copy_within(half.., 0);
+        self.window[half..].copy_from_slice(block);
+
+        for (s, &x) in scratch.iter_mut().zip(&self.window) {
+            *s = Complex::new(x, 0.0);
+        }
+        fft.forward(scratch);
+
+        self.newest = (self.newest + 1) % self.spectra.len();
+        self.spectra[self.newest].copy_from_slice(&scratch[..=half]);
+    }
+
+    /// Add the spectrum of the filtered input to `out`.
+    #[inline]
+    pub fn accumulate(&self, filter: &PartitionedFilter, out: &mut [Complex]) {
+        assert!(filter.len() <= self.spectra.len());
+        let n = self.spectra.len();
+        for (age, partition) in filter.partitions.iter().enumerate() {
+            let input = &self.spectra[(self.newest + n - age) % n];
+            for ((y, &x), &h) in out.iter_mut().zip(input).zip(partition) {
+                *y += x * h;
+            }
+        }
+    }
+}
+
+/// Transform an accumulated output spectrum back and keep the valid (second) half.
+///
+/// `spectrum` holds the non-negative frequencies and is used as scratch space of full length.
+pub(crate) fn overlap_save_output(spectrum: &mut [Complex], fft: &Fft, out: &mut [f32]) {
+    let n = fft.len();
+    let half = n / 2;
+    for k in 1..half {
+        spectrum[n - k] = spectrum[k].conj();
+    }
+    fft.inverse(spectrum);
+    for (y, x) in out.iter_mut().zip(&spectrum[half..]) {
+        *y = x.re;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transform_roundtrip_and_impulse() {
+        let fft = Fft::new(16);
+        let mut data = vec![Complex::default(); 16];
+        data[1] = Complex::new(1.0, 0.0);
+        fft.forward(&mut data);
+        for (k, x) in data.iter().enumerate() {
+            let phi = -2.0 * PI * k as f32 / 16.0;
+            assert!((x.re - phi.cos()).abs() < 1e-6 && (x.im - phi.sin()).abs() < 1e-6);
+        }
+        fft.inverse(&mut data);
+        for (i, x) in data.iter().enumerate() {
+            let expected = if i == 1 { 1.0 } else { 0.0 };
+            assert!((x.re - expected).abs() < 1e-6 && x.im.abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn partitioned_convolution_matches_direct_convolution() {
+        let block = 8;
+        let fft = Fft::new(2 * block);
+        let h: Vec<f32> = (0..29).map(|i| ((i * 7) % 11) as f32 - 5.0).collect();
+        let x: Vec<f32> = (0..80)
+            .map(|i| ((i * 5) % 13) as f32 / 13.0 - 0.5)
+            .collect();
+
+        let filter = PartitionedFilter::new(&h, &fft);
+        let mut fdl = FrequencyDelayLine::new(filter.len(), &fft);
+        let mut scratch = vec![Complex::default(); fft.len()];
+        let mut y = vec![];
+        for chunk in x.chunks(block) {
+            fdl.push(chunk, &fft, &mut scratch);
+            let mut spectrum = vec![Complex::default(); fft.len()];
+            fdl.accumulate(&filter, &mut spectrum[..=block]);
+            let mut out = vec![0.0; block];
+            overlap_save_output(&mut spectrum, &fft, &mut out);
+            y.extend(out);
+        }
+
+        for (n, yn) in y.iter().enumerate() {
+            let direct: f32 = (0..=n)
+                .filter(|&k| n - k < h.len())
+                .map(|k| x[k] * h[n - k])
+                .sum();
+            assert!((yn - direct).abs() < 1e-3, "{}: {} != {}", n, yn, direct);
+        }
+    }
+}
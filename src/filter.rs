@@ -0,0 +1,336 @@
+//! Simple recursive filters for processing audio samples
+
+use std::f32::consts::PI;
+
+/// Reference frequency for specifying low-pass filters by their high-frequency gain
+pub const REFERENCE_FREQUENCY: f32 = 5000.0;
+
+/// One-pole low-pass filter with smoothly adjustable cutoff
+#[derive(Debug, Clone)]
+pub struct OnePoleLowpass {
+    state: f32,
+    coefficient: f32,
+    target_coefficient: f32,
+}
+
+impl OnePoleLowpass {
+    /// Create a filter that initially passes all frequencies
+    pub fn new() -> Self {
+        OnePoleLowpass {
+            state: 0.0,
+            coefficient: 1.0,
+            target_coefficient: 1.0,
+        }
+    }
+
+    /// Compute the filter coefficient for a given attenuation `hf_gain` at the
+    /// `REFERENCE_FREQUENCY`.
+    ///
+    /// A gain of 1 disables the filter.
+    pub fn coefficient(hf_gain: f32, sample_rate: u32) -> f32 {
+        if hf_gain >= 1.0 {
+            return 1.0;
+        }
+        let hf_gain = hf_gain.max(1e-3);
+        let cutoff = REFERENCE_FREQUENCY / (1.0 / (hf_gain * hf_gain) - 1.0).sqrt();
+        let cutoff = cutoff.min(sample_rate as f32 / 2.0);
+        1.0 - (-2.0 * PI * cutoff / sample_rate as f32).exp()
+    }
+
+    /// Set the coefficient that the filter approaches smoothly
+    pub fn set_target(&mut self, coefficient: f32) {
+        self.target_coefficient = coefficient;
+    }
+
+    /// Set the coefficient immediately
+    pub fn set_coefficient(&mut self, coefficient: f32) {
+        self.coefficient = coefficient;
+        self.target_coefficient = coefficient;
+    }
+
+    /// Filter one sample and move the coefficient by at most `max_step` towards its target
+    #[inline]
+    pub fn process(&mut self, x: f32, max_step: f32) -> f32 {
+        let d = self.target_coefficient - self.coefficient;
+        self.coefficient += d.max(-max_step).min(max_step);
+        self.state += self.coefficient * (x - self.state);
+        self.state
+    }
+}
+
+impl Default for OnePoleLowpass {
+    fn default() -> Self {
+        OnePoleLowpass::new()
+    }
+}
+
+/// First-order near-field compensation filter
+///
+/// A source at distance `r` has a pronounced bass boost in its first-order components, which
+/// ambisonic playback over loudspeakers at distance `R` must compensate. The combined effect is
+/// the shelving filter `(s + c/r) / (s + c/R)`, which leaves high frequencies unchanged and
+/// amplifies low frequencies by `R / r`.
+///
+/// The filter is discretized with a matched z-transform: the zero is derived from the source
+/// distance and the pole from the loudspeaker radius. A zero equal to the pole disables the
+/// filter.
+#[derive(Debug, Clone)]
+pub struct NearFieldFilter {
+    x1: f32,
+    y1: f32,
+    zero: f32,
+    target_zero: f32,
+    pole: f32,
+    target_pole: f32,
+}
+
+impl NearFieldFilter {
+    /// Create a filter that initially passes all frequencies
+    pub fn new() -> Self {
+        NearFieldFilter {
+            x1: 0.0,
+            y1: 0.0,
+            zero: 0.0,
+            target_zero: 0.0,
+            pole: 0.0,
+            target_pole: 0.0,
+        }
+    }
+
+    /// Compute the zero or pole for a given distance (in meters)
+    pub fn coefficient(distance: f32, speed_of_sound: f32, sample_rate: u32) -> f32 {
+        (-speed_of_sound / (distance * sample_rate as f32)).exp()
+    }
+
+    /// Set the zero and pole that the filter approaches smoothly
+    ///
+    /// Both move at the same rate, so that switching compensation on or off does not cause a
+    /// transient while only one of them has changed.
+    pub fn set_target(&mut self, zero: f32, pole: f32) {
+        self.target_zero = zero;
+        self.target_pole = pole;
+    }
+
+    /// Set zero and pole immediately
+    pub fn set_coefficients(&mut self, zero: f32, pole: f32) {
+        self.zero = zero;
+        self.target_zero = zero;
+        self.pole = pole;
+        self.target_pole = pole;
+    }
+
+    /// Filter one sample and move zero and pole by at most `max_step` towards their targets
+    #[inline]
+    pub fn process(&mut self, x: f32, max_step: f32) -> f32 {
+        let d = self.target_zero - self.zero;
+        self.zero += d.clamp(-max_step, max_step);
+        let d = self.target_pole - self.pole;
+        self.pole += d.clamp(-max_step, max_step);
+
+        // normalize to unit gain at the nyquist frequency
+        let gain = (1.0 + self.pole) / (1.0 + self.zero);
+        let y = gain * (x - self.zero * self.x1) + self.pole * self.y1;
+        self.x1 = x;
+        self.y1 = y;
+        y
+    }
+}
+
+impl Default for NearFieldFilter {
+    fn default() -> Self {
+        NearFieldFilter::new()
+    }
+}
+
+/// Second-order IIR filter section
+#[derive(Debug, Clone)]
+pub struct Biquad {
+    b: [f32; 3],
+    a: [f32; 2],
+    z: [f32; 2],
+}
+
+impl Biquad {
+    /// Butterworth low-pass with given cutoff frequency
+    pub fn lowpass(cutoff: f32, sample_rate: u32) -> Self {
+        let (cos, alpha) = Biquad::prewarp(cutoff, sample_rate);
+        let b1 = 1.0 - cos;
+        Biquad::normalized(
+            [b1 / 2.0, b1, b1 / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    /// Butterworth high-pass with given cutoff frequency
+    pub fn highpass(cutoff: f32, sample_rate: u32) -> Self {
+        let (cos, alpha) = Biquad::prewarp(cutoff, sample_rate);
+        let b1 = 1.0 + cos;
+        Biquad::normalized(
+            [b1 / 2.0, -b1, b1 / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    fn prewarp(cutoff: f32, sample_rate: u32) -> (f32, f32) {
+        let w = 2.0 * PI * cutoff / sample_rate as f32;
+        (w.cos(), w.sin() * std::f32::consts::FRAC_1_SQRT_2)
+    }
+
+    fn normalized(b: [f32; 3], a: [f32; 3]) -> Self {
+        Biquad {
+            b: [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            a: [a[1] / a[0], a[2] / a[0]],
+            z: [0.0; 2],
+        }
+    }
+
+    /// Filter one sample (transposed direct form II)
+    #[inline]
+    pub fn process(&mut self, x: f32) -> f32 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// Fourth-order Linkwitz-Riley crossover
+///
+/// Splits a signal into a low and a high band that are in phase at all frequencies, so that
+/// their sum has a flat magnitude response.
+#[derive(Debug, Clone)]
+pub struct Crossover {
+    low: [Biquad; 2],
+    high: [Biquad; 2],
+}
+
+impl Crossover {
+    pub fn new(frequency: f32, sample_rate: u32) -> Self {
+        let lp = Biquad::lowpass(frequency, sample_rate);
+        let hp = Biquad::highpass(frequency, sample_rate);
+        Crossover {
+            low: [lp.clone(), lp],
+            high: [hp.clone(), hp],
+        }
+    }
+
+    /// Split one sample into low and high band
+    #[inline]
+    pub fn process(&mut self, x: f32) -> (f32, f32) {
+        let low = self.low[0].process(x);
+        let low = self.low[1].process(low);
+        let high = self.high[0].process(x);
+        let high = self.high[1].process(high);
+        (low, high)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gain_at(coefficient: f32, freq: f32, sample_rate: u32) -> f32 {
+        let mut filter = OnePoleLowpass::new();
+        filter.set_coefficient(coefficient);
+        let w = 2.0 * PI * freq / sample_rate as f32;
+        let n = sample_rate as usize;
+        let mut peak = 0.0f32;
+        for i in 0..n {
+            let y = filter.process((w * i as f32).sin(), 0.0);
+            if i > n / 2 {
+                peak = peak.max(y.abs());
+            }
+        }
+        peak
+    }
+
+    #[test]
+    fn near_field_filter_boosts_bass() {
+        let zero = NearFieldFilter::coefficient(0.5, 343.0, 48000);
+        let pole = NearFieldFilter::coefficient(2.0, 343.0, 48000);
+        let mut filter = NearFieldFilter::new();
+        filter.set_coefficients(zero, pole);
+
+        let dc = (0..10000).map(|_| filter.process(1.0, 0.0)).last().unwrap();
+        assert!((dc - 4.0).abs() < 1e-2, "{}", dc);
+
+        let nyquist = (0..10000)
+            .map(|i| filter.process(if i % 2 == 0 { 1.0 } else { -1.0 }, 0.0))
+            .last()
+            .unwrap();
+        assert!((nyquist.abs() - 1.0).abs() < 1e-3, "{}", nyquist);
+    }
+
+    #[test]
+    fn near_field_filter_with_zero_at_pole_passes_everything() {
+        let p = NearFieldFilter::coefficient(1.0, 343.0, 48000);
+        let mut filter = NearFieldFilter::new();
+        filter.set_coefficients(p, p);
+        for i in 0..100 {
+            let x = (i as f32 * 0.3).sin();
+            assert!((filter.process(x, 0.0) - x).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn near_field_filter_switches_without_transient() {
+        let zero = NearFieldFilter::coefficient(0.5, 343.0, 48000);
+        let pole = NearFieldFilter::coefficient(2.0, 343.0, 48000);
+        let mut filter = NearFieldFilter::new();
+
+        // switch compensation on and off while playing a constant signal
+        let mut output = vec![];
+        filter.set_target(zero, pole);
+        output.extend((0..10000).map(|_| filter.process(1.0, 0.001)));
+        assert!((output[9999] - 4.0).abs() < 1e-2, "{}", output[9999]);
+        filter.set_target(0.0, 0.0);
+        output.extend((0..10000).map(|_| filter.process(1.0, 0.001)));
+        assert!((output[19999] - 1.0).abs() < 1e-3, "{}", output[19999]);
+
+        let peak = output.iter().cloned().fold(0.0, f32::max);
+        assert!(peak < 4.01, "{}", peak);
+    }
+
+    fn crossover_gains(freq: f32) -> (f32, f32, f32) {
+        let mut xo = Crossover::new(500.0, 48000);
+        let w = 2.0 * PI * freq / 48000.0;
+        let (mut low, mut high, mut sum) = (0.0f32, 0.0f32, 0.0f32);
+        for i in 0..48000 {
+            let (l, h) = xo.process((w * i as f32).sin());
+            if i > 24000 {
+                low = low.max(l.abs());
+                high = high.max(h.abs());
+                sum = sum.max((l + h).abs());
+            }
+        }
+        (low, high, sum)
+    }
+
+    #[test]
+    fn crossover_bands_sum_to_flat_response() {
+        for &f in &[50.0, 250.0, 500.0, 1000.0, 5000.0] {
+            let (_, _, sum) = crossover_gains(f);
+            assert!((sum - 1.0).abs() < 1e-2, "{} Hz: {}", f, sum);
+        }
+
+        let (low, high, _) = crossover_gains(500.0);
+        assert!((low - 0.5).abs() < 1e-2 && (high - 0.5).abs() < 1e-2);
+
+        let (low, high, _) = crossover_gains(50.0);
+        assert!(low > 0.99 && high < 0.01);
+    }
+
+    #[test]
+    fn unit_gain_passes_everything() {
+        assert_eq!(OnePoleLowpass::coefficient(1.0, 48000), 1.0);
+        assert!((gain_at(1.0, 10000.0, 48000) - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn attenuation_at_reference_frequency() {
+        let c = OnePoleLowpass::coefficient(0.5, 48000);
+        let g = gain_at(c, REFERENCE_FREQUENCY, 48000);
+        assert!((g - 0.5).abs() < 0.05, "{}", g);
+        assert!(gain_at(c, 100.0, 48000) > 0.95);
+    }
+}
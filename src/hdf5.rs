@@ -0,0 +1,1958 @@
+//! Minimal reader for HDF5 files.
+//!
+//! Supports the subset of the format that netCDF-4 uses for SOFA files: superblock versions 0
+//! to 3, object header versions 1 and 2, groups stored in symbol tables, as compact links, or as
+//! dense links in a fractal heap, and datasets with compact, contiguous, or chunked layout.
+//! Chunks may be indexed by any of the chunk indexes up to HDF5 1.14 (version 1 and 2 B-trees,
+//! single chunks, implicit, fixed, and extensible arrays), and compressed with deflate and
+//! shuffled. Only numeric data and fixed-length string attributes can be read. Checksums are
+//! not verified.
+
+use std::collections::HashSet;
+
+use crate::inflate::zlib_decompress;
+
+const SIGNATURE: &[u8] = b"\x89HDF\r\n\x1a\n";
+const UNDEFINED_ADDRESS: u64 = u64::MAX;
+
+/// Maximum size of an unlimited dimension
+const UNLIMITED: u64 = u64::MAX;
+
+/// Deepest B-tree or fractal heap that is followed; deeper structures are considered corrupt
+const MAX_DEPTH: usize = 32;
+
+/// Largest expansion of deflated data, which bounds the size of compressed datasets
+const MAX_DEFLATE_RATIO: u64 = 1032;
+
+const MSG_DATASPACE: u16 = 0x0001;
+const MSG_LINK_INFO: u16 = 0x0002;
+const MSG_DATATYPE: u16 = 0x0003;
+const MSG_LINK: u16 = 0x0006;
+const MSG_LAYOUT: u16 = 0x0008;
+const MSG_FILTER_PIPELINE: u16 = 0x000B;
+const MSG_ATTRIBUTE: u16 = 0x000C;
+const MSG_CONTINUATION: u16 = 0x0010;
+const MSG_SYMBOL_TABLE: u16 = 0x0011;
+
+const FILTER_DEFLATE: u16 = 1;
+const FILTER_SHUFFLE: u16 = 2;
+const FILTER_FLETCHER32: u16 = 3;
+
+/// An HDF5 file, held completely in memory
+pub(crate) struct File {
+    data: Vec<u8>,
+    base: u64,
+    offset_size: usize,
+    length_size: usize,
+    root: u64,
+}
+
+/// A dataset's shape, element type, storage, and attributes
+pub(crate) struct Dataset {
+    shape: Vec<u64>,
+    /// maximum shape, which determines the order of chunks in array chunk indexes
+    max_shape: Vec<u64>,
+    datatype: Datatype,
+    layout: Layout,
+    filters: Vec<Filter>,
+    attributes: Vec<Attribute>,
+}
+
+#[derive(Clone)]
+enum Datatype {
+    Integer {
+        size: usize,
+        signed: bool,
+        big_endian: bool,
+    },
+    Float {
+        size: usize,
+        big_endian: bool,
+    },
+    String {
+        size: usize,
+    },
+    Other,
+}
+
+enum Layout {
+    Compact(Vec<u8>),
+    Contiguous {
+        address: u64,
+        size: u64,
+    },
+    /// chunk dimensions, including the element size as the last dimension, and the chunk index
+    Chunked {
+        dims: Vec<u64>,
+        index: ChunkIndex,
+    },
+}
+
+/// Address of the structure that locates the chunks of a dataset
+enum ChunkIndex {
+    BtreeV1(u64),
+    /// the only chunk of the dataset, and its size
+    Single(u64, u64),
+    /// chunks of equal size, stored one after the other in the order of a fixed array
+    Implicit(u64),
+    FixedArray(u64),
+    ExtensibleArray(u64),
+    BtreeV2(u64),
+}
+
+/// A chunk's position in the dataset, in elements, and its location in the file
+struct Chunk {
+    offset: Vec<u64>,
+    address: u64,
+    size: u64,
+    filter_mask: u32,
+}
+
+struct Filter {
+    id: u16,
+    client_data: Vec<u32>,
+}
+
+struct Attribute {
+    name: String,
+    datatype: Datatype,
+    data: Vec<u8>,
+}
+
+struct Message<'a> {
+    kind: u16,
+    data: &'a [u8],
+}
+
+/// Sequential reader of little-endian fields
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+    offset_size: usize,
+    length_size: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let data: &'a [u8] = self.data;
+        let bytes = self
+            .pos
+            .checked_add(n)
+            .and_then(|end| data.get(self.pos..end))
+            .ok_or("unexpected end of HDF5 structure")?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, n: usize) -> Result<(), String> {
+        self.bytes(n).map(|_| ())
+    }
+
+    fn uint(&mut self, n: usize) -> Result<u64, String> {
+        let bytes = self.bytes(n)?;
+        Ok(bytes
+            .iter()
+            .rev()
+            .fold(0u64, |acc, &b| acc << 8 | u64::from(b)))
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        self.uint(2).map(|x| x as u16)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        self.uint(4).map(|x| x as u32)
+    }
+
+    fn offset(&mut self) -> Result<u64, String> {
+        let n = self.offset_size;
+        let x = self.uint(n)?;
+        Ok(if n < 8 && x == (1 << (8 * n)) - 1 {
+            UNDEFINED_ADDRESS
+        } else {
+            x
+        })
+    }
+
+    fn length(&mut self) -> Result<u64, String> {
+        let n = self.length_size;
+        self.uint(n)
+    }
+
+    fn signature(&mut self, expected: &[u8]) -> Result<(), String> {
+        if self.bytes(expected.len())? != expected {
+            return Err(format!(
+                "expected HDF5 structure '{}'",
+                String::from_utf8_lossy(expected)
+            ));
+        }
+        Ok(())
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len().saturating_sub(self.pos)
+    }
+}
+
+impl File {
+    /// Parse the superblock of an HDF5 file.
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, String> {
+        // the superblock may follow a user block of 512, 1024, 2048, ... bytes
+        let mut start = 0;
+        while data.get(start..start + SIGNATURE.len()) != Some(SIGNATURE) {
+            start = if start == 0 { 512 } else { start * 2 };
+            if start >= data.len() {
+                return Err("not an HDF5 file".to_string());
+            }
+        }
+
+        let mut c = Cursor {
+            data: &data,
+            pos: start + SIGNATURE.len(),
+            offset_size: 8,
+            length_size: 8,
+        };
+        let version = c.u8()?;
+        match version {
+            0 | 1 => c.skip(4)?,
+            2 | 3 => {}
+            _ => return Err(format!("unsupported HDF5 superblock version {}", version)),
+        }
+        c.offset_size = c.u8()? as usize;
+        c.length_size = c.u8()? as usize;
+        if ![2, 4, 8].contains(&c.offset_size) || ![2, 4, 8].contains(&c.length_size) {
+            return Err("unsupported HDF5 offset or length size".to_string());
+        }
+        let (base, root) = if version < 2 {
+            c.skip(1 + 2 + 2 + 4)?;
+            if version == 1 {
+                c.skip(4)?;
+            }
+            let base = c.offset()?;
+            c.skip(3 * c.offset_size)?;
+            // the root group's symbol table entry starts with the offset of its name
+            c.offset()?;
+            (base, c.offset()?)
+        } else {
+            c.skip(1)?;
+            let base = c.offset()?;
+            c.skip(2 * c.offset_size)?;
+            (base, c.offset()?)
+        };
+
+        Ok(File {
+            offset_size: c.offset_size,
+            length_size: c.length_size,
+            data,
+            base,
+            root,
+        })
+    }
+
+    /// Address of the root group's object header
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    fn cursor(&self, address: u64) -> Result<Cursor<'_>, String> {
+        let pos = address
+            .checked_add(self.base)
+            .filter(|&p| p < self.data.len() as u64)
+            .ok_or("HDF5 address out of range")?;
+        Ok(Cursor {
+            data: &self.data,
+            pos: pos as usize,
+            offset_size: self.offset_size,
+            length_size: self.length_size,
+        })
+    }
+
+    fn slice(&self, address: u64, size: u64) -> Result<&[u8], String> {
+        let start = address
+            .checked_add(self.base)
+            .ok_or("HDF5 address out of range")?;
+        start
+            .checked_add(size)
+            .filter(|&end| end <= self.data.len() as u64)
+            .map(|end| &self.data[start as usize..end as usize])
+            .ok_or_else(|| "HDF5 data out of range".to_string())
+    }
+
+    /// Cursor over the `size` bytes at position `start` of the file data
+    fn block(&self, start: usize, size: usize) -> Result<Cursor<'_>, String> {
+        let end = start
+            .checked_add(size)
+            .filter(|&end| end <= self.data.len())
+            .ok_or("HDF5 object header out of range")?;
+        Ok(Cursor {
+            data: &self.data[..end],
+            pos: start,
+            offset_size: self.offset_size,
+            length_size: self.length_size,
+        })
+    }
+
+    /// Cursor over a message or other structure held outside the file data
+    fn cursor_over<'a>(&self, data: &'a [u8]) -> Cursor<'a> {
+        Cursor {
+            data,
+            pos: 0,
+            offset_size: self.offset_size,
+            length_size: self.length_size,
+        }
+    }
+
+    /// All messages of the object header at `address`
+    fn messages(&self, address: u64) -> Result<Vec<Message<'_>>, String> {
+        let mut c = self.cursor(address)?;
+        let mut messages = vec![];
+        // continuation blocks that were already read, to reject cycles
+        let mut visited = HashSet::new();
+
+        if c.data.get(c.pos..c.pos + 4) == Some(b"OHDR") {
+            c.skip(4)?;
+            let version = c.u8()?;
+            if version != 2 {
+                return Err(format!("unsupported object header version {}", version));
+            }
+            let flags = c.u8()?;
+            if flags & 0x20 != 0 {
+                c.skip(16)?;
+            }
+            if flags & 0x10 != 0 {
+                c.skip(4)?;
+            }
+            let size = c.uint(1 << (flags & 0x03))? as usize;
+            let mut blocks = vec![(c.pos, size)];
+            while let Some((start, size)) = blocks.pop() {
+                let mut block = self.block(start, size)?;
+                // a message header is 4 bytes, plus 2 if creation order is tracked
+                let header_size = if flags & 0x04 != 0 { 6 } else { 4 };
+                while block.remaining() >= header_size {
+                    let kind = u16::from(block.u8()?);
+                    let size = block.u16()? as usize;
+                    block.skip(header_size - 3)?;
+                    let data = block.bytes(size)?;
+                    if kind == MSG_CONTINUATION {
+                        let (address, length) = self.continuation(data)?;
+                        if !visited.insert(address) {
+                            return Err("cyclic object header continuation".to_string());
+                        }
+                        let mut chunk = self.cursor(address)?;
+                        chunk.signature(b"OCHK")?;
+                        // the chunk starts with a signature and ends with a checksum
+                        let size = (length as usize)
+                            .checked_sub(8)
+                            .ok_or("object header continuation too short")?;
+                        blocks.push((chunk.pos, size));
+                    } else {
+                        messages.push(Message { kind, data });
+                    }
+                }
+            }
+        } else {
+            let version = c.u8()?;
+            if version != 1 {
+                return Err(format!("unsupported object header version {}", version));
+            }
+            c.skip(1)?;
+            let mut n_messages = c.u16()? as usize;
+            c.skip(4)?;
+            let size = c.u32()? as usize;
+            // the messages are aligned to 8 bytes
+            c.skip(4)?;
+            let mut blocks = vec![(c.pos, size)];
+            while let Some((start, size)) = blocks.pop() {
+                let mut block = self.block(start, size)?;
+                while n_messages > 0 && block.remaining() >= 8 {
+                    let kind = block.u16()?;
+                    let size = block.u16()? as usize;
+                    block.skip(4)?;
+                    let data = block.bytes(size)?;
+                    n_messages -= 1;
+                    if kind == MSG_CONTINUATION {
+                        let (address, length) = self.continuation(data)?;
+                        if !visited.insert(address) {
+                            return Err("cyclic object header continuation".to_string());
+                        }
+                        let chunk = self.cursor(address)?;
+                        blocks.push((chunk.pos, length as usize));
+                    } else {
+                        messages.push(Message { kind, data });
+                    }
+                }
+            }
+        }
+
+        Ok(messages)
+    }
+
+    fn continuation(&self, data: &[u8]) -> Result<(u64, u64), String> {
+        let mut c = self.cursor_over(data);
+        Ok((c.offset()?, c.length()?))
+    }
+
+    /// Names and object header addresses of a group's members
+    pub fn members(&self, group: u64) -> Result<Vec<(String, u64)>, String> {
+        let mut members = vec![];
+        for msg in self.messages(group)? {
+            match msg.kind {
+                MSG_SYMBOL_TABLE => {
+                    let mut c = self.cursor_over(msg.data);
+                    let btree = c.offset()?;
+                    let heap = c.offset()?;
+                    self.symbol_table(btree, heap, &mut members)?;
+                }
+                MSG_LINK => {
+                    if let Some(link) = self.link(&mut self.cursor_over(msg.data))? {
+                        members.push(link);
+                    }
+                }
+                MSG_LINK_INFO => {
+                    let mut c = self.cursor_over(msg.data);
+                    c.skip(1)?;
+                    let flags = c.u8()?;
+                    if flags & 0x01 != 0 {
+                        c.skip(8)?;
+                    }
+                    let heap = c.offset()?;
+                    if heap != UNDEFINED_ADDRESS {
+                        self.dense_links(heap, &mut members)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(members)
+    }
+
+    /// Parse a link message, returning the name and address of hard links.
+    fn link(&self, c: &mut Cursor) -> Result<Option<(String, u64)>, String> {
+        let version = c.u8()?;
+        if version != 1 {
+            return Err(format!("unsupported link message version {}", version));
+        }
+        let flags = c.u8()?;
+        let link_type = if flags & 0x08 != 0 { c.u8()? } else { 0 };
+        if flags & 0x04 != 0 {
+            c.skip(8)?;
+        }
+        if flags & 0x10 != 0 {
+            c.skip(1)?;
+        }
+        let name_length = c.uint(1 << (flags & 0x03))? as usize;
+        let name = String::from_utf8_lossy(c.bytes(name_length)?).into_owned();
+        match link_type {
+            0 => Ok(Some((name, c.offset()?))),
+            _ => {
+                // soft and external links are skipped
+                let length = c.u16()? as usize;
+                c.skip(length)?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn symbol_table(
+        &self,
+        btree: u64,
+        heap: u64,
+        members: &mut Vec<(String, u64)>,
+    ) -> Result<(), String> {
+        let mut c = self.cursor(heap)?;
+        c.signature(b"HEAP")?;
+        c.skip(4)?;
+        c.length()?;
+        c.length()?;
+        let heap_data = c.offset()?;
+
+        let mut nodes = vec![btree];
+        let mut visited = HashSet::new();
+        while let Some(node) = nodes.pop() {
+            if !visited.insert(node) {
+                return Err("cyclic group B-tree".to_string());
+            }
+            let mut c = self.cursor(node)?;
+            c.signature(b"TREE")?;
+            if c.u8()? != 0 {
+                return Err("expected a group B-tree node".to_string());
+            }
+            let level = c.u8()?;
+            let entries = c.u16()? as usize;
+            c.skip(2 * self.offset_size)?;
+            for _ in 0..entries {
+                c.length()?;
+                let child = c.offset()?;
+                if level > 0 {
+                    nodes.push(child);
+                } else {
+                    self.symbol_node(child, heap_data, members)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn symbol_node(
+        &self,
+        address: u64,
+        heap_data: u64,
+        members: &mut Vec<(String, u64)>,
+    ) -> Result<(), String> {
+        let mut c = self.cursor(address)?;
+        c.signature(b"SNOD")?;
+        c.skip(2)?;
+        let symbols = c.u16()?;
+        for _ in 0..symbols {
+            let name_offset = c.offset()?;
+            let object = c.offset()?;
+            c.skip(4 + 4 + 16)?;
+
+            let name = self.cursor(
+                heap_data
+                    .checked_add(name_offset)
+                    .ok_or("HDF5 address out of range")?,
+            )?;
+            let name = &name.data[name.pos..];
+            let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            members.push((String::from_utf8_lossy(&name[..end]).into_owned(), object));
+        }
+        Ok(())
+    }
+
+    /// Read the link messages stored in the managed objects of a fractal heap.
+    ///
+    /// The objects are parsed sequentially from the heap's direct blocks, which works for heaps
+    /// that were written once and never had objects removed.
+    fn dense_links(&self, heap: u64, members: &mut Vec<(String, u64)>) -> Result<(), String> {
+        let mut c = self.cursor(heap)?;
+        c.signature(b"FRHP")?;
+        c.skip(1 + 2)?;
+        let filter_length = c.u16()?;
+        let flags = c.u8()?;
+        c.skip(4)?;
+        c.length()?;
+        c.offset()?;
+        c.length()?;
+        c.offset()?;
+        for _ in 0..8 {
+            c.length()?;
+        }
+        let width = c.u16()? as u64;
+        let start_size = c.length()?;
+        let max_direct_size = c.length()?;
+        let max_heap_bits = c.u16()? as usize;
+        c.skip(2)?;
+        let root = c.offset()?;
+        let root_rows = c.u16()? as usize;
+        if filter_length != 0 {
+            return Err("filtered fractal heaps are not supported".to_string());
+        }
+
+        let heap = FractalHeap {
+            width,
+            start_size,
+            max_direct_size,
+            offset_bytes: max_heap_bits.div_ceil(8),
+            checksummed: flags & 0x02 != 0,
+        };
+
+        let mut blocks = vec![];
+        if root_rows == 0 {
+            blocks.push((root, start_size));
+        } else {
+            let mut visited = HashSet::new();
+            self.indirect_block(&heap, root, root_rows, 0, &mut visited, &mut blocks)?;
+        }
+
+        for (address, size) in blocks {
+            let mut c = self.cursor(address)?;
+            c.signature(b"FHDB")?;
+            c.skip(1)?;
+            c.offset()?;
+            c.skip(heap.offset_bytes)?;
+            if heap.checksummed {
+                c.skip(4)?;
+            }
+            let end = (size as usize)
+                .checked_sub(heap.header_size(self.offset_size))
+                .and_then(|n| c.pos.checked_add(n))
+                .ok_or("invalid fractal heap block size")?
+                .min(self.data.len());
+            let mut objects = Cursor {
+                data: &self.data[..end],
+                ..c
+            };
+            // free space at the end of the block is zero
+            while objects.remaining() > 2 && objects.data[objects.pos] == 1 {
+                if let Some(link) = self.link(&mut objects)? {
+                    members.push(link);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Collect the direct blocks of an indirect block of the fractal heap.
+    fn indirect_block(
+        &self,
+        heap: &FractalHeap,
+        address: u64,
+        rows: usize,
+        depth: usize,
+        visited: &mut HashSet<u64>,
+        blocks: &mut Vec<(u64, u64)>,
+    ) -> Result<(), String> {
+        if depth > MAX_DEPTH || !visited.insert(address) {
+            return Err("invalid fractal heap".to_string());
+        }
+        let mut c = self.cursor(address)?;
+        c.signature(b"FHIB")?;
+        c.skip(1)?;
+        c.offset()?;
+        c.skip(heap.offset_bytes)?;
+
+        let mut children = vec![];
+        for row in 0..rows {
+            let size = heap.row_size(row).ok_or("fractal heap row too large")?;
+            for _ in 0..heap.width {
+                let child = c.offset()?;
+                if child != UNDEFINED_ADDRESS {
+                    children.push((child, size));
+                }
+            }
+        }
+
+        for (child, size) in children {
+            if size <= heap.max_direct_size {
+                blocks.push((child, size));
+            } else {
+                let rows = heap
+                    .rows_of_indirect(size)
+                    .ok_or("invalid fractal heap block size")?;
+                self.indirect_block(heap, child, rows, depth + 1, visited, blocks)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse the object header of a dataset.
+    pub fn dataset(&self, address: u64) -> Result<Dataset, String> {
+        let mut space = None;
+        let mut datatype = None;
+        let mut layout = None;
+        let mut filters = vec![];
+        let mut attributes = vec![];
+
+        for msg in self.messages(address)? {
+            match msg.kind {
+                MSG_DATASPACE => space = Some(self.dataspace(msg.data)?),
+                MSG_DATATYPE => datatype = Some(parse_datatype(msg.data)?),
+                MSG_LAYOUT => layout = Some(self.layout(msg.data)?),
+                MSG_FILTER_PIPELINE => filters = self.filters(msg.data)?,
+                MSG_ATTRIBUTE => attributes.push(self.attribute(msg.data)?),
+                _ => {}
+            }
+        }
+
+        let (shape, max_shape) = space.ok_or("dataset without dataspace")?;
+        Ok(Dataset {
+            shape,
+            max_shape,
+            datatype: datatype.ok_or("dataset without datatype")?,
+            layout: layout.ok_or("dataset without layout")?,
+            filters,
+            attributes,
+        })
+    }
+
+    /// Current and maximum dimensions of a dataspace
+    fn dataspace(&self, data: &[u8]) -> Result<(Vec<u64>, Vec<u64>), String> {
+        let mut c = self.cursor_over(data);
+        let version = c.u8()?;
+        let rank = c.u8()? as usize;
+        let flags = c.u8()?;
+        match version {
+            1 => c.skip(1 + 4)?,
+            2 => c.skip(1)?,
+            _ => return Err(format!("unsupported dataspace version {}", version)),
+        }
+        let dims: Vec<u64> = (0..rank).map(|_| c.length()).collect::<Result<_, _>>()?;
+        if flags & 0x01 == 0 {
+            return Ok((dims.clone(), dims));
+        }
+        let unlimited = u64::MAX >> (64 - 8 * self.length_size);
+        let max_dims = (0..rank)
+            .map(|_| {
+                c.length()
+                    .map(|d| if d == unlimited { UNLIMITED } else { d })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok((dims, max_dims))
+    }
+
+    fn layout(&self, data: &[u8]) -> Result<Layout, String> {
+        let mut c = self.cursor_over(data);
+        let version = c.u8()?;
+        if version < 3 {
+            return Err(format!("unsupported data layout version {}", version));
+        }
+        match c.u8()? {
+            0 => {
+                let size = c.u16()? as usize;
+                Ok(Layout::Compact(c.bytes(size)?.to_vec()))
+            }
+            1 => Ok(Layout::Contiguous {
+                address: c.offset()?,
+                size: c.length()?,
+            }),
+            2 if version == 3 => {
+                let rank = c.u8()? as usize;
+                let btree = c.offset()?;
+                let dims = (0..rank)
+                    .map(|_| c.u32().map(u64::from))
+                    .collect::<Result<_, _>>()?;
+                Ok(Layout::Chunked {
+                    dims,
+                    index: ChunkIndex::BtreeV1(btree),
+                })
+            }
+            2 => {
+                let flags = c.u8()?;
+                let rank = c.u8()? as usize;
+                let dim_bytes = c.u8()? as usize;
+                let dims: Vec<u64> = (0..rank)
+                    .map(|_| c.uint(dim_bytes))
+                    .collect::<Result<_, _>>()?;
+                let index = match c.u8()? {
+                    1 => {
+                        let filtered_size = if flags & 0x02 != 0 {
+                            let size = c.length()?;
+                            c.skip(4)?;
+                            Some(size)
+                        } else {
+                            None
+                        };
+                        let address = c.offset()?;
+                        // the last dimension is the element size
+                        let size = match filtered_size {
+                            Some(size) => size,
+                            None => byte_size(&dims, 1).ok_or("chunk size overflows")?,
+                        };
+                        ChunkIndex::Single(address, size)
+                    }
+                    2 => ChunkIndex::Implicit(c.offset()?),
+                    3 => {
+                        // the page size is also stored in the fixed array header
+                        c.skip(1)?;
+                        ChunkIndex::FixedArray(c.offset()?)
+                    }
+                    4 => {
+                        // the creation parameters are also stored in the extensible array header
+                        c.skip(5)?;
+                        ChunkIndex::ExtensibleArray(c.offset()?)
+                    }
+                    5 => {
+                        // the node size and split and merge percentages of the B-tree
+                        c.skip(4 + 1 + 1)?;
+                        ChunkIndex::BtreeV2(c.offset()?)
+                    }
+                    index => return Err(format!("unsupported chunk index type {}", index)),
+                };
+                Ok(Layout::Chunked { dims, index })
+            }
+            class => Err(format!("unsupported data layout class {}", class)),
+        }
+    }
+
+    fn filters(&self, data: &[u8]) -> Result<Vec<Filter>, String> {
+        let mut c = self.cursor_over(data);
+        let version = c.u8()?;
+        let n = c.u8()?;
+        if version == 1 {
+            c.skip(6)?;
+        }
+        let mut filters = vec![];
+        for _ in 0..n {
+            let id = c.u16()?;
+            let name_length = if version == 1 || id >= 256 {
+                c.u16()? as usize
+            } else {
+                0
+            };
+            c.skip(2)?;
+            let n_values = c.u16()? as usize;
+            if version == 1 {
+                c.skip(name_length.div_ceil(8) * 8)?;
+            } else {
+                c.skip(name_length)?;
+            }
+            let client_data = (0..n_values).map(|_| c.u32()).collect::<Result<_, _>>()?;
+            if version == 1 && n_values % 2 == 1 {
+                c.skip(4)?;
+            }
+            filters.push(Filter { id, client_data });
+        }
+        Ok(filters)
+    }
+
+    fn attribute(&self, data: &[u8]) -> Result<Attribute, String> {
+        let mut c = self.cursor_over(data);
+        let version = c.u8()?;
+        c.skip(1)?;
+        let name_size = c.u16()? as usize;
+        let datatype_size = c.u16()? as usize;
+        let dataspace_size = c.u16()? as usize;
+        if version == 3 {
+            c.skip(1)?;
+        }
+        let padded = |n: usize| if version == 1 { n.div_ceil(8) * 8 } else { n };
+
+        let name = c.bytes(padded(name_size))?;
+        let end = name.iter().position(|&b| b == 0).unwrap_or(name_size);
+        let name = String::from_utf8_lossy(&name[..end]).into_owned();
+        let datatype = parse_datatype(c.bytes(padded(datatype_size))?)?;
+        let (shape, _) = self.dataspace(c.bytes(padded(dataspace_size))?)?;
+
+        let size = datatype
+            .size()
+            .and_then(|s| byte_size(&shape, s))
+            .unwrap_or(u64::MAX)
+            .min(c.remaining() as u64) as usize;
+        let data = c.bytes(size)?.to_vec();
+        Ok(Attribute {
+            name,
+            datatype,
+            data,
+        })
+    }
+
+    /// Read all elements of a numeric dataset, in row-major order.
+    pub fn read(&self, dataset: &Dataset) -> Result<Vec<f64>, String> {
+        let size = match dataset.datatype {
+            Datatype::Integer { size, .. } | Datatype::Float { size, .. } => size,
+            _ => return Err("dataset is not numeric".to_string()),
+        };
+        if !(1..=8).contains(&size) {
+            return Err("unsupported numeric type".to_string());
+        }
+
+        // the data is stored in the file, so its size is limited by the size of the file
+        let compressed = dataset.filters.iter().any(|f| f.id == FILTER_DEFLATE);
+        let limit = self.data.len() as u64 * if compressed { MAX_DEFLATE_RATIO } else { 1 };
+        byte_size(&dataset.shape, size)
+            .filter(|&bytes| bytes <= limit)
+            .ok_or("dataset is larger than the file")?;
+        let n = dataset.len();
+
+        let raw = match &dataset.layout {
+            Layout::Compact(data) => data.clone(),
+            Layout::Contiguous { address, .. } if *address == UNDEFINED_ADDRESS => {
+                vec![0; n * size]
+            }
+            Layout::Contiguous { address, size } => self.slice(*address, *size)?.to_vec(),
+            Layout::Chunked { dims, index } => self.read_chunked(dataset, dims, index, size)?,
+        };
+        if raw.len() < n * size {
+            return Err("dataset is truncated".to_string());
+        }
+        raw.chunks(size)
+            .take(n)
+            .map(|x| dataset.datatype.convert(x))
+            .collect()
+    }
+
+    fn read_chunked(
+        &self,
+        dataset: &Dataset,
+        dims: &[u64],
+        index: &ChunkIndex,
+        size: usize,
+    ) -> Result<Vec<u8>, String> {
+        let rank = dataset.shape.len();
+        if dims.len() != rank + 1 || dataset.max_shape.len() != rank {
+            return Err("chunk dimensions do not match the dataset".to_string());
+        }
+        let chunk_shape = &dims[..rank];
+        if chunk_shape.contains(&0) {
+            return Err("invalid chunk dimensions".to_string());
+        }
+        let chunk_size = byte_size(dims, 1).ok_or("chunk size overflows")?;
+        let mut out = vec![0; dataset.len() * size];
+
+        let mut chunks = vec![];
+        match *index {
+            ChunkIndex::BtreeV1(address) if address == UNDEFINED_ADDRESS => {}
+            ChunkIndex::BtreeV1(address) => {
+                self.chunk_tree(address, rank, 0, &mut HashSet::new(), &mut chunks)?
+            }
+            ChunkIndex::Single(address, size) => chunks.push(Chunk {
+                offset: vec![0; rank],
+                address,
+                size,
+                filter_mask: 0,
+            }),
+            ChunkIndex::BtreeV2(address) => {
+                self.chunk_btree_v2(address, chunk_shape, chunk_size, &mut chunks)?
+            }
+            ChunkIndex::Implicit(address) => {
+                let entry = |i: u64| {
+                    let address = i
+                        .checked_mul(chunk_size)
+                        .and_then(|offset| address.checked_add(offset))
+                        .ok_or("HDF5 address out of range")?;
+                    Ok(Some((address, chunk_size, 0)))
+                };
+                self.array_chunks(dataset, chunk_shape, false, entry, &mut chunks)?
+            }
+            ChunkIndex::FixedArray(address) => {
+                let array = self.fixed_array(address, chunk_size)?;
+                let entry = |i| array.entry(self, i);
+                self.array_chunks(dataset, chunk_shape, false, entry, &mut chunks)?
+            }
+            ChunkIndex::ExtensibleArray(address) => {
+                let array = self.extensible_array(address, chunk_size)?;
+                let entry = |i| array.entry(self, i);
+                self.array_chunks(dataset, chunk_shape, true, entry, &mut chunks)?
+            }
+        }
+
+        for chunk in chunks {
+            let mut data = self.slice(chunk.address, chunk.size)?.to_vec();
+            for (i, filter) in dataset.filters.iter().enumerate().rev() {
+                if chunk.filter_mask & (1 << i) == 0 {
+                    data = filter.apply(data)?;
+                }
+            }
+            copy_chunk(
+                &data,
+                &chunk.offset,
+                chunk_shape,
+                &dataset.shape,
+                size,
+                &mut out,
+            )?;
+        }
+        Ok(out)
+    }
+
+    /// Collect the chunks indexed by a version 1 B-tree.
+    fn chunk_tree(
+        &self,
+        address: u64,
+        rank: usize,
+        depth: usize,
+        visited: &mut HashSet<u64>,
+        chunks: &mut Vec<Chunk>,
+    ) -> Result<(), String> {
+        if depth > MAX_DEPTH || !visited.insert(address) {
+            return Err("invalid chunk B-tree".to_string());
+        }
+        let mut c = self.cursor(address)?;
+        c.signature(b"TREE")?;
+        if c.u8()? != 1 {
+            return Err("expected a chunk B-tree node".to_string());
+        }
+        let level = c.u8()?;
+        let entries = c.u16()?;
+        c.skip(2 * self.offset_size)?;
+        for _ in 0..entries {
+            let chunk_size = c.u32()?;
+            let filter_mask = c.u32()?;
+            let offset: Vec<u64> = (0..=rank).map(|_| c.uint(8)).collect::<Result<_, _>>()?;
+            let child = c.offset()?;
+            if level > 0 {
+                self.chunk_tree(child, rank, depth + 1, visited, chunks)?;
+            } else {
+                chunks.push(Chunk {
+                    offset: offset[..rank].to_vec(),
+                    address: child,
+                    size: u64::from(chunk_size),
+                    filter_mask,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Collect the chunks of an array chunk index, which has an entry for every chunk position.
+    ///
+    /// Entries are ordered row-major over the chunks of the maximum dataset shape. The
+    /// unlimited dimension of an extensible array comes first.
+    fn array_chunks(
+        &self,
+        dataset: &Dataset,
+        chunk_shape: &[u64],
+        extensible: bool,
+        entry: impl Fn(u64) -> Result<Option<(u64, u64, u32)>, String>,
+        chunks: &mut Vec<Chunk>,
+    ) -> Result<(), String> {
+        let rank = chunk_shape.len();
+        let unlimited: Vec<_> = (0..rank)
+            .filter(|&d| dataset.max_shape[d] == UNLIMITED)
+            .collect();
+        let order: Vec<usize> = match (extensible, unlimited.as_slice()) {
+            (false, []) => (0..rank).collect(),
+            (true, &[u]) => Some(u)
+                .into_iter()
+                .chain((0..rank).filter(|&d| d != u))
+                .collect(),
+            _ => return Err("chunk index does not match the dataset's dimensions".to_string()),
+        };
+        if (0..rank).any(|d| dataset.shape[d] > dataset.max_shape[d]) {
+            return Err("dataset exceeds its maximum shape".to_string());
+        }
+        let n_chunks = |extent: u64, d: usize| extent.div_ceil(chunk_shape[d]);
+
+        // visit the chunks that overlap the dataset in row-major order
+        let counts: Vec<u64> = (0..rank).map(|d| n_chunks(dataset.shape[d], d)).collect();
+        if counts.contains(&0) {
+            return Ok(());
+        }
+        let mut scaled = vec![0u64; rank];
+        loop {
+            let mut i = 0u64;
+            for (k, &d) in order.iter().enumerate() {
+                i = if k == 0 {
+                    scaled[d]
+                } else {
+                    i.checked_mul(n_chunks(dataset.max_shape[d], d))
+                        .and_then(|i| i.checked_add(scaled[d]))
+                        .ok_or("too many chunks")?
+                };
+            }
+            if let Some((address, size, filter_mask)) = entry(i)? {
+                let offset = (0..rank).map(|d| scaled[d] * chunk_shape[d]).collect();
+                chunks.push(Chunk {
+                    offset,
+                    address,
+                    size,
+                    filter_mask,
+                });
+            }
+
+            let mut d = rank;
+            loop {
+                if d == 0 {
+                    return Ok(());
+                }
+                d -= 1;
+                scaled[d] += 1;
+                if scaled[d] < counts[d] {
+                    break;
+                }
+                scaled[d] = 0;
+            }
+        }
+    }
+
+    /// Parse the header of a fixed array chunk index.
+    fn fixed_array(&self, address: u64, chunk_size: u64) -> Result<FixedArray, String> {
+        let mut c = self.cursor(address)?;
+        c.signature(b"FAHD")?;
+        if c.u8()? != 0 {
+            return Err("unsupported fixed array version".to_string());
+        }
+        let entry = self.chunk_entry_format(c.u8()?, c.u8()?, chunk_size)?;
+        let page_bits = c.u8()?;
+        let n_entries = c.length()?;
+        let data_block = c.offset()?;
+        let mut c = self.cursor(data_block)?;
+        c.signature(b"FADB")?;
+        Ok(FixedArray {
+            entry,
+            page_size: 1u64
+                .checked_shl(page_bits.into())
+                .ok_or("invalid page size")?,
+            n_entries,
+            data_block,
+        })
+    }
+
+    /// Parse the header and index block of an extensible array chunk index.
+    fn extensible_array(&self, address: u64, chunk_size: u64) -> Result<ExtensibleArray, String> {
+        let mut c = self.cursor(address)?;
+        c.signature(b"EAHD")?;
+        if c.u8()? != 0 {
+            return Err("unsupported extensible array version".to_string());
+        }
+        let entry = self.chunk_entry_format(c.u8()?, c.u8()?, chunk_size)?;
+        let max_bits = u32::from(c.u8()?);
+        let index_entries = u64::from(c.u8()?);
+        let min_block_entries = u64::from(c.u8()?);
+        let min_super_block_pointers = u64::from(c.u8()?);
+        let page_bits = u32::from(c.u8()?);
+        // statistics, of which only the number of entries that were ever set is needed
+        c.skip(4 * self.length_size)?;
+        let n_entries = c.length()?;
+        c.skip(self.length_size)?;
+        let index_block = c.offset()?;
+
+        if !min_block_entries.is_power_of_two()
+            || !min_super_block_pointers.is_power_of_two()
+            || max_bits > 64
+            || min_block_entries.trailing_zeros() > max_bits
+        {
+            return Err("invalid extensible array parameters".to_string());
+        }
+        let n_super_blocks = 1 + (max_bits - min_block_entries.trailing_zeros()) as usize;
+        // the index block holds the data blocks of the first super blocks directly
+        let direct_super_blocks = 2 * min_super_block_pointers.trailing_zeros() as usize;
+        if direct_super_blocks > n_super_blocks {
+            return Err("invalid extensible array parameters".to_string());
+        }
+
+        let mut c = self.cursor(index_block)?;
+        c.signature(b"EAIB")?;
+        Ok(ExtensibleArray {
+            entry,
+            index_entries,
+            min_block_entries,
+            page_size: 1u64.checked_shl(page_bits).ok_or("invalid page size")?,
+            offset_bytes: max_bits.div_ceil(8) as usize,
+            n_super_blocks,
+            direct_super_blocks,
+            direct_blocks: 2 * (min_super_block_pointers - 1),
+            n_entries,
+            index_block,
+        })
+    }
+
+    /// Check the client and entry size of a fixed or extensible array chunk index.
+    fn chunk_entry_format(
+        &self,
+        client: u8,
+        entry_size: u8,
+        chunk_size: u64,
+    ) -> Result<ChunkEntry, String> {
+        let size = entry_size as usize;
+        match client {
+            0 if size == self.offset_size => Ok(ChunkEntry {
+                size,
+                chunk_size: None,
+                unfiltered_size: chunk_size,
+            }),
+            1 => {
+                let n = size
+                    .checked_sub(self.offset_size + 4)
+                    .filter(|n| (1..=8).contains(n))
+                    .ok_or("invalid chunk index entry size")?;
+                Ok(ChunkEntry {
+                    size,
+                    chunk_size: Some(n),
+                    unfiltered_size: chunk_size,
+                })
+            }
+            _ => Err("unsupported chunk index entries".to_string()),
+        }
+    }
+
+    /// Collect the chunks indexed by a version 2 B-tree.
+    fn chunk_btree_v2(
+        &self,
+        address: u64,
+        chunk_shape: &[u64],
+        chunk_size: u64,
+        chunks: &mut Vec<Chunk>,
+    ) -> Result<(), String> {
+        let mut c = self.cursor(address)?;
+        c.signature(b"BTHD")?;
+        if c.u8()? != 0 {
+            return Err("unsupported B-tree version".to_string());
+        }
+        let filtered = match c.u8()? {
+            10 => false,
+            11 => true,
+            _ => return Err("expected a chunk B-tree".to_string()),
+        };
+        let node_size = u64::from(c.u32()?);
+        let record_size = u64::from(c.u16()?);
+        let depth = c.u16()? as usize;
+        c.skip(2)?;
+        let root = c.offset()?;
+        let root_records = u64::from(c.u16()?);
+        if depth > MAX_DEPTH || record_size == 0 {
+            return Err("invalid chunk B-tree".to_string());
+        }
+
+        let rank = chunk_shape.len();
+        let size_bytes = if filtered {
+            let n = (record_size as usize)
+                .checked_sub(self.offset_size + 4 + 8 * rank)
+                .filter(|n| (1..=8).contains(n))
+                .ok_or("invalid chunk B-tree record size")?;
+            Some(n)
+        } else {
+            None
+        };
+
+        // the size of the record counts in internal nodes depends on the maximum number of
+        // records below them
+        let mut levels = vec![BtreeLevel {
+            max_records: node_size.saturating_sub(BTREE_NODE_OVERHEAD) / record_size,
+            total_bytes: 0,
+            max_total: node_size.saturating_sub(BTREE_NODE_OVERHEAD) / record_size,
+        }];
+        let count_bytes = enc_size(levels[0].max_records);
+        for level in 1..=depth {
+            let below = &levels[level - 1];
+            let pointer = (self.offset_size + count_bytes) as u64
+                + if level > 1 {
+                    below.total_bytes as u64
+                } else {
+                    0
+                };
+            let max_records =
+                node_size.saturating_sub(BTREE_NODE_OVERHEAD + pointer) / (record_size + pointer);
+            let max_total = (max_records + 1)
+                .saturating_mul(below.max_total)
+                .saturating_add(max_records);
+            levels.push(BtreeLevel {
+                max_records,
+                total_bytes: enc_size(max_total),
+                max_total,
+            });
+        }
+
+        let format = BtreeFormat {
+            levels,
+            count_bytes,
+            size_bytes,
+            chunk_shape,
+            chunk_size,
+        };
+        self.btree_v2_node(
+            &format,
+            root,
+            root_records,
+            depth,
+            &mut HashSet::new(),
+            chunks,
+        )
+    }
+
+    fn btree_v2_node(
+        &self,
+        format: &BtreeFormat,
+        address: u64,
+        n_records: u64,
+        depth: usize,
+        visited: &mut HashSet<u64>,
+        chunks: &mut Vec<Chunk>,
+    ) -> Result<(), String> {
+        if address == UNDEFINED_ADDRESS && n_records == 0 {
+            return Ok(());
+        }
+        if !visited.insert(address) || n_records > format.levels[depth].max_records {
+            return Err("invalid chunk B-tree".to_string());
+        }
+        let mut c = self.cursor(address)?;
+        c.signature(if depth == 0 { b"BTLF" } else { b"BTIN" })?;
+        c.skip(2)?;
+        for _ in 0..n_records {
+            let address = c.offset()?;
+            let (size, filter_mask) = match format.size_bytes {
+                Some(n) => (c.uint(n)?, c.u32()?),
+                None => (format.chunk_size, 0),
+            };
+            let offset = format
+                .chunk_shape
+                .iter()
+                .map(|&d| c.uint(8).map(|scaled| scaled.saturating_mul(d)))
+                .collect::<Result<_, _>>()?;
+            if address != UNDEFINED_ADDRESS {
+                chunks.push(Chunk {
+                    offset,
+                    address,
+                    size,
+                    filter_mask,
+                });
+            }
+        }
+        if depth > 0 {
+            for _ in 0..=n_records {
+                let child = c.offset()?;
+                let records = c.uint(format.count_bytes)?;
+                if depth > 1 {
+                    c.skip(format.levels[depth - 1].total_bytes)?;
+                }
+                self.btree_v2_node(format, child, records, depth - 1, visited, chunks)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Signature, version, type, and checksum of a version 2 B-tree node
+const BTREE_NODE_OVERHEAD: u64 = 4 + 1 + 1 + 4;
+
+/// Number of bytes needed to store numbers up to `max`, as in version 2 B-trees
+fn enc_size(max: u64) -> usize {
+    (63 - max.max(1).leading_zeros()) as usize / 8 + 1
+}
+
+/// Whether bit `i` of a bitmap is set, counting from the most significant bit
+fn bit_set(bitmap: &[u8], i: u64) -> bool {
+    bitmap
+        .get((i / 8) as usize)
+        .is_some_and(|b| b & (0x80 >> (i % 8)) != 0)
+}
+
+/// Layout of the entries of fixed and extensible array chunk indexes
+struct ChunkEntry {
+    size: usize,
+    /// number of bytes of the chunk size, if chunks are filtered
+    chunk_size: Option<usize>,
+    unfiltered_size: u64,
+}
+
+impl ChunkEntry {
+    /// Read an entry: the address, size, and filter mask of an allocated chunk
+    fn read(&self, file: &File, position: u64) -> Result<Option<(u64, u64, u32)>, String> {
+        let mut c = file.cursor(position)?;
+        let address = c.offset()?;
+        let (size, filter_mask) = match self.chunk_size {
+            Some(n) => (c.uint(n)?, c.u32()?),
+            None => (self.unfiltered_size, 0),
+        };
+        if address == UNDEFINED_ADDRESS {
+            return Ok(None);
+        }
+        Ok(Some((address, size, filter_mask)))
+    }
+}
+
+/// Chunk index with one entry for each chunk of a dataset with fixed maximum dimensions
+struct FixedArray {
+    entry: ChunkEntry,
+    page_size: u64,
+    n_entries: u64,
+    data_block: u64,
+}
+
+impl FixedArray {
+    fn entry(&self, file: &File, i: u64) -> Result<Option<(u64, u64, u32)>, String> {
+        if i >= self.n_entries {
+            return Ok(None);
+        }
+        let esize = self.entry.size as u64;
+        // signature, version, client, and header address
+        let prefix = self.data_block + 4 + 1 + 1 + file.offset_size as u64;
+        let position = if self.n_entries > self.page_size {
+            // large arrays are split into pages, which are only valid if initialized
+            let n_pages = self.n_entries.div_ceil(self.page_size);
+            let bitmap = file.slice(prefix, n_pages.div_ceil(8))?;
+            let page = i / self.page_size;
+            if !bit_set(bitmap, page) {
+                return Ok(None);
+            }
+            let page_bytes = self.page_size * esize + 4;
+            prefix + n_pages.div_ceil(8) + 4 + page * page_bytes + (i % self.page_size) * esize
+        } else {
+            prefix + i * esize
+        };
+        self.entry.read(file, position)
+    }
+}
+
+/// Chunk index of a dataset with one unlimited dimension, which grows in blocks that double in
+/// size
+struct ExtensibleArray {
+    entry: ChunkEntry,
+    /// number of entries in the index block
+    index_entries: u64,
+    /// number of entries in the data blocks of the first super block
+    min_block_entries: u64,
+    page_size: u64,
+    /// number of bytes of block offsets
+    offset_bytes: usize,
+    n_super_blocks: usize,
+    /// number of super blocks whose data blocks are listed in the index block
+    direct_super_blocks: usize,
+    /// number of data block addresses in the index block
+    direct_blocks: u64,
+    n_entries: u64,
+    index_block: u64,
+}
+
+impl ExtensibleArray {
+    fn entry(&self, file: &File, i: u64) -> Result<Option<(u64, u64, u32)>, String> {
+        if i >= self.n_entries {
+            return Ok(None);
+        }
+        let esize = self.entry.size as u64;
+        let offset_size = file.offset_size as u64;
+        // signature, version, client, and header address
+        let prefix = 4 + 1 + 1 + offset_size;
+        let index_entries = self.index_block + prefix;
+        if i < self.index_entries {
+            return self.entry.read(file, index_entries + i * esize);
+        }
+        let block_addresses = index_entries + self.index_entries * esize;
+        let super_block_addresses = block_addresses + self.direct_blocks * offset_size;
+
+        // super block `s` has 2^(s/2) data blocks of 2^((s+1)/2) times the minimum size
+        let j = i - self.index_entries;
+        let s = (63 - (j / self.min_block_entries + 1).leading_zeros()) as usize;
+        if s >= self.n_super_blocks {
+            return Err("invalid extensible array index".to_string());
+        }
+        let (mut first_entry, mut first_block) = (0u64, 0u64);
+        for k in 0..s {
+            let (n_blocks, block_entries) = self.super_block(k);
+            first_entry += n_blocks * block_entries;
+            first_block += n_blocks;
+        }
+        let (n_blocks, block_entries) = self.super_block(s);
+        let block = (j - first_entry) / block_entries;
+        let k = (j - first_entry) % block_entries;
+        let paged = block_entries > self.page_size;
+        let page = k / self.page_size;
+
+        let read_address = |position| file.cursor(position)?.offset();
+        let data_block = if s < self.direct_super_blocks {
+            read_address(block_addresses + (first_block + block) * offset_size)?
+        } else {
+            let position =
+                super_block_addresses + (s - self.direct_super_blocks) as u64 * offset_size;
+            let super_block = read_address(position)?;
+            if super_block == UNDEFINED_ADDRESS {
+                return Ok(None);
+            }
+            let mut c = file.cursor(super_block)?;
+            c.signature(b"EASB")?;
+            let mut position = super_block + prefix + self.offset_bytes as u64;
+            if paged {
+                // each data block has a bitmap of its initialized pages
+                let n_pages = block_entries / self.page_size;
+                let bitmap_size = n_blocks * n_pages.div_ceil(8);
+                let bitmap = file.slice(position, bitmap_size)?;
+                if !bit_set(bitmap, block * n_pages + page) {
+                    return Ok(None);
+                }
+                position += bitmap_size;
+            }
+            read_address(position + block * offset_size)?
+        };
+        if data_block == UNDEFINED_ADDRESS {
+            return Ok(None);
+        }
+
+        let mut c = file.cursor(data_block)?;
+        c.signature(b"EADB")?;
+        let entries = data_block + prefix + self.offset_bytes as u64;
+        let position = if paged {
+            let page_bytes = self.page_size * esize + 4;
+            entries + 4 + page * page_bytes + (k % self.page_size) * esize
+        } else {
+            entries + k * esize
+        };
+        self.entry.read(file, position)
+    }
+
+    /// Number of data blocks of a super block, and their number of entries
+    fn super_block(&self, s: usize) -> (u64, u64) {
+        (1 << (s / 2), (1 << s.div_ceil(2)) * self.min_block_entries)
+    }
+}
+
+/// Record layout and node capacities of a version 2 chunk B-tree
+struct BtreeFormat<'a> {
+    /// capacities of the nodes, from the leaves up
+    levels: Vec<BtreeLevel>,
+    /// number of bytes of the record counts of child nodes
+    count_bytes: usize,
+    /// number of bytes of the chunk size, if chunks are filtered
+    size_bytes: Option<usize>,
+    chunk_shape: &'a [u64],
+    chunk_size: u64,
+}
+
+struct BtreeLevel {
+    max_records: u64,
+    /// number of bytes of the total record counts below a node of this level
+    total_bytes: usize,
+    max_total: u64,
+}
+
+/// Number of bytes of an array of given shape, unless it overflows
+fn byte_size(shape: &[u64], element_size: usize) -> Option<u64> {
+    shape
+        .iter()
+        .try_fold(element_size as u64, |n, &d| n.checked_mul(d))
+}
+
+/// Copy the elements of a chunk at `offset` into a row-major array of given `shape`.
+fn copy_chunk(
+    chunk: &[u8],
+    offset: &[u64],
+    chunk_shape: &[u64],
+    shape: &[u64],
+    size: usize,
+    out: &mut [u8],
+) -> Result<(), String> {
+    let n = chunk_shape.iter().try_fold(1u64, |n, &d| n.checked_mul(d));
+    match n.and_then(|n| n.checked_mul(size as u64)) {
+        Some(bytes) if bytes <= chunk.len() as u64 => {}
+        _ => return Err("chunk is truncated".to_string()),
+    }
+    let rank = shape.len();
+    let mut index = vec![0u64; rank];
+    for i in 0..n.unwrap_or(0) as usize {
+        let mut target = 0u64;
+        let mut inside = true;
+        for d in 0..rank {
+            let x = offset[d].saturating_add(index[d]);
+            inside &= x < shape[d];
+            if inside {
+                target = target * shape[d] + x;
+            }
+        }
+        if inside {
+            let target = target as usize * size;
+            out[target..target + size].copy_from_slice(&chunk[i * size..(i + 1) * size]);
+        }
+        // advance the row-major index within the chunk
+        for d in (0..rank).rev() {
+            index[d] += 1;
+            if index[d] < chunk_shape[d] {
+                break;
+            }
+            index[d] = 0;
+        }
+    }
+    Ok(())
+}
+
+struct FractalHeap {
+    width: u64,
+    start_size: u64,
+    max_direct_size: u64,
+    offset_bytes: usize,
+    checksummed: bool,
+}
+
+impl FractalHeap {
+    fn row_size(&self, row: usize) -> Option<u64> {
+        if row < 2 {
+            Some(self.start_size)
+        } else {
+            // rows are counted with 16 bits
+            let shift = (row - 1) as u32;
+            self.start_size
+                .checked_shl(shift)
+                .filter(|size| size >> shift == self.start_size)
+        }
+    }
+
+    fn rows_of_indirect(&self, size: u64) -> Option<usize> {
+        let log2 = |x: u64| 63usize.checked_sub(x.leading_zeros() as usize);
+        let first = log2(self.start_size.checked_mul(self.width)?)?;
+        log2(size)?.checked_sub(first).map(|rows| rows + 1)
+    }
+
+    fn header_size(&self, offset_size: usize) -> usize {
+        4 + 1 + offset_size + self.offset_bytes + if self.checksummed { 4 } else { 0 }
+    }
+}
+
+fn parse_datatype(data: &[u8]) -> Result<Datatype, String> {
+    let bytes = data.get(..8).ok_or("datatype message too short")?;
+    let class = bytes[0] & 0x0f;
+    let flags = bytes[1];
+    let size = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as usize;
+    Ok(match class {
+        0 => Datatype::Integer {
+            size,
+            signed: flags & 0x08 != 0,
+            big_endian: flags & 0x01 != 0,
+        },
+        1 => Datatype::Float {
+            size,
+            big_endian: flags & 0x01 != 0,
+        },
+        3 => Datatype::String { size },
+        _ => Datatype::Other,
+    })
+}
+
+impl Datatype {
+    /// Size of an element in bytes, if known
+    fn size(&self) -> Option<usize> {
+        match *self {
+            Datatype::Integer { size, .. }
+            | Datatype::Float { size, .. }
+            | Datatype::String { size } => Some(size),
+            Datatype::Other => None,
+        }
+    }
+
+    fn convert(&self, bytes: &[u8]) -> Result<f64, String> {
+        let mut b = [0u8; 8];
+        let n = bytes.len();
+        b[..n].copy_from_slice(bytes);
+        let big_endian = match *self {
+            Datatype::Integer { big_endian, .. } | Datatype::Float { big_endian, .. } => big_endian,
+            _ => return Err("dataset is not numeric".to_string()),
+        };
+        if big_endian {
+            b[..n].reverse();
+        }
+        match *self {
+            Datatype::Float { size: 4, .. } => {
+                Ok(f64::from(f32::from_le_bytes([b[0], b[1], b[2], b[3]])))
+            }
+            Datatype::Float { size: 8, .. } => Ok(f64::from_le_bytes(b)),
+            Datatype::Integer { size, signed, .. } if size <= 8 => {
+                let x = u64::from_le_bytes(b);
+                Ok(if signed && size < 8 {
+                    // sign-extend
+                    let shift = 64 - 8 * size;
+                    ((x << shift) as i64 >> shift) as f64
+                } else if signed {
+                    x as i64 as f64
+                } else {
+                    x as f64
+                })
+            }
+            _ => Err("unsupported numeric type".to_string()),
+        }
+    }
+}
+
+impl Filter {
+    fn apply(&self, data: Vec<u8>) -> Result<Vec<u8>, String> {
+        match self.id {
+            FILTER_DEFLATE => zlib_decompress(&data),
+            FILTER_SHUFFLE => {
+                let size = self.client_data.first().cloned().unwrap_or(1) as usize;
+                Ok(unshuffle(&data, size))
+            }
+            FILTER_FLETCHER32 => {
+                let n = data.len().saturating_sub(4);
+                Ok(data[..n].to_vec())
+            }
+            id => Err(format!("unsupported HDF5 filter {}", id)),
+        }
+    }
+}
+
+/// Undo the byte shuffle, which groups the `k`-th bytes of all elements together.
+fn unshuffle(data: &[u8], size: usize) -> Vec<u8> {
+    if size <= 1 {
+        return data.to_vec();
+    }
+    let n = data.len() / size;
+    let mut out = data.to_vec();
+    for i in 0..n {
+        for b in 0..size {
+            out[i * size + b] = data[b * n + i];
+        }
+    }
+    out
+}
+
+impl Dataset {
+    /// Size of each dimension
+    pub fn shape(&self) -> &[u64] {
+        &self.shape
+    }
+
+    /// Total number of elements
+    ///
+    /// Only valid for datasets whose size was checked by `File::read`.
+    fn len(&self) -> usize {
+        self.shape.iter().product::<u64>() as usize
+    }
+
+    /// Value of a fixed-length string attribute, or `None` if there is no such attribute
+    ///
+    /// Fails if the attribute has another type, such as a variable-length string.
+    pub fn string_attribute(&self, name: &str) -> Result<Option<String>, String> {
+        let attr = match self.attributes.iter().find(|a| a.name == name) {
+            Some(attr) => attr,
+            None => return Ok(None),
+        };
+        match attr.datatype {
+            Datatype::String { .. } => {
+                let end = attr
+                    .data
+                    .iter()
+                    .position(|&b| b == 0)
+                    .unwrap_or(attr.data.len());
+                Ok(Some(
+                    String::from_utf8_lossy(&attr.data[..end])
+                        .trim()
+                        .to_string(),
+                ))
+            }
+            _ => Err(format!("attribute '{}' is not a fixed-length string", name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unshuffle_bytes() {
+        let shuffled = [1, 2, 3, 10, 20, 30];
+        assert_eq!(unshuffle(&shuffled, 2), vec![1, 10, 2, 20, 3, 30]);
+    }
+
+    #[test]
+    fn copy_partial_chunks() {
+        // a 3x3 array stored in 2x2 chunks
+        let shape = [3, 3];
+        let mut out = vec![0u8; 9];
+        copy_chunk(&[1, 2, 4, 5], &[0, 0], &[2, 2], &shape, 1, &mut out).unwrap();
+        copy_chunk(&[3, 0, 6, 0], &[0, 2], &[2, 2], &shape, 1, &mut out).unwrap();
+        copy_chunk(&[7, 8, 0, 0], &[2, 0], &[2, 2], &shape, 1, &mut out).unwrap();
+        copy_chunk(&[9, 0, 0, 0], &[2, 2], &[2, 2], &shape, 1, &mut out).unwrap();
+        assert_eq!(out, vec![1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    }
+
+    /// A file image with 2x2 chunks of a `rows` x 3 array of bytes, counting up from 1, at
+    /// address 1000
+    fn chunked_image(rows: u64) -> Vec<u8> {
+        let mut image = vec![0; 1000];
+        for (r, c) in (0..rows.div_ceil(2)).flat_map(|r| (0..2).map(move |c| (r, c))) {
+            for (i, j) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+                let (row, col) = (2 * r + i, 2 * c + j);
+                let value = if row < rows && col < 3 {
+                    row * 3 + col + 1
+                } else {
+                    0
+                };
+                image.push(value as u8);
+            }
+        }
+        image
+    }
+
+    fn put(image: &mut [u8], address: usize, parts: &[&[u8]]) {
+        let bytes = parts.concat();
+        image[address..address + bytes.len()].copy_from_slice(&bytes);
+    }
+
+    /// Address of the chunk in `chunked_image`, in row-major order
+    fn chunk(i: u64) -> [u8; 8] {
+        (1000 + 4 * i).to_le_bytes()
+    }
+
+    fn read_chunked_image(
+        image: Vec<u8>,
+        shape: &[u64],
+        max_shape: &[u64],
+        index: ChunkIndex,
+    ) -> Vec<f64> {
+        let file = File {
+            data: image,
+            base: 0,
+            offset_size: 8,
+            length_size: 8,
+            root: 0,
+        };
+        let dataset = Dataset {
+            shape: shape.to_vec(),
+            max_shape: max_shape.to_vec(),
+            datatype: Datatype::Integer {
+                size: 1,
+                signed: false,
+                big_endian: false,
+            },
+            layout: Layout::Chunked {
+                dims: vec![2, 2, 1],
+                index,
+            },
+            filters: vec![],
+            attributes: vec![],
+        };
+        file.read(&dataset).unwrap()
+    }
+
+    fn counting(n: usize) -> Vec<f64> {
+        (1..=n).map(|x| x as f64).collect()
+    }
+
+    #[test]
+    fn read_implicit_chunk_index() {
+        let image = chunked_image(3);
+        let data = read_chunked_image(image, &[3, 3], &[3, 3], ChunkIndex::Implicit(1000));
+        assert_eq!(data, counting(9));
+    }
+
+    #[test]
+    fn read_fixed_array_chunk_index() {
+        let mut image = chunked_image(3);
+        let none = UNDEFINED_ADDRESS.to_le_bytes();
+        // four entries in pages of two, of which only the first page was written
+        let n_entries = 4u64.to_le_bytes();
+        let data_block = 200u64.to_le_bytes();
+        put(
+            &mut image,
+            100,
+            &[b"FAHD\0\0\x08\x01", &n_entries, &data_block],
+        );
+        let header = 100u64.to_le_bytes();
+        let first_page = [&chunk(0)[..], &chunk(1), &[0; 4]].concat();
+        let second_page = [&none[..], &none, &[0; 4]].concat();
+        let bitmap = [0x80, 0, 0, 0, 0];
+        put(
+            &mut image,
+            200,
+            &[b"FADB\0\0", &header, &bitmap, &first_page, &second_page],
+        );
+
+        let data = read_chunked_image(image, &[3, 3], &[3, 3], ChunkIndex::FixedArray(100));
+        let mut expected = counting(6);
+        expected.extend([0.0; 3]);
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn read_extensible_array_chunk_index() {
+        let mut image = chunked_image(6);
+        let none = UNDEFINED_ADDRESS.to_le_bytes();
+        let header = 100u64.to_le_bytes();
+        // one entry in the index block, data blocks of 1 and 2 entries listed in the index block,
+        // and a super block with two data blocks of 2 entries
+        let stats: Vec<u8> = [0u64, 0, 0, 0, 6, 0]
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .collect();
+        let index_block = 200u64.to_le_bytes();
+        put(
+            &mut image,
+            100,
+            &[b"EAHD\0\0\x08\x03\x01\x01\x02\x0a", &stats, &index_block],
+        );
+        let (block1, block2, super_block) = (300u64, 400u64, 500u64);
+        put(
+            &mut image,
+            200,
+            &[
+                b"EAIB\0\0",
+                &header,
+                &chunk(0),
+                &block1.to_le_bytes(),
+                &block2.to_le_bytes(),
+                &super_block.to_le_bytes(),
+                &none,
+            ],
+        );
+        put(&mut image, 300, &[b"EADB\0\0", &header, &[1], &chunk(1)]);
+        put(
+            &mut image,
+            400,
+            &[b"EADB\0\0", &header, &[2], &chunk(2), &chunk(3)],
+        );
+        let block3 = 600u64.to_le_bytes();
+        put(
+            &mut image,
+            500,
+            &[b"EASB\0\0", &header, &[4], &block3, &none],
+        );
+        put(
+            &mut image,
+            600,
+            &[b"EADB\0\0", &header, &[4], &chunk(4), &chunk(5)],
+        );
+
+        let max_shape = [UNLIMITED, 3];
+        let index = ChunkIndex::ExtensibleArray(100);
+        let data = read_chunked_image(image, &[6, 3], &max_shape, index);
+        assert_eq!(data, counting(18));
+    }
+
+    /// A version 2 B-tree node with chunk records
+    fn btree_node(signature: &[u8], records: &[(u64, u64, u64)]) -> Vec<u8> {
+        let mut node = [signature, b"\0\x0a"].concat();
+        for &(i, row, col) in records {
+            node.extend(chunk(i));
+            node.extend(row.to_le_bytes());
+            node.extend(col.to_le_bytes());
+        }
+        node
+    }
+
+    /// Header of a version 2 B-tree of chunk records, with 512-byte nodes
+    fn btree_header(depth: u16, root_records: u16) -> Vec<u8> {
+        let mut header = b"BTHD\0\x0a".to_vec();
+        header.extend(512u32.to_le_bytes());
+        header.extend(24u16.to_le_bytes());
+        header.extend(depth.to_le_bytes());
+        header.extend([100, 40]);
+        header.extend(200u64.to_le_bytes());
+        header.extend(root_records.to_le_bytes());
+        header.extend(4u64.to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn read_btree_v2_chunk_index() {
+        let mut image = chunked_image(3);
+        let records = [(0, 0, 0), (1, 0, 1), (2, 1, 0), (3, 1, 1)];
+        put(&mut image, 100, &[&btree_header(0, 4)]);
+        put(&mut image, 200, &[&btree_node(b"BTLF", &records)]);
+        let data = read_chunked_image(image, &[3, 3], &[3, 3], ChunkIndex::BtreeV2(100));
+        assert_eq!(data, counting(9));
+
+        // a root node with one record and two leaves
+        let mut image = chunked_image(3);
+        put(&mut image, 100, &[&btree_header(1, 1)]);
+        let mut root = btree_node(b"BTIN", &[(1, 0, 1)]);
+        for (child, records) in [(300u64, 1u8), (400, 2)] {
+            root.extend(child.to_le_bytes());
+            root.push(records);
+        }
+        put(&mut image, 200, &[&root]);
+        put(&mut image, 300, &[&btree_node(b"BTLF", &[(0, 0, 0)])]);
+        put(
+            &mut image,
+            400,
+            &[&btree_node(b"BTLF", &[(2, 1, 0), (3, 1, 1)])],
+        );
+        let data = read_chunked_image(image, &[3, 3], &[3, 3], ChunkIndex::BtreeV2(100));
+        assert_eq!(data, counting(9));
+    }
+
+    #[test]
+    fn string_attributes() {
+        let attribute = |datatype, data: &[u8]| Attribute {
+            name: "Type".to_string(),
+            datatype,
+            data: data.to_vec(),
+        };
+        let mut dataset = Dataset {
+            shape: vec![],
+            max_shape: vec![],
+            datatype: Datatype::Other,
+            layout: Layout::Compact(vec![]),
+            filters: vec![],
+            attributes: vec![],
+        };
+        assert_eq!(dataset.string_attribute("Type"), Ok(None));
+
+        let text = attribute(Datatype::String { size: 10 }, b"cartesian\0");
+        dataset.attributes = vec![text];
+        assert_eq!(
+            dataset.string_attribute("Type"),
+            Ok(Some("cartesian".to_string()))
+        );
+
+        // variable-length strings, as written for netCDF string attributes
+        dataset.attributes = vec![attribute(Datatype::Other, &[0; 16])];
+        assert!(dataset.string_attribute("Type").is_err());
+    }
+
+    #[test]
+    fn reject_other_files() {
+        assert!(File::from_bytes(b"not an hdf5 file at all".to_vec()).is_err());
+    }
+}
@@ -0,0 +1,380 @@
+//! Reading and writing HRIR sets in the text and binary `.hrir` formats.
+//!
+//! Both formats describe a set of virtual speakers, each with *B-format* decoding weights and
+//! the impulse responses from its direction to both ears.
+//!
+//! The text format starts with the sample rate, followed by one block of three lines per virtual
+//! speaker, separated by empty lines. The first line of a block holds the speaker's weights as
+//! `w, x, y, z`, optionally followed by the higher-order channels, so it has 4, 9, or 16 values.
+//! The other lines hold the HRIRs of the left and the right ear.
+//!
+//! The binary format stores the same data in little-endian byte order:
+//!
+//! | Field                     | Type                            |
+//! |---------------------------|---------------------------------|
+//! | magic                     | the 8 bytes `AMBHRIR\0`         |
+//! | format version            | `u16`, currently 1              |
+//! | sample rate               | `u32`                           |
+//! | number of speakers        | `u32`                           |
+//!
+//! followed by each speaker:
+//!
+//! | Field                     | Type                            |
+//! |---------------------------|---------------------------------|
+//! | ambisonic order           | `u8`                            |
+//! | weights                   | `(order + 1)²` × `f32`          |
+//! | left HRIR length          | `u32`                           |
+//! | left HRIR                 | length × `f32`                  |
+//! | right HRIR length         | `u32`                           |
+//! | right HRIR                | length × `f32`                  |
+//!
+//! Weights are stored in the library's internal channel order and normalization (FuMa).
+
+use std::fs;
+use std::io::{Read, Write};
+
+use crate::bformat::{n_channels, Bweights, MAX_CHANNELS, MAX_ORDER};
+use crate::error::Error;
+use crate::renderer::{HrtfConfig, VirtualSpeaker};
+
+const MAGIC: &[u8; 8] = b"AMBHRIR\0";
+const VERSION: u16 = 1;
+
+/// Range of sample rates that HRIR sets may be recorded at
+const SAMPLE_RATES: std::ops::RangeInclusive<u32> = 8000..=768_000;
+
+/// Longest accepted HRIR in samples
+const MAX_HRIR_LENGTH: usize = 65536;
+
+impl HrtfConfig {
+    /// Load a set of virtual speakers and their HRIRs from a `.hrir` file.
+    ///
+    /// The file may be in text or binary format. See `from_bytes`.
+    pub fn from_file(filename: &str) -> Result<Self, Error> {
+        HrtfConfig::from_bytes(&fs::read(filename)?)
+    }
+
+    /// Load a set of virtual speakers and their HRIRs from a reader.
+    ///
+    /// See `from_bytes`.
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Self, Error> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        HrtfConfig::from_bytes(&data)
+    }
+
+    /// Load a set of virtual speakers and their HRIRs from memory, for example from data
+    /// embedded with `include_bytes!`.
+    ///
+    /// Data starting with the magic bytes `AMBHRIR\0` is read in the binary format, anything else
+    /// must be in the text format.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        let config = if data.starts_with(MAGIC) {
+            read_binary(&data[MAGIC.len()..])?
+        } else {
+            let text = std::str::from_utf8(data).map_err(|_| {
+                Error::InvalidData("HRIR data is neither binary nor text".to_string())
+            })?;
+            parse_text(text)?
+        };
+        config.validated()
+    }
+
+    /// Check that a loaded HRIR set can be rendered.
+    ///
+    /// Rejects sets without speakers, implausible sample rates, overly long HRIRs, and values
+    /// that are not finite.
+    pub(crate) fn validated(self) -> Result<Self, Error> {
+        let invalid = |msg: String| Err(Error::InvalidData(msg));
+        if !SAMPLE_RATES.contains(&self.sample_rate) {
+            return invalid(format!("unsupported HRIR sample rate {}", self.sample_rate));
+        }
+        if self.virtual_speakers.is_empty() {
+            return invalid("HRIR set without virtual speakers".to_string());
+        }
+        for speaker in &self.virtual_speakers {
+            if speaker.left_hrir.len().max(speaker.right_hrir.len()) > MAX_HRIR_LENGTH {
+                return invalid("HRIR is too long".to_string());
+            }
+            let values = speaker.bweights.components().iter();
+            let mut values = values.chain(&speaker.left_hrir).chain(&speaker.right_hrir);
+            if values.any(|x| !x.is_finite()) {
+                return invalid("HRIR set contains values that are not finite".to_string());
+            }
+        }
+        Ok(self)
+    }
+
+    /// Write the virtual speakers and their HRIRs in the binary `.hrir` format.
+    ///
+    /// Together with `from_file`, this converts text files to the binary format, which is
+    /// smaller and much faster to load.
+    pub fn write_binary<W: Write>(&self, mut writer: W) -> Result<(), Error> {
+        let mut data = Vec::new();
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        data.extend_from_slice(&self.sample_rate.to_le_bytes());
+        data.extend_from_slice(&(self.virtual_speakers.len() as u32).to_le_bytes());
+
+        for speaker in &self.virtual_speakers {
+            let order = speaker.bweights.order();
+            data.push(order as u8);
+            extend_f32s(
+                &mut data,
+                &speaker.bweights.components()[..n_channels(order)],
+            );
+            for hrir in &[&speaker.left_hrir, &speaker.right_hrir] {
+                data.extend_from_slice(&(hrir.len() as u32).to_le_bytes());
+                extend_f32s(&mut data, hrir);
+            }
+        }
+
+        writer.write_all(&data)?;
+        Ok(())
+    }
+}
+
+fn extend_f32s(data: &mut Vec<u8>, values: &[f32]) {
+    for x in values {
+        data.extend_from_slice(&x.to_le_bytes());
+    }
+}
+
+fn read_binary(data: &[u8]) -> Result<HrtfConfig, Error> {
+    let mut reader = BinaryReader { data };
+    let version = u16::from_le_bytes([reader.byte()?, reader.byte()?]);
+    if version != VERSION {
+        return Err(Error::InvalidData(format!(
+            "unsupported binary HRIR version {}",
+            version
+        )));
+    }
+    let sample_rate = reader.u32()?;
+    let n_speakers = reader.u32()?;
+    let mut virtual_speakers = Vec::new();
+    for _ in 0..n_speakers {
+        let order = reader.byte()? as usize;
+        if order > MAX_ORDER {
+            return Err(Error::InvalidData(format!(
+                "unsupported ambisonic order {}",
+                order
+            )));
+        }
+        let mut components = [0.0; MAX_CHANNELS];
+        components[..n_channels(order)].copy_from_slice(&reader.f32s(n_channels(order))?);
+
+        let n = reader.u32()? as usize;
+        let left_hrir = reader.f32s(n)?;
+        let n = reader.u32()? as usize;
+        let right_hrir = reader.f32s(n)?;
+
+        virtual_speakers.push(VirtualSpeaker {
+            bweights: Bweights::from_components(order, components),
+            left_hrir,
+            right_hrir,
+        });
+    }
+
+    if !reader.data.is_empty() {
+        return Err(Error::InvalidData(
+            "unexpected data after the last speaker".to_string(),
+        ));
+    }
+
+    Ok(HrtfConfig {
+        sample_rate,
+        virtual_speakers,
+    })
+}
+
+struct BinaryReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> BinaryReader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.data.len() < n {
+            return Err(Error::InvalidData(
+                "binary HRIR data is truncated".to_string(),
+            ));
+        }
+        let (bytes, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn f32s(&mut self, n: usize) -> Result<Vec<f32>, Error> {
+        let bytes = self
+            .bytes(n.checked_mul(4).ok_or_else(|| {
+                Error::InvalidData("binary HRIR data is truncated".to_string())
+            })?)?;
+        Ok(bytes
+            .chunks(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect())
+    }
+}
+
+fn parse_text(text: &str) -> Result<HrtfConfig, Error> {
+    let mut lines = text.lines().enumerate().map(|(i, line)| (i + 1, line));
+    let (n, line) = lines
+        .next()
+        .ok_or_else(|| Error::InvalidData("empty HRIR file".to_string()))?;
+    let token = line.trim();
+    let sample_rate = token
+        .parse::<f32>()
+        .ok()
+        .filter(|&fs| fs >= 1.0 && fs.fract() == 0.0)
+        .ok_or_else(|| {
+            Error::at_token(n, line, token, format!("invalid sample rate '{}'", token))
+        })?;
+
+    let mut virtual_speakers = Vec::new();
+    let mut block = Vec::new();
+    for (n, line) in lines {
+        if !line.trim().is_empty() {
+            block.push((n, line));
+            continue;
+        }
+        if !block.is_empty() {
+            virtual_speakers.push(parse_speaker(&block)?);
+            block.clear();
+        }
+    }
+    if !block.is_empty() {
+        virtual_speakers.push(parse_speaker(&block)?);
+    }
+
+    Ok(HrtfConfig {
+        sample_rate: sample_rate as u32,
+        virtual_speakers,
+    })
+}
+
+/// Parse the weights and HRIRs of a virtual speaker from three numbered lines.
+fn parse_speaker(block: &[(usize, &str)]) -> Result<VirtualSpeaker, Error> {
+    let (n, line) = block[0];
+    if block.len() != 3 {
+        return Err(Error::at_token(
+            n,
+            line,
+            line,
+            format!(
+                "expected a line of weights and two HRIRs, found {} lines",
+                block.len()
+            ),
+        ));
+    }
+
+    let weights = parse_values(n, line)?;
+    if ![4, 9, 16].contains(&weights.len()) {
+        return Err(Error::at_token(
+            n,
+            line,
+            line,
+            format!("expected 4, 9, or 16 weights, found {}", weights.len()),
+        ));
+    }
+
+    Ok(VirtualSpeaker {
+        bweights: weights.into_iter().collect(),
+        left_hrir: parse_values(block[1].0, block[1].1)?,
+        right_hrir: parse_values(block[2].0, block[2].1)?,
+    })
+}
+
+/// Parse a line of comma-separated numbers.
+fn parse_values(n: usize, line: &str) -> Result<Vec<f32>, Error> {
+    line.split(',')
+        .map(|token| {
+            let token = token.trim();
+            token
+                .parse()
+                .map_err(|_| Error::at_token(n, line, token, format!("invalid number '{}'", token)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_HRIR: &str = include_str!("../tools/test.hrir");
+
+    #[test]
+    fn parse_text_file() {
+        let config = HrtfConfig::from_bytes(TEST_HRIR.as_bytes()).unwrap();
+        assert_eq!(config.sample_rate, 48000);
+        assert_eq!(config.virtual_speakers.len(), 4);
+    }
+
+    #[test]
+    fn report_position_of_text_errors() {
+        let position = |text| match parse_text(text) {
+            Err(Error::Parse { line, column, .. }) => (line, column),
+            _ => panic!("expected a parse error"),
+        };
+        assert_eq!(position("fast\n"), (1, 1));
+        assert_eq!(position("48000\n\n1, 0, 0, 0\n0.5, 0.x\n0.5\n"), (4, 6));
+        assert_eq!(position("48000\n\n1, 0, 0\n0.5\n0.5\n"), (3, 1));
+        assert_eq!(position("48000\n\n1, 0, 0, 0\n0.5\n\n"), (3, 1));
+    }
+
+    #[test]
+    fn binary_roundtrip() {
+        let text = HrtfConfig::from_reader(TEST_HRIR.as_bytes()).unwrap();
+        let mut binary = Vec::new();
+        text.write_binary(&mut binary).unwrap();
+        assert!(binary.len() < TEST_HRIR.len() / 2);
+
+        let loaded = HrtfConfig::from_bytes(&binary).unwrap();
+        assert_eq!(loaded.sample_rate, text.sample_rate);
+        assert_eq!(loaded.virtual_speakers.len(), text.virtual_speakers.len());
+        for (a, b) in loaded.virtual_speakers.iter().zip(&text.virtual_speakers) {
+            assert_eq!(a.bweights.order(), b.bweights.order());
+            assert_eq!(a.bweights.components(), b.bweights.components());
+            assert_eq!(a.left_hrir, b.left_hrir);
+            assert_eq!(a.right_hrir, b.right_hrir);
+        }
+    }
+
+    #[test]
+    fn reject_corrupt_binary_data() {
+        let mut binary = Vec::new();
+        HrtfConfig::default().write_binary(&mut binary).unwrap();
+
+        let truncated = HrtfConfig::from_bytes(&binary[..binary.len() - 1]);
+        assert!(matches!(truncated, Err(Error::InvalidData(_))));
+
+        // loading must fail or succeed, but never panic or exhaust memory
+        for i in 0..binary.len() {
+            let mut data = binary.clone();
+            data[i] ^= 0xff;
+            let _ = HrtfConfig::from_bytes(&data);
+        }
+
+        binary[MAGIC.len()] = 2;
+        let future = HrtfConfig::from_bytes(&binary);
+        assert!(matches!(future, Err(Error::InvalidData(msg)) if msg.contains("version 2")));
+    }
+
+    #[test]
+    fn reject_sets_that_cannot_be_rendered() {
+        let invalid = |text: &str| match HrtfConfig::from_bytes(text.as_bytes()) {
+            Err(Error::InvalidData(msg)) => msg,
+            _ => panic!("expected invalid data"),
+        };
+        assert!(invalid("48000\n").contains("without virtual speakers"));
+        assert!(invalid("1\n\n1, 0, 0, 0\n0.5\n0.5\n").contains("sample rate"));
+        assert!(invalid("48000\n\n1, 0, 0, 0\n0.5, NaN\n0.5\n").contains("not finite"));
+    }
+}
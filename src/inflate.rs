@@ -0,0 +1,324 @@
+//! Decompression of zlib streams (RFC 1950 and RFC 1951).
+//!
+//! A small, allocation-friendly decoder in the spirit of zlib's `puff.c`. It is only used to
+//! read compressed HRTF data sets, so it favors simplicity over speed.
+
+/// Decompress a zlib stream, verifying its header and checksum.
+pub(crate) fn zlib_decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+    if data.len() < 6 {
+        return Err("zlib stream too short".to_string());
+    }
+    let (cmf, flg) = (data[0], data[1]);
+    if cmf & 0x0f != 8 || (u16::from(cmf) << 8 | u16::from(flg)) % 31 != 0 {
+        return Err("invalid zlib header".to_string());
+    }
+    if flg & 0x20 != 0 {
+        return Err("zlib preset dictionaries are not supported".to_string());
+    }
+
+    let mut inflater = Inflater {
+        input: &data[2..],
+        position: 0,
+        bit_buffer: 0,
+        bit_count: 0,
+        output: Vec::with_capacity(data.len() * 4),
+    };
+    inflater.inflate()?;
+
+    let trailer = inflater.position;
+    let checksum = data
+        .get(2 + trailer..2 + trailer + 4)
+        .ok_or("missing zlib checksum")?;
+    let expected = u32::from_be_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]);
+    if adler32(&inflater.output) != expected {
+        return Err("zlib checksum mismatch".to_string());
+    }
+    Ok(inflater.output)
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &x in chunk {
+            a += u32::from(x);
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    b << 16 | a
+}
+
+const MAX_BITS: usize = 15;
+
+/// Canonical Huffman code, stored as the number of codes of each length and the symbols
+/// ordered by code
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self, String> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &l in lengths {
+            counts[l as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // reject over-subscribed codes; incomplete codes are allowed
+        let mut left = 1i32;
+        for &c in &counts[1..] {
+            left = 2 * left - i32::from(c);
+            if left < 0 {
+                return Err("over-subscribed Huffman code".to_string());
+            }
+        }
+
+        let mut offsets = [0u16; MAX_BITS + 1];
+        for len in 1..MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &l) in lengths.iter().enumerate() {
+            if l != 0 {
+                symbols[offsets[l as usize] as usize] = symbol as u16;
+                offsets[l as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+}
+
+struct Inflater<'a> {
+    input: &'a [u8],
+    position: usize,
+    bit_buffer: u32,
+    bit_count: u32,
+    output: Vec<u8>,
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Order in which code length code lengths are stored
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+impl<'a> Inflater<'a> {
+    fn bits(&mut self, n: u32) -> Result<u32, String> {
+        while self.bit_count < n {
+            let byte = *self
+                .input
+                .get(self.position)
+                .ok_or("unexpected end of compressed data")?;
+            self.position += 1;
+            self.bit_buffer |= u32::from(byte) << self.bit_count;
+            self.bit_count += 8;
+        }
+        let value = self.bit_buffer & ((1u64 << n) - 1) as u32;
+        self.bit_buffer >>= n;
+        self.bit_count -= n;
+        Ok(value)
+    }
+
+    fn decode(&mut self, code: &Huffman) -> Result<u16, String> {
+        let (mut value, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..=MAX_BITS {
+            value |= self.bits(1)? as i32;
+            let count = i32::from(code.counts[len]);
+            if value - count < first {
+                return Ok(code.symbols[(index + value - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            value <<= 1;
+        }
+        Err("invalid Huffman code".to_string())
+    }
+
+    fn inflate(&mut self) -> Result<(), String> {
+        loop {
+            let last = self.bits(1)?;
+            match self.bits(2)? {
+                0 => self.stored()?,
+                1 => {
+                    let (lengths, distances) = fixed_codes()?;
+                    self.codes(&lengths, &distances)?
+                }
+                2 => {
+                    let (lengths, distances) = self.dynamic_codes()?;
+                    self.codes(&lengths, &distances)?
+                }
+                _ => return Err("invalid deflate block type".to_string()),
+            }
+            if last == 1 {
+                return Ok(());
+            }
+        }
+    }
+
+    fn stored(&mut self) -> Result<(), String> {
+        // discard the remaining bits of the current byte
+        self.bit_buffer = 0;
+        self.bit_count = 0;
+        let header = self
+            .input
+            .get(self.position..self.position + 4)
+            .ok_or("unexpected end of compressed data")?;
+        let len = u16::from_le_bytes([header[0], header[1]]);
+        let nlen = u16::from_le_bytes([header[2], header[3]]);
+        if len != !nlen {
+            return Err("corrupt stored block length".to_string());
+        }
+        self.position += 4;
+        let data = self
+            .input
+            .get(self.position..self.position + len as usize)
+            .ok_or("unexpected end of compressed data")?;
+        self.output.extend_from_slice(data);
+        self.position += len as usize;
+        Ok(())
+    }
+
+    fn dynamic_codes(&mut self) -> Result<(Huffman, Huffman), String> {
+        let n_lengths = self.bits(5)? as usize + 257;
+        let n_distances = self.bits(5)? as usize + 1;
+        let n_codes = self.bits(4)? as usize + 4;
+        if n_lengths > 286 || n_distances > 30 {
+            return Err("too many length or distance codes".to_string());
+        }
+
+        let mut code_lengths = [0u8; 19];
+        for &i in &CODE_LENGTH_ORDER[..n_codes] {
+            code_lengths[i] = self.bits(3)? as u8;
+        }
+        let code_length_code = Huffman::new(&code_lengths)?;
+
+        let mut lengths = vec![0u8; n_lengths + n_distances];
+        let mut i = 0;
+        while i < lengths.len() {
+            let symbol = self.decode(&code_length_code)?;
+            let (value, repeat) = match symbol {
+                0..=15 => (symbol as u8, 1),
+                16 => {
+                    let previous = *lengths[..i].last().ok_or("repeat without length")?;
+                    (previous, 3 + self.bits(2)? as usize)
+                }
+                17 => (0, 3 + self.bits(3)? as usize),
+                _ => (0, 11 + self.bits(7)? as usize),
+            };
+            if i + repeat > lengths.len() {
+                return Err("too many code lengths".to_string());
+            }
+            for l in &mut lengths[i..i + repeat] {
+                *l = value;
+            }
+            i += repeat;
+        }
+
+        if lengths[256] == 0 {
+            return Err("missing end-of-block code".to_string());
+        }
+        Ok((
+            Huffman::new(&lengths[..n_lengths])?,
+            Huffman::new(&lengths[n_lengths..])?,
+        ))
+    }
+
+    fn codes(&mut self, lengths: &Huffman, distances: &Huffman) -> Result<(), String> {
+        loop {
+            let symbol = self.decode(lengths)? as usize;
+            match symbol {
+                0..=255 => self.output.push(symbol as u8),
+                256 => return Ok(()),
+                _ => {
+                    let symbol = symbol - 257;
+                    if symbol >= LENGTH_BASE.len() {
+                        return Err("invalid length code".to_string());
+                    }
+                    let len = LENGTH_BASE[symbol] as usize
+                        + self.bits(u32::from(LENGTH_EXTRA[symbol]))? as usize;
+
+                    let symbol = self.decode(distances)? as usize;
+                    if symbol >= DIST_BASE.len() {
+                        return Err("invalid distance code".to_string());
+                    }
+                    let dist = DIST_BASE[symbol] as usize
+                        + self.bits(u32::from(DIST_EXTRA[symbol]))? as usize;
+                    if dist > self.output.len() {
+                        return Err("distance too far back".to_string());
+                    }
+
+                    let start = self.output.len() - dist;
+                    for k in 0..len {
+                        let x = self.output[start + k];
+                        self.output.push(x);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn fixed_codes() -> Result<(Huffman, Huffman), String> {
+    let mut lengths = [0u8; 288];
+    for (symbol, l) in lengths.iter_mut().enumerate() {
+        *l = match symbol {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5; 30])?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn decompress_all_block_types() {
+        // zlib.compress(b"hello hello hello hello")
+        let fixed = hex("789ccb48cdc9c957c8402701680308b1");
+        assert_eq!(zlib_decompress(&fixed).unwrap(), b"hello hello hello hello");
+
+        // zlib.compress(b"stored block", 0)
+        let stored = hex("7801010c00f3ff73746f72656420626c6f636b1f8004bd");
+        assert_eq!(zlib_decompress(&stored).unwrap(), b"stored block");
+
+        let expected: Vec<u8> = (0..3000)
+            .map(|i| b"abcdefgh "[(i * i + i / 7) % 9])
+            .collect();
+        let dynamic = include_bytes!("../tests/data/dynamic.zlib");
+        assert_eq!(zlib_decompress(dynamic).unwrap(), expected);
+    }
+
+    #[test]
+    fn reject_corrupt_checksum() {
+        let corrupt = hex("789ccb48cdc9c957c8402701680308b2");
+        assert!(zlib_decompress(&corrupt).is_err());
+    }
+}
@@ -1,14 +1,25 @@
 /*!
 ## Compose and play 3D audio.
 
-The ambisonic library provides 3D sound scene support on top of [`rodio`](https://crates.io/crates/rodio).
-It allows positioning and moving sound sources freely in 3D space around a virtual listener,
-and playing the resulting spatial mix in real-time over a sound card.
+The ambisonic library provides 3D sound scene support on top of
+[`rodio`](https://crates.io/crates/rodio). It allows positioning and moving sound sources freely in
+3D space around a virtual listener, and playing the resulting spatial mix in real-time over a sound
+card.
 
 ### Features:
 - Realistic directional audio
 - Take `rodio` sound sources and place them in space
-- Doppler effect on moving sounds
+- Doppler effect on moving sounds, optionally with true propagation delay
+- Rotate the whole sound scene
+- Listener with position, orientation, and velocity; sources in world coordinates
+- OpenAL-like distance models with air absorption
+- Directional sources with sound cones
+- Occlusion and obstruction filtering, optionally driven by a line-of-sight test
+- Near-field compensation for sources close to the listener
+- Bass management with a subwoofer channel and per-source LFE sends
+- Routing of renderer outputs to arbitrary device channels
+- Loading and exporting AmbDec decoder presets
+- Object panning with VBAP as an alternative to ambisonic decoding
 
 ## Usage Example
 
@@ -17,7 +28,7 @@ use std::thread::sleep;
 use std::time::Duration;
 use ambisonic::{rodio, AmbisonicBuilder};
 
-let scene = AmbisonicBuilder::default().build();
+let scene = AmbisonicBuilder::default().build()?;
 
 let source = rodio::source::SineWave::new(440);
 let mut sound = scene.play_at(source, [50.0, 1.0, 0.0]);
@@ -29,6 +40,7 @@ for i in 0..1000 {
     sleep(Duration::from_millis(10));
 }
 sound.set_velocity([0.0, 0.0, 0.0]);
+# Ok::<(), ambisonic::Error>(())
 ```
 
 ### Technical Details
@@ -39,30 +51,85 @@ their audio playback equipment. This leads to a clear separation of audio scene
 rendering. For details, see [Wikipedia](https://en.wikipedia.org/wiki/Ambisonics).
 
 In its current state, the library allows spatial composition of single-channel `rodio` sources
-into a first-order *B-format* stream. The chosen renderer then decodes the *B-format* stream
-into audio signals for playback.
+into a *B-format* stream of up to third order. Higher orders localize sources more sharply, at
+the cost of more computation. The order is selected with `AmbisonicBuilder::with_order`.
+The chosen renderer then decodes the *B-format* stream into audio signals for playback.
 
 Currently, the following renderers are available:
 
 - Stereo: simple and efficient playback on two stereo speakers or headphones
 - HRTF: realistic 3D sound over headphones using head related transfer functions
-
-Although at the moment only stereo output is supported, the *B-format* abstraction should make
-it easy to implement arbitrary speaker configurations in the future.
+- Speakers: playback over arbitrary loudspeaker layouts, such as quad, 5.0, 7.0, or cube;
+  irregular layouts are best served by the AllRAD decoder
+- Panning: object-based playback over loudspeaker layouts, where each source is panned
+  directly between the nearest speakers with VBAP instead of being decoded from *B-format*
+
+Speaker renderers optionally decode in two bands, with max-rE weighting above a crossover
+frequency for sharper high-frequency localization, and compensate for speakers at different
+distances from the listener. With bass management, low frequencies are redirected from the
+speakers to an additional subwoofer channel.
+
+Decoders designed with external tools can be loaded from AmbDec presets with
+`SpeakerConfig::from_ambdec`, and computed decoders can be exported with `to_ambdec`.
+
+Measured HRTF sets can be loaded from SOFA files with `HrtfConfig::from_sofa`, which picks or
+interpolates the impulse responses for a layout of virtual speakers.
+HRIR sets can also be loaded from memory or any reader, in the text `.hrir` format or a compact
+binary format; the `convert_hrir` example converts text and SOFA files to the binary format.
+HRIRs are resampled automatically when their sample rate differs from the scene's.
 */
 
+mod ambdec;
+mod bass;
 mod bformat;
 mod bmixer;
 mod bstream;
+mod compensation;
+mod convention;
+mod device;
+mod directivity;
+mod distance;
+mod dualband;
+mod error;
+mod fft;
+mod filter;
+mod hdf5;
+mod hrir;
+mod inflate;
+mod linalg;
+mod listener;
+mod occlusion;
+mod panning;
+mod propagation;
 mod renderer;
+mod resample;
+mod rotation;
+mod routing;
+mod sofa;
+mod speakers;
+mod vbap;
 
 pub mod constants;
+pub use bass::{BassManagement, BassSource};
+pub use bformat::{Bformat, Bweights, EncodingParams, MAX_CHANNELS, MAX_ORDER};
 pub mod sources;
-pub use bmixer::{bmixer, BmixerComposer, BstreamMixer};
+pub use bmixer::{bmixer, bmixer_with_order, BmixerComposer, BstreamMixer};
 pub use bstream::{bstream, Bstream, BstreamConfig, SoundController};
+pub use convention::{BformatExport, BformatImport, ChannelOrder, Convention, Normalization};
+pub use directivity::Directivity;
+pub use distance::{DistanceModel, Falloff};
+pub use error::Error;
+pub use listener::Listener;
+pub use occlusion::{LineOfSight, Occlusion};
+pub use panning::PanningConfig;
+pub use propagation::PropagationModel;
 pub use renderer::{BstreamHrtfRenderer, BstreamStereoRenderer, HrtfConfig, StereoConfig};
 pub use rodio;
+pub use rotation::{rotator, BstreamRotator, Rotation, RotationController};
+pub use routing::{ChannelMap, ChannelRouter};
+pub use speakers::{BstreamSpeakerRenderer, DecodingMethod, SpeakerConfig, SpeakerLayout};
 
+use panning::Panner;
 use std::f32;
 use std::sync::Arc;
 
@@ -73,6 +140,12 @@ pub enum PlaybackConfiguration {
 
     /// Headphone playback using head related transfer functions
     Hrtf(HrtfConfig),
+
+    /// Playback over an arbitrary loudspeaker layout
+    Speakers(SpeakerConfig),
+
+    /// Playback over a loudspeaker layout, panning each source directly with VBAP
+    Panning(PanningConfig),
 }
 
 impl Default for PlaybackConfiguration {
@@ -93,11 +166,26 @@ impl From<HrtfConfig> for PlaybackConfiguration {
     }
 }
 
+impl From<SpeakerConfig> for PlaybackConfiguration {
+    fn from(cfg: SpeakerConfig) -> Self {
+        PlaybackConfiguration::Speakers(cfg)
+    }
+}
+
+impl From<PanningConfig> for PlaybackConfiguration {
+    fn from(cfg: PanningConfig) -> Self {
+        PlaybackConfiguration::Panning(cfg)
+    }
+}
+
 /// A builder object for creating `Ambisonic` contexts
 pub struct AmbisonicBuilder {
     device: Option<rodio::Device>,
     sample_rate: u32,
+    order: usize,
+    distance_model: DistanceModel,
     config: PlaybackConfiguration,
+    channel_map: Option<ChannelMap>,
 }
 
 impl AmbisonicBuilder {
@@ -107,34 +195,67 @@ impl AmbisonicBuilder {
     }
 
     /// Build the ambisonic context
-    pub fn build(self) -> Ambisonic {
-        let (stream, stream_handle) = if let Some(device) = self.device {
-            rodio::OutputStream::try_from_device(&device).unwrap()
-        } else {
-            rodio::OutputStream::try_default().unwrap()
-        };
-
-        let sink = rodio::Sink::try_new(&stream_handle).unwrap();
-
-        let (mixer, controller) = bmixer::bmixer(self.sample_rate);
-
-        match self.config {
-            PlaybackConfiguration::Stereo(cfg) => {
-                let output = renderer::BstreamStereoRenderer::new(mixer, cfg);
-                sink.append(output);
-            }
-
-            PlaybackConfiguration::Hrtf(cfg) => {
-                let output = renderer::BstreamHrtfRenderer::new(mixer, cfg);
-                sink.append(output);
-            }
-        }
-
-        Ambisonic {
+    ///
+    /// The device is opened with as many channels as the renderer, or the channel map, needs.
+    /// Fails if the channel map uses outputs that the renderer does not have, or if the audio
+    /// output stream can't be opened, for example because no sound card is available or the
+    /// device has too few channels.
+    pub fn build(self) -> Result<Ambisonic, Error> {
+        let (output, controller, rotation): (Box<dyn rodio::Source<Item = f32> + Send>, _, _) =
+            match self.config {
+                PlaybackConfiguration::Stereo(cfg) => {
+                    let (mixer, controller) =
+                        bmixer::bmixer_with_order(self.sample_rate, self.order);
+                    let (rotator, rotation) = rotation::rotator(mixer, self.order);
+                    let output = renderer::BstreamStereoRenderer::new(rotator, cfg);
+                    (Box::new(output), controller, Some(rotation))
+                }
+
+                PlaybackConfiguration::Hrtf(cfg) => {
+                    let (mixer, controller) =
+                        bmixer::bmixer_with_order(self.sample_rate, self.order);
+                    let (rotator, rotation) = rotation::rotator(mixer, self.order);
+                    let output = renderer::BstreamHrtfRenderer::new(rotator, cfg);
+                    (Box::new(output), controller, Some(rotation))
+                }
+
+                PlaybackConfiguration::Speakers(cfg) => {
+                    let (mixer, controller) =
+                        bmixer::bmixer_with_order(self.sample_rate, self.order);
+                    let (rotator, rotation) = rotation::rotator(mixer, self.order);
+                    let cfg = cfg.with_order(self.order);
+                    let output = speakers::BstreamSpeakerRenderer::new(rotator, cfg);
+                    (Box::new(output), controller, Some(rotation))
+                }
+
+                PlaybackConfiguration::Panning(cfg) => {
+                    let (mixer, controller) =
+                        bmixer::panning_mixer(self.sample_rate, Panner::new(cfg.layout()));
+                    let output = panning::PanningRenderer::new(mixer, &cfg);
+                    // panned sources do not form a sound field that could be rotated
+                    (Box::new(output), controller, None)
+                }
+            };
+        controller.set_default_distance_model(self.distance_model);
+
+        let map = self.channel_map.unwrap_or_else(|| {
+            let outputs: Vec<_> = (0..output.channels() as usize).collect();
+            ChannelMap::from_device_channels(&outputs)
+        });
+        map.check_outputs(output.channels() as usize)
+            .map_err(Error::InvalidData)?;
+        let (stream, sink) =
+            device::OutputStream::open(self.device, map.device_channels(), self.sample_rate)?;
+        // device channels beyond the map stay silent
+        let map = map.with_device_channels(stream.channels());
+        sink.append(ChannelRouter::new(output, map));
+
+        Ok(Ambisonic {
             sink,
             output_stream: stream,
             composer: controller,
-        }
+            rotation,
+        })
     }
 
     /// Select device (defaults to `rodio::default_output_device()`
@@ -153,10 +274,44 @@ impl AmbisonicBuilder {
         }
     }
 
+    /// Set ambisonic order of the mix (defaults to 1)
+    ///
+    /// Higher orders give sharper localization of sound sources, provided the renderer makes use
+    /// of them. Must not exceed `MAX_ORDER`.
+    pub fn with_order(self, order: usize) -> Self {
+        assert!(
+            order <= MAX_ORDER,
+            "ambisonic order must not exceed {}",
+            MAX_ORDER
+        );
+        AmbisonicBuilder { order, ..self }
+    }
+
+    /// Set the default distance model of the scene
+    ///
+    /// Sources that do not specify their own distance model use this one.
+    pub fn with_distance_model(self, distance_model: DistanceModel) -> Self {
+        AmbisonicBuilder {
+            distance_model,
+            ..self
+        }
+    }
+
     /// Set playback configuration
     pub fn with_config(self, config: PlaybackConfiguration) -> Self {
         AmbisonicBuilder { config, ..self }
     }
+
+    /// Map the renderer's outputs to the channels of the device
+    ///
+    /// By default, the outputs are played on the first device channels in the order of the
+    /// renderer (e.g. left and right, or the order of the speaker layout).
+    pub fn with_channel_map(self, map: ChannelMap) -> Self {
+        AmbisonicBuilder {
+            channel_map: Some(map),
+            ..self
+        }
+    }
 }
 
 impl Default for AmbisonicBuilder {
@@ -164,7 +319,10 @@ impl Default for AmbisonicBuilder {
         AmbisonicBuilder {
             device: None,
             sample_rate: 48000,
+            order: 1,
+            distance_model: DistanceModel::default(),
             config: PlaybackConfiguration::default(),
+            channel_map: None,
         }
     }
 }
@@ -177,12 +335,22 @@ pub struct Ambisonic {
     #[allow(dead_code)]
     sink: rodio::Sink,
     #[allow(dead_code)]
-    output_stream: rodio::OutputStream,
+    output_stream: device::OutputStream,
 
     composer: Arc<BmixerComposer>,
+    rotation: Option<RotationController>,
 }
 
 impl Ambisonic {
+    /// Controller for rotating the whole sound scene
+    ///
+    /// Rotating the scene affects all sources at once, which is much cheaper than updating each
+    /// source's position. Returns `None` for object panning playback, which has no sound field
+    /// to rotate; rotate the listener instead.
+    pub fn rotation_controller(&self) -> Option<&RotationController> {
+        self.rotation.as_ref()
+    }
+
     /// Add a single-channel `Source` to the sound scene at a position relative to the listener
     ///
     /// Returns a controller object that can be used to control the source during playback.
@@ -220,4 +388,64 @@ impl Ambisonic {
         self.composer
             .play(input, BstreamConfig::new().with_position(pos))
     }
+
+    /// Add a single-channel `Source` to the sound scene with detailed configuration.
+    ///
+    /// Returns a controller object that can be used to control the source during playback.
+    #[inline(always)]
+    pub fn play_with_config<I>(&self, input: I, config: BstreamConfig) -> SoundController
+    where
+        I: rodio::Source<Item = f32> + Send + 'static,
+    {
+        self.composer.play(input, config)
+    }
+
+    /// Add a single-channel `Source` to the sound scene at a position in world coordinates.
+    ///
+    /// The returned controller interprets positions and velocities in world coordinates. They are
+    /// transformed to the listener's point of view, which is set with `set_listener_position`,
+    /// `set_listener_orientation`, and `set_listener_velocity`.
+    #[inline(always)]
+    pub fn play_in_world<I>(&self, input: I, pos: [f32; 3]) -> SoundController
+    where
+        I: rodio::Source<Item = f32> + Send + 'static,
+    {
+        self.composer.play(
+            input,
+            BstreamConfig::new()
+                .with_head_relative(false)
+                .with_position(pos),
+        )
+    }
+
+    /// Set the line-of-sight test that attenuates sources behind obstacles
+    ///
+    /// Applies to all sources that do not specify their own test. Pass `None` to disable.
+    pub fn set_line_of_sight(&self, line_of_sight: Option<Arc<dyn LineOfSight>>) {
+        self.composer.set_line_of_sight(line_of_sight);
+    }
+
+    /// Current position, orientation, and velocity of the listener in world coordinates
+    pub fn listener(&self) -> Listener {
+        self.composer.listener()
+    }
+
+    /// Set the listener's position in world coordinates
+    pub fn set_listener_position(&self, pos: [f32; 3]) {
+        self.composer.update_listener(|l| l.position = pos);
+    }
+
+    /// Set the listener's orientation in world coordinates
+    pub fn set_listener_orientation(&self, orientation: Rotation) {
+        self.composer
+            .update_listener(|l| l.orientation = orientation);
+    }
+
+    /// Set the listener's velocity in world coordinates
+    ///
+    /// The velocity determines how much doppler effect to apply to sources in world coordinates
+    /// but has no effect on the listener's position.
+    pub fn set_listener_velocity(&self, vel: [f32; 3]) {
+        self.composer.update_listener(|l| l.velocity = vel);
+    }
 }
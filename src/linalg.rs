@@ -0,0 +1,280 @@
+//! Minimal dense linear algebra for computing rotation and decoding matrices.
+//!
+//! These routines run when configuring the signal chain, never in the audio thread, so they
+//! favor simplicity and numerical robustness over speed.
+
+use std::ops::{Index, IndexMut, Mul};
+
+/// Dense row-major matrix
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Matrix {
+    rows: usize,
+    cols: usize,
+    data: Vec<f64>,
+}
+
+impl Matrix {
+    pub fn zeros(rows: usize, cols: usize) -> Self {
+        Matrix {
+            rows,
+            cols,
+            data: vec![0.0; rows * cols],
+        }
+    }
+
+    pub fn identity(n: usize) -> Self {
+        Matrix::from_fn(n, n, |i, j| if i == j { 1.0 } else { 0.0 })
+    }
+
+    pub fn from_fn(rows: usize, cols: usize, f: impl Fn(usize, usize) -> f64) -> Self {
+        let mut m = Matrix::zeros(rows, cols);
+        for i in 0..rows {
+            for j in 0..cols {
+                m[(i, j)] = f(i, j);
+            }
+        }
+        m
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    pub fn transpose(&self) -> Matrix {
+        Matrix::from_fn(self.cols, self.rows, |i, j| self[(j, i)])
+    }
+
+    /// Inverse of a square matrix by Gauss-Jordan elimination with partial pivoting.
+    ///
+    /// Returns `None` if the matrix is (numerically) singular.
+    pub fn inverse(&self) -> Option<Matrix> {
+        assert_eq!(self.rows, self.cols);
+        let n = self.rows;
+        let mut a = self.clone();
+        let mut inv = Matrix::identity(n);
+
+        for col in 0..n {
+            let pivot = (col..n)
+                .max_by(|&i, &j| a[(i, col)].abs().partial_cmp(&a[(j, col)].abs()).unwrap())
+                .unwrap();
+
+            if a[(pivot, col)].abs() < SINGULAR_EPS {
+                return None;
+            }
+
+            a.swap_rows(col, pivot);
+            inv.swap_rows(col, pivot);
+
+            let p = a[(col, col)];
+            for j in 0..n {
+                a[(col, j)] /= p;
+                inv[(col, j)] /= p;
+            }
+
+            for i in 0..n {
+                if i == col {
+                    continue;
+                }
+                let f = a[(i, col)];
+                if f == 0.0 {
+                    continue;
+                }
+                for j in 0..n {
+                    a[(i, j)] -= f * a[(col, j)];
+                    inv[(i, j)] -= f * inv[(col, j)];
+                }
+            }
+        }
+
+        Some(inv)
+    }
+
+    /// Moore-Penrose pseudo-inverse.
+    ///
+    /// Directions in which the matrix is (numerically) degenerate are ignored, so the result is
+    /// the minimum-norm least-squares solution.
+    pub fn pseudo_inverse(&self) -> Matrix {
+        // pinv(A) = A^T pinv(A A^T), with the symmetric A A^T inverted via its eigenvalues
+        let gram = self * &self.transpose();
+        let (eigenvalues, eigenvectors) = gram.symmetric_eigen();
+        let max = eigenvalues.iter().fold(0.0f64, |m, &e| m.max(e.abs()));
+
+        let n = gram.rows;
+        let mut gram_pinv = Matrix::zeros(n, n);
+        for (k, &e) in eigenvalues.iter().enumerate() {
+            if e.abs() <= max * RANK_EPS {
+                continue;
+            }
+            for i in 0..n {
+                for j in 0..n {
+                    gram_pinv[(i, j)] += eigenvectors[(i, k)] * eigenvectors[(j, k)] / e;
+                }
+            }
+        }
+
+        &self.transpose() * &gram_pinv
+    }
+
+    /// Eigenvalues and eigenvectors (in columns) of a symmetric matrix by the cyclic Jacobi
+    /// method.
+    pub fn symmetric_eigen(&self) -> (Vec<f64>, Matrix) {
+        assert_eq!(self.rows, self.cols);
+        let n = self.rows;
+        let mut a = self.clone();
+        let mut v = Matrix::identity(n);
+
+        for _ in 0..JACOBI_MAX_SWEEPS {
+            let off: f64 = (0..n)
+                .flat_map(|i| (0..n).filter(move |&j| j != i).map(move |j| (i, j)))
+                .map(|(i, j)| a[(i, j)] * a[(i, j)])
+                .sum();
+            if off < SINGULAR_EPS * SINGULAR_EPS {
+                break;
+            }
+
+            for p in 0..n {
+                for q in p + 1..n {
+                    if a[(p, q)] == 0.0 {
+                        continue;
+                    }
+                    let theta = (a[(q, q)] - a[(p, p)]) / (2.0 * a[(p, q)]);
+                    let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                    let c = 1.0 / (t * t + 1.0).sqrt();
+                    let s = t * c;
+
+                    for k in 0..n {
+                        let akp = a[(k, p)];
+                        let akq = a[(k, q)];
+                        a[(k, p)] = c * akp - s * akq;
+                        a[(k, q)] = s * akp + c * akq;
+                    }
+                    for k in 0..n {
+                        let apk = a[(p, k)];
+                        let aqk = a[(q, k)];
+                        a[(p, k)] = c * apk - s * aqk;
+                        a[(q, k)] = s * apk + c * aqk;
+                    }
+                    for k in 0..n {
+                        let vkp = v[(k, p)];
+                        let vkq = v[(k, q)];
+                        v[(k, p)] = c * vkp - s * vkq;
+                        v[(k, q)] = s * vkp + c * vkq;
+                    }
+                }
+            }
+        }
+
+        ((0..n).map(|i| a[(i, i)]).collect(), v)
+    }
+
+    fn swap_rows(&mut self, i: usize, j: usize) {
+        if i == j {
+            return;
+        }
+        for k in 0..self.cols {
+            self.data.swap(i * self.cols + k, j * self.cols + k);
+        }
+    }
+}
+
+const SINGULAR_EPS: f64 = 1e-12;
+
+/// Relative size below which eigenvalues are treated as zero by `pseudo_inverse`
+const RANK_EPS: f64 = 1e-9;
+
+const JACOBI_MAX_SWEEPS: usize = 100;
+
+impl Index<(usize, usize)> for Matrix {
+    type Output = f64;
+
+    fn index(&self, (i, j): (usize, usize)) -> &f64 {
+        &self.data[i * self.cols + j]
+    }
+}
+
+impl IndexMut<(usize, usize)> for Matrix {
+    fn index_mut(&mut self, (i, j): (usize, usize)) -> &mut f64 {
+        &mut self.data[i * self.cols + j]
+    }
+}
+
+impl Mul for &Matrix {
+    type Output = Matrix;
+
+    fn mul(self, rhs: &Matrix) -> Matrix {
+        assert_eq!(self.cols, rhs.rows);
+        let mut out = Matrix::zeros(self.rows, rhs.cols);
+        for i in 0..self.rows {
+            for k in 0..self.cols {
+                let a = self[(i, k)];
+                if a == 0.0 {
+                    continue;
+                }
+                for j in 0..rhs.cols {
+                    out[(i, j)] += a * rhs[(k, j)];
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inverse_of_well_conditioned_matrix() {
+        let a = Matrix::from_fn(4, 4, |i, j| if i == j { 4.0 } else { (i + j) as f64 * 0.3 });
+        let b = &a * &a.inverse().unwrap();
+        for i in 0..4 {
+            for j in 0..4 {
+                let expected = if i == j { 1.0 } else { 0.0 };
+                assert!((b[(i, j)] - expected).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn pseudo_inverse_of_rank_deficient_matrix() {
+        // the third row is the sum of the first two
+        let a = Matrix::from_fn(3, 5, |i, j| match i {
+            0 => (j as f64).sin(),
+            1 => (j as f64 * 0.7).cos(),
+            _ => (j as f64).sin() + (j as f64 * 0.7).cos(),
+        });
+        let p = a.pseudo_inverse();
+        assert_eq!((p.rows(), p.cols()), (5, 3));
+
+        // A pinv(A) A = A
+        let apa = &(&a * &p) * &a;
+        for i in 0..3 {
+            for j in 0..5 {
+                assert!((apa[(i, j)] - a[(i, j)]).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn eigen_decomposition_reconstructs_matrix() {
+        let a = Matrix::from_fn(4, 4, |i, j| 1.0 / (1 + i + j) as f64);
+        let (e, v) = a.symmetric_eigen();
+        let d = Matrix::from_fn(4, 4, |i, j| if i == j { e[i] } else { 0.0 });
+        let b = &(&v * &d) * &v.transpose();
+        for i in 0..4 {
+            for j in 0..4 {
+                assert!((a[(i, j)] - b[(i, j)]).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn singular_matrix_has_no_inverse() {
+        let a = Matrix::from_fn(3, 3, |i, j| (i * j) as f64);
+        assert!(a.inverse().is_none());
+    }
+}
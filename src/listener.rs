@@ -0,0 +1,74 @@
+//! The listener of a sound scene
+
+use crate::rotation::Rotation;
+
+/// Position, orientation, and velocity of the listener in world coordinates.
+///
+/// The orientation is the rotation that turns the default viewing direction (front, along the
+/// `y` axis) to the listener's actual viewing direction.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Listener {
+    pub position: [f32; 3],
+    pub orientation: Rotation,
+    pub velocity: [f32; 3],
+}
+
+impl Listener {
+    /// Transform a position in world coordinates to the listener's point of view
+    pub fn relative_position(&self, pos: [f32; 3]) -> [f32; 3] {
+        self.orientation.inverse().rotate([
+            pos[0] - self.position[0],
+            pos[1] - self.position[1],
+            pos[2] - self.position[2],
+        ])
+    }
+
+    /// Transform a velocity in world coordinates to the listener's point of view
+    pub fn relative_velocity(&self, vel: [f32; 3]) -> [f32; 3] {
+        self.orientation.inverse().rotate([
+            vel[0] - self.velocity[0],
+            vel[1] - self.velocity[1],
+            vel[2] - self.velocity[2],
+        ])
+    }
+}
+
+impl Default for Listener {
+    fn default() -> Self {
+        Listener {
+            position: [0.0, 0.0, 0.0],
+            orientation: Rotation::identity(),
+            velocity: [0.0, 0.0, 0.0],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    #[test]
+    fn source_in_front_of_turned_listener() {
+        let listener = Listener {
+            position: [1.0, 1.0, 0.0],
+            orientation: Rotation::from_euler(-PI / 2.0, 0.0, 0.0),
+            velocity: [0.0, 0.0, 0.0],
+        };
+
+        // the listener looks to the right, so a source right of them is in front
+        let p = listener.relative_position([3.0, 1.0, 0.0]);
+        assert!(p[0].abs() < 1e-6);
+        assert!((p[1] - 2.0).abs() < 1e-6);
+        assert!(p[2].abs() < 1e-6);
+    }
+
+    #[test]
+    fn listener_velocity_is_subtracted() {
+        let listener = Listener {
+            velocity: [0.0, 5.0, 0.0],
+            ..Listener::default()
+        };
+        assert_eq!(listener.relative_velocity([0.0, 5.0, 0.0]), [0.0, 0.0, 0.0]);
+    }
+}
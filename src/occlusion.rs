@@ -0,0 +1,96 @@
+//! Attenuation of the direct sound path by obstacles
+
+use crate::filter::REFERENCE_FREQUENCY;
+
+/// Attenuation of the direct path between a source and the listener
+///
+/// Combines a broadband gain with a low-pass filter. This describes both *occlusion*, where the
+/// source is completely separated from the listener (e.g. behind a closed door), and
+/// *obstruction*, where an obstacle blocks the direct path but sound can still pass around it.
+/// Since sources have no reverberant path (yet), both apply to the direct path and are combined
+/// multiplicatively.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Occlusion {
+    gain: f32,
+    hf_gain: f32,
+}
+
+impl Occlusion {
+    /// No attenuation
+    pub fn none() -> Self {
+        Occlusion {
+            gain: 1.0,
+            hf_gain: 1.0,
+        }
+    }
+
+    /// Attenuate with broadband `gain` and an additional high-frequency gain `hf_gain`.
+    ///
+    /// Like air absorption, `hf_gain` is the gain at the filter reference frequency of 5 kHz.
+    pub fn new(gain: f32, hf_gain: f32) -> Self {
+        assert!(gain >= 0.0);
+        assert!((0.0..=1.0).contains(&hf_gain));
+        Occlusion { gain, hf_gain }
+    }
+
+    /// Attenuate with broadband `gain` and a low-pass filter with given `cutoff` frequency
+    /// (in Hz).
+    pub fn with_cutoff(gain: f32, cutoff: f32) -> Self {
+        assert!(cutoff > 0.0);
+        let ratio = REFERENCE_FREQUENCY / cutoff;
+        Occlusion::new(gain, 1.0 / (1.0 + ratio * ratio).sqrt())
+    }
+
+    /// Broadband gain
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    /// Gain at the filter reference frequency of 5 kHz
+    pub fn hf_gain(&self) -> f32 {
+        self.hf_gain
+    }
+
+    /// Combine two attenuations
+    pub fn then(self, other: Occlusion) -> Occlusion {
+        Occlusion {
+            gain: self.gain * other.gain,
+            hf_gain: self.hf_gain * other.hf_gain,
+        }
+    }
+}
+
+impl Default for Occlusion {
+    fn default() -> Self {
+        Occlusion::none()
+    }
+}
+
+/// User-supplied line-of-sight test
+///
+/// The scene queries this whenever a source or the listener moves, to determine how strongly
+/// the direct path between them is blocked. Implementations typically cast a ray through the
+/// game world. Queries run on the thread that updates the scene, never in the audio thread, and
+/// without holding any locks of the scene, so implementations may query the scene themselves.
+pub trait LineOfSight: Send + Sync {
+    /// Attenuation of the direct path from `source` to `listener` (in world coordinates)
+    fn query(&self, listener: [f32; 3], source: [f32; 3]) -> Occlusion;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cutoff_at_reference_frequency_is_3db_down() {
+        let o = Occlusion::with_cutoff(1.0, REFERENCE_FREQUENCY);
+        assert!((o.hf_gain() - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6);
+        assert!(Occlusion::with_cutoff(1.0, 500.0).hf_gain() < 0.1);
+    }
+
+    #[test]
+    fn attenuations_combine() {
+        let o = Occlusion::new(0.5, 0.5).then(Occlusion::new(0.5, 0.2));
+        assert_eq!(o, Occlusion::new(0.25, 0.1));
+    }
+}
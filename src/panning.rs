@@ -0,0 +1,275 @@
+//! Object-based rendering with vector base amplitude panning.
+//!
+//! Instead of encoding sources into an ambisonic sound field, each source is panned directly
+//! between the two or three speakers closest to its direction. This keeps point sources sharp on
+//! large speaker rigs, at the cost of a sound field that depends on the speaker layout.
+//!
+//! Panned sources bypass *B-format* and are mixed into one feed per speaker, so there is no
+//! limit on the number of speakers.
+
+use std::time::Duration;
+
+use rodio::Source;
+
+use crate::bass::{BassManagement, BassSource};
+use crate::bmixer::PanningMixer;
+use crate::distance::DistanceModel;
+use crate::speakers::{SpeakerLayout, SpeakerOutput};
+use crate::vbap::Vbap;
+
+/// Object panning playback configuration
+///
+/// Sources are panned over the triangulated speaker layout with VBAP. Scene rotation does not
+/// apply to panned sources; rotate the listener instead.
+#[derive(Debug, Clone)]
+pub struct PanningConfig {
+    layout: SpeakerLayout,
+    bass_management: Option<BassManagement>,
+}
+
+impl PanningConfig {
+    /// Create a configuration for the given layout
+    pub fn new(layout: SpeakerLayout) -> Self {
+        PanningConfig {
+            layout,
+            bass_management: None,
+        }
+    }
+
+    /// Enable bass management (disabled by default).
+    ///
+    /// The subwoofer is always fed from the main speakers, since panned sources have no
+    /// omnidirectional component.
+    pub fn with_bass_management(mut self, bass: BassManagement) -> Self {
+        self.bass_management = Some(bass.with_source(BassSource::Mains));
+        self
+    }
+
+    /// The speaker layout
+    pub fn layout(&self) -> &SpeakerLayout {
+        &self.layout
+    }
+}
+
+/// Plays the speaker feeds of panned sources
+pub(crate) struct PanningRenderer {
+    mixer: PanningMixer,
+    output: SpeakerOutput,
+    mains: Vec<f32>,
+    next_channel: usize,
+}
+
+impl PanningRenderer {
+    pub fn new(mixer: PanningMixer, config: &PanningConfig) -> Self {
+        let output =
+            SpeakerOutput::new(&config.layout, config.bass_management, mixer.sample_rate());
+        PanningRenderer {
+            mixer,
+            mains: vec![0.0; config.layout.len()],
+            next_channel: output.channels(),
+            output,
+        }
+    }
+}
+
+impl Source for PanningRenderer {
+    #[inline(always)]
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    #[inline(always)]
+    fn channels(&self) -> u16 {
+        self.output.channels() as u16
+    }
+
+    #[inline(always)]
+    fn sample_rate(&self) -> u32 {
+        self.mixer.sample_rate()
+    }
+
+    #[inline(always)]
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl Iterator for PanningRenderer {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_channel >= self.output.channels() {
+            let lfe = self.mixer.mix(&mut self.mains);
+            self.output.process(0.0, lfe, &mut self.mains);
+            self.next_channel = 0;
+        }
+
+        let x = self.output.frame()[self.next_channel];
+        self.next_channel += 1;
+        Some(x)
+    }
+}
+
+/// Computes speaker gains from source positions
+pub(crate) struct Panner {
+    vbap: Vbap,
+    n_speakers: usize,
+}
+
+impl Panner {
+    pub fn new(layout: &SpeakerLayout) -> Self {
+        Panner {
+            vbap: Vbap::new(layout.directions()),
+            n_speakers: layout.len(),
+        }
+    }
+
+    /// Equal gains on all speakers, with unit total energy
+    pub fn omni(&self) -> Vec<f32> {
+        vec![1.0 / (self.n_speakers as f32).sqrt(); self.n_speakers]
+    }
+
+    /// Speaker gains for a source at given position.
+    ///
+    /// Like `Bweights::from_position_with`, the source is attenuated according to the distance
+    /// `model` and gradually spreads over all speakers inside the `interior_radius`.
+    pub fn gains(&self, pos: [f32; 3], model: &DistanceModel, interior_radius: f32) -> Vec<f32> {
+        let dist = (pos[0] * pos[0] + pos[1] * pos[1] + pos[2] * pos[2]).sqrt();
+        let falloff = model.gain(dist);
+
+        let mut gains = self.omni();
+        if dist >= INTERIOR_EPS {
+            let direction = [pos[0] / dist, pos[1] / dist, pos[2] / dist];
+            self.vbap.gains_preserving_energy(direction, &mut gains);
+
+            // blend energies, so that the total energy stays constant
+            let f = if dist < interior_radius {
+                dist / interior_radius
+            } else {
+                1.0
+            };
+            let spread = (1.0 - f) / self.n_speakers as f32;
+            for g in &mut gains {
+                *g = (f * *g * *g + spread).sqrt();
+            }
+        }
+
+        for g in &mut gains {
+            *g *= falloff;
+        }
+        gains
+    }
+}
+
+/// Sources closer than this to the listener are spread over all speakers
+const INTERIOR_EPS: f32 = 1e-6;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distance::Falloff;
+
+    fn energy(g: &[f32]) -> f32 {
+        g.iter().map(|x| x * x).sum()
+    }
+
+    #[test]
+    fn source_at_speaker_plays_only_there() {
+        let layout = SpeakerLayout::surround_5_0();
+        let panner = Panner::new(&layout);
+        let model = DistanceModel::default();
+        for (i, d) in layout.directions().iter().enumerate() {
+            let pos = [d[0] * 5.0, d[1] * 5.0, d[2] * 5.0];
+            let g = panner.gains(pos, &model, 0.5);
+            assert!(g[i] > 0.0);
+            for (j, gj) in g.iter().enumerate() {
+                if j != i {
+                    assert!(gj.abs() < 1e-4, "{} {:?}", i, g);
+                }
+            }
+            assert!((energy(&g) - model.gain(5.0).powi(2)).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn interior_sources_spread_with_constant_energy() {
+        let panner = Panner::new(&SpeakerLayout::quad());
+        let model = DistanceModel::new(Falloff::None);
+        let near = panner.gains([0.0, 0.25, 0.0], &model, 1.0);
+        let far = panner.gains([0.0, 4.0, 0.0], &model, 1.0);
+        let center = panner.gains([0.0, 0.0, 0.0], &model, 1.0);
+
+        assert!(near[2] > 0.0 && far[2] == 0.0);
+        assert_eq!(center, [0.5; 4]);
+        for g in &[near, far, center] {
+            assert!((energy(g) - 1.0).abs() < 1e-4, "{:?}", g);
+        }
+    }
+
+    #[test]
+    fn elevated_sources_keep_their_energy() {
+        let panner = Panner::new(&SpeakerLayout::quad());
+        let model = DistanceModel::new(Falloff::None);
+        for &elevation in &[0.0f32, 30.0, 60.0, 90.0, -45.0] {
+            let e = elevation.to_radians();
+            let g = panner.gains([0.0, 2.0 * e.cos(), 2.0 * e.sin()], &model, 1.0);
+            assert!((energy(&g) - 1.0).abs() < 1e-4, "{}: {:?}", elevation, g);
+        }
+    }
+
+    fn renderer(
+        config: &PanningConfig,
+    ) -> (PanningRenderer, std::sync::Arc<crate::BmixerComposer>) {
+        let (mixer, composer) = crate::bmixer::panning_mixer(1000, Panner::new(config.layout()));
+        (PanningRenderer::new(mixer, config), composer)
+    }
+
+    #[test]
+    fn controller_moves_panned_source() {
+        use crate::bstream::BstreamConfig;
+
+        let (mut renderer, composer) = renderer(&PanningConfig::new(SpeakerLayout::quad()));
+        assert_eq!(renderer.channels(), 4);
+
+        let input = crate::sources::Constant::new(1.0, 1000);
+        let mut sound = composer.play(input, BstreamConfig::new().with_position([-1.0, 1.0, 0.0]));
+        let frame: Vec<_> = renderer.by_ref().skip(4 * 2000).take(4).collect();
+        // the default distance model attenuates the source at distance √2
+        assert!((frame[0] - 0.5f32.sqrt()).abs() < 1e-3, "{:?}", frame);
+        assert!(frame[1..].iter().all(|x| x.abs() < 1e-3));
+
+        sound.adjust_position([1.0, -1.0, 0.0]);
+        let frame: Vec<_> = renderer.by_ref().skip(4 * 2000).take(4).collect();
+        assert!((frame[3] - 0.5f32.sqrt()).abs() < 1e-3, "{:?}", frame);
+        assert!(frame[..3].iter().all(|x| x.abs() < 1e-3));
+    }
+
+    #[test]
+    fn large_layouts_get_a_feed_per_speaker() {
+        use crate::bstream::BstreamConfig;
+
+        let angles: Vec<_> = (0..24)
+            .map(|i| (i as f32 * std::f32::consts::PI / 12.0, 0.0))
+            .collect();
+        let layout = SpeakerLayout::from_angles(&angles);
+        let config = PanningConfig::new(layout.clone())
+            .with_bass_management(BassManagement::new(24).with_crossover(10.0));
+        let (mut renderer, composer) = renderer(&config);
+        assert_eq!(renderer.channels(), 25);
+
+        let input = crate::sources::Noise::new(1000);
+        let d = layout.directions()[20];
+        let _sound = composer.play(input, BstreamConfig::new().with_position(d));
+        let frames: Vec<_> = renderer.by_ref().skip(25 * 100).take(25 * 100).collect();
+        let level = |ch: usize| {
+            frames
+                .iter()
+                .skip(ch)
+                .step_by(25)
+                .map(|x| x * x)
+                .sum::<f32>()
+        };
+        assert!(level(20) > 1.0);
+        assert!((0..24).filter(|&ch| ch != 20).all(|ch| level(ch) < 1e-6));
+    }
+}
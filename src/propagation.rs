@@ -0,0 +1,136 @@
+//! Propagation of sound from the source to the listener
+
+/// How sound travels from a source to the listener
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum PropagationModel {
+    /// Sound arrives instantly. The doppler effect is approximated by changing the playback
+    /// speed according to the source's velocity.
+    #[default]
+    Instantaneous,
+
+    /// Sound travels at the speed of sound through a variable delay line. The doppler effect
+    /// emerges from changes in distance, and velocities are used to predict the distance between
+    /// position updates.
+    ///
+    /// Sources can be up to `max_distance` meters away from the listener; more distant sources
+    /// are delayed as if they were at `max_distance`.
+    Delayed { max_distance: f32 },
+}
+
+impl PropagationModel {
+    /// Delay line model for sources up to 500 meters away
+    pub fn delayed() -> Self {
+        PropagationModel::Delayed {
+            max_distance: DEFAULT_MAX_DISTANCE,
+        }
+    }
+}
+
+const DEFAULT_MAX_DISTANCE: f32 = 500.0;
+
+/// Variable delay line with smoothly changing, fractional delay
+pub(crate) struct DelayLine {
+    buffer: Vec<f32>,
+    write_pos: usize,
+    delay: f32,
+    target: f32,
+    target_rate: f32,
+    smoothing: f32,
+    sample_rate: f32,
+}
+
+impl DelayLine {
+    /// Create a delay line for delays up to `max_delay` seconds
+    pub fn new(max_delay: f32, sample_rate: u32) -> Self {
+        let max_delay = max_delay * sample_rate as f32;
+        let len = max_delay.ceil() as usize + 2;
+        DelayLine {
+            buffer: vec![0.0; len],
+            write_pos: 0,
+            delay: 0.0,
+            target: 0.0,
+            target_rate: 0.0,
+            smoothing: 1.0 / (DELAY_SMOOTHING * sample_rate as f32),
+            sample_rate: sample_rate as f32,
+        }
+    }
+
+    fn max_delay(&self) -> f32 {
+        (self.buffer.len() - 2) as f32
+    }
+
+    /// Set the rate at which the delay is expected to change (in seconds per second)
+    pub fn set_rate(&mut self, rate: f32) {
+        self.target_rate = rate;
+    }
+
+    /// Set delay (in seconds) immediately
+    pub fn set_delay(&mut self, delay: f32, rate: f32) {
+        self.set_target(delay, rate);
+        self.delay = self.target;
+    }
+
+    /// Set the delay (in seconds) that is approached smoothly, and the rate at which it is
+    /// expected to change (in seconds per second).
+    pub fn set_target(&mut self, delay: f32, rate: f32) {
+        self.target = (delay * self.sample_rate).clamp(0.0, self.max_delay());
+        self.target_rate = rate;
+    }
+
+    /// Current delay in samples
+    pub fn delay(&self) -> f32 {
+        self.delay
+    }
+
+    /// Push a new sample into the delay line and read the delayed output
+    pub fn process(&mut self, x: f32) -> f32 {
+        let n = self.buffer.len();
+
+        let step = self.target_rate + (self.target - self.delay) * self.smoothing;
+        self.delay += step.clamp(-MAX_DELAY_RATE, MAX_DELAY_RATE);
+        self.delay = self.delay.clamp(0.0, self.max_delay());
+        self.target = (self.target + self.target_rate).clamp(0.0, self.max_delay());
+
+        self.buffer[self.write_pos] = x;
+
+        let read_pos = self.write_pos as f32 + n as f32 - self.delay;
+        let i = read_pos.floor();
+        let frac = read_pos - i;
+        let i = i as usize % n;
+        let y = self.buffer[i] * (1.0 - frac) + self.buffer[(i + 1) % n] * frac;
+
+        self.write_pos = (self.write_pos + 1) % n;
+        y
+    }
+}
+
+/// Time constant for following changes of the target delay (in seconds)
+const DELAY_SMOOTHING: f32 = 0.05;
+
+/// Maximum change of delay per sample; limits the doppler shift to between half and one and a
+/// half times the original pitch
+const MAX_DELAY_RATE: f32 = 0.5;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_delay() {
+        let mut dl = DelayLine::new(0.01, 1000);
+        dl.set_delay(3.0 / 1000.0, 0.0);
+        let out: Vec<_> = (1..10).map(|i| dl.process(i as f32)).collect();
+        assert_eq!(out, vec![0.0, 0.0, 0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+    }
+
+    #[test]
+    fn increasing_delay_lowers_pitch() {
+        let mut dl = DelayLine::new(0.01, 1000);
+        dl.set_delay(0.0, 0.1);
+        let out: Vec<_> = (0..20).map(|i| dl.process(i as f32)).collect();
+        // the input ramp rises by 1 per sample, the output only by 0.9
+        for w in out[5..].windows(2) {
+            assert!((w[1] - w[0] - 0.9).abs() < 1e-4, "{:?}", out);
+        }
+    }
+}
@@ -1,13 +1,13 @@
 //! Render *B-format* audio streams to streams suitable for playback on audio equipment.
 
-use std::collections::VecDeque;
-use std::fs::File;
-use std::io::{BufReader, Read};
 use std::time::Duration;
 
 use rodio::Source;
 
-use crate::bformat::{Bformat, Bweights};
+use crate::bformat::{n_channels, Bformat, Bweights};
+use crate::compensation::DistanceCompensation;
+use crate::dualband::DualBand;
+use crate::fft::{overlap_save_output, Complex, Fft, FrequencyDelayLine, PartitionedFilter};
 
 /// Stereo Playback configuration
 ///
@@ -18,6 +18,8 @@ use crate::bformat::{Bformat, Bweights};
 pub struct StereoConfig {
     left_mic: Bweights,
     right_mic: Bweights,
+    crossover: Option<f32>,
+    distances: Option<[f32; 2]>,
 }
 
 impl StereoConfig {
@@ -30,6 +32,24 @@ impl StereoConfig {
     pub fn set_right_direction(&mut self, dir: [f32; 3]) {
         self.right_mic = Bweights::virtual_microphone(dir, 0.5)
     }
+
+    /// Set the distances of the left and right speaker from the listener (in meters).
+    ///
+    /// If the speakers are not equidistant, the closer one is delayed and attenuated so that
+    /// sound from both speakers arrives aligned at the listener.
+    pub fn set_distances(&mut self, left: f32, right: f32) {
+        assert!(left > 0.0 && right > 0.0);
+        self.distances = Some([left, right]);
+    }
+
+    /// Enable dual-band decoding with given crossover frequency (in Hz), or disable it with
+    /// `None` (disabled by default).
+    ///
+    /// Above the crossover, the sound field is weighted for sharper high-frequency localization
+    /// (max-rE). Typical crossover frequencies are between 400 and 700 Hz.
+    pub fn set_dual_band(&mut self, crossover: Option<f32>) {
+        self.crossover = crossover;
+    }
 }
 
 impl Default for StereoConfig {
@@ -37,6 +57,8 @@ impl Default for StereoConfig {
         StereoConfig {
             left_mic: Bweights::virtual_microphone([-1.0, 1.0, 0.0], 0.5),
             right_mic: Bweights::virtual_microphone([1.0, 1.0, 0.0], 0.5),
+            crossover: None,
+            distances: None,
         }
     }
 }
@@ -49,16 +71,30 @@ pub struct BstreamStereoRenderer<I> {
     buffered_sample: Option<f32>,
     left_mic: Bweights,
     right_mic: Bweights,
+    dual_band: Option<DualBand>,
+    compensation: Option<DistanceCompensation>,
 }
 
-impl<I> BstreamStereoRenderer<I> {
+impl<I> BstreamStereoRenderer<I>
+where
+    I: Source<Item = Bformat>,
+{
     /// Construct a new stereo renderer with default settings
     pub fn new(input: I, config: StereoConfig) -> Self {
+        // the virtual microphones are first order
+        let dual_band = config
+            .crossover
+            .map(|f| DualBand::new(1, f, input.sample_rate()));
+        let compensation = config
+            .distances
+            .map(|d| DistanceCompensation::new(&d, input.sample_rate()));
         BstreamStereoRenderer {
             input,
             buffered_sample: None,
             left_mic: config.left_mic,
             right_mic: config.right_mic,
+            dual_band,
+            compensation,
         }
     }
 }
@@ -98,10 +134,16 @@ where
         match self.buffered_sample.take() {
             Some(s) => Some(s),
             None => {
-                let sample = self.input.next()?;
+                let mut sample = self.input.next()?;
+                if let Some(db) = &mut self.dual_band {
+                    sample = db.process(sample);
+                }
 
-                let left = self.left_mic.dot(sample);
-                let right = self.right_mic.dot(sample);
+                let mut frame = [self.left_mic.dot(sample), self.right_mic.dot(sample)];
+                if let Some(comp) = &mut self.compensation {
+                    comp.process(&mut frame);
+                }
+                let [left, right] = frame;
 
                 // emit left channel now, and right channel next time
                 self.buffered_sample = Some(right);
@@ -117,66 +159,39 @@ where
 /// between both ears, depending on the direction of the sound.
 ///
 /// The default setting uses a set of real but arbitrary HRIRs, that may not be suitable for
-/// all listeners.
+/// all listeners. It decodes only the first order of the sound field; sets with more virtual
+/// speakers and higher-order weights can be loaded from `.hrir` or SOFA files.
 pub struct HrtfConfig {
-    sample_rate: u32,
-    virtual_speakers: Vec<VirtualSpeaker>,
-}
-
-impl HrtfConfig {
-    pub fn from_file(filename: &str) -> Self {
-        // todo: proper error handling
-        let file = File::open(filename).unwrap();
-        let mut bufr = BufReader::new(file);
-        let mut data = String::new();
-        bufr.read_to_string(&mut data).unwrap();
-
-        let mut lines = data.split('\n');
-        let fs: f32 = lines.next().unwrap().parse().unwrap();
-        assert_eq!(lines.next(), Some(""));
-
-        let mut virtual_speakers = Vec::new();
-
-        loop {
-            let bweights: Bweights = match lines.next() {
-                None | Some("") => break,
-                Some(l) => l.split(", ").map(|s| s.parse().unwrap()).collect(),
-            };
-
-            let left_hrir: Vec<f32> = match lines.next() {
-                None | Some("") => panic!("expected hrir"),
-                Some(l) => l.split(", ").map(|s| s.parse().unwrap()).collect(),
-            };
-
-            let right_hrir: Vec<f32> = match lines.next() {
-                None | Some("") => panic!("expected hrir"),
-                Some(l) => l.split(", ").map(|s| s.parse().unwrap()).collect(),
-            };
-
-            assert_eq!(lines.next(), Some(""));
-
-            virtual_speakers.push(VirtualSpeaker {
-                bweights,
-                left_hrir,
-                right_hrir,
-            });
-        }
-
-        assert!(lines.next().is_none());
-
-        HrtfConfig {
-            sample_rate: fs as u32,
-            virtual_speakers,
-        }
-    }
+    pub(crate) sample_rate: u32,
+    pub(crate) virtual_speakers: Vec<VirtualSpeaker>,
 }
 
 /// Render a *B-format* stream for headphones using head related transfer functions.
+///
+/// Since convolution is linear, the HRIRs of all virtual speakers are combined into one pair of
+/// filters per *B-format* channel, so the cost does not depend on the number of speakers. The
+/// filters are applied with uniformly partitioned FFT convolution in blocks of 128 samples,
+/// which delays the output by one block.
 pub struct BstreamHrtfRenderer<I> {
     input: I,
     buffered_output: Option<f32>,
-    convolution_buffers: Vec<VecDeque<f32>>,
-    virtual_speakers: Vec<VirtualSpeaker>,
+    fft: Fft,
+    channels: Vec<HrtfChannel>,
+    position: usize,
+    output: [Vec<f32>; 2],
+    spectra: [Vec<Complex>; 2],
+    scratch: Vec<Complex>,
+}
+
+/// Number of samples processed at once by the HRTF renderer
+const HRTF_BLOCK_SIZE: usize = 128;
+
+/// Input and filters of one *B-format* channel
+struct HrtfChannel {
+    block: Vec<f32>,
+    history: FrequencyDelayLine,
+    left: PartitionedFilter,
+    right: PartitionedFilter,
 }
 
 impl<I> BstreamHrtfRenderer<I>
@@ -184,23 +199,85 @@ where
     I: Source<Item = Bformat>,
 {
     /// Construct a new HRTF renderer with default settings
+    ///
+    /// HRIRs recorded at a different sample rate than the input are resampled.
     pub fn new(input: I, config: HrtfConfig) -> Self {
-        assert_eq!(config.sample_rate, input.sample_rate());
+        let config = config.resampled(input.sample_rate());
 
-        let convolution_buffers = config
-            .virtual_speakers
+        let fft = Fft::new(2 * HRTF_BLOCK_SIZE);
+        let speakers = &config.virtual_speakers;
+        let order = speakers
+            .iter()
+            .map(|s| s.bweights.order())
+            .max()
+            .unwrap_or(0);
+        let length = speakers
             .iter()
-            .map(|speaker| {
-                let n = speaker.left_hrir.len().max(speaker.right_hrir.len());
-                VecDeque::from(vec![0.0; n])
+            .map(|s| s.left_hrir.len().max(s.right_hrir.len()))
+            .max()
+            .unwrap_or(0);
+
+        let channels = (0..n_channels(order))
+            .map(|c| {
+                let mut left = vec![0.0; length];
+                let mut right = vec![0.0; length];
+                for speaker in speakers {
+                    let w = speaker.bweights.components()[c];
+                    for (y, h) in left.iter_mut().zip(&speaker.left_hrir) {
+                        *y += w * h;
+                    }
+                    for (y, h) in right.iter_mut().zip(&speaker.right_hrir) {
+                        *y += w * h;
+                    }
+                }
+                let left = PartitionedFilter::new(&left, &fft);
+                let right = PartitionedFilter::new(&right, &fft);
+                HrtfChannel {
+                    block: vec![0.0; HRTF_BLOCK_SIZE],
+                    history: FrequencyDelayLine::new(left.len(), &fft),
+                    left,
+                    right,
+                }
             })
             .collect();
 
         BstreamHrtfRenderer {
             input,
             buffered_output: None,
-            convolution_buffers,
-            virtual_speakers: config.virtual_speakers,
+            channels,
+            position: 0,
+            output: [vec![0.0; HRTF_BLOCK_SIZE], vec![0.0; HRTF_BLOCK_SIZE]],
+            spectra: [
+                vec![Complex::default(); fft.len()],
+                vec![Complex::default(); fft.len()],
+            ],
+            scratch: vec![Complex::default(); fft.len()],
+            fft,
+        }
+    }
+
+    /// Convolve the collected block of input with the filters of all channels.
+    fn process_block(&mut self) {
+        let bins = HRTF_BLOCK_SIZE + 1;
+        for spectrum in &mut self.spectra {
+            for x in spectrum.iter_mut() {
+                *x = Complex::default();
+            }
+        }
+
+        let [left, right] = &mut self.spectra;
+        for channel in &mut self.channels {
+            channel
+                .history
+                .push(&channel.block, &self.fft, &mut self.scratch);
+            channel.history.accumulate(&channel.left, &mut left[..bins]);
+            channel
+                .history
+                .accumulate(&channel.right, &mut right[..bins]);
+        }
+
+        for (spectrum, output) in self.spectra.iter_mut().zip(&mut self.output) {
+            overlap_save_output(spectrum, &self.fft, output);
         }
     }
 }
@@ -242,28 +319,17 @@ where
             None => {
                 let sample = self.input.next()?;
 
-                let (mut left, mut right) = (0.0, 0.0);
-
-                for (speaker, buffer) in self
-                    .virtual_speakers
-                    .iter()
-                    .zip(self.convolution_buffers.iter_mut())
-                {
-                    let signal = speaker.bweights.dot(sample);
-                    buffer.pop_back();
-                    buffer.push_front(signal);
+                for (channel, &x) in self.channels.iter_mut().zip(sample.components()) {
+                    channel.block[self.position] = x;
+                }
 
-                    left += buffer
-                        .iter()
-                        .zip(&speaker.left_hrir)
-                        .map(|(s, h)| s * h)
-                        .sum::<f32>();
+                let left = self.output[0][self.position];
+                let right = self.output[1][self.position];
 
-                    right += buffer
-                        .iter()
-                        .zip(&speaker.right_hrir)
-                        .map(|(s, h)| s * h)
-                        .sum::<f32>();
+                self.position += 1;
+                if self.position == HRTF_BLOCK_SIZE {
+                    self.process_block();
+                    self.position = 0;
                 }
 
                 // emit left channel now, and right channel next time
@@ -274,10 +340,11 @@ where
     }
 }
 
-struct VirtualSpeaker {
-    bweights: Bweights,
-    left_hrir: Vec<f32>,
-    right_hrir: Vec<f32>,
+/// Decoding weights of a virtual speaker, and the HRIRs from its direction to both ears
+pub(crate) struct VirtualSpeaker {
+    pub bweights: Bweights,
+    pub left_hrir: Vec<f32>,
+    pub right_hrir: Vec<f32>,
 }
 
 #[allow(clippy::excessive_precision)]
@@ -0,0 +1,570 @@
+//! Rotate *B-format* sound fields.
+//!
+//! Rotating the whole sound field is much cheaper than updating the position of every single
+//! source. The rotator sits between a `BstreamMixer` and a renderer.
+
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rodio::Source;
+
+use crate::bformat::{n_channels, Bformat, Bweights, MAX_CHANNELS, MAX_ORDER};
+use crate::linalg::Matrix;
+
+/// A rotation in 3D space, represented as unit quaternion.
+///
+/// Angles follow the library's coordinate system (`x` right, `y` front, `z` up). Yaw turns
+/// around the `z` axis (positive yaw turns from front to the left), pitch around the `x` axis
+/// (positive pitch turns from front upwards), and roll around the `y` axis (positive roll turns
+/// from up to the right).
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Rotation {
+    w: f32,
+    x: f32,
+    y: f32,
+    z: f32,
+}
+
+impl Rotation {
+    /// The rotation that does nothing
+    pub fn identity() -> Self {
+        Rotation {
+            w: 1.0,
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        }
+    }
+
+    /// Construct a rotation from a quaternion `[w, x, y, z]` (does not need to be normalized)
+    pub fn from_quaternion(q: [f32; 4]) -> Self {
+        let l = (q[0] * q[0] + q[1] * q[1] + q[2] * q[2] + q[3] * q[3]).sqrt();
+        Rotation {
+            w: q[0] / l,
+            x: q[1] / l,
+            y: q[2] / l,
+            z: q[3] / l,
+        }
+    }
+
+    /// Construct a rotation by `angle` radians around `axis` (does not need to be normalized)
+    pub fn from_axis_angle(axis: [f32; 3], angle: f32) -> Self {
+        let l = (axis[0] * axis[0] + axis[1] * axis[1] + axis[2] * axis[2]).sqrt();
+        let s = (angle / 2.0).sin() / l;
+        Rotation {
+            w: (angle / 2.0).cos(),
+            x: axis[0] * s,
+            y: axis[1] * s,
+            z: axis[2] * s,
+        }
+    }
+
+    /// Construct a rotation from yaw, pitch and roll angles in radians.
+    ///
+    /// The rotations are applied in order roll, pitch, yaw.
+    pub fn from_euler(yaw: f32, pitch: f32, roll: f32) -> Self {
+        Rotation::from_axis_angle([0.0, 0.0, 1.0], yaw)
+            .after(Rotation::from_axis_angle([1.0, 0.0, 0.0], pitch))
+            .after(Rotation::from_axis_angle([0.0, 1.0, 0.0], roll))
+    }
+
+    /// Quaternion `[w, x, y, z]` of the rotation
+    pub fn quaternion(&self) -> [f32; 4] {
+        [self.w, self.x, self.y, self.z]
+    }
+
+    /// Combine two rotations; `other` is applied first, then `self`.
+    pub fn after(self, other: Rotation) -> Rotation {
+        let (a, b) = (self, other);
+        Rotation {
+            w: a.w * b.w - a.x * b.x - a.y * b.y - a.z * b.z,
+            x: a.w * b.x + a.x * b.w + a.y * b.z - a.z * b.y,
+            y: a.w * b.y - a.x * b.z + a.y * b.w + a.z * b.x,
+            z: a.w * b.z + a.x * b.y - a.y * b.x + a.z * b.w,
+        }
+    }
+
+    /// The rotation that undoes this rotation
+    pub fn inverse(&self) -> Rotation {
+        Rotation {
+            w: self.w,
+            x: -self.x,
+            y: -self.y,
+            z: -self.z,
+        }
+    }
+
+    /// Rotate a vector
+    pub fn rotate(&self, v: [f32; 3]) -> [f32; 3] {
+        // v' = v + 2w (q x v) + 2 q x (q x v)
+        let q = [self.x, self.y, self.z];
+        let t = cross(q, v);
+        let t = [2.0 * t[0], 2.0 * t[1], 2.0 * t[2]];
+        let u = cross(q, t);
+        [
+            v[0] + self.w * t[0] + u[0],
+            v[1] + self.w * t[1] + u[1],
+            v[2] + self.w * t[2] + u[2],
+        ]
+    }
+
+    /// Angle in radians between two rotations
+    pub fn angle_to(&self, other: &Rotation) -> f32 {
+        let d = self.dot(other).abs().min(1.0);
+        2.0 * d.acos()
+    }
+
+    /// Spherical linear interpolation from `self` (`t == 0`) to `other` (`t == 1`)
+    pub fn slerp(&self, other: &Rotation, t: f32) -> Rotation {
+        let mut d = self.dot(other);
+        let mut b = *other;
+        if d < 0.0 {
+            // take the shorter path
+            d = -d;
+            b = Rotation {
+                w: -b.w,
+                x: -b.x,
+                y: -b.y,
+                z: -b.z,
+            };
+        }
+
+        let (fa, fb) = if d > 0.9995 {
+            (1.0 - t, t)
+        } else {
+            let theta = d.acos();
+            let s = theta.sin();
+            (((1.0 - t) * theta).sin() / s, (t * theta).sin() / s)
+        };
+
+        Rotation::from_quaternion([
+            self.w * fa + b.w * fb,
+            self.x * fa + b.x * fb,
+            self.y * fa + b.y * fb,
+            self.z * fa + b.z * fb,
+        ])
+    }
+
+    fn dot(&self, other: &Rotation) -> f32 {
+        self.w * other.w + self.x * other.x + self.y * other.y + self.z * other.z
+    }
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Rotation::identity()
+    }
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+/// Computes *B-format* rotation matrices of a given order.
+///
+/// The matrix `M` of a rotation `R` satisfies `Y(R d) = M Y(d)` for the encoding `Y` of any
+/// direction `d`. It is obtained by least squares from a set of sampling directions.
+pub(crate) struct RotationMatrixBuilder {
+    order: usize,
+    directions: Vec<[f32; 3]>,
+    projection: Matrix,
+}
+
+impl RotationMatrixBuilder {
+    pub fn new(order: usize) -> Self {
+        assert!(order <= MAX_ORDER);
+        let n = n_channels(order);
+        let directions = fibonacci_sphere(2 * MAX_CHANNELS);
+
+        let encoding = encoding_matrix(&directions, order);
+        let gram = &encoding * &encoding.transpose();
+        let projection = &encoding.transpose()
+            * &gram
+                .inverse()
+                .expect("sampling directions do not span the B-format");
+        debug_assert_eq!(projection.cols(), n);
+
+        RotationMatrixBuilder {
+            order,
+            directions,
+            projection,
+        }
+    }
+
+    pub fn matrix(&self, rotation: &Rotation) -> [[f32; MAX_CHANNELS]; MAX_CHANNELS] {
+        let rotated: Vec<_> = self
+            .directions
+            .iter()
+            .map(|&d| rotation.rotate(d))
+            .collect();
+        let m = &encoding_matrix(&rotated, self.order) * &self.projection;
+
+        let mut out = [[0.0; MAX_CHANNELS]; MAX_CHANNELS];
+        for (i, row) in out.iter_mut().enumerate().take(m.rows()) {
+            for (j, x) in row.iter_mut().enumerate().take(m.cols()) {
+                *x = m[(i, j)] as f32;
+            }
+        }
+        out
+    }
+}
+
+/// Matrix of B-format encoding weights; one column per direction
+pub(crate) fn encoding_matrix(directions: &[[f32; 3]], order: usize) -> Matrix {
+    let weights: Vec<_> = directions
+        .iter()
+        .map(|&d| Bweights::from_direction(d, order))
+        .collect();
+    Matrix::from_fn(n_channels(order), directions.len(), |i, j| {
+        weights[j].components()[i] as f64
+    })
+}
+
+/// Approximately uniformly distributed points on the unit sphere
+pub(crate) fn fibonacci_sphere(n: usize) -> Vec<[f32; 3]> {
+    let golden_angle = PI * (3.0 - 5f32.sqrt());
+    (0..n)
+        .map(|i| {
+            let z = 1.0 - (2 * i + 1) as f32 / n as f32;
+            let r = (1.0 - z * z).sqrt();
+            let phi = golden_angle * i as f32;
+            [r * phi.cos(), r * phi.sin(), z]
+        })
+        .collect()
+}
+
+/// Construct a sound field rotator and associated controller.
+///
+/// The rotator processes *B-format* streams of the given `order`.
+pub fn rotator<I>(input: I, order: usize) -> (BstreamRotator<I>, RotationController)
+where
+    I: Source<Item = Bformat>,
+{
+    let bridge = Arc::new(RotatorBridge {
+        commands: Mutex::new(Vec::new()),
+        pending_commands: AtomicBool::new(false),
+    });
+
+    let builder = RotationMatrixBuilder::new(order);
+    let matrix = builder.matrix(&Rotation::identity());
+
+    let smoothing = DEFAULT_SMOOTHING.as_secs_f32();
+    let rotator = BstreamRotator {
+        input,
+        bridge: bridge.clone(),
+        n: n_channels(order),
+        builder,
+        current: Rotation::identity(),
+        target: Rotation::identity(),
+        smoothing,
+        matrix,
+        step: [[0.0; MAX_CHANNELS]; MAX_CHANNELS],
+        block_position: 0,
+        is_static: true,
+    };
+
+    let controller = RotationController { bridge };
+
+    (rotator, controller)
+}
+
+/// Number of samples between updates of the rotation matrix
+const BLOCK_SIZE: usize = 64;
+
+/// Default time constant for approaching a new rotation
+const DEFAULT_SMOOTHING: Duration = Duration::from_millis(20);
+
+/// Rotate a *B-format* stream
+///
+/// Rotation changes are applied smoothly by interpolating the rotation matrix.
+pub struct BstreamRotator<I> {
+    input: I,
+    bridge: Arc<RotatorBridge>,
+    n: usize,
+    builder: RotationMatrixBuilder,
+
+    current: Rotation,
+    target: Rotation,
+    smoothing: f32,
+
+    matrix: [[f32; MAX_CHANNELS]; MAX_CHANNELS],
+    step: [[f32; MAX_CHANNELS]; MAX_CHANNELS],
+    block_position: usize,
+    is_static: bool,
+}
+
+impl<I> BstreamRotator<I>
+where
+    I: Source<Item = Bformat>,
+{
+    fn process_commands(&mut self) {
+        let mut commands = self.bridge.commands.lock().unwrap();
+
+        for cmd in commands.drain(..) {
+            match cmd {
+                RotatorCommand::Jump(r) => {
+                    self.current = r;
+                    self.target = r;
+                    self.matrix = self.builder.matrix(&r);
+                    self.step = [[0.0; MAX_CHANNELS]; MAX_CHANNELS];
+                }
+                RotatorCommand::Approach(r) => self.target = r,
+                RotatorCommand::Smoothing(s) => self.smoothing = s,
+            }
+        }
+        self.is_static = false;
+
+        self.bridge.pending_commands.store(false, Ordering::SeqCst);
+    }
+
+    /// Move current rotation towards the target and prepare interpolation of the matrix.
+    fn update_block(&mut self) {
+        if self.current == self.target {
+            // the last block arrived at the current rotation
+            self.step = [[0.0; MAX_CHANNELS]; MAX_CHANNELS];
+            self.matrix = self.builder.matrix(&self.current);
+            self.is_static = true;
+            return;
+        }
+
+        let dt = BLOCK_SIZE as f32 / self.input.sample_rate() as f32;
+        let alpha = if self.smoothing > 0.0 {
+            1.0 - (-dt / self.smoothing).exp()
+        } else {
+            1.0
+        };
+
+        self.current = if self.current.angle_to(&self.target) < ANGLE_EPS {
+            self.target
+        } else {
+            self.current.slerp(&self.target, alpha)
+        };
+
+        let next = self.builder.matrix(&self.current);
+        for ((step, next), current) in self.step.iter_mut().zip(&next).zip(&self.matrix) {
+            for ((s, n), c) in step.iter_mut().zip(next).zip(current) {
+                *s = (n - c) / BLOCK_SIZE as f32;
+            }
+        }
+    }
+}
+
+const ANGLE_EPS: f32 = 1e-4;
+
+impl<I> Source for BstreamRotator<I>
+where
+    I: Source<Item = Bformat>,
+{
+    #[inline(always)]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    #[inline(always)]
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    #[inline(always)]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline(always)]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+impl<I> Iterator for BstreamRotator<I>
+where
+    I: Source<Item = Bformat>,
+{
+    type Item = Bformat;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bridge.pending_commands.load(Ordering::SeqCst) {
+            self.process_commands();
+        }
+
+        let sample = self.input.next()?;
+
+        if !self.is_static {
+            if self.block_position == 0 {
+                self.update_block();
+            }
+            self.block_position = (self.block_position + 1) % BLOCK_SIZE;
+        }
+
+        let n = self.n;
+        let x = sample.components();
+        let mut y = [0.0; MAX_CHANNELS];
+        for ((y, row), step) in y[..n].iter_mut().zip(&mut self.matrix).zip(&self.step) {
+            *y = row[..n].iter().zip(&x[..n]).map(|(m, x)| m * x).sum();
+            if !self.is_static {
+                for (m, s) in row[..n].iter_mut().zip(&step[..n]) {
+                    *m += s;
+                }
+            }
+        }
+        Some(Bformat::from_components(y).with_lfe(sample.lfe()))
+    }
+}
+
+#[derive(Debug)]
+enum RotatorCommand {
+    Jump(Rotation),
+    Approach(Rotation),
+    Smoothing(f32),
+}
+
+/// Bridges a rotator and its controller across threads
+struct RotatorBridge {
+    commands: Mutex<Vec<RotatorCommand>>,
+    pending_commands: AtomicBool,
+}
+
+/// Controls the rotation of a `BstreamRotator`
+///
+/// The controller can be cloned and sent to other threads.
+#[derive(Clone)]
+pub struct RotationController {
+    bridge: Arc<RotatorBridge>,
+}
+
+impl RotationController {
+    /// Set rotation of the sound field
+    ///
+    /// Abruptly changing the rotation may cause popping artifacts. Use `adjust_rotation` to
+    /// dynamically change the rotation during playback.
+    pub fn set_rotation(&self, rotation: Rotation) {
+        self.send_command(RotatorCommand::Jump(rotation));
+    }
+
+    /// Adjust rotation of the sound field
+    ///
+    /// The sound field transitions smoothly to the new rotation.
+    pub fn adjust_rotation(&self, rotation: Rotation) {
+        self.send_command(RotatorCommand::Approach(rotation));
+    }
+
+    /// Set the time constant for transitions with `adjust_rotation` (defaults to 20 ms)
+    pub fn set_smoothing(&self, time: Duration) {
+        self.send_command(RotatorCommand::Smoothing(time.as_secs_f32()));
+    }
+
+    fn send_command(&self, cmd: RotatorCommand) {
+        self.bridge.commands.lock().unwrap().push(cmd);
+        self.bridge.pending_commands.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bformat::Bweights;
+    use crate::bstream::{bstream, BstreamConfig};
+    use crate::sources::Constant;
+
+    fn assert_vec_close(a: [f32; 3], b: [f32; 3]) {
+        for (x, y) in a.iter().zip(&b) {
+            assert!((x - y).abs() < 1e-5, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn composed_rotations_apply_the_argument_first() {
+        let yaw = Rotation::from_axis_angle([0.0, 0.0, 1.0], PI / 2.0);
+        let pitch = Rotation::from_axis_angle([1.0, 0.0, 0.0], PI / 2.0);
+        // pitching leaves the right unchanged, and turning left moves it to the front
+        let v = yaw.after(pitch).rotate([1.0, 0.0, 0.0]);
+        assert_vec_close(v, [0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn yaw_turns_front_to_left() {
+        let r = Rotation::from_euler(PI / 2.0, 0.0, 0.0);
+        assert_vec_close(r.rotate([0.0, 1.0, 0.0]), [-1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn pitch_turns_front_upwards() {
+        let r = Rotation::from_euler(0.0, PI / 2.0, 0.0);
+        assert_vec_close(r.rotate([0.0, 1.0, 0.0]), [0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn roll_turns_up_to_the_right() {
+        let r = Rotation::from_euler(0.0, 0.0, PI / 2.0);
+        assert_vec_close(r.rotate([0.0, 0.0, 1.0]), [1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn inverse_undoes_rotation() {
+        let r = Rotation::from_euler(0.3, -1.2, 2.0);
+        let v = [0.3, 0.4, -0.5];
+        assert_vec_close(r.inverse().rotate(r.rotate(v)), v);
+    }
+
+    #[test]
+    fn rotation_matrix_moves_encoded_sources() {
+        let r = Rotation::from_euler(0.3, -1.2, 2.0);
+        let builder = RotationMatrixBuilder::new(MAX_ORDER);
+        let m = builder.matrix(&r);
+
+        let dir = [0.2, -0.7, 0.4];
+        let before = Bweights::from_direction(dir, MAX_ORDER);
+        let after = Bweights::from_direction(r.rotate(dir), MAX_ORDER);
+
+        for (row, expected) in m.iter().zip(after.components()) {
+            let y: f32 = row
+                .iter()
+                .zip(before.components())
+                .map(|(m, b)| m * b)
+                .sum();
+            assert!((y - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn rotator_applies_rotation_to_stream() {
+        let (stream, _) = bstream(
+            Constant::new(1.0, 48000),
+            BstreamConfig::new()
+                .with_order(2)
+                .with_position([0.0, 1.0, 0.0]),
+        );
+        let (mut rotator, controller) = rotator(stream, 2);
+
+        controller.set_rotation(Rotation::from_euler(-PI / 2.0, 0.0, 0.0));
+        let right = Bweights::from_direction([1.0, 0.0, 0.0], 2);
+        let y = right.dot(rotator.next().unwrap());
+        assert!((y - right.dot(right.scale(1.0))).abs() < 1e-4);
+    }
+
+    #[test]
+    fn rotator_approaches_target_smoothly() {
+        let (stream, _) = bstream(
+            Constant::new(1.0, 48000),
+            BstreamConfig::new().with_position([0.0, 1.0, 0.0]),
+        );
+        let (mut rotator, controller) = rotator(stream, 1);
+
+        controller.adjust_rotation(Rotation::from_euler(PI / 2.0, 0.0, 0.0));
+        let left = Bweights::new(0.0, -1.0, 0.0, 0.0);
+
+        let mut previous = left.dot(rotator.next().unwrap());
+        for _ in 0..4800 {
+            let y = left.dot(rotator.next().unwrap());
+            assert!((y - previous).abs() < 0.01);
+            previous = y;
+        }
+        assert!((previous - 1.0).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,219 @@
+//! Mapping of renderer outputs to device channels.
+
+use std::time::Duration;
+
+use rodio::Source;
+
+/// Assignment of renderer outputs to the channels of the audio device
+///
+/// Renderers produce their outputs in a fixed order, such as left and right for stereo, or the
+/// order of the speaker layout. Each device channel plays at most one renderer output, but an
+/// output may feed several device channels. Device channels without an output stay silent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelMap {
+    feeds: Vec<Option<usize>>,
+}
+
+impl ChannelMap {
+    /// Create a map for a device with `device_channels` channels, all of which are unused.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `device_channels` is zero.
+    pub fn new(device_channels: usize) -> Self {
+        assert!(device_channels > 0);
+        ChannelMap {
+            feeds: vec![None; device_channels],
+        }
+    }
+
+    /// Create a map that sends renderer output `i` to device channel `channels[i]`.
+    ///
+    /// The device has as many channels as needed to hold the highest channel index, and at least
+    /// one.
+    pub fn from_device_channels(channels: &[usize]) -> Self {
+        let n = channels.iter().max().map_or(1, |&c| c + 1);
+        channels
+            .iter()
+            .enumerate()
+            .fold(ChannelMap::new(n), |map, (output, &channel)| {
+                map.with_route(output, channel)
+            })
+    }
+
+    /// Send renderer output `output` to device channel `channel`.
+    ///
+    /// Replaces the channel's previous feed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `channel` is not a channel of the device.
+    pub fn with_route(mut self, output: usize, channel: usize) -> Self {
+        assert!(channel < self.feeds.len());
+        self.feeds[channel] = Some(output);
+        self
+    }
+
+    /// Extend the map to a device with `device_channels` channels; the added channels are unused.
+    pub(crate) fn with_device_channels(mut self, device_channels: usize) -> Self {
+        assert!(device_channels >= self.feeds.len());
+        self.feeds.resize(device_channels, None);
+        self
+    }
+
+    /// Number of device channels
+    pub fn device_channels(&self) -> usize {
+        self.feeds.len()
+    }
+
+    /// The renderer output that feeds each device channel
+    pub fn feeds(&self) -> &[Option<usize>] {
+        &self.feeds
+    }
+
+    /// Check that the map only uses outputs of a renderer with `n_outputs` outputs.
+    pub(crate) fn check_outputs(&self, n_outputs: usize) -> Result<(), String> {
+        match self
+            .feeds
+            .iter()
+            .flatten()
+            .find(|&&output| output >= n_outputs)
+        {
+            Some(output) => Err(format!(
+                "channel map uses output {}, but the renderer has only {} outputs",
+                output, n_outputs
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Rearrange the interleaved channels of a renderer according to a `ChannelMap`.
+pub struct ChannelRouter<I> {
+    input: I,
+    feeds: Vec<Option<usize>>,
+    input_frame: Vec<f32>,
+    next_channel: usize,
+}
+
+impl<I> ChannelRouter<I>
+where
+    I: Source<Item = f32>,
+{
+    /// Construct a new router
+    ///
+    /// # Panics
+    ///
+    /// Panics if the map refers to an output that the input does not have.
+    pub fn new(input: I, map: ChannelMap) -> Self {
+        let n_inputs = input.channels() as usize;
+        if let Err(e) = map.check_outputs(n_inputs) {
+            panic!("{}", e);
+        }
+        ChannelRouter {
+            input,
+            next_channel: map.feeds.len(),
+            feeds: map.feeds,
+            input_frame: vec![0.0; n_inputs],
+        }
+    }
+}
+
+impl<I> Source for ChannelRouter<I>
+where
+    I: Source<Item = f32>,
+{
+    #[inline(always)]
+    fn current_frame_len(&self) -> Option<usize> {
+        // the rest of the buffered input frame, and the remaining input frames
+        let buffered = self.feeds.len() - self.next_channel;
+        let frames = self
+            .input
+            .current_frame_len()?
+            .checked_div(self.input_frame.len())?;
+        Some(buffered + frames * self.feeds.len())
+    }
+
+    #[inline(always)]
+    fn channels(&self) -> u16 {
+        self.feeds.len() as u16
+    }
+
+    #[inline(always)]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline(always)]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+impl<I> Iterator for ChannelRouter<I>
+where
+    I: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_channel >= self.feeds.len() {
+            for x in &mut self.input_frame {
+                *x = self.input.next()?;
+            }
+            self.next_channel = 0;
+        }
+
+        let x = match self.feeds[self.next_channel] {
+            Some(output) => self.input_frame[output],
+            None => 0.0,
+        };
+        self.next_channel += 1;
+        Some(x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    #[test]
+    fn routes_outputs_to_device_channels() {
+        let input = SamplesBuffer::new(2, 1000, vec![1.0, 2.0, 3.0, 4.0]);
+        let map = ChannelMap::new(4)
+            .with_route(1, 0)
+            .with_route(0, 2)
+            .with_route(1, 3);
+        let router = ChannelRouter::new(input, map);
+        assert_eq!(router.channels(), 4);
+
+        let out: Vec<_> = router.collect();
+        assert_eq!(out, vec![2.0, 0.0, 1.0, 2.0, 4.0, 0.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn frame_length_counts_device_channels() {
+        let input = SamplesBuffer::new(2, 1000, vec![1.0, 2.0, 3.0, 4.0]).buffered();
+        let mut router = ChannelRouter::new(input, ChannelMap::new(4).with_route(0, 1));
+        assert_eq!(router.current_frame_len(), Some(8));
+        router.next();
+        assert_eq!(router.current_frame_len(), Some(7));
+    }
+
+    #[test]
+    fn device_channels_from_output_order() {
+        let map = ChannelMap::from_device_channels(&[2, 0]);
+        assert_eq!(map.feeds(), &[Some(1), None, Some(0)]);
+
+        let map = ChannelMap::from_device_channels(&[]);
+        assert_eq!(map.feeds(), &[None]);
+    }
+
+    #[test]
+    fn reject_missing_outputs() {
+        let map = ChannelMap::from_device_channels(&[0, 1, 2]);
+        assert!(map.check_outputs(3).is_ok());
+        assert!(map.check_outputs(2).is_err());
+    }
+}
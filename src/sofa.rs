@@ -0,0 +1,446 @@
+//! Loading HRTF sets from SOFA files.
+//!
+//! SOFA (AES69) stores measured head-related impulse responses in netCDF-4 files, which are a
+//! subset of HDF5. Files following the *SimpleFreeFieldHRIR* convention hold one pair of impulse
+//! responses per measured source position. The HRIRs of a virtual speaker are interpolated from
+//! the measurements at the corners of the grid triangle that contains the speaker's direction,
+//! after aligning their onsets.
+
+use std::fs;
+
+use crate::error::Error;
+use crate::hdf5;
+use crate::renderer::{HrtfConfig, VirtualSpeaker};
+use crate::speakers::{SpeakerConfig, SpeakerLayout};
+use crate::vbap::Vbap;
+
+impl HrtfConfig {
+    /// Load HRIRs from a SOFA file for a first-order decoder with eight virtual speakers.
+    ///
+    /// The virtual speakers are placed at the corners of a cube. See `from_sofa_with_speakers`.
+    pub fn from_sofa(filename: &str) -> Result<Self, Error> {
+        HrtfConfig::from_sofa_with_speakers(filename, SpeakerConfig::new(SpeakerLayout::cube()))
+    }
+
+    /// Load HRIRs from a SOFA file for the given virtual speakers.
+    ///
+    /// The file must follow the *SimpleFreeFieldHRIR* convention. Each virtual speaker gets the
+    /// measurement that matches its direction, or an interpolation of the measurements that
+    /// surround it, weighted like the speakers of a VBAP triangle. The impulse responses,
+    /// including their measurement delays, are shifted to a common onset before they are blended,
+    /// so the interaural time difference is interpolated instead of smearing the responses. The
+    /// sample rate is taken from the file.
+    ///
+    /// Only the decoder of the speaker configuration is used; its speaker distances, dual-band
+    /// decoding, and bass management are ignored.
+    pub fn from_sofa_with_speakers(filename: &str, speakers: SpeakerConfig) -> Result<Self, Error> {
+        let data = fs::read(filename)?;
+        read_sofa(data, &speakers)
+            .map_err(|e| Error::InvalidData(format!("invalid SOFA file: {}", e)))?
+            .validated()
+    }
+}
+
+/// Measurements closer than this angle (in radians) to a speaker are used as they are
+const EXACT_MATCH: f32 = 1e-3;
+
+/// Number of measurements around a speaker that are triangulated to find the surrounding
+/// triangle
+const NEIGHBOURHOOD: usize = 16;
+
+/// The onset of an impulse response is the first sample that reaches this fraction of its peak
+/// (-20dB)
+const ONSET_THRESHOLD: f32 = 0.1;
+
+/// Longest accepted measurement delay in samples, far longer than any delay between a sound
+/// source and the ears
+const MAX_DELAY: f64 = 65536.0;
+
+/// Measured impulse responses of a *SimpleFreeFieldHRIR* data set
+struct Measurements {
+    sample_rate: f64,
+    /// source directions in library coordinates
+    directions: Vec<[f32; 3]>,
+    /// impulse responses, indexed by measurement and ear
+    hrirs: Vec<[Vec<f32>; 2]>,
+    /// delays of the impulse responses in samples, indexed by measurement and ear
+    delays: Vec<[usize; 2]>,
+}
+
+fn read_sofa(data: Vec<u8>, speakers: &SpeakerConfig) -> Result<HrtfConfig, String> {
+    let measurements = Measurements::read(data)?;
+
+    let decoder = speakers.decoder();
+    let virtual_speakers = speakers
+        .layout()
+        .directions()
+        .iter()
+        .zip(decoder)
+        .map(|(&direction, bweights)| {
+            let [left_hrir, right_hrir] = measurements.interpolate(direction);
+            VirtualSpeaker {
+                bweights,
+                left_hrir,
+                right_hrir,
+            }
+        })
+        .collect();
+
+    Ok(HrtfConfig {
+        sample_rate: measurements.sample_rate.round() as u32,
+        virtual_speakers,
+    })
+}
+
+/// Add `weight * x`, shifted by `offset` samples, to `y`, extending `y` if necessary.
+///
+/// Samples shifted to before the start of `y` are dropped.
+fn accumulate(y: &mut Vec<f32>, x: &[f32], offset: isize, weight: f32) {
+    let skip = (-offset).max(0) as usize;
+    let start = offset.max(0) as usize;
+    let x = &x[skip.min(x.len())..];
+    if y.len() < start + x.len() {
+        y.resize(start + x.len(), 0.0);
+    }
+    for (yi, xi) in y[start..].iter_mut().zip(x) {
+        *yi += weight * xi;
+    }
+}
+
+/// Index of the first sample that reaches `ONSET_THRESHOLD` of the peak magnitude
+fn onset(x: &[f32]) -> usize {
+    let peak = x.iter().fold(0.0f32, |peak, xi| peak.max(xi.abs()));
+    x.iter()
+        .position(|xi| xi.abs() >= ONSET_THRESHOLD * peak && peak > 0.0)
+        .unwrap_or(0)
+}
+
+impl Measurements {
+    fn read(data: Vec<u8>) -> Result<Self, String> {
+        let file = hdf5::File::from_bytes(data)?;
+        let members = file.members(file.root())?;
+        let variable = |name: &str| match members.iter().find(|(n, _)| n == name) {
+            Some(&(_, address)) => file.dataset(address).map(Some),
+            None => Ok(None),
+        };
+        let required =
+            |name: &str| variable(name)?.ok_or_else(|| format!("missing variable '{}'", name));
+
+        let ir = required("Data.IR")?;
+        let (n_measurements, length) = match *ir.shape() {
+            [m, 2, n] if m > 0 => (m as usize, n as usize),
+            _ => return Err("Data.IR must have the dimensions M x 2 x N".to_string()),
+        };
+        let ir = file.read(&ir)?;
+
+        let sample_rate = *file
+            .read(&required("Data.SamplingRate")?)?
+            .first()
+            .ok_or("empty sampling rate")?;
+        if !(sample_rate >= 1.0 && sample_rate <= f64::from(u32::MAX)) {
+            return Err(format!("invalid sampling rate {}", sample_rate));
+        }
+
+        let positions = required("SourcePosition")?;
+        // positions are spherical unless the type says otherwise
+        let spherical = match positions.string_attribute("Type")?.as_deref() {
+            None | Some("spherical") => true,
+            Some("cartesian") => false,
+            Some(t) => return Err(format!("unknown source position type '{}'", t)),
+        };
+        let rows = measurement_rows(positions.shape(), 3, n_measurements, "SourcePosition")?;
+        let positions = file.read(&positions)?;
+        let directions = (0..n_measurements)
+            .map(|m| {
+                let i = if rows == 1 { 0 } else { 3 * m };
+                let p = [positions[i], positions[i + 1], positions[i + 2]];
+                sofa_direction(p, spherical)
+            })
+            .collect::<Result<_, _>>()?;
+
+        // delays are given in samples, per measurement or for all measurements
+        let delays = match variable("Data.Delay")? {
+            Some(delay) => {
+                let rows = measurement_rows(delay.shape(), 2, n_measurements, "Data.Delay")?;
+                let delay = file.read(&delay)?;
+                if let Some(d) = delay.iter().find(|&&d| !(0.0..=MAX_DELAY).contains(&d)) {
+                    return Err(format!("invalid delay {}", d));
+                }
+                (0..n_measurements)
+                    .map(|m| {
+                        let i = if rows == 1 { 0 } else { 2 * m };
+                        [delay[i].round() as usize, delay[i + 1].round() as usize]
+                    })
+                    .collect()
+            }
+            None => vec![[0; 2]; n_measurements],
+        };
+
+        let hrirs = (0..n_measurements)
+            .map(|m| {
+                let hrir = |ear: usize| {
+                    let start = (2 * m + ear) * length;
+                    ir[start..start + length]
+                        .iter()
+                        .map(|&x| x as f32)
+                        .collect()
+                };
+                [hrir(0), hrir(1)]
+            })
+            .collect();
+
+        Ok(Measurements {
+            sample_rate,
+            directions,
+            hrirs,
+            delays,
+        })
+    }
+
+    /// Interpolated impulse responses of both ears for a given direction
+    fn interpolate(&self, direction: [f32; 3]) -> [Vec<f32>; 2] {
+        let weights = self.weights(direction);
+        let mut hrirs = [vec![], vec![]];
+        for (ear, hrir) in hrirs.iter_mut().enumerate() {
+            let onsets: Vec<_> = weights
+                .iter()
+                .map(|&(m, _)| self.delays[m][ear] + onset(&self.hrirs[m][ear]))
+                .collect();
+            let target = weights
+                .iter()
+                .zip(&onsets)
+                .map(|(&(_, w), &onset)| w * onset as f32)
+                .sum::<f32>()
+                .round() as isize;
+            for (&(m, weight), &onset) in weights.iter().zip(&onsets) {
+                let offset = target - onset as isize + self.delays[m][ear] as isize;
+                accumulate(hrir, &self.hrirs[m][ear], offset, weight);
+            }
+        }
+        hrirs
+    }
+
+    /// Measurements to interpolate for a given direction, and their weights, which sum to one.
+    ///
+    /// The measurements closest to the direction are triangulated, and the corners of the
+    /// triangle that contains the direction are weighted like VBAP gains.
+    fn weights(&self, direction: [f32; 3]) -> Vec<(usize, f32)> {
+        let mut nearest: Vec<_> = self
+            .directions
+            .iter()
+            .map(|d| {
+                let cos = d[0] * direction[0] + d[1] * direction[1] + d[2] * direction[2];
+                cos.clamp(-1.0, 1.0).acos()
+            })
+            .enumerate()
+            .collect();
+        nearest.sort_by(|a, b| a.1.total_cmp(&b.1));
+        nearest.truncate(NEIGHBOURHOOD);
+
+        if nearest[0].1 < EXACT_MATCH {
+            return vec![(nearest[0].0, 1.0)];
+        }
+
+        let directions: Vec<_> = nearest.iter().map(|&(m, _)| self.directions[m]).collect();
+        let corners = Vbap::new(&directions)
+            .triangle_gains(direction)
+            .map(|(speakers, gains)| {
+                speakers
+                    .iter()
+                    .zip(&gains)
+                    .filter(|&(&s, &g)| s < nearest.len() && g > 0.0)
+                    .map(|(&s, &g)| (nearest[s].0, g))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        // directions outside the measured grid use the closest measurement
+        let total: f32 = corners.iter().map(|&(_, g)| g).sum();
+        if total <= 0.0 {
+            return vec![(nearest[0].0, 1.0)];
+        }
+        corners.into_iter().map(|(m, g)| (m, g / total)).collect()
+    }
+}
+
+/// Check that a variable has one row per measurement, or a single row for all measurements.
+fn measurement_rows(
+    shape: &[u64],
+    columns: u64,
+    n_measurements: usize,
+    name: &str,
+) -> Result<usize, String> {
+    match *shape {
+        [rows, c] if c == columns && (rows == 1 || rows == n_measurements as u64) => {
+            Ok(rows as usize)
+        }
+        _ => Err(format!(
+            "{} must have the dimensions M x {} or 1 x {}",
+            name, columns, columns
+        )),
+    }
+}
+
+/// Convert a SOFA position to a direction in library coordinates.
+///
+/// SOFA coordinates have x pointing to the front and y to the left; spherical positions are
+/// given as azimuth (counter-clockwise from the front) and elevation in degrees.
+fn sofa_direction(p: [f64; 3], spherical: bool) -> Result<[f32; 3], String> {
+    let [x, y, z] = if spherical {
+        let (azimuth, elevation) = (p[0].to_radians(), p[1].to_radians());
+        [
+            azimuth.cos() * elevation.cos(),
+            azimuth.sin() * elevation.cos(),
+            elevation.sin(),
+        ]
+    } else {
+        p
+    };
+    let norm = (x * x + y * y + z * z).sqrt();
+    if !norm.is_finite() {
+        return Err("invalid source position".to_string());
+    }
+    if norm < 1e-9 {
+        return Err("source position at the listener".to_string());
+    }
+    Ok([(-y / norm) as f32, (x / norm) as f32, (z / norm) as f32])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_fixture(data: &[u8], layout: SpeakerLayout) -> HrtfConfig {
+        read_sofa(data.to_vec(), &SpeakerConfig::new(layout)).unwrap()
+    }
+
+    /// The fixtures contain six measurements from the front, left, back, right, top, and bottom,
+    /// in that order. The impulse responses of measurement `m` start with `10 * (m + 1)` in the
+    /// left ear and `10 * (m + 1) + 1` in the right ear, after an optional delay.
+    fn first_taps(config: &HrtfConfig) -> Vec<[f32; 2]> {
+        let first = |h: &[f32]| h.iter().cloned().find(|&x| x != 0.0).unwrap();
+        config
+            .virtual_speakers
+            .iter()
+            .map(|s| [first(&s.left_hrir), first(&s.right_hrir)])
+            .collect()
+    }
+
+    #[test]
+    fn pick_measurements_in_speaker_directions() {
+        let layout = SpeakerLayout::new(vec![
+            [0.0, 1.0, 0.0],
+            [-1.0, 0.0, 0.0],
+            [0.0, -1.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0],
+        ]);
+        let expected = vec![
+            [10.0, 11.0],
+            [20.0, 21.0],
+            [30.0, 31.0],
+            [40.0, 41.0],
+            [50.0, 51.0],
+        ];
+
+        let config = read_fixture(
+            include_bytes!("../tests/data/symbol_table.sofa"),
+            layout.clone(),
+        );
+        assert_eq!(config.sample_rate, 48000);
+        assert_eq!(first_taps(&config), expected);
+
+        let config = read_fixture(include_bytes!("../tests/data/dense_links.sofa"), layout);
+        assert_eq!(config.sample_rate, 44100);
+        assert_eq!(first_taps(&config), expected);
+    }
+
+    #[test]
+    #[ignore = "needs tests/data/netcdf4.sofa from tools/make_netcdf_sofa_fixture.py"]
+    fn read_file_written_by_libhdf5() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/netcdf4.sofa");
+        let data = fs::read(path).unwrap();
+        let layout = SpeakerLayout::new(vec![[0.0, 1.0, 0.0], [0.0, 0.0, -1.0]]);
+        let config = read_fixture(&data, layout);
+        assert_eq!(config.sample_rate, 48000);
+        assert_eq!(first_taps(&config), vec![[10.0, 11.0], [60.0, 61.0]]);
+        let speaker = &config.virtual_speakers[0];
+        assert_eq!(speaker.right_hrir[..3], [0.0, 0.0, 11.0]);
+    }
+
+    #[test]
+    fn interpolate_between_measurements() {
+        let config = read_fixture(
+            include_bytes!("../tests/data/symbol_table.sofa"),
+            SpeakerLayout::cube(),
+        );
+        // the upper front left corner is equally far from the front, left, and top measurements
+        let [left, right] = first_taps(&config)[0];
+        assert!((left - 80.0 / 3.0).abs() < 1e-3, "{}", left);
+        assert!((right - 83.0 / 3.0).abs() < 1e-3, "{}", right);
+    }
+
+    #[test]
+    fn apply_measurement_delays() {
+        // the first fixture delays the right ear by two samples
+        let config = read_fixture(
+            include_bytes!("../tests/data/symbol_table.sofa"),
+            SpeakerLayout::new(vec![[0.0, 1.0, 0.0]]),
+        );
+        let speaker = &config.virtual_speakers[0];
+        assert_eq!(speaker.right_hrir.len(), speaker.left_hrir.len() + 2);
+        assert_eq!(speaker.right_hrir[..3], [0.0, 0.0, 11.0]);
+    }
+
+    #[test]
+    fn align_onsets_before_blending() {
+        let s = std::f32::consts::FRAC_1_SQRT_2;
+        let mut impulse = vec![0.0; 8];
+        impulse[2] = 1.0;
+        // the same impulse measured from the front, left, and top with different delays
+        let measurements = Measurements {
+            sample_rate: 48000.0,
+            directions: vec![[0.0, 1.0, 0.0], [-1.0, 0.0, 0.0], [0.0, 0.0, 1.0]],
+            hrirs: vec![[impulse.clone(), impulse.clone()]; 3],
+            delays: vec![[0, 3], [6, 3], [3, 3]],
+        };
+
+        let [left, right] = measurements.interpolate([-s, s, 0.0]);
+        let peak = |h: &[f32]| {
+            let i = h.iter().position(|&x| x != 0.0).unwrap();
+            (i, h[i], h.iter().filter(|&&x| x != 0.0).count())
+        };
+        // halfway between front and left, the onset is halfway between theirs
+        assert_eq!(peak(&left), (5, 1.0, 1));
+        assert_eq!(peak(&right), (5, 1.0, 1));
+
+        // the upper front left corner blends all three measurements
+        let c = 3f32.sqrt().recip();
+        let [left, _] = measurements.interpolate([-c, c, c]);
+        let (i, x, n) = peak(&left);
+        assert_eq!((i, n), (5, 1));
+        assert!((x - 1.0).abs() < 1e-6, "{}", x);
+    }
+
+    #[test]
+    fn reject_corrupt_files() {
+        let speakers = SpeakerConfig::new(SpeakerLayout::cube());
+        let fixtures: [&[u8]; 2] = [
+            include_bytes!("../tests/data/symbol_table.sofa"),
+            include_bytes!("../tests/data/dense_links.sofa"),
+        ];
+        for fixture in &fixtures {
+            // loading must fail or succeed, but never panic, hang, or exhaust memory
+            for len in 0..fixture.len() {
+                let _ = read_sofa(fixture[..len].to_vec(), &speakers);
+            }
+            for i in 0..fixture.len() {
+                for &mask in &[0x01, 0x10, 0x80, 0xff] {
+                    let mut data = fixture.to_vec();
+                    data[i] ^= mask;
+                    let _ = read_sofa(data, &speakers);
+                }
+            }
+        }
+    }
+}
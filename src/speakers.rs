@@ -0,0 +1,825 @@
+//! Playback over arbitrary loudspeaker layouts.
+
+use std::f32::consts::PI;
+use std::time::Duration;
+
+use rodio::Source;
+
+use crate::bass::{BassManagement, BassManager};
+use crate::bformat::{n_channels, Bformat, Bweights, MAX_CHANNELS, MAX_ORDER};
+use crate::compensation::DistanceCompensation;
+use crate::dualband::DualBand;
+use crate::linalg::Matrix;
+use crate::rotation::encoding_matrix;
+use crate::vbap::Vbap;
+
+/// Arrangement of loudspeakers around the listener
+///
+/// Speakers are given by their direction from the listener, in the library's coordinate system
+/// (`x` right, `y` front, `z` up). Ideally, all speakers are at the same distance from the
+/// listener. Otherwise, their distances should be specified, so that renderers can compensate.
+/// The order of the speakers determines the order of the output channels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpeakerLayout {
+    directions: Vec<[f32; 3]>,
+    distances: Option<Vec<f32>>,
+}
+
+impl SpeakerLayout {
+    /// Custom layout from speaker directions, which do not need to be normalized.
+    pub fn new(directions: Vec<[f32; 3]>) -> Self {
+        assert!(!directions.is_empty());
+        let directions = directions
+            .into_iter()
+            .map(|d| {
+                let l = (d[0] * d[0] + d[1] * d[1] + d[2] * d[2]).sqrt();
+                assert!(l > 0.0);
+                [d[0] / l, d[1] / l, d[2] / l]
+            })
+            .collect();
+        SpeakerLayout {
+            directions,
+            distances: None,
+        }
+    }
+
+    /// Custom layout from physical speaker positions relative to the listener (in meters).
+    ///
+    /// Both direction and distance of each speaker are taken from its position.
+    pub fn from_positions(positions: Vec<[f32; 3]>) -> Self {
+        let distances = positions
+            .iter()
+            .map(|p| (p[0] * p[0] + p[1] * p[1] + p[2] * p[2]).sqrt())
+            .collect();
+        SpeakerLayout::new(positions).with_distances(distances)
+    }
+
+    /// Set the distance of each speaker from the listener (in meters).
+    ///
+    /// Renderers delay and attenuate closer speakers, so that sound from all speakers arrives
+    /// aligned at the listener.
+    pub fn with_distances(mut self, distances: Vec<f32>) -> Self {
+        assert_eq!(distances.len(), self.directions.len());
+        assert!(distances.iter().all(|&d| d > 0.0));
+        self.distances = Some(distances);
+        self
+    }
+
+    /// Custom layout from `(azimuth, elevation)` pairs in radians.
+    ///
+    /// The azimuth is measured counter-clockwise from the front, so that positive angles are to
+    /// the left. The elevation is positive above the horizontal plane.
+    pub fn from_angles(angles: &[(f32, f32)]) -> Self {
+        SpeakerLayout::new(
+            angles
+                .iter()
+                .map(|&(azimuth, elevation)| direction(azimuth, elevation))
+                .collect(),
+        )
+    }
+
+    /// Four speakers at the corners of a square.
+    ///
+    /// Channel order: front left, front right, back left, back right.
+    pub fn quad() -> Self {
+        SpeakerLayout::from_degrees(&[(45.0, 0.0), (-45.0, 0.0), (135.0, 0.0), (-135.0, 0.0)])
+    }
+
+    /// ITU-R BS.775 5.0 surround layout.
+    ///
+    /// Channel order: left, right, center, left surround, right surround.
+    pub fn surround_5_0() -> Self {
+        SpeakerLayout::from_degrees(&[
+            (30.0, 0.0),
+            (-30.0, 0.0),
+            (0.0, 0.0),
+            (110.0, 0.0),
+            (-110.0, 0.0),
+        ])
+    }
+
+    /// 7.0 surround layout.
+    ///
+    /// Channel order: left, right, center, left back, right back, left side, right side.
+    pub fn surround_7_0() -> Self {
+        SpeakerLayout::from_degrees(&[
+            (30.0, 0.0),
+            (-30.0, 0.0),
+            (0.0, 0.0),
+            (150.0, 0.0),
+            (-150.0, 0.0),
+            (90.0, 0.0),
+            (-90.0, 0.0),
+        ])
+    }
+
+    /// 7.0.4 surround layout with four height speakers.
+    ///
+    /// Channel order: the 7.0 layout, followed by upper front left, upper front right, upper
+    /// back left, and upper back right at 45º elevation.
+    pub fn surround_7_0_4() -> Self {
+        SpeakerLayout::from_degrees(&[
+            (30.0, 0.0),
+            (-30.0, 0.0),
+            (0.0, 0.0),
+            (150.0, 0.0),
+            (-150.0, 0.0),
+            (90.0, 0.0),
+            (-90.0, 0.0),
+            (45.0, 45.0),
+            (-45.0, 45.0),
+            (135.0, 45.0),
+            (-135.0, 45.0),
+        ])
+    }
+
+    /// Eight speakers in a regular horizontal ring.
+    ///
+    /// The first speaker is at 22.5º to the left of the front, the others follow
+    /// counter-clockwise.
+    pub fn octagon() -> Self {
+        let angles: Vec<_> = (0..8).map(|i| (22.5 + 45.0 * i as f32, 0.0)).collect();
+        SpeakerLayout::from_degrees(&angles)
+    }
+
+    /// Eight speakers at the corners of a cube.
+    ///
+    /// Channel order: upper front left, upper front right, upper back left, upper back right,
+    /// followed by the lower speakers in the same order.
+    pub fn cube() -> Self {
+        SpeakerLayout::new(vec![
+            [-1.0, 1.0, 1.0],
+            [1.0, 1.0, 1.0],
+            [-1.0, -1.0, 1.0],
+            [1.0, -1.0, 1.0],
+            [-1.0, 1.0, -1.0],
+            [1.0, 1.0, -1.0],
+            [-1.0, -1.0, -1.0],
+            [1.0, -1.0, -1.0],
+        ])
+    }
+
+    /// Normalized speaker directions
+    pub fn directions(&self) -> &[[f32; 3]] {
+        &self.directions
+    }
+
+    /// Speaker distances, if specified
+    pub fn distances(&self) -> Option<&[f32]> {
+        self.distances.as_deref()
+    }
+
+    /// Number of speakers
+    pub fn len(&self) -> usize {
+        self.directions.len()
+    }
+
+    /// A layout always has at least one speaker
+    pub fn is_empty(&self) -> bool {
+        self.directions.is_empty()
+    }
+
+    fn from_degrees(angles: &[(f32, f32)]) -> Self {
+        let to_rad = PI / 180.0;
+        SpeakerLayout::from_angles(
+            &angles
+                .iter()
+                .map(|&(a, e)| (a * to_rad, e * to_rad))
+                .collect::<Vec<_>>(),
+        )
+    }
+}
+
+/// Unit vector for given azimuth and elevation (in radians)
+fn direction(azimuth: f32, elevation: f32) -> [f32; 3] {
+    [
+        -azimuth.sin() * elevation.cos(),
+        azimuth.cos() * elevation.cos(),
+        elevation.sin(),
+    ]
+}
+
+/// How the decoding matrix is computed from the speaker layout
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DecodingMethod {
+    /// Each speaker picks up the sound field in its own direction, normalized by the layout's
+    /// energy in each channel. Robust, but accurate only for regular layouts.
+    Sampling,
+
+    /// The speaker signals reproduce the *B-format* sound field as closely as possible, which
+    /// is obtained by inverting the encoding matrix of the layout. Accurate for layouts that
+    /// cover the sound field well, but can produce excessive levels for irregular layouts.
+    ModeMatching,
+
+    /// All-round ambisonic decoding (AllRAD): the sound field is decoded to a dense, regular
+    /// virtual layout, which is then panned to the real speakers with VBAP. Works well for
+    /// irregular layouts, such as surround setups with height speakers.
+    AllRad,
+}
+
+/// Loudspeaker playback configuration
+///
+/// Decodes the sound field of given ambisonic order for a speaker layout. The decoding matrix
+/// is computed when the renderer is constructed.
+#[derive(Debug, Clone)]
+pub struct SpeakerConfig {
+    layout: SpeakerLayout,
+    method: DecodingMethod,
+    order: usize,
+    crossover: Option<f32>,
+    bass_management: Option<BassManagement>,
+    matrix: Option<Vec<Bweights>>,
+    hf_matrix: Option<Vec<Bweights>>,
+}
+
+impl SpeakerConfig {
+    /// Create a configuration for the given layout with default settings
+    pub fn new(layout: SpeakerLayout) -> Self {
+        SpeakerConfig {
+            layout,
+            method: DecodingMethod::ModeMatching,
+            order: 1,
+            crossover: None,
+            bass_management: None,
+            matrix: None,
+            hf_matrix: None,
+        }
+    }
+
+    /// Use precomputed decoding weights instead of computing them from the layout.
+    ///
+    /// With a crossover, `hf_matrix` decodes the high band and `matrix` the low band. Otherwise,
+    /// `matrix` decodes the full band.
+    pub(crate) fn with_matrices(
+        mut self,
+        matrix: Vec<Bweights>,
+        hf_matrix: Option<Vec<Bweights>>,
+    ) -> Self {
+        assert_eq!(matrix.len(), self.layout.len());
+        assert!(hf_matrix
+            .as_ref()
+            .is_none_or(|hf| hf.len() == self.layout.len() && self.crossover.is_some()));
+        self.order = matrix.iter().map(Bweights::order).max().unwrap_or(0);
+        self.matrix = Some(matrix);
+        self.hf_matrix = hf_matrix;
+        self
+    }
+
+    /// Set how the decoding matrix is computed (defaults to mode matching).
+    pub fn with_method(mut self, method: DecodingMethod) -> Self {
+        self.method = method;
+        self
+    }
+
+    /// Set the ambisonic order of the decoded sound field (defaults to 1).
+    ///
+    /// When playing through an `Ambisonic` context, the order is set to the scene's order.
+    /// Decoders loaded from presets keep their own order.
+    pub fn with_order(mut self, order: usize) -> Self {
+        assert!(order <= MAX_ORDER);
+        if self.matrix.is_none() {
+            self.order = order;
+        }
+        self
+    }
+
+    /// Enable dual-band decoding with given crossover frequency in Hz (disabled by default).
+    ///
+    /// Above the crossover, the sound field is weighted for sharper high-frequency localization
+    /// (max-rE). Typical crossover frequencies are between 400 and 700 Hz.
+    pub fn with_dual_band(mut self, crossover: f32) -> Self {
+        assert!(crossover > 0.0);
+        self.crossover = Some(crossover);
+        self
+    }
+
+    /// Enable bass management (disabled by default).
+    ///
+    /// The renderer outputs an additional subwoofer channel, which plays the low frequencies of
+    /// all speakers and the LFE signals of the streams.
+    pub fn with_bass_management(mut self, bass: BassManagement) -> Self {
+        assert!(bass.lfe_channel() <= self.layout.len());
+        self.bass_management = Some(bass);
+        self
+    }
+
+    /// The speaker layout
+    pub fn layout(&self) -> &SpeakerLayout {
+        &self.layout
+    }
+
+    /// The ambisonic order of the decoded sound field
+    pub fn order(&self) -> usize {
+        self.order
+    }
+
+    /// The crossover frequency of dual-band decoding, if enabled
+    pub fn crossover(&self) -> Option<f32> {
+        self.crossover
+    }
+
+    /// Separate decoding weights for the high band, if the decoder was loaded from a preset
+    pub(crate) fn hf_decoder(&self) -> Option<&[Bweights]> {
+        self.hf_matrix.as_deref()
+    }
+
+    /// One set of decoding weights per speaker
+    pub(crate) fn decoder(&self) -> Vec<Bweights> {
+        if let Some(matrix) = &self.matrix {
+            return matrix.clone();
+        }
+        let encoding = encoding_matrix(self.layout.directions(), self.order);
+        let decoding = match self.method {
+            DecodingMethod::Sampling => sampling_decoder(&encoding),
+            DecodingMethod::ModeMatching => encoding.pseudo_inverse(),
+            DecodingMethod::AllRad => allrad_decoder(self.layout.directions(), self.order),
+        };
+        decoder_weights(&decoding, self.order)
+    }
+}
+
+/// Transposed encoding matrix, normalized so that a regular layout reproduces each channel
+/// with unit gain
+fn sampling_decoder(encoding: &Matrix) -> Matrix {
+    let n = encoding.cols();
+    let energy: Vec<f64> = (0..encoding.rows())
+        .map(|c| (0..n).map(|s| encoding[(c, s)] * encoding[(c, s)]).sum())
+        .collect();
+    Matrix::from_fn(n, encoding.rows(), |s, c| {
+        if energy[c] < ENERGY_EPS {
+            0.0
+        } else {
+            encoding[(c, s)] / energy[c]
+        }
+    })
+}
+
+const ENERGY_EPS: f64 = 1e-9;
+
+/// Decode to a virtual layout, and pan the virtual speakers to the real ones
+fn allrad_decoder(directions: &[[f32; 3]], order: usize) -> Matrix {
+    let virtual_layout = spherical_design(ALLRAD_DESIGN_DEGREE);
+    let virtual_dirs: Vec<_> = virtual_layout.iter().map(|&(d, _)| d).collect();
+    let encoding = encoding_matrix(&virtual_dirs, order);
+
+    // sampling decoder of the virtual layout, exact thanks to the quadrature weights
+    let energy: Vec<f64> = (0..encoding.rows())
+        .map(|c| {
+            virtual_layout
+                .iter()
+                .enumerate()
+                .map(|(v, &(_, w))| w * encoding[(c, v)] * encoding[(c, v)])
+                .sum()
+        })
+        .collect();
+    let virtual_decoder = Matrix::from_fn(virtual_dirs.len(), encoding.rows(), |v, c| {
+        virtual_layout[v].1 * encoding[(c, v)] / energy[c]
+    });
+
+    let vbap = Vbap::new(directions);
+    let mut gains = vec![0.0; directions.len()];
+    let mut panning = Matrix::zeros(directions.len(), virtual_dirs.len());
+    for (v, &d) in virtual_dirs.iter().enumerate() {
+        vbap.gains(d, &mut gains);
+        for (s, &g) in gains.iter().enumerate() {
+            panning[(s, v)] = g as f64;
+        }
+    }
+
+    &panning * &virtual_decoder
+}
+
+/// The virtual layout integrates spherical harmonics exactly up to this degree, which
+/// corresponds to a spherical t-design with t = 21.
+const ALLRAD_DESIGN_DEGREE: usize = 21;
+
+/// Directions and quadrature weights (summing to 1) that integrate polynomials on the sphere
+/// exactly up to degree `t`.
+///
+/// Combines Gauss-Legendre nodes in elevation with equally spaced azimuths. Unlike a t-design,
+/// the weights are not uniform, but the quadrature is just as exact.
+fn spherical_design(t: usize) -> Vec<([f32; 3], f64)> {
+    let n_rings = t / 2 + 1;
+    let n_azimuths = t + 1;
+    let mut points = Vec::with_capacity(n_rings * n_azimuths);
+    for (z, w) in gauss_legendre(n_rings) {
+        let r = (1.0 - z * z).sqrt();
+        for k in 0..n_azimuths {
+            let phi = 2.0 * std::f64::consts::PI * k as f64 / n_azimuths as f64;
+            points.push((
+                [(r * phi.cos()) as f32, (r * phi.sin()) as f32, z as f32],
+                w / (2.0 * n_azimuths as f64),
+            ));
+        }
+    }
+    points
+}
+
+/// Nodes and weights of the `n`-point Gauss-Legendre quadrature on [-1, 1]
+fn gauss_legendre(n: usize) -> Vec<(f64, f64)> {
+    (0..n)
+        .map(|i| {
+            let mut x = (std::f64::consts::PI * (i as f64 + 0.75) / (n as f64 + 0.5)).cos();
+            loop {
+                let (p, dp) = legendre(n, x);
+                let dx = p / dp;
+                x -= dx;
+                if dx.abs() < 1e-15 {
+                    break;
+                }
+            }
+            let (_, dp) = legendre(n, x);
+            (x, 2.0 / ((1.0 - x * x) * dp * dp))
+        })
+        .collect()
+}
+
+/// Legendre polynomial of degree `n` and its derivative at `x`
+fn legendre(n: usize, x: f64) -> (f64, f64) {
+    let (mut p0, mut p1) = (1.0, x);
+    for k in 2..=n {
+        let p2 = ((2 * k - 1) as f64 * x * p1 - (k - 1) as f64 * p0) / k as f64;
+        p0 = p1;
+        p1 = p2;
+    }
+    let dp = n as f64 * (x * p1 - p0) / (x * x - 1.0);
+    (p1, dp)
+}
+
+/// Convert the rows of a decoding matrix (speakers × channels) to weights
+fn decoder_weights(decoding: &Matrix, order: usize) -> Vec<Bweights> {
+    debug_assert_eq!(decoding.cols(), n_channels(order));
+    (0..decoding.rows())
+        .map(|s| {
+            let mut components = [0.0; MAX_CHANNELS];
+            for (c, x) in components.iter_mut().enumerate().take(decoding.cols()) {
+                *x = decoding[(s, c)] as f32;
+            }
+            Bweights::from_components(order, components)
+        })
+        .collect()
+}
+
+/// Render a *B-format* stream to an arbitrary loudspeaker layout.
+///
+/// Produces one interleaved channel per speaker, in the order of the layout. With bass
+/// management, the subwoofer channel is inserted at its configured position.
+pub struct BstreamSpeakerRenderer<I> {
+    input: I,
+    speakers: Vec<Bweights>,
+    hf_speakers: Option<Vec<Bweights>>,
+    dual_band: Option<DualBand>,
+    output: SpeakerOutput,
+    mains: Vec<f32>,
+    next_channel: usize,
+}
+
+impl<I> BstreamSpeakerRenderer<I>
+where
+    I: Source<Item = Bformat>,
+{
+    /// Construct a new speaker renderer
+    pub fn new(input: I, config: SpeakerConfig) -> Self {
+        let speakers = config.decoder();
+        let hf_speakers = config.hf_decoder().map(<[_]>::to_vec);
+        let dual_band = config.crossover.map(|f| match hf_speakers {
+            // the high band is weighted by its own matrix
+            Some(_) => {
+                DualBand::with_weights(config.order, [1.0; MAX_ORDER + 1], f, input.sample_rate())
+            }
+            None => DualBand::new(config.order, f, input.sample_rate()),
+        });
+        let output =
+            SpeakerOutput::new(&config.layout, config.bass_management, input.sample_rate());
+        BstreamSpeakerRenderer {
+            input,
+            dual_band,
+            mains: vec![0.0; speakers.len()],
+            next_channel: output.frame.len(),
+            output,
+            speakers,
+            hf_speakers,
+        }
+    }
+}
+
+impl<I> Source for BstreamSpeakerRenderer<I>
+where
+    I: Source<Item = Bformat>,
+{
+    #[inline(always)]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    #[inline(always)]
+    fn channels(&self) -> u16 {
+        self.output.frame.len() as u16
+    }
+
+    #[inline(always)]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline(always)]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+impl<I> Iterator for BstreamSpeakerRenderer<I>
+where
+    I: Source<Item = Bformat>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_channel >= self.output.frame.len() {
+            let mut sample = self.input.next()?;
+            match (&mut self.dual_band, &self.hf_speakers) {
+                (Some(db), Some(hf_speakers)) => {
+                    let (low, high) = db.split(sample);
+                    let speakers = self.speakers.iter().zip(hf_speakers);
+                    for (out, (lf, hf)) in self.mains.iter_mut().zip(speakers) {
+                        *out = lf.dot(low) + hf.dot(high);
+                    }
+                    // bass management takes W and the LFE signal from the low band
+                    sample = low;
+                }
+                (db, _) => {
+                    if let Some(db) = db {
+                        sample = db.process(sample);
+                    }
+                    for (out, speaker) in self.mains.iter_mut().zip(&self.speakers) {
+                        *out = speaker.dot(sample);
+                    }
+                }
+            }
+            self.output
+                .process(sample.components()[0], sample.lfe(), &mut self.mains);
+            self.next_channel = 0;
+        }
+
+        let x = self.output.frame[self.next_channel];
+        self.next_channel += 1;
+        Some(x)
+    }
+}
+
+/// Turns speaker signals into output frames with bass management and distance compensation
+pub(crate) struct SpeakerOutput {
+    bass: Option<BassManager>,
+    compensation: Option<DistanceCompensation>,
+    frame: Vec<f32>,
+}
+
+impl SpeakerOutput {
+    pub fn new(layout: &SpeakerLayout, bass: Option<BassManagement>, sample_rate: u32) -> Self {
+        let compensation = layout
+            .distances()
+            .map(|d| DistanceCompensation::new(d, sample_rate));
+        let bass = bass.map(|bm| BassManager::new(bm, layout.len(), sample_rate));
+        SpeakerOutput {
+            frame: vec![0.0; layout.len() + bass.is_some() as usize],
+            bass,
+            compensation,
+        }
+    }
+
+    /// Number of output channels, including the subwoofer
+    pub fn channels(&self) -> usize {
+        self.frame.len()
+    }
+
+    /// The last processed frame
+    pub fn frame(&self) -> &[f32] {
+        &self.frame
+    }
+
+    /// Process one sample of each speaker, given the omnidirectional component `w` and the LFE
+    /// signal for bass management.
+    ///
+    /// The speaker signals are modified in place. They are aligned before bass management, so
+    /// that the subwoofer plays the bass of each speaker with that speaker's delay. The subwoofer
+    /// itself is assumed to be as far away as the farthest speaker.
+    #[inline]
+    pub fn process(&mut self, w: f32, lfe: f32, mains: &mut [f32]) {
+        if let Some(comp) = &mut self.compensation {
+            comp.process(mains);
+        }
+        let sub = self
+            .bass
+            .as_mut()
+            .map(|bm| (bm.config().lfe_channel(), bm.process(w, lfe, mains)));
+        match sub {
+            Some((channel, x)) => {
+                self.frame[..channel].copy_from_slice(&mains[..channel]);
+                self.frame[channel] = x;
+                self.frame[channel + 1..].copy_from_slice(&mains[channel..]);
+            }
+            None => self.frame.copy_from_slice(mains),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn speaker_signals(config: &SpeakerConfig, dir: [f32; 3]) -> Vec<f32> {
+        let order = config.order;
+        let sample = Bweights::from_direction(dir, order).scale(1.0);
+        config.decoder().iter().map(|w| w.dot(sample)).collect()
+    }
+
+    fn loudest(signals: &[f32]) -> usize {
+        (0..signals.len())
+            .max_by(|&i, &j| signals[i].partial_cmp(&signals[j]).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn plane_wave_is_loudest_in_matching_speaker() {
+        for layout in &[
+            SpeakerLayout::quad(),
+            SpeakerLayout::surround_5_0(),
+            SpeakerLayout::surround_7_0(),
+            SpeakerLayout::octagon(),
+            SpeakerLayout::cube(),
+            SpeakerLayout::surround_7_0_4(),
+        ] {
+            // at first order, AllRAD spreads the field too widely for the closely spaced front
+            // speakers of surround layouts
+            for &(method, order) in &[
+                (DecodingMethod::Sampling, 1),
+                (DecodingMethod::ModeMatching, 1),
+                (DecodingMethod::AllRad, 3),
+            ] {
+                let config = SpeakerConfig::new(layout.clone())
+                    .with_method(method)
+                    .with_order(order);
+                for (i, &dir) in layout.directions().iter().enumerate() {
+                    let signals = speaker_signals(&config, dir);
+                    assert_eq!(loudest(&signals), i, "{:?} {:?}", method, layout);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn regular_layouts_preserve_amplitude() {
+        for layout in &[SpeakerLayout::octagon(), SpeakerLayout::cube()] {
+            for &method in &[DecodingMethod::Sampling, DecodingMethod::ModeMatching] {
+                let config = SpeakerConfig::new(layout.clone()).with_method(method);
+                let sum: f32 = speaker_signals(&config, [0.3, 0.8, 0.1]).iter().sum();
+                assert!((sum - 1.0).abs() < 1e-4, "{}", sum);
+            }
+        }
+    }
+
+    #[test]
+    fn higher_orders_localize_more_sharply() {
+        let layout = SpeakerLayout::from_angles(
+            &(0..16)
+                .map(|i| (i as f32 * PI / 8.0, 0.0))
+                .collect::<Vec<_>>(),
+        );
+        let first = speaker_signals(&SpeakerConfig::new(layout.clone()), [0.0, 1.0, 0.0]);
+        let third = speaker_signals(&SpeakerConfig::new(layout).with_order(3), [0.0, 1.0, 0.0]);
+        assert!(third[0] > first[0]);
+
+        // fraction of the energy in the front speaker and its neighbours
+        let focus = |g: &[f32]| {
+            let front = g[0] * g[0] + g[1] * g[1] + g[15] * g[15];
+            front / g.iter().map(|x| x * x).sum::<f32>()
+        };
+        assert!(focus(&third) > focus(&first));
+    }
+
+    #[test]
+    fn spherical_design_integrates_exactly() {
+        let design = spherical_design(ALLRAD_DESIGN_DEGREE);
+        let total: f64 = design.iter().map(|&(_, w)| w).sum();
+        assert!((total - 1.0).abs() < 1e-12);
+
+        // mean of z^2 and x^4 over the sphere
+        let z2: f64 = design.iter().map(|&(d, w)| w * (d[2] * d[2]) as f64).sum();
+        let x4: f64 = design.iter().map(|&(d, w)| w * (d[0] as f64).powi(4)).sum();
+        assert!((z2 - 1.0 / 3.0).abs() < 1e-6);
+        assert!((x4 - 1.0 / 5.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn allrad_keeps_energy_constant_on_irregular_layouts() {
+        let spread = |method| {
+            let config = SpeakerConfig::new(SpeakerLayout::surround_7_0_4())
+                .with_method(method)
+                .with_order(3);
+            let energies: Vec<f32> = (0..36)
+                .map(|i| {
+                    let a = i as f32 * PI / 18.0;
+                    let g = speaker_signals(&config, [a.sin(), a.cos(), 0.2]);
+                    g.iter().map(|x| x * x).sum()
+                })
+                .collect();
+            let max = energies.iter().cloned().fold(0.0, f32::max);
+            let min = energies.iter().cloned().fold(f32::INFINITY, f32::min);
+            max / min
+        };
+
+        let allrad = spread(DecodingMethod::AllRad);
+        assert!(allrad < 1.5, "{}", allrad);
+        assert!(allrad < spread(DecodingMethod::ModeMatching));
+    }
+
+    #[test]
+    fn renderer_aligns_speakers_at_different_distances() {
+        use crate::constants::SPEED_OF_SOUND;
+        use crate::sources::Constant;
+
+        let d = SPEED_OF_SOUND / 1000.0;
+        let layout = SpeakerLayout::from_positions(vec![
+            [-10.0 * d, 10.0 * d, 0.0],
+            [5.0 * d, 5.0 * d, 0.0],
+            [-5.0 * d, -5.0 * d, 0.0],
+            [10.0 * d, -10.0 * d, 0.0],
+        ]);
+        let (input, _) =
+            crate::bstream::bstream(Constant::new(1.0, 1000), crate::BstreamConfig::new());
+        let renderer = BstreamSpeakerRenderer::new(input, SpeakerConfig::new(layout));
+        let frames: Vec<f32> = renderer.take(4 * 10).collect();
+
+        // the close speakers start later and quieter
+        let first_nonzero = |ch: usize| (0..10).find(|&i| frames[4 * i + ch] != 0.0).unwrap();
+        assert_eq!(first_nonzero(0), 0);
+        assert!(first_nonzero(1) > 0);
+        assert!((frames[4 * 9 + 1] / frames[4 * 9] - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn renderer_interleaves_speaker_channels() {
+        let input = crate::sources::Constant::new(1.0, 1000);
+        let (bstream, _) = crate::bstream::bstream(
+            input,
+            crate::bstream::BstreamConfig::new().with_position([-1.0, 1.0, 0.0]),
+        );
+        let mut renderer =
+            BstreamSpeakerRenderer::new(bstream, SpeakerConfig::new(SpeakerLayout::quad()));
+        assert_eq!(renderer.channels(), 4);
+
+        let frame: Vec<_> = renderer.by_ref().skip(4 * 2000).take(4).collect();
+        assert_eq!(loudest(&frame), 0);
+        assert!(frame[3] < frame[1] && frame[3] < frame[2]);
+    }
+
+    #[test]
+    fn bass_management_inserts_subwoofer_channel() {
+        use crate::constants::SPEED_OF_SOUND;
+
+        let d = SPEED_OF_SOUND / 1000.0;
+        let bass = BassManagement::new(3).with_crossover(100.0);
+        let distant = SpeakerLayout::surround_5_0().with_distances(vec![10.0 * d; 5]);
+        let close_center = SpeakerLayout::surround_5_0().with_distances(vec![
+            10.0 * d,
+            10.0 * d,
+            5.0 * d,
+            10.0 * d,
+            10.0 * d,
+        ]);
+
+        for layout in &[SpeakerLayout::surround_5_0(), distant, close_center.clone()] {
+            let input = crate::sources::Constant::new(1.0, 1000);
+            let (bstream, _) = crate::bstream::bstream(
+                input,
+                crate::bstream::BstreamConfig::new()
+                    .with_position([0.0, 1.0, 0.0])
+                    .with_lfe_send(1.0),
+            );
+            let config = SpeakerConfig::new(layout.clone()).with_bass_management(bass);
+            let mut renderer = BstreamSpeakerRenderer::new(bstream, config);
+            assert_eq!(renderer.channels(), 6);
+
+            // a constant signal ends up entirely in the subwoofer
+            let frame: Vec<_> = renderer.by_ref().skip(6 * 2000).take(6).collect();
+            assert_eq!(loudest(&frame), 3);
+            assert!(frame
+                .iter()
+                .enumerate()
+                .all(|(i, x)| i == 3 || x.abs() < 1e-2));
+        }
+
+        // the bass of the close center speaker is delayed like the speaker itself
+        let mut output = SpeakerOutput::new(&close_center, Some(bass), 1000);
+        let frames: Vec<_> = (0..10)
+            .map(|i| {
+                let x = if i == 0 { 1.0 } else { 0.0 };
+                output.process(0.0, 0.0, &mut [0.0, 0.0, x, 0.0, 0.0]);
+                output.frame().to_vec()
+            })
+            .collect();
+        let onset = |ch: usize| frames.iter().position(|f| f[ch] != 0.0);
+        assert_eq!(onset(2), Some(5));
+        assert_eq!(onset(3), Some(5));
+    }
+}
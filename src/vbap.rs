@@ -0,0 +1,306 @@
+//! Vector base amplitude panning (VBAP) on a triangulated loudspeaker hull.
+
+use crate::linalg::Matrix;
+
+/// Triangulation of a loudspeaker layout for vector base amplitude panning
+///
+/// The convex hull of the speaker directions is split into triangles. A source is panned between
+/// the three speakers of the triangle that contains its direction. Gaps in the layout, such as
+/// the missing floor in most setups, are closed with imaginary speakers whose signals are
+/// discarded or spread over the neighbouring real speakers.
+pub(crate) struct Vbap {
+    n_real: usize,
+    triangles: Vec<Triangle>,
+    /// real speakers that share a triangle with each imaginary speaker
+    neighbours: Vec<Vec<usize>>,
+}
+
+struct Triangle {
+    speakers: [usize; 3],
+    /// inverse of the matrix with the speaker directions in its columns
+    inverse: [[f32; 3]; 3],
+}
+
+impl Vbap {
+    /// Triangulate the given (normalized) speaker directions
+    pub fn new(directions: &[[f32; 3]]) -> Self {
+        let mut speakers = directions.to_vec();
+        for &pole in &[[0.0, 0.0, 1.0], [0.0, 0.0, -1.0]] {
+            let covered = directions
+                .iter()
+                .any(|d| dot(*d, pole) > IMAGINARY_SPEAKER_COS);
+            if !covered {
+                speakers.push(pole);
+            }
+        }
+
+        let triangles = convex_hull(&speakers)
+            .into_iter()
+            .filter_map(|speakers_idx| {
+                let m = Matrix::from_fn(3, 3, |i, j| speakers[speakers_idx[j]][i] as f64);
+                let inv = m.inverse()?;
+                let mut inverse = [[0.0; 3]; 3];
+                for (i, row) in inverse.iter_mut().enumerate() {
+                    for (j, x) in row.iter_mut().enumerate() {
+                        *x = inv[(i, j)] as f32;
+                    }
+                }
+                Some(Triangle {
+                    speakers: speakers_idx,
+                    inverse,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let n_real = directions.len();
+        let neighbours = (n_real..speakers.len())
+            .map(|imaginary| {
+                let mut real: Vec<_> = triangles
+                    .iter()
+                    .filter(|tri| tri.speakers.contains(&imaginary))
+                    .flat_map(|tri| tri.speakers.iter().cloned())
+                    .filter(|&s| s < n_real)
+                    .collect();
+                real.sort_unstable();
+                real.dedup();
+                real
+            })
+            .collect();
+
+        Vbap {
+            n_real,
+            triangles,
+            neighbours,
+        }
+    }
+
+    /// Compute the energy-normalized gains of the real speakers for a source in `direction`.
+    ///
+    /// The gains of imaginary speakers are dropped, so sources close to a gap in the layout
+    /// lose energy.
+    pub fn gains(&self, direction: [f32; 3], out: &mut [f32]) {
+        assert_eq!(out.len(), self.n_real);
+        for g in out.iter_mut() {
+            *g = 0.0;
+        }
+        if let Some((speakers, g)) = self.triangle_gains(direction) {
+            for (&s, gi) in speakers.iter().zip(&g) {
+                if s < self.n_real {
+                    out[s] = *gi;
+                }
+            }
+        }
+    }
+
+    /// Compute the gains of the real speakers for a source in `direction`, with unit energy.
+    ///
+    /// The energy of each imaginary speaker is spread evenly over its neighbouring real
+    /// speakers, so sources in gaps of the layout keep their loudness.
+    pub fn gains_preserving_energy(&self, direction: [f32; 3], out: &mut [f32]) {
+        assert_eq!(out.len(), self.n_real);
+        for g in out.iter_mut() {
+            *g = 0.0;
+        }
+        if let Some((speakers, g)) = self.triangle_gains(direction) {
+            for (&s, gi) in speakers.iter().zip(&g) {
+                if s < self.n_real {
+                    out[s] += gi * gi;
+                } else {
+                    let neighbours = &self.neighbours[s - self.n_real];
+                    for &n in neighbours {
+                        out[n] += gi * gi / neighbours.len() as f32;
+                    }
+                }
+            }
+            for g in out.iter_mut() {
+                *g = g.sqrt();
+            }
+        }
+    }
+
+    /// Speakers of the triangle that contains `direction`, and their energy-normalized gains.
+    ///
+    /// Indices from the number of real speakers on refer to imaginary speakers.
+    pub fn triangle_gains(&self, direction: [f32; 3]) -> Option<([usize; 3], [f32; 3])> {
+        let tri_gains = self.triangles.iter().find_map(|tri| {
+            let mut g = [0.0; 3];
+            for (gi, row) in g.iter_mut().zip(&tri.inverse) {
+                *gi = dot(*row, direction);
+            }
+            if g.iter().all(|&gi| gi >= -INSIDE_EPS) {
+                Some((tri, g))
+            } else {
+                None
+            }
+        });
+
+        let (tri, g) = tri_gains?;
+        let g = [g[0].max(0.0), g[1].max(0.0), g[2].max(0.0)];
+        let norm = (g[0] * g[0] + g[1] * g[1] + g[2] * g[2]).sqrt();
+        if norm == 0.0 {
+            return None;
+        }
+        Some((tri.speakers, [g[0] / norm, g[1] / norm, g[2] / norm]))
+    }
+}
+
+/// Add an imaginary speaker at a pole if no real speaker is within 60º of it.
+const IMAGINARY_SPEAKER_COS: f32 = 0.5;
+
+const INSIDE_EPS: f32 = 1e-5;
+
+/// Tolerance for points that lie on a hull face
+const HULL_EPS: f32 = 1e-5;
+
+/// Triangular faces of the convex hull of points on the unit sphere that face away from the
+/// listener in the center.
+///
+/// If the points cover only part of the sphere, faces on the side of the hull that faces the
+/// listener are dropped. Brute force, but speaker layouts are small. Coplanar points may produce
+/// overlapping triangles, which does no harm since `gains` uses the first triangle that contains
+/// a direction.
+fn convex_hull(points: &[[f32; 3]]) -> Vec<[usize; 3]> {
+    let n = points.len();
+    let mut faces = vec![];
+    for i in 0..n {
+        for j in i + 1..n {
+            for k in j + 1..n {
+                let normal = cross(sub(points[j], points[i]), sub(points[k], points[i]));
+                let len = dot(normal, normal).sqrt();
+                if len < HULL_EPS {
+                    continue;
+                }
+                let normal = [normal[0] / len, normal[1] / len, normal[2] / len];
+                let offset = dot(normal, points[i]);
+
+                let mut above = false;
+                let mut below = false;
+                for (l, &p) in points.iter().enumerate() {
+                    if l == i || l == j || l == k {
+                        continue;
+                    }
+                    let d = dot(normal, p) - offset;
+                    above |= d > HULL_EPS;
+                    below |= d < -HULL_EPS;
+                }
+
+                // a face of the hull has all other points on one side, and faces away from the
+                // listener, which is on the same side
+                let facing_away = if above {
+                    offset < 0.0
+                } else if below {
+                    offset > 0.0
+                } else {
+                    true
+                };
+                if !(above && below) && offset.abs() > HULL_EPS && facing_away {
+                    faces.push([i, j, k]);
+                }
+            }
+        }
+    }
+    faces
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalized(d: [f32; 3]) -> [f32; 3] {
+        let l = dot(d, d).sqrt();
+        [d[0] / l, d[1] / l, d[2] / l]
+    }
+
+    #[test]
+    fn source_at_speaker_uses_only_that_speaker() {
+        let layout: Vec<_> = [
+            [-1.0, 1.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [0.0, -1.0, 0.0],
+            [0.0, 1.0, 1.0],
+        ]
+        .iter()
+        .map(|&d| normalized(d))
+        .collect();
+        let vbap = Vbap::new(&layout);
+        let mut g = vec![0.0; 4];
+        for (i, &d) in layout.iter().enumerate() {
+            vbap.gains(d, &mut g);
+            for (j, &gj) in g.iter().enumerate() {
+                let expected = if i == j { 1.0 } else { 0.0 };
+                assert!((gj - expected).abs() < 1e-4, "{} {:?}", i, g);
+            }
+        }
+    }
+
+    #[test]
+    fn partial_layout_uses_outer_faces() {
+        // a cap of speakers above the front, which adds an imaginary speaker at the bottom
+        let layout: Vec<_> = [
+            [-0.3, 1.0, 1.5],
+            [0.3, 1.0, 1.5],
+            [-0.3, 1.0, 0.9],
+            [0.3, 1.0, 0.9],
+            [0.0, 1.0, 1.2],
+        ]
+        .iter()
+        .map(|&d| normalized(d))
+        .collect();
+        let vbap = Vbap::new(&layout);
+
+        // the faces from the imaginary speaker to the upper speakers must not catch sources
+        // below them
+        let (speakers, _) = vbap.triangle_gains(normalized([0.0, 1.0, 1.0])).unwrap();
+        assert!(speakers.iter().all(|&s| s < layout.len()), "{:?}", speakers);
+    }
+
+    #[test]
+    fn horizontal_layout_pans_between_neighbours() {
+        let layout: Vec<_> = (0..6)
+            .map(|i| {
+                let a = i as f32 * std::f32::consts::PI / 3.0;
+                [a.sin(), a.cos(), 0.0]
+            })
+            .collect();
+        let vbap = Vbap::new(&layout);
+
+        let mut g = vec![0.0; 6];
+        let a = std::f32::consts::PI / 6.0;
+        vbap.gains([a.sin(), a.cos(), 0.0], &mut g);
+        assert!((g[0] - g[1]).abs() < 1e-4);
+        assert!((g[0] * g[0] + g[1] * g[1] - 1.0).abs() < 1e-4);
+        assert!(g[2..].iter().all(|&x| x == 0.0));
+
+        // elevated sources are panned towards the imaginary speaker and lose energy
+        vbap.gains(normalized([0.0, 1.0, 1.0]), &mut g);
+        assert!(g.iter().map(|x| x * x).sum::<f32>() < 0.9);
+
+        // unless the imaginary speaker's energy is spread over its neighbours
+        vbap.gains_preserving_energy(normalized([0.0, 1.0, 1.0]), &mut g);
+        assert!((g.iter().map(|x| x * x).sum::<f32>() - 1.0).abs() < 1e-4);
+        assert!(g[1..].iter().all(|&x| x < g[0]), "{:?}", g);
+
+        vbap.gains_preserving_energy([0.0, 0.0, 1.0], &mut g);
+        assert!(
+            g.iter().all(|&x| (x - 6f32.recip().sqrt()).abs() < 1e-4),
+            "{:?}",
+            g
+        );
+    }
+}
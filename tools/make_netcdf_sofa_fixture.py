@@ -0,0 +1,68 @@
+#!/usr/bin/env python3
+# -*- coding: utf-8 -*-
+
+""" Write tests/data/netcdf4.sofa with the netCDF4 module, which stores it through libhdf5.
+
+The file holds the same six SimpleFreeFieldHRIR measurements as the fixtures written by
+make_sofa_fixtures.py, but its layout is chosen by the reference implementation instead of our own
+reading of the HDF5 format: dimension scales, attributes in dense storage, and a chunked and
+deflated Data.IR with an unlimited measurement dimension. Source positions are spherical, and the
+right ear is delayed by two samples.
+
+Usage: make_netcdf_sofa_fixture.py <output directory>
+"""
+
+import os
+import sys
+
+import numpy as np
+from netCDF4 import Dataset
+
+N_TAPS = 8
+
+# azimuth and elevation in degrees, as in SOFA (azimuth counter-clockwise from the front)
+SPHERICAL = [(0, 0), (90, 0), (180, 0), (270, 0), (0, 90), (0, -90)]
+
+
+def impulse_response(m, ear):
+    return [10 * (m + 1) + ear if n == 0 else 0.01 * (m + 1) * n for n in range(N_TAPS)]
+
+
+def write(filename):
+    with Dataset(filename, 'w', format='NETCDF4') as f:
+        f.Conventions = 'SOFA'
+        f.Version = '1.0'
+        f.SOFAConventions = 'SimpleFreeFieldHRIR'
+        f.SOFAConventionsVersion = '1.0'
+        f.DataType = 'FIR'
+        f.RoomType = 'free field'
+
+        f.createDimension('M', None)
+        f.createDimension('R', 2)
+        f.createDimension('N', N_TAPS)
+        f.createDimension('C', 3)
+        f.createDimension('I', 1)
+
+        positions = f.createVariable('SourcePosition', 'f8', ('M', 'C'))
+        positions.Type = 'spherical'
+        positions.Units = 'degree, degree, metre'
+        positions[:] = [(az, el, 1.5) for az, el in SPHERICAL]
+
+        sample_rate = f.createVariable('Data.SamplingRate', 'f8', ('I',))
+        sample_rate.Units = 'hertz'
+        sample_rate[:] = [48000.0]
+
+        delay = f.createVariable('Data.Delay', 'f8', ('I', 'R'))
+        delay[:] = [[0.0, 2.0]]
+
+        ir = f.createVariable('Data.IR', 'f8', ('M', 'R', 'N'),
+                              zlib=True, chunksizes=(2, 2, N_TAPS))
+        ir[:] = np.array([[impulse_response(m, ear) for ear in range(2)]
+                          for m in range(len(SPHERICAL))])
+
+
+if __name__ == '__main__':
+    if len(sys.argv) != 2:
+        print(__doc__)
+        sys.exit(1)
+    write(os.path.join(sys.argv[1], 'netcdf4.sofa'))
@@ -0,0 +1,330 @@
+#!/usr/bin/env python3
+# -*- coding: utf-8 -*-
+
+""" Write the small SOFA files in tests/data, which are used to test the SOFA loader.
+
+Both files contain the same six SimpleFreeFieldHRIR measurements from the front, left, back,
+right, top, and bottom. They are written with the standard library only, and exercise different
+parts of the HDF5 format:
+
+- symbol_table.sofa: superblock version 0, version 1 object headers, a symbol table group, and
+  a chunked, shuffled, and deflated Data.IR. Source positions are spherical, and the right ear is
+  delayed by two samples.
+- dense_links.sofa: superblock version 2, version 2 object headers with a continuation chunk, a
+  group with links in a fractal heap, and contiguous data. Source positions are cartesian.
+
+tests/data/netcdf4.sofa is written through libhdf5 by make_netcdf_sofa_fixture.py instead.
+
+Usage: make_sofa_fixtures.py <output directory>
+"""
+
+import os
+import struct
+import sys
+import zlib
+
+UNDEF = 0xffffffffffffffff
+N_TAPS = 8
+
+# azimuth and elevation in degrees, as in SOFA (azimuth counter-clockwise from the front)
+SPHERICAL = [(0, 0), (90, 0), (180, 0), (270, 0), (0, 90), (0, -90)]
+CARTESIAN = [(1, 0, 0), (0, 1, 0), (-1, 0, 0), (0, -1, 0), (0, 0, 1), (0, 0, -1)]
+
+
+def impulse_response(m, ear):
+    return [10 * (m + 1) + ear if n == 0 else 0.01 * (m + 1) * n for n in range(N_TAPS)]
+
+
+def lookup3(data, init=0):
+    """ Jenkins' lookup3 hash, as used for HDF5 checksums """
+    mask = 0xffffffff
+
+    def rot(x, k):
+        return ((x << k) | (x >> (32 - k))) & mask
+
+    def mix(a, b, c):
+        a = (a - c) & mask; a ^= rot(c, 4); c = (c + b) & mask
+        b = (b - a) & mask; b ^= rot(a, 6); a = (a + c) & mask
+        c = (c - b) & mask; c ^= rot(b, 8); b = (b + a) & mask
+        a = (a - c) & mask; a ^= rot(c, 16); c = (c + b) & mask
+        b = (b - a) & mask; b ^= rot(a, 19); a = (a + c) & mask
+        c = (c - b) & mask; c ^= rot(b, 4); b = (b + a) & mask
+        return a, b, c
+
+    def final(a, b, c):
+        c ^= b; c = (c - rot(b, 14)) & mask
+        a ^= c; a = (a - rot(c, 11)) & mask
+        b ^= a; b = (b - rot(a, 25)) & mask
+        c ^= b; c = (c - rot(b, 16)) & mask
+        a ^= c; a = (a - rot(c, 4)) & mask
+        b ^= a; b = (b - rot(a, 14)) & mask
+        c ^= b; c = (c - rot(b, 24)) & mask
+        return a, b, c
+
+    length = len(data)
+    a = b = c = (0xdeadbeef + length + init) & mask
+    pos = 0
+    while length - pos > 12:
+        x, y, z = struct.unpack_from('<III', data, pos)
+        a, b, c = mix((a + x) & mask, (b + y) & mask, (c + z) & mask)
+        pos += 12
+    tail = data[pos:]
+    if not tail:
+        return c
+    tail = tail + bytes(12 - len(tail))
+    x, y, z = struct.unpack('<III', tail)
+    a, b, c = final((a + x) & mask, (b + y) & mask, (c + z) & mask)
+    return c
+
+
+class Writer:
+    """ Appends structures to a file image, 8-byte aligned """
+
+    def __init__(self, reserved):
+        self.data = bytearray(reserved)
+
+    def append(self, block):
+        self.data += bytes(-len(self.data) % 8)
+        address = len(self.data)
+        self.data += block
+        return address
+
+    def patch(self, address, block):
+        self.data[address:address + len(block)] = block
+
+
+def offset(x):
+    return struct.pack('<Q', x)
+
+
+def pad8(b):
+    return b + bytes(-len(b) % 8)
+
+
+def float_type(size):
+    if size == 8:
+        props = struct.pack('<HHBBBBI', 0, 64, 52, 11, 0, 52, 1023)
+        return bytes([0x11, 0x20, 63, 0]) + struct.pack('<I', 8) + props
+    props = struct.pack('<HHBBBBI', 0, 32, 23, 8, 0, 23, 127)
+    return bytes([0x11, 0x20, 31, 0]) + struct.pack('<I', 4) + props
+
+
+def string_type(length):
+    return bytes([0x13, 0, 0, 0]) + struct.pack('<I', length)
+
+
+def dataspace_v1(shape):
+    return bytes([1, len(shape), 0, 0, 0, 0, 0, 0]) + b''.join(offset(d) for d in shape)
+
+
+def dataspace_v2(shape):
+    kind = 1 if shape else 0
+    return bytes([2, len(shape), 0, kind]) + b''.join(offset(d) for d in shape)
+
+
+def contiguous(address, size):
+    return bytes([3, 1]) + offset(address) + offset(size)
+
+
+def pack(values, size):
+    return struct.pack('<%d%s' % (len(values), 'd' if size == 8 else 'f'), *values)
+
+
+def object_header_v1(messages):
+    body = b''
+    for kind, data in messages:
+        data = pad8(data)
+        body += struct.pack('<HHB3x', kind, len(data), 0) + data
+    return struct.pack('<BBHII4x', 1, 0, len(messages), 1, len(body)) + body
+
+
+def object_header_v2(messages):
+    body = b''.join(struct.pack('<BHB', kind, len(data), 0) + data for kind, data in messages)
+    # flags: two bytes for the size of chunk 0
+    block = b'OHDR' + bytes([2, 0x01]) + struct.pack('<H', len(body)) + body
+    return block + struct.pack('<I', lookup3(block))
+
+
+def continuation_chunk(messages):
+    body = b''.join(struct.pack('<BHB', kind, len(data), 0) + data for kind, data in messages)
+    block = b'OCHK' + body
+    return block + struct.pack('<I', lookup3(block))
+
+
+def ir_data():
+    return [x for m in range(len(SPHERICAL)) for ear in range(2) for x in impulse_response(m, ear)]
+
+
+def symbol_table_file():
+    w = Writer(96)
+    datasets = {}
+
+    # Data.IR: chunks of two measurements, shuffled and deflated
+    shape = (len(SPHERICAL), 2, N_TAPS)
+    values = ir_data()
+    chunk_len = 2 * 2 * N_TAPS
+    entries = b''
+    for i in range(shape[0] // 2):
+        raw = pack(values[i * chunk_len:(i + 1) * chunk_len], 8)
+        n = len(raw) // 8
+        shuffled = bytes(raw[e * 8 + b] for b in range(8) for e in range(n))
+        compressed = zlib.compress(shuffled, 9)
+        address = w.append(compressed)
+        entries += struct.pack('<II', len(compressed), 0) + b''.join(
+            offset(x) for x in (2 * i, 0, 0, 0)) + offset(address)
+    final_key = struct.pack('<II', 0, 0) + b''.join(offset(x) for x in shape + (0,))
+    btree = w.append(b'TREE' + bytes([1, 0]) + struct.pack('<H', shape[0] // 2) +
+                     offset(UNDEF) + offset(UNDEF) + entries + final_key)
+    layout = bytes([3, 2, 4]) + offset(btree) + struct.pack('<IIII', 2, 2, N_TAPS, 8)
+    filters = bytes([1, 2]) + bytes(6)
+    filters += struct.pack('<HHHHI4x', 2, 0, 0, 1, 8)
+    filters += struct.pack('<HHHHI4x', 1, 0, 0, 1, 9)
+    datasets['Data.IR'] = w.append(object_header_v1([
+        (0x0001, dataspace_v1(shape)),
+        (0x0003, float_type(8)),
+        (0x0008, layout),
+        (0x000B, filters),
+    ]))
+
+    def contiguous_dataset(shape, values, attributes=()):
+        raw = pack(values, 8)
+        address = w.append(raw)
+        messages = [
+            (0x0001, dataspace_v1(shape)),
+            (0x0003, float_type(8)),
+            (0x0008, contiguous(address, len(raw))),
+        ]
+        for name, value in attributes:
+            name = name.encode() + b'\0'
+            dtype = string_type(len(value))
+            space = dataspace_v1(())
+            attr = struct.pack('<BBHHH', 1, 0, len(name), len(dtype), len(space))
+            attr += pad8(name) + pad8(dtype) + pad8(space) + value.encode()
+            messages.append((0x000C, attr))
+        return w.append(object_header_v1(messages))
+
+    positions = [x for az, el in SPHERICAL for x in (az, el, 1.2)]
+    datasets['SourcePosition'] = contiguous_dataset(
+        (len(SPHERICAL), 3), positions, [('Type', 'spherical'), ('Units', 'degree, degree, metre')])
+    datasets['Data.SamplingRate'] = contiguous_dataset((1,), [48000.0])
+    datasets['Data.Delay'] = contiguous_dataset((1, 2), [0.0, 2.0])
+
+    # root group: local heap with the names, one symbol table node, and a B-tree
+    names = sorted(datasets)
+    heap_data = bytearray(8)
+    name_offsets = {}
+    for name in names:
+        name_offsets[name] = len(heap_data)
+        heap_data += pad8(name.encode() + b'\0')
+    heap_data_address = w.append(bytes(heap_data))
+    heap = w.append(b'HEAP' + bytes(4) + offset(len(heap_data)) + offset(UNDEF) +
+                    offset(heap_data_address))
+    snod = b'SNOD' + bytes([1, 0]) + struct.pack('<H', len(names))
+    for name in names:
+        snod += offset(name_offsets[name]) + offset(datasets[name]) + bytes(24)
+    snod = w.append(snod)
+    btree = w.append(b'TREE' + bytes([0, 0]) + struct.pack('<H', 1) + offset(UNDEF) +
+                     offset(UNDEF) + offset(0) + offset(snod) + offset(name_offsets[names[-1]]))
+    root = w.append(object_header_v1([(0x0011, offset(btree) + offset(heap))]))
+
+    superblock = b'\x89HDF\r\n\x1a\n' + bytes([0, 0, 0, 0, 0, 8, 8, 0])
+    superblock += struct.pack('<HHI', 4, 16, 0)
+    superblock += offset(0) + offset(UNDEF) + offset(len(w.data)) + offset(UNDEF)
+    superblock += offset(0) + offset(root) + struct.pack('<II', 1, 0) + offset(btree) + offset(heap)
+    w.patch(0, superblock)
+    return bytes(w.data)
+
+
+def dense_links_file():
+    w = Writer(48)
+    datasets = {}
+
+    def dataset(shape, values, size, attributes=()):
+        raw = pack(values, size)
+        address = w.append(raw)
+        messages = [
+            (0x0001, dataspace_v2(shape)),
+            (0x0003, float_type(size)),
+            (0x0008, contiguous(address, len(raw))),
+        ]
+        attrs = []
+        for name, value in attributes:
+            name = name.encode() + b'\0'
+            dtype = string_type(len(value))
+            space = dataspace_v2(())
+            attr = struct.pack('<BBHHHB', 3, 0, len(name), len(dtype), len(space), 0)
+            attrs.append((0x000C, attr + name + dtype + space + value.encode()))
+        if attrs:
+            # store the attributes in a continuation chunk
+            chunk = continuation_chunk(attrs)
+            messages.append((0x0010, offset(w.append(chunk)) + offset(len(chunk))))
+        return w.append(object_header_v2(messages))
+
+    datasets['Data.IR'] = dataset((len(CARTESIAN), 2, N_TAPS), ir_data(), 4)
+    positions = [1.5 * x for p in CARTESIAN for x in p]
+    datasets['SourcePosition'] = dataset(
+        (len(CARTESIAN), 3), positions, 8, [('Type', 'cartesian'), ('Units', 'metre')])
+    datasets['Data.SamplingRate'] = dataset((1,), [44100.0], 8)
+
+    # fractal heap with one direct block holding the link messages
+    block_size = 512
+    heap_bits = 32
+    links = []
+    for name in sorted(datasets):
+        encoded = name.encode()
+        links.append((name, bytes([1, 0, len(encoded)]) + encoded + offset(datasets[name])))
+    header_address = len(w.data) + (-len(w.data) % 8)
+    header_size = 14 + 12 * 8 + 22 + 10 + 4
+    block_address = header_address + header_size + (-header_size % 8)
+    block = b'FHDB' + bytes([0]) + offset(header_address) + struct.pack('<I', 0)
+    heap_ids = []
+    for name, link in links:
+        heap_ids.append((name, bytes([0]) + struct.pack('<I', len(block)) +
+                         struct.pack('<H', len(link))))
+        block += link
+    used = len(block)
+    block += bytes(block_size - len(block))
+
+    btree_address = block_address + block_size
+    frhp = b'FRHP' + bytes([0]) + struct.pack('<HHBI', 7, 0, 0, block_size)
+    frhp += offset(0) + offset(UNDEF) + offset(block_size - used) + offset(UNDEF)
+    frhp += offset(block_size) + offset(block_size) + offset(block_size) + offset(len(links))
+    frhp += offset(0) * 4
+    frhp += struct.pack('<H', 4) + offset(block_size) + offset(65536)
+    frhp += struct.pack('<HH', heap_bits, 0)
+    frhp += offset(block_address) + struct.pack('<H', 0)
+    frhp += struct.pack('<I', lookup3(frhp))
+    assert w.append(frhp) == header_address and len(frhp) == header_size
+    assert w.append(block) == block_address
+
+    # version 2 B-tree indexing the links by name hash
+    records = sorted((lookup3(name.encode()), heap_id) for name, heap_id in heap_ids)
+    leaf = b'BTLF' + bytes([0, 5]) + b''.join(struct.pack('<I', h) + i for h, i in records)
+    leaf += struct.pack('<I', lookup3(leaf))
+    # the header is 38 bytes long
+    leaf_address = btree_address + 40
+    header = b'BTHD' + bytes([0, 5]) + struct.pack('<IHHBB', 512, 11, 0, 100, 40)
+    header += offset(leaf_address) + struct.pack('<H', len(records)) + offset(len(records))
+    header += struct.pack('<I', lookup3(header))
+    assert w.append(header) == btree_address
+    assert w.append(leaf) == leaf_address
+
+    link_info = bytes([0, 0]) + offset(header_address) + offset(btree_address)
+    root = w.append(object_header_v2([(0x0002, link_info)]))
+
+    superblock = b'\x89HDF\r\n\x1a\n' + bytes([2, 8, 8, 0])
+    superblock += offset(0) + offset(UNDEF) + offset(len(w.data)) + offset(root)
+    superblock += struct.pack('<I', lookup3(superblock))
+    w.patch(0, superblock)
+    return bytes(w.data)
+
+
+if __name__ == '__main__':
+    if len(sys.argv) != 2:
+        print("Usage: make_sofa_fixtures.py <output directory>")
+        sys.exit(-1)
+    outdir = sys.argv[1]
+    with open(os.path.join(outdir, 'symbol_table.sofa'), 'wb') as f:
+        f.write(symbol_table_file())
+    with open(os.path.join(outdir, 'dense_links.sofa'), 'wb') as f:
+        f.write(dense_links_file())